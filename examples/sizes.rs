@@ -2,10 +2,13 @@ use libalpm_rs;
 
 fn main() {
     let i = libalpm_rs::db::new_interner();
-    let (_, dbs) = libalpm_rs::config::extract_relevant_config();
-    let dbs = dbs
-        .keys()
-        .map(|k| libalpm_rs::db::parse_syncdb(i.clone(), k).unwrap())
+    let config = libalpm_rs::config::extract_relevant_config().unwrap();
+    let dbs = config
+        .repos
+        .iter()
+        .map(|r| {
+            libalpm_rs::db::parse_syncdb(i.clone(), &r.name, &libalpm_rs::events::NoEvents).unwrap()
+        })
         .reduce(|mut acc, e| {
             acc.extend(e);
             acc
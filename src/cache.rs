@@ -0,0 +1,279 @@
+//! Keeping the package cache dirs in check, like paccache.
+use crate::db::{Interner, Istr, Package, QuickResolve, Version, parse_pkg_filename};
+use crate::util::digest_matches;
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// What [clean] removes, like the flags of paccache.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CleanOptions {
+    /// `-k`: how many of the newest versions of each package are kept.
+    pub keep_versions: usize,
+    /// `-u`: also remove every version of packages that are not installed.
+    pub remove_uninstalled: bool,
+    /// `-d`: only report what would be removed.
+    pub dry_run: bool,
+}
+
+/// paccache's default of keeping the 3 newest versions.
+impl Default for CleanOptions {
+    fn default() -> Self {
+        Self {
+            keep_versions: 3,
+            remove_uninstalled: false,
+            dry_run: false,
+        }
+    }
+}
+
+/// What [clean] removed, or would have without dry_run.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Cleaned {
+    /// Package files, their signatures are not listed but removed with them.
+    pub removed: Vec<PathBuf>,
+    /// Bytes freed, including the signatures.
+    pub freed: u64,
+}
+
+/// Package files in dir, files not named like packages are skipped.
+fn package_files(dir: &Path) -> io::Result<Vec<(String, Version, String, PathBuf)>> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut ret = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        let Some(filename) = path.file_name().and_then(|f| f.to_str()) else {
+            continue;
+        };
+        if let Some((name, version, arch, _)) = parse_pkg_filename(filename) {
+            ret.push((name.to_owned(), version, arch.to_owned(), path.clone()));
+        }
+    }
+    Ok(ret)
+}
+
+/// Like `paccache -r`: groups the package files of all cache_dirs by name and arch,
+/// orders each group by version, newest first, and removes all but the newest keep_versions.
+/// With remove_uninstalled, packages whose name is not in installed lose all versions.
+/// Signatures are removed along with their packages, other files are left alone.
+/// returns the removed files, sorted, and the space they took up
+pub fn clean(
+    cache_dirs: &[PathBuf],
+    installed: &[&str],
+    options: CleanOptions,
+) -> io::Result<Cleaned> {
+    let mut groups: HashMap<(String, String), Vec<(Version, PathBuf)>> = HashMap::new();
+    for dir in cache_dirs {
+        for (name, version, arch, path) in package_files(dir)? {
+            groups
+                .entry((name, arch))
+                .or_default()
+                .push((version, path));
+        }
+    }
+    let mut cleaned = Cleaned::default();
+    for ((name, _), mut files) in groups {
+        let keep = if options.remove_uninstalled && !installed.contains(&name.as_str()) {
+            0
+        } else {
+            options.keep_versions
+        };
+        files.sort_by(|(a, _), (b, _)| b.cmp(a));
+        cleaned
+            .removed
+            .extend(files.into_iter().skip(keep).map(|(_, path)| path));
+    }
+    cleaned.removed.sort();
+    for path in &cleaned.removed {
+        let mut sig = path.as_os_str().to_owned();
+        sig.push(".sig");
+        for file in [path.as_path(), Path::new(&sig)] {
+            let len = match std::fs::metadata(file) {
+                Ok(m) => m.len(),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            if !options.dry_run {
+                std::fs::remove_file(file)?;
+            }
+            cleaned.freed += len;
+        }
+    }
+    Ok(cleaned)
+}
+
+/// How a cached package file differs from its sync db entry.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Damage {
+    /// Shorter or longer than the CSIZE of the db, usually an interrupted copy.
+    Size { expected: u64, actual: u64 },
+    /// The size is right but the content is not, "sha256" or "md5".
+    Checksum(&'static str),
+}
+
+/// Checks the package files of cache_dirs against the entries with the same FILENAME
+/// in syncdbs, like pacman does before installing them.
+/// Like pacman, md5 is only checked for packages without sha256.
+/// Files no sync db knows are skipped, as there is nothing to check them against.
+/// Damaged files should be removed, so they get downloaded again.
+/// returns the damaged files, sorted
+pub fn verify(
+    i: &Interner,
+    cache_dirs: &[PathBuf],
+    syncdbs: &[(&str, HashMap<Istr, Package>)],
+) -> io::Result<Vec<(PathBuf, Damage)>> {
+    let mut by_filename = HashMap::new();
+    for (_, db) in syncdbs {
+        for p in db.values() {
+            if let Some(filename) = p.filename {
+                by_filename
+                    .entry(filename.r(&i.borrow()).to_owned())
+                    .or_insert(p);
+            }
+        }
+    }
+    let mut damaged = Vec::new();
+    for dir in cache_dirs {
+        for (_, _, _, path) in package_files(dir)? {
+            let filename = path
+                .file_name()
+                .and_then(|f| f.to_str())
+                .unwrap_or_default();
+            let Some(p) = by_filename.get(filename) else {
+                continue;
+            };
+            let actual = std::fs::metadata(&path)?.len();
+            let damage = match (p.csize, &p.sha256sum, &p.md5sum) {
+                (Some(expected), _, _) if expected != actual => {
+                    Some(Damage::Size { expected, actual })
+                }
+                (_, Some(sha256), _) if !digest_matches::<sha2::Sha256>(sha256, &path)? => {
+                    Some(Damage::Checksum("sha256"))
+                }
+                (_, None, Some(md5)) if !digest_matches::<md5::Md5>(md5, &path)? => {
+                    Some(Damage::Checksum("md5"))
+                }
+                _ => None,
+            };
+            if let Some(damage) = damage {
+                damaged.push((path, damage));
+            }
+        }
+    }
+    damaged.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(damaged)
+}
+
+#[test]
+fn test_clean() {
+    let dir = crate::util::test_dir("cache_clean");
+    let (a, b) = (dir.join("a"), dir.join("b"));
+    std::fs::create_dir_all(&a).unwrap();
+    std::fs::create_dir_all(&b).unwrap();
+    let files = [
+        (&a, "foo-1.10-1-x86_64.pkg.tar.zst"),
+        (&a, "foo-1.9-1-x86_64.pkg.tar.zst"),
+        (&b, "foo-1.2-1-x86_64.pkg.tar.zst"),
+        (&b, "foo-1.2-1-x86_64.pkg.tar.zst.sig"),
+        (&a, "foo-1.0-1-any.pkg.tar.zst"),
+        (&a, "bar-1-1-x86_64.pkg.tar.xz"),
+        (&a, "bar-2-1-x86_64.pkg.tar.xz"),
+        (&a, "notes.txt"),
+    ];
+    for (dir, file) in files {
+        std::fs::write(dir.join(file), b"12345").unwrap();
+    }
+    let dirs = [a.clone(), b.clone(), dir.join("missing")];
+    let options = CleanOptions {
+        keep_versions: 2,
+        dry_run: true,
+        ..Default::default()
+    };
+    let dry = clean(&dirs, &["foo"], options).unwrap();
+    assert_eq!(dry.removed, [b.join("foo-1.2-1-x86_64.pkg.tar.zst")]);
+    assert_eq!(dry.freed, 10);
+    assert!(b.join("foo-1.2-1-x86_64.pkg.tar.zst").exists());
+
+    let options = CleanOptions {
+        keep_versions: 1,
+        remove_uninstalled: true,
+        dry_run: false,
+    };
+    let cleaned = clean(&dirs, &["foo"], options).unwrap();
+    assert_eq!(
+        cleaned.removed,
+        [
+            a.join("bar-1-1-x86_64.pkg.tar.xz"),
+            a.join("bar-2-1-x86_64.pkg.tar.xz"),
+            a.join("foo-1.9-1-x86_64.pkg.tar.zst"),
+            b.join("foo-1.2-1-x86_64.pkg.tar.zst"),
+        ]
+    );
+    assert_eq!(cleaned.freed, 25);
+    let mut left: Vec<_> = std::fs::read_dir(&a)
+        .unwrap()
+        .chain(std::fs::read_dir(&b).unwrap())
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect();
+    left.sort();
+    assert_eq!(
+        left,
+        [
+            "foo-1.0-1-any.pkg.tar.zst",
+            "foo-1.10-1-x86_64.pkg.tar.zst",
+            "notes.txt"
+        ]
+    );
+}
+
+#[test]
+fn test_verify() {
+    use crate::db::{new_interner, test_desc};
+    let dir = crate::util::test_dir("cache_verify");
+    let files = [
+        ("good", "package contents"),
+        ("short", "package"),
+        ("corrupt", "package contentz"),
+        ("unknown", "whatever"),
+    ];
+    for (name, contents) in files {
+        std::fs::write(dir.join(format!("{name}-1-1-any.pkg.tar.zst")), contents).unwrap();
+    }
+    let i = new_interner();
+    // sha256 of "package contents"
+    let sha256 = "b9e2b98ba957e07c86e3bdab8f9d3bc4d15d4fd29ed0d02824af172924c0b651";
+    let core: HashMap<_, _> = ["good", "short", "corrupt"]
+        .map(|name| {
+            let file = format!("{name}-1-1-any.pkg.tar.zst");
+            let extra = [
+                ("FILENAME", file.as_str()),
+                ("CSIZE", "16"),
+                ("SHA256SUM", sha256),
+            ];
+            Package::from_str(i.clone(), &test_desc(name, "1-1", &extra)).unwrap()
+        })
+        .into_iter()
+        .map(|p| (p.name, p))
+        .collect();
+    let damaged = verify(&i, std::slice::from_ref(&dir), &[("core", core)]).unwrap();
+    assert_eq!(
+        damaged,
+        [
+            (
+                dir.join("corrupt-1-1-any.pkg.tar.zst"),
+                Damage::Checksum("sha256")
+            ),
+            (
+                dir.join("short-1-1-any.pkg.tar.zst"),
+                Damage::Size {
+                    expected: 16,
+                    actual: 7
+                }
+            ),
+        ]
+    );
+}
@@ -1,12 +1,22 @@
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
 mod parse;
-use parse::Config;
+mod siglevel;
+pub mod write;
+pub use parse::ParseError;
+use parse::{Config, Sections};
+pub use siglevel::{SigCheck, SigLevel, SigRequirement, SigTrust};
 
 // Parses the string as a pacman-flavored ini file.
 // Key-Value pairs outside of an explicit section are retrievable under the "" section.
-fn parse_pacman_config(i: &str) -> Result<Config<'_>, nom::Err<nom::error::Error<&str>>> {
-    parse::sec_kv_map(i).map(|(_, v)| v)
+fn parse_pacman_config(i: &str) -> Result<Config<'_>, ParseError> {
+    parse::parse_all(i, parse::sec_kv_map)
+}
+
+// Like parse_pacman_config, but sections stay in order and repeated sections are kept.
+fn parse_pacman_sections(i: &str) -> Result<Sections<'_>, ParseError> {
+    parse::parse_all(i, parse::sec_kv_list)
 }
 
 fn try_remove_first<T>(mut vec: Vec<T>) -> Option<T> {
@@ -17,91 +27,885 @@ fn try_remove_first<T>(mut vec: Vec<T>) -> Option<T> {
     }
 }
 
+/// Removes key and returns its first value, for options that may only be set once.
+fn single_option<'c>(section: &mut HashMap<&str, Vec<&'c str>>, key: &str) -> Option<&'c str> {
+    section
+        .remove(key)
+        .and_then(try_remove_first)
+        .map(str::trim)
+}
+
+/// Removes key and returns whether it was present, for flags without value like Color.
+fn flag_option(section: &mut HashMap<&str, Vec<&str>>, key: &str) -> bool {
+    section.remove(key).is_some()
+}
+
+/// Removes key and returns all of its whitespace separated values,
+/// for options that take lists and may be repeated.
+fn list_option(section: &mut HashMap<&str, Vec<&str>>, key: &str) -> Vec<String> {
+    section
+        .remove(key)
+        .unwrap_or_default()
+        .iter()
+        .flat_map(|v| v.split_whitespace())
+        .map(ToOwned::to_owned)
+        .collect()
+}
+
+/// Nesting is only needed for mirrorlists including other mirrorlists,
+/// anything deeper is almost certainly a misconfiguration.
+const MAX_INCLUDE_DEPTH: usize = 10;
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, ParseError),
+    /// The file includes itself, possibly through other files.
+    IncludeCycle(PathBuf),
+    /// Includes are nested deeper than [MAX_INCLUDE_DEPTH].
+    IncludeDepth(PathBuf),
+    /// Unknown Usage token
+    Usage(String),
+    /// (key, value) of an option with a value that can not be used.
+    Value(String, String),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(p, e) => write!(f, "could not read {}: {e}", p.display()),
+            ConfigError::Parse(p, e) => write!(f, "could not parse {}: {e}", p.display()),
+            ConfigError::IncludeCycle(p) => write!(f, "{} includes itself", p.display()),
+            ConfigError::IncludeDepth(p) => write!(
+                f,
+                "includes nested deeper than {MAX_INCLUDE_DEPTH} at {}",
+                p.display()
+            ),
+            ConfigError::Usage(token) => write!(f, "invalid Usage {token}"),
+            ConfigError::Value(key, value) => write!(f, "invalid value for {key}: {value}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Problems in a config that do not prevent using it, see [PacmanConfig::validate].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConfigWarning {
+    /// (section, key) that pacman does not know about.
+    UnknownKey(String, String),
+    /// Repo with neither Server nor a working Include, it can not be synced.
+    NoServer(String),
+    /// Repo section declared more than once, only the first one is used.
+    DuplicateSection(String),
+    /// (section, file, error) of an Include that could not be read, it is skipped.
+    UnreachableInclude(String, PathBuf, String),
+    /// (section, token) of a SigLevel token that is ignored.
+    InvalidSigLevel(String, String),
+}
+
+impl std::fmt::Display for ConfigWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigWarning::UnknownKey(section, key) => {
+                write!(f, "unknown key {key} in section [{section}]")
+            }
+            ConfigWarning::NoServer(repo) => write!(f, "repo {repo} has no Server"),
+            ConfigWarning::DuplicateSection(repo) => {
+                write!(f, "repo {repo} is declared more than once")
+            }
+            ConfigWarning::UnreachableInclude(section, p, e) => {
+                write!(f, "include {} in [{section}]: {e}", p.display())
+            }
+            ConfigWarning::InvalidSigLevel(section, token) => {
+                write!(f, "invalid SigLevel {token} in section [{section}]")
+            }
+        }
+    }
+}
+
+/// Options pacman knows but this crate does not interpret, they do not cause warnings.
+const UNINTERPRETED_OPTIONS: &[&str] = &[
+    "LocalFileSigLevel",
+    "RemoteFileSigLevel",
+    "DownloadUser",
+    "Include",
+];
+
+/// Applies SigLevel tokens one at a time, so invalid ones can be skipped with a warning.
+/// Skipping a token never makes the level less strict than intended.
+fn apply_sig_level(
+    level: SigLevel,
+    tokens: &[String],
+    section: &str,
+    warnings: &mut Vec<ConfigWarning>,
+) -> SigLevel {
+    tokens.iter().fold(level, |level, token| {
+        level.apply(&[token]).unwrap_or_else(|token| {
+            warnings.push(ConfigWarning::InvalidSigLevel(section.to_owned(), token));
+            level
+        })
+    })
+}
+
+/// Warns about every key left in section after the known ones have been removed.
+fn unknown_keys(
+    section: &str,
+    keys: HashMap<&str, Vec<&str>>,
+    known: &[&str],
+    warnings: &mut Vec<ConfigWarning>,
+) {
+    let mut unknown: Vec<_> = keys.into_keys().filter(|k| !known.contains(k)).collect();
+    unknown.sort_unstable();
+    warnings.extend(
+        unknown
+            .into_iter()
+            .map(|k| ConfigWarning::UnknownKey(section.to_owned(), k.to_owned())),
+    );
+}
+
+/// Server and CacheServer entries of one section, in order.
+#[derive(Default)]
+struct Servers {
+    servers: Vec<String>,
+    cache_servers: Vec<String>,
+}
+
+impl Servers {
+    /// Takes the Server and CacheServer lines of the section of repo and expands its Includes
+    /// where they appear, so mirrors stay in the order of the file like in pacman.
+    /// Relative includes are resolved relative to dir.
+    fn take(
+        section: &mut HashMap<&str, Vec<&str>>,
+        dir: &Path,
+        repo: &str,
+        stack: &mut Vec<PathBuf>,
+        warnings: &mut Vec<ConfigWarning>,
+    ) -> Result<Self, ConfigError> {
+        let mut lines: Vec<(&str, &str)> = ["Server", "CacheServer", "Include"]
+            .into_iter()
+            .flat_map(|k| {
+                section
+                    .remove(k)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(move |v| (k, v))
+            })
+            .collect();
+        // the values all point into the text of the same file, so their addresses are their order
+        lines.sort_by_key(|(_, v)| v.as_ptr());
+        let mut servers = Self::default();
+        for (k, v) in lines {
+            let v = v.trim();
+            match k {
+                "Server" => servers.servers.push(v.to_owned()),
+                "CacheServer" => servers.cache_servers.push(v.to_owned()),
+                _ => servers.extend(included_servers(&dir.join(v), repo, stack, warnings)?),
+            }
+        }
+        Ok(servers)
+    }
+
+    fn extend(&mut self, other: Self) {
+        self.servers.extend(other.servers);
+        self.cache_servers.extend(other.cache_servers);
+    }
+}
+
+/// Servers of an included file in order, following its Includes recursively.
+/// Relative includes are resolved relative to the including file.
+/// stack holds the files currently being included to detect cycles.
+/// Unreadable files are skipped with a warning, like pacman does.
+fn included_servers(
+    path: &Path,
+    section: &str,
+    stack: &mut Vec<PathBuf>,
+    warnings: &mut Vec<ConfigWarning>,
+) -> Result<Servers, ConfigError> {
+    let unreachable = |e: std::io::Error| {
+        ConfigWarning::UnreachableInclude(section.to_owned(), path.to_owned(), e.to_string())
+    };
+    let canonical = match path.canonicalize() {
+        Ok(c) => c,
+        Err(e) => {
+            warnings.push(unreachable(e));
+            return Ok(Servers::default());
+        }
+    };
+    if stack.contains(&canonical) {
+        return Err(ConfigError::IncludeCycle(path.to_owned()));
+    }
+    if stack.len() >= MAX_INCLUDE_DEPTH {
+        return Err(ConfigError::IncludeDepth(path.to_owned()));
+    }
+    let s = match std::fs::read_to_string(path) {
+        Ok(s) => s,
+        Err(e) => {
+            warnings.push(unreachable(e));
+            return Ok(Servers::default());
+        }
+    };
+    let mut inc = parse_pacman_config(&s).map_err(|e| ConfigError::Parse(path.to_owned(), e))?;
+
+    let mut servers = Servers::default();
+    if let Some(mut prelude) = inc.remove("") {
+        stack.push(canonical);
+        let dir = path.parent().unwrap_or(path);
+        servers = Servers::take(&mut prelude, dir, section, stack, warnings)?;
+        stack.pop();
+    }
+    Ok(servers)
+}
+
+/// Options missing from pacman.conf are set to pacman's defaults.
 #[derive(Clone, Debug)]
 pub struct PacmanConfig {
-    pub ignores: Vec<String>,
-    /// repo -> url
-    pub repo_urls: HashMap<String, String>,
-    pub cache_dir: std::path::PathBuf,
+    pub root_dir: std::path::PathBuf,
+    /// Inside of root_dir unless set explicitly.
     pub db_path: std::path::PathBuf,
+    /// Whether DBPath was set, otherwise db_path follows the root,
+    /// also when it is changed like with `pacman -r`.
+    pub db_path_explicit: bool,
+    pub cache_dirs: Vec<std::path::PathBuf>,
+    /// Inside of root_dir unless set explicitly.
+    pub log_file: std::path::PathBuf,
+    /// Whether LogFile was set, like db_path_explicit.
+    pub log_file_explicit: bool,
+    pub gpg_dir: std::path::PathBuf,
+    /// The system hook dir `/usr/share/libalpm/hooks/` followed by the HookDirs,
+    /// which default to `/etc/pacman.d/hooks/`. Later ones take precedence.
+    pub hook_dirs: Vec<std::path::PathBuf>,
+    pub hold_pkg: Vec<String>,
+    /// IgnorePkg
+    pub ignores: Vec<String>,
+    pub ignore_groups: Vec<String>,
+    pub no_upgrade: Vec<String>,
+    pub no_extract: Vec<String>,
+    /// Allowed package architectures besides any, the first one is the primary.
+    /// auto is already resolved.
+    pub architectures: Vec<String>,
+    /// At least 1, which means sequential downloads.
+    pub parallel_downloads: u32,
+    pub sig_level: SigLevel,
+    pub check_space: bool,
+    pub color: bool,
+    pub no_progress_bar: bool,
+    pub verbose_pkg_lists: bool,
+    pub i_love_candy: bool,
+    pub use_syslog: bool,
+    pub disable_download_timeout: bool,
+    pub disable_sandbox: bool,
+    /// Never empty.
+    pub clean_method: Vec<CleanMethod>,
+    /// External downloader, %o is the output file and %u the url.
+    pub xfer_command: Option<String>,
+    /// In the order of the sections, which is the repo priority.
+    pub repos: Vec<Repo>,
+    warnings: Vec<ConfigWarning>,
 }
 
-/// Reads the pacman config and extracts relevant information.
-/// Resolves one level of Include.
-/// Does not support glob syntax in includes.
-pub fn extract_relevant_config() -> PacmanConfig {
-    let pacman_config = std::fs::read_to_string("/etc/pacman.conf").unwrap();
-    let mut pacman_config = parse_pacman_config(&pacman_config).unwrap();
-    let arch = pacman_config["options"]["Architecture"]
-        .first()
-        .map(|s| s.trim());
-    let arch = match arch {
-        Some("auto") | None => std::env::consts::ARCH,
-        Some("x86_64") => "x86_64",
-        _ => panic!("unknown architecture"),
-    };
-    let ignores = pacman_config
-        .get_mut("options")
-        .and_then(|m| m.remove("IgnorePkg"))
-        .and_then(try_remove_first);
-    let ignores: Vec<String> = if let Some(ignores) = ignores {
-        ignores
-            .trim()
-            .split(' ')
-            .map(str::trim)
-            .filter(|s| !s.is_empty())
-            .map(ToOwned::to_owned)
-            .collect()
-    } else {
-        Vec::new()
+/// A repo section with its includes resolved.
+#[derive(Clone, Debug)]
+pub struct Repo {
+    pub name: String,
+    /// Urls in the order they should be tried, with $arch and $repo substituted.
+    /// Empty if neither Server nor Include were usable.
+    pub servers: Vec<String>,
+    /// Global SigLevel with the repo's overrides applied.
+    pub sig_level: SigLevel,
+    pub usage: Usage,
+    /// CacheServer urls, tried before the regular servers.
+    /// Unlike servers, pacman does not give up on them after errors.
+    pub cache_servers: Vec<String>,
+}
+
+impl Repo {
+    /// All urls in the order they should be tried, cache servers first.
+    pub fn urls(&self) -> impl Iterator<Item = &str> {
+        self.cache_servers
+            .iter()
+            .chain(&self.servers)
+            .map(String::as_str)
+    }
+}
+
+impl PacmanConfig {
+    /// Problems found while parsing, empty if the config is fine.
+    pub fn validate(&self) -> &[ConfigWarning] {
+        &self.warnings
+    }
+
+    /// The first repo named name, later sections with the same name are ignored.
+    pub fn repo(&self, name: &str) -> Option<&Repo> {
+        self.repos.iter().find(|r| r.name == name)
+    }
+
+    /// Whether the file at path (relative to root) must not be overwritten on upgrade,
+    /// the new version should be installed as .pacnew instead.
+    pub fn no_upgrade_matches(&self, path: &str) -> bool {
+        crate::util::match_patterns(&self.no_upgrade, path)
+    }
+
+    /// Whether the file at path (relative to root) must not be extracted.
+    pub fn no_extract_matches(&self, path: &str) -> bool {
+        crate::util::match_patterns(&self.no_extract, path)
+    }
+
+    /// Whether packages built for arch may be installed.
+    pub fn arch_allowed(&self, arch: &str) -> bool {
+        arch == "any" || self.architectures.iter().any(|a| a == arch)
+    }
+
+    /// Whether the package matches one of the HoldPkg globs.
+    pub fn is_held(&self, name: &str) -> bool {
+        self.hold_pkg.iter().any(|p| crate::util::fnmatch(p, name))
+    }
+
+    /// Returns the held packages among the names of packages that are about to be removed.
+    /// Like pacman, frontends should require explicit confirmation before removing them.
+    pub fn held_packages<'n>(&self, names: impl IntoIterator<Item = &'n str>) -> Vec<&'n str> {
+        names.into_iter().filter(|n| self.is_held(n)).collect()
+    }
+}
+
+/// Which packages `pacman -Sc` keeps in the cache.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CleanMethod {
+    /// Packages that are still installed, the default.
+    KeepInstalled,
+    /// Packages that are still in a sync db.
+    KeepCurrent,
+}
+
+impl std::str::FromStr for CleanMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "KeepInstalled" => Ok(Self::KeepInstalled),
+            "KeepCurrent" => Ok(Self::KeepCurrent),
+            s => Err(s.to_owned()),
+        }
+    }
+}
+
+/// What a repo may be used for, pacman's `Usage`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Usage {
+    /// refresh
+    pub sync: bool,
+    pub search: bool,
+    pub install: bool,
+    pub upgrade: bool,
+}
+
+impl Usage {
+    pub const ALL: Self = Self {
+        sync: true,
+        search: true,
+        install: true,
+        upgrade: true,
     };
-    let db_path = pacman_config
-        .get_mut("options")
-        .and_then(|m| m.remove("DBPath"))
-        .and_then(try_remove_first)
-        .unwrap_or_else(|| "/var/lib/pacman/");
-    let cache_dir = pacman_config
-        .get_mut("options")
-        .and_then(|m| m.remove("CacheDir"))
-        .and_then(try_remove_first)
-        .unwrap_or_else(|| "/var/cache/pacman/pkg");
-    let mut repos = HashMap::new();
-    for (k, mut v) in pacman_config {
-        if k.is_empty() || k == "options" {
-            continue;
+
+    /// No tokens means All, like in pacman.
+    /// Returns the offending token on error.
+    pub fn parse<S: AsRef<str>>(tokens: &[S]) -> Result<Self, String> {
+        if tokens.is_empty() {
+            return Ok(Self::ALL);
+        }
+        let mut usage = Self {
+            sync: false,
+            search: false,
+            install: false,
+            upgrade: false,
+        };
+        for token in tokens {
+            match token.as_ref() {
+                "Sync" => usage.sync = true,
+                "Search" => usage.search = true,
+                "Install" => usage.install = true,
+                "Upgrade" => usage.upgrade = true,
+                "All" => usage = Self::ALL,
+                t => return Err(t.to_owned()),
+            }
+        }
+        Ok(usage)
+    }
+}
+
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/pacman.conf";
+/// Environment variable overriding [DEFAULT_CONFIG_PATH].
+pub const CONFIG_PATH_ENV: &str = "PACMAN_CONF";
+
+/// The config file to use, [CONFIG_PATH_ENV] if set, otherwise [DEFAULT_CONFIG_PATH].
+pub fn config_path() -> PathBuf {
+    std::env::var_os(CONFIG_PATH_ENV)
+        .map(Into::into)
+        .unwrap_or_else(|| DEFAULT_CONFIG_PATH.into())
+}
+
+/// Reads the pacman config from [config_path] and extracts relevant information.
+/// Resolves nested Includes up to [MAX_INCLUDE_DEPTH].
+/// Does not support glob syntax in includes.
+pub fn extract_relevant_config() -> Result<PacmanConfig, ConfigError> {
+    PacmanConfig::from_path(&config_path())
+}
+
+impl PacmanConfig {
+    /// Relative includes are resolved relative to the directory of path.
+    pub fn from_path(path: &Path) -> Result<Self, ConfigError> {
+        let s = std::fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_owned(), e))?;
+        let base_dir = path.parent().unwrap_or(Path::new("/"));
+        parse_pacman_sections(&s)
+            .map_err(|e| ConfigError::Parse(path.to_owned(), e))
+            .and_then(|c| typed_config(c, base_dir))
+    }
+
+    /// Relative includes are resolved relative to base_dir.
+    pub fn from_str(s: &str, base_dir: &Path) -> Result<Self, ConfigError> {
+        parse_pacman_sections(s)
+            .map_err(|e| ConfigError::Parse(base_dir.to_owned(), e))
+            .and_then(|c| typed_config(c, base_dir))
+    }
+}
+
+fn typed_config(sections: Sections<'_>, base_dir: &Path) -> Result<PacmanConfig, ConfigError> {
+    let mut warnings = Vec::new();
+    // Repeated options sections are merged, repos are kept in order.
+    let mut options: HashMap<&str, Vec<&str>> = HashMap::new();
+    let mut repo_sections: Vec<(&str, HashMap<&str, Vec<&str>>)> = Vec::new();
+    for (name, section) in sections {
+        match name {
+            "options" => section
+                .into_iter()
+                .for_each(|(k, v)| options.entry(k).or_default().extend(v)),
+            // pacman refuses directives outside of sections
+            "" => unknown_keys("", section, &[], &mut warnings),
+            name if repo_sections.iter().any(|(n, _)| *n == name) => {
+                warnings.push(ConfigWarning::DuplicateSection(name.to_owned()))
+            }
+            name => repo_sections.push((name, section)),
         }
-        let server = v
-            .remove("Server")
+    }
+
+    // auto is what uname reports, same as pacman.
+    let mut architectures: Vec<String> = list_option(&mut options, "Architecture")
+        .into_iter()
+        .map(|a| match a.as_str() {
+            "auto" => std::env::consts::ARCH.to_owned(),
+            _ => a,
+        })
+        .collect();
+    if architectures.is_empty() {
+        architectures.push(std::env::consts::ARCH.to_owned());
+    }
+    // $arch in servers is always the primary architecture.
+    let arch = architectures[0].as_str();
+    let root_dir = Path::new(single_option(&mut options, "RootDir").unwrap_or("/"));
+    let db_path = single_option(&mut options, "DBPath").map(PathBuf::from);
+    let db_path_explicit = db_path.is_some();
+    let db_path = db_path.unwrap_or_else(|| root_dir.join("var/lib/pacman/"));
+    let log_file = single_option(&mut options, "LogFile").map(PathBuf::from);
+    let log_file_explicit = log_file.is_some();
+    let log_file = log_file.unwrap_or_else(|| root_dir.join("var/log/pacman.log"));
+    let mut cache_dirs: Vec<std::path::PathBuf> = list_option(&mut options, "CacheDir")
+        .into_iter()
+        .map(Into::into)
+        .collect();
+    if cache_dirs.is_empty() {
+        cache_dirs.push("/var/cache/pacman/pkg/".into());
+    }
+    let mut hook_dirs: Vec<std::path::PathBuf> = list_option(&mut options, "HookDir")
+        .into_iter()
+        .map(Into::into)
+        .collect();
+    if hook_dirs.is_empty() {
+        hook_dirs.push("/etc/pacman.d/hooks/".into());
+    }
+    hook_dirs.insert(0, "/usr/share/libalpm/hooks/".into());
+    let sig_level = apply_sig_level(
+        SigLevel::default(),
+        &list_option(&mut options, "SigLevel"),
+        "options",
+        &mut warnings,
+    );
+    let parallel_downloads = single_option(&mut options, "ParallelDownloads")
+        .map(|s| match s.parse() {
+            Ok(0) | Err(_) => Err(ConfigError::Value("ParallelDownloads".into(), s.into())),
+            Ok(n) => Ok(n),
+        })
+        .transpose()?
+        .unwrap_or(1);
+    let mut clean_method = list_option(&mut options, "CleanMethod")
+        .iter()
+        .map(|s| s.parse())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|s| ConfigError::Value("CleanMethod".into(), s))?;
+    if clean_method.is_empty() {
+        clean_method.push(CleanMethod::KeepInstalled);
+    }
+    let mut repos = Vec::new();
+    for (k, mut v) in repo_sections {
+        let servers = Servers::take(&mut v, base_dir, k, &mut Vec::new(), &mut warnings)?;
+        if servers.servers.is_empty() && servers.cache_servers.is_empty() {
+            warnings.push(ConfigWarning::NoServer(k.to_owned()));
+        }
+        let substitute = |servers: Vec<String>| -> Vec<String> {
+            servers
+                .into_iter()
+                .map(|s| s.replace("$arch", arch).replace("$repo", k))
+                .collect()
+        };
+        repos.push(Repo {
+            name: k.to_owned(),
+            servers: substitute(servers.servers),
+            sig_level: apply_sig_level(
+                sig_level,
+                &list_option(&mut v, "SigLevel"),
+                k,
+                &mut warnings,
+            ),
+            usage: Usage::parse(&list_option(&mut v, "Usage")).map_err(ConfigError::Usage)?,
+            cache_servers: substitute(servers.cache_servers),
+        });
+        unknown_keys(k, v, &[], &mut warnings);
+    }
+
+    let config = PacmanConfig {
+        root_dir: root_dir.to_owned(),
+        db_path,
+        db_path_explicit,
+        cache_dirs,
+        log_file,
+        log_file_explicit,
+        gpg_dir: single_option(&mut options, "GPGDir")
+            .unwrap_or("/etc/pacman.d/gnupg/")
+            .into(),
+        hook_dirs,
+        hold_pkg: list_option(&mut options, "HoldPkg"),
+        ignores: list_option(&mut options, "IgnorePkg"),
+        ignore_groups: list_option(&mut options, "IgnoreGroup"),
+        no_upgrade: list_option(&mut options, "NoUpgrade"),
+        no_extract: list_option(&mut options, "NoExtract"),
+        architectures,
+        parallel_downloads,
+        sig_level,
+        check_space: flag_option(&mut options, "CheckSpace"),
+        color: flag_option(&mut options, "Color"),
+        no_progress_bar: flag_option(&mut options, "NoProgressBar"),
+        verbose_pkg_lists: flag_option(&mut options, "VerbosePkgLists"),
+        i_love_candy: flag_option(&mut options, "ILoveCandy"),
+        use_syslog: flag_option(&mut options, "UseSyslog"),
+        disable_download_timeout: flag_option(&mut options, "DisableDownloadTimeout"),
+        disable_sandbox: flag_option(&mut options, "DisableSandbox"),
+        clean_method,
+        // The command contains spaces, so it can not be a list_option
+        xfer_command: options
+            .remove("XferCommand")
             .and_then(try_remove_first)
-            .map(ToOwned::to_owned)
-            .or_else(|| {
-                v.remove("Include").and_then(|v| {
-                    v.into_iter()
-                        .filter_map(|i| {
-                            let s = std::fs::read_to_string(i).unwrap();
-                            let mut inc = parse_pacman_config(&s).unwrap();
-                            inc.get_mut("")
-                                .unwrap()
-                                .remove("Server")
-                                .and_then(try_remove_first)
-                                .map(ToOwned::to_owned)
-                        })
-                        .next()
-                })
-            })
-            .unwrap();
-        let server = server.replace("$arch", arch).replace("$repo", k);
-        repos.insert(k.to_owned(), server);
+            .map(|s| s.trim().to_owned()),
+        repos,
+        warnings: Vec::new(),
+    };
+    unknown_keys("options", options, UNINTERPRETED_OPTIONS, &mut warnings);
+    Ok(PacmanConfig { warnings, ..config })
+}
+
+/// Typed config from a string, without reading /etc/pacman.conf.
+#[cfg(test)]
+pub(crate) fn test_config(s: &str) -> PacmanConfig {
+    PacmanConfig::from_str(s, Path::new("/")).unwrap()
+}
+
+#[test]
+fn test_usage() {
+    assert_eq!(Usage::parse::<&str>(&[]), Ok(Usage::ALL));
+    assert_eq!(Usage::parse(&["Sync", "All"]), Ok(Usage::ALL));
+    let u = Usage::parse(&["Sync", "Search"]).unwrap();
+    assert!(u.sync && u.search && !u.install && !u.upgrade);
+    assert_eq!(Usage::parse(&["Sometimes"]), Err("Sometimes".to_owned()));
+}
+
+#[test]
+fn test_typed_config() {
+    let conf = "[options]\nArchitecture = auto\nCacheDir = /a/\nCacheDir = /b/ /c/\n\
+        IgnorePkg = foo bar\nIgnorePkg = baz\nHoldPkg = pacman glibc\nParallelDownloads = 5\n\
+        CheckSpace\nColor\n#VerbosePkgLists\nILoveCandy\nSigLevel = Required DatabaseOptional\nLogFile = /tmp/log\n\
+        [core]\nServer = https://example.com/$repo/os/$arch\n\
+        [custom]\nSigLevel = Optional TrustAll\nUsage = Install\nServer = file:///repo\n";
+    let c = test_config(conf);
+    assert_eq!(c.cache_dirs.len(), 3);
+    assert_eq!(
+        c.hook_dirs,
+        [
+            Path::new("/usr/share/libalpm/hooks/"),
+            Path::new("/etc/pacman.d/hooks/")
+        ]
+    );
+    assert_eq!(c.ignores, ["foo", "bar", "baz"]);
+    assert_eq!(c.hold_pkg, ["pacman", "glibc"]);
+    assert_eq!(c.parallel_downloads, 5);
+    assert!(c.check_space);
+    assert!(c.color && c.i_love_candy && !c.verbose_pkg_lists);
+    assert_eq!(c.clean_method, [CleanMethod::KeepInstalled]);
+    assert_eq!(c.xfer_command, None);
+    assert_eq!(c.sig_level, SigLevel::default());
+    assert_eq!(c.log_file, Path::new("/tmp/log"));
+    assert_eq!(c.db_path, std::path::Path::new("/var/lib/pacman/"));
+    assert_eq!(
+        c.repo("core").unwrap().servers,
+        [format!(
+            "https://example.com/core/os/{}",
+            std::env::consts::ARCH
+        )]
+    );
+    assert_eq!(
+        c.repos.iter().map(|r| r.name.as_str()).collect::<Vec<_>>(),
+        ["core", "custom"]
+    );
+    assert_eq!(c.repo("core").unwrap().sig_level, c.sig_level);
+    let custom = c.repo("custom").unwrap().sig_level;
+    assert_eq!(custom.package.requirement, SigRequirement::Optional);
+    assert_eq!(custom.database.trust, SigTrust::TrustAll);
+    assert_eq!(c.repo("core").unwrap().usage, Usage::ALL);
+    let custom = c.repo("custom").unwrap().usage;
+    assert!(custom.install && !custom.upgrade && !custom.search);
+
+    let xfer = test_config(
+        "[options]\nCleanMethod = KeepCurrent KeepInstalled\n\
+        XferCommand = /usr/bin/curl -L -C - -f -o %o %u\n",
+    );
+    assert_eq!(
+        xfer.clean_method,
+        [CleanMethod::KeepCurrent, CleanMethod::KeepInstalled]
+    );
+    assert_eq!(
+        xfer.xfer_command.as_deref(),
+        Some("/usr/bin/curl -L -C - -f -o %o %u")
+    );
+    for bad in [
+        "ParallelDownloads = 0",
+        "ParallelDownloads = many",
+        "CleanMethod = KeepAll",
+    ] {
+        let conf = format!("[options]\n{bad}\n");
+        assert!(matches!(
+            PacmanConfig::from_str(&conf, Path::new("/")),
+            Err(ConfigError::Value(..))
+        ));
     }
+}
 
-    PacmanConfig {
-        ignores,
-        repo_urls: repos,
-        db_path: db_path.to_owned().into(),
-        cache_dir: cache_dir.to_owned().into(),
+#[test]
+fn test_defaults() {
+    let c = test_config("[core]\nServer = https://example.com/$repo/os/$arch\n");
+    assert_eq!(c.root_dir, Path::new("/"));
+    assert_eq!(c.db_path, Path::new("/var/lib/pacman/"));
+    assert_eq!(c.cache_dirs, [Path::new("/var/cache/pacman/pkg/")]);
+    assert_eq!(c.log_file, Path::new("/var/log/pacman.log"));
+    assert_eq!(c.gpg_dir, Path::new("/etc/pacman.d/gnupg/"));
+    assert_eq!(c.architectures, [std::env::consts::ARCH]);
+    assert_eq!(c.parallel_downloads, 1);
+    assert_eq!(c.sig_level, SigLevel::default());
+    assert!(!c.check_space);
+    assert!(!c.color && !c.i_love_candy);
+    assert_eq!(c.repos.len(), 1);
+
+    let c = test_config("[options]\nRootDir = /mnt\nLogFile = /log\n");
+    assert_eq!(c.db_path, Path::new("/mnt/var/lib/pacman/"));
+    assert_eq!(c.log_file, Path::new("/log"));
+    assert!(!c.db_path_explicit && c.log_file_explicit);
+    assert_eq!(c.cache_dirs, [Path::new("/var/cache/pacman/pkg/")]);
+}
+
+#[test]
+fn test_architectures() {
+    let c = test_config(
+        "[options]\nArchitecture = x86_64_v3 auto\n[core]\nServer = https://example.com/$arch/$repo\n",
+    );
+    assert_eq!(c.architectures, ["x86_64_v3", std::env::consts::ARCH]);
+    assert_eq!(
+        c.repo("core").unwrap().servers,
+        ["https://example.com/x86_64_v3/core"]
+    );
+    assert!(c.arch_allowed("x86_64_v3"));
+    assert!(c.arch_allowed("any"));
+    assert!(!c.arch_allowed("riscv64"));
+
+    let c = test_config("[options]\nArchitecture = aarch64\n");
+    assert_eq!(c.architectures, ["aarch64"]);
+}
+
+#[test]
+fn test_no_upgrade_extract() {
+    let c = test_config(
+        "[options]\nArchitecture = auto\nNoUpgrade = etc/pacman.conf etc/ssh/*\n\
+        NoExtract = usr/share/help/* usr/share/locale/*\nNoExtract = !usr/share/locale/en*\n",
+    );
+    assert!(c.no_upgrade_matches("etc/pacman.conf"));
+    assert!(c.no_upgrade_matches("/etc/ssh/sshd_config"));
+    assert!(!c.no_upgrade_matches("etc/fstab"));
+    assert!(c.no_extract_matches("usr/share/help/C/x.page"));
+    assert!(c.no_extract_matches("usr/share/locale/de/x.mo"));
+    assert!(!c.no_extract_matches("usr/share/locale/en/x.mo"));
+}
+
+#[test]
+fn test_hold_pkg() {
+    let c = test_config("[options]\nArchitecture = auto\nHoldPkg = pacman glibc linux*\n");
+    assert!(c.is_held("pacman"));
+    assert!(c.is_held("linux-lts"));
+    assert!(!c.is_held("vim"));
+    assert_eq!(
+        c.held_packages(["vim", "glibc", "linux", "zsh"]),
+        ["glibc", "linux"]
+    );
+}
+
+#[test]
+fn test_nested_include() {
+    let dir = crate::util::test_dir("include");
+    let a = dir.join("a");
+    let b = dir.join("b");
+    let c = dir.join("c");
+    std::fs::write(&a, format!("Server = a1\nInclude = {}\n", b.display())).unwrap();
+    std::fs::write(&b, format!("Server = b1\nInclude = {}\n", c.display())).unwrap();
+    std::fs::write(&c, "Server = c1\nServer = c2\n").unwrap();
+    assert_eq!(
+        included_servers(&a, "r", &mut Vec::new(), &mut Vec::new())
+            .unwrap()
+            .servers,
+        ["a1", "b1", "c1", "c2"]
+    );
+    std::fs::write(&b, format!("Include = {}\nServer = b1\n", c.display())).unwrap();
+    assert_eq!(
+        included_servers(&a, "r", &mut Vec::new(), &mut Vec::new())
+            .unwrap()
+            .servers,
+        ["a1", "c1", "c2", "b1"]
+    );
+    std::fs::write(&b, format!("Server = b1\nInclude = {}\n", c.display())).unwrap();
+
+    std::fs::write(&c, format!("Server = c1\nInclude = {}\n", a.display())).unwrap();
+    assert!(matches!(
+        included_servers(&a, "r", &mut Vec::new(), &mut Vec::new()),
+        Err(ConfigError::IncludeCycle(_))
+    ));
+
+    let deep = dir.join("deep");
+    std::fs::write(&deep, format!("Include = {}\n", deep.display())).unwrap();
+    let mut stack = (0..MAX_INCLUDE_DEPTH)
+        .map(|n| n.to_string().into())
+        .collect();
+    assert!(matches!(
+        included_servers(&deep, "r", &mut stack, &mut Vec::new()),
+        Err(ConfigError::IncludeDepth(_))
+    ));
+
+    std::fs::write(&c, "Server = c1\n").unwrap();
+    let conf = format!(
+        "[options]\nArchitecture = auto\n[r]\nInclude = {}\n",
+        b.display()
+    );
+    let conf = test_config(&conf);
+    assert_eq!(conf.repo("r").unwrap().servers, ["b1", "c1"]);
+
+    let conf = format!(
+        "[options]\nArchitecture = auto\n[r2]\nServer = s/$repo\nInclude = {}\nServer = t\n",
+        c.display()
+    );
+    let conf = test_config(&conf);
+    assert_eq!(conf.repo("r2").unwrap().servers, ["s/r2", "c1", "t"]);
+
+    std::fs::write(&c, "CacheServer = http://cache/$repo\nServer = c1\n").unwrap();
+    let conf = format!(
+        "[options]\nArchitecture = auto\n[r3]\nServer = s\nInclude = {}\nCacheServer = d\n",
+        c.display()
+    );
+    let conf = test_config(&conf);
+    assert_eq!(
+        conf.repo("r3").unwrap().cache_servers,
+        ["http://cache/r3", "d"]
+    );
+    assert_eq!(
+        conf.repo("r3").unwrap().urls().collect::<Vec<_>>(),
+        ["http://cache/r3", "d", "s", "c1"]
+    );
+    assert!(conf.validate().is_empty());
+}
+
+#[test]
+fn test_from_path() {
+    let dir = crate::util::test_dir("from_path");
+    std::fs::create_dir(dir.join("pacman.d")).unwrap();
+    std::fs::write(
+        dir.join("pacman.d/mirrorlist"),
+        "Include = more\nServer = https://a/$repo\n",
+    )
+    .unwrap();
+    std::fs::write(dir.join("pacman.d/more"), "Server = https://b/$repo\n").unwrap();
+    let conf = "[options]\nArchitecture = auto\n[core]\nInclude = pacman.d/mirrorlist\n";
+    std::fs::write(dir.join("pacman.conf"), conf).unwrap();
+
+    let c = PacmanConfig::from_path(&dir.join("pacman.conf")).unwrap();
+    assert_eq!(
+        c.repo("core").unwrap().servers,
+        ["https://b/core", "https://a/core"]
+    );
+    let c = PacmanConfig::from_str(conf, &dir).unwrap();
+    assert_eq!(
+        c.repo("core").unwrap().servers,
+        ["https://b/core", "https://a/core"]
+    );
+    assert!(c.validate().is_empty());
+    let c = PacmanConfig::from_str(conf, Path::new("/nonexistent")).unwrap();
+    assert!(matches!(
+        c.validate(),
+        [
+            ConfigWarning::UnreachableInclude(..),
+            ConfigWarning::NoServer(_)
+        ]
+    ));
+    assert!(matches!(
+        PacmanConfig::from_path(&dir.join("nope.conf")),
+        Err(ConfigError::Io(..))
+    ));
+    std::fs::write(dir.join("broken.conf"), "[options]\nCheckSpace\n[core\n").unwrap();
+    match PacmanConfig::from_path(&dir.join("broken.conf")) {
+        Err(ConfigError::Parse(_, e)) => assert_eq!((e.line, e.column), (3, 1)),
+        other => panic!("{other:?}"),
     }
 }
 
+#[test]
+fn test_validate() {
+    let c = test_config(
+        "Stray = 1\n[options]\nArchitecture = auto\nColor\nFrobnicate = yes\n\
+        SigLevel = Optional Sometimes\n[core]\nServer = a\nSigLevel = PackageNever Bogus\n\
+        CacheServer = c\nMirror = b\n[empty]\nUsage = Sync\n[core]\nServer = dup\n",
+    );
+    assert_eq!(
+        c.validate(),
+        [
+            ConfigWarning::UnknownKey("".into(), "Stray".into()),
+            ConfigWarning::DuplicateSection("core".into()),
+            ConfigWarning::InvalidSigLevel("options".into(), "Sometimes".into()),
+            ConfigWarning::InvalidSigLevel("core".into(), "Bogus".into()),
+            ConfigWarning::UnknownKey("core".into(), "Mirror".into()),
+            ConfigWarning::NoServer("empty".into()),
+            ConfigWarning::UnknownKey("options".into(), "Frobnicate".into()),
+        ]
+    );
+    // valid tokens around invalid ones still apply
+    assert_eq!(c.sig_level.package.requirement, SigRequirement::Optional);
+    assert_eq!(
+        c.repo("core").unwrap().sig_level.package.requirement,
+        SigRequirement::Never
+    );
+    assert_eq!(c.repo("core").unwrap().servers, ["a"]);
+    assert!(c.repo("empty").unwrap().servers.is_empty());
+}
+
 #[test]
 fn pacman_conf() {
     let i = std::fs::read_to_string("/etc/pacman.conf").unwrap();
@@ -3,7 +3,7 @@ use nom::{
     branch::alt,
     bytes::complete::{take_until, take_while, take_while1},
     character::complete::{alphanumeric1, char, multispace0},
-    combinator::{iterator, opt, recognize},
+    combinator::{iterator, opt},
     multi::many0,
     sequence::{delimited, terminated},
 };
@@ -23,44 +23,62 @@ fn test_section() {
     assert!(section("test]").is_err());
 }
 
-fn kv(i: &str) -> IResult<&str, (&str, Option<&str>)> {
-    let (i, name) = recognize(alt((
-        alphanumeric1,
-        // comments
-        delimited(char('#'), take_while(|c| c != '\n'), char('\n')),
-    )))
-    .parse(i)?;
-    if name.starts_with('#') {
-        return Ok((i, (name, None)));
-    }
+/// One line inside of a section.
+#[derive(Debug, PartialEq, Eq)]
+enum Item<'a> {
+    Comment,
+    /// A key without value like `CheckSpace`, its presence means true.
+    Flag(&'a str),
+    Pair(&'a str, &'a str),
+}
+
+fn comment(i: &str) -> IResult<&str, Item<'_>> {
+    (char('#'), take_while(|c| c != '\n'))
+        .map(|_| Item::Comment)
+        .parse(i)
+}
+
+fn kv(i: &str) -> IResult<&str, Item<'_>> {
+    let (i, name) = alphanumeric1(i)?;
 
     let eq = (many0(char(' ')), char('='), many0(char(' ')));
     let value = take_while1(|c| c != '\n' && c != ';');
     let trailer = take_while(|c| c == '\n' || c == ';');
 
     let (i, val) = opt((eq, value, trailer)).parse(i)?;
-    let val = val.map(|(_, v, _)| v);
+    let item = match val {
+        Some((_, v, _)) => Item::Pair(name, v),
+        None => Item::Flag(name),
+    };
 
-    Ok((i, (name, val)))
+    Ok((i, item))
 }
 
 #[test]
 fn test_kv() {
-    assert_eq!(kv("a=b"), Ok(("", ("a", "b".into()))));
-    assert_eq!(kv("a=b b2 b3"), Ok(("", ("a", "b b2 b3".into()))));
-    assert_eq!(kv("a=b  "), Ok(("", ("a", "b  ".into()))));
-    assert_eq!(kv("a   =   b"), Ok(("", ("a", "b".into()))));
-    assert_eq!(kv("a=b\n\n\n"), Ok(("", ("a", "b".into()))));
-    assert_eq!(kv("a\n=\nb"), Ok(("\n=\nb", ("a", None))));
+    assert_eq!(kv("a=b"), Ok(("", Item::Pair("a", "b"))));
+    assert_eq!(kv("a=b b2 b3"), Ok(("", Item::Pair("a", "b b2 b3"))));
+    assert_eq!(kv("a=b  "), Ok(("", Item::Pair("a", "b  "))));
+    assert_eq!(kv("a   =   b"), Ok(("", Item::Pair("a", "b"))));
+    assert_eq!(kv("a=b\n\n\n"), Ok(("", Item::Pair("a", "b"))));
+    assert_eq!(kv("a\n=\nb"), Ok(("\n=\nb", Item::Flag("a"))));
+    assert_eq!(comment("#a=b\nc"), Ok(("\nc", Item::Comment)));
+    assert_eq!(comment("# no newline"), Ok(("", Item::Comment)));
 }
 
+/// Flags are keys with no values.
 fn key_value_map(i: &str) -> IResult<&str, HashMap<&str, Vec<&str>>> {
-    let mut i = iterator(i, terminated(kv, opt(multispace0)));
-    // skip comments
+    let mut i = iterator(i, terminated(alt((comment, kv)), opt(multispace0)));
 
     let mut ret: HashMap<&str, Vec<&str>> = HashMap::new();
-    for (k, v) in i.by_ref().filter(|(n, _)| !n.starts_with('#')) {
-        ret.entry(k).or_default().extend(v);
+    for item in i.by_ref() {
+        match item {
+            Item::Comment => (),
+            Item::Flag(k) => {
+                ret.entry(k).or_default();
+            }
+            Item::Pair(k, v) => ret.entry(k).or_default().push(v),
+        }
     }
 
     i.finish().map(|(i, ())| (i, ret))
@@ -73,26 +91,97 @@ fn test_kvm() {
     assert_eq!(parse.1["a"], vec!("b"));
     assert_eq!(parse.1["b"], vec!("c"));
     assert_eq!(parse.1["d"], vec!("e"));
-    let parse = key_value_map("a=b\n b=c\n d=e").unwrap();
+    let parse = key_value_map("a=b\n#c=d\nflag\n b=c\n d=e\n#end").unwrap();
     assert_eq!(parse.0, "");
     assert_eq!(parse.1["a"], vec!("b"));
     assert_eq!(parse.1["b"], vec!("c"));
     assert_eq!(parse.1["d"], vec!("e"));
+    assert!(parse.1["flag"].is_empty());
+    assert!(!parse.1.contains_key("c"));
 }
 
 pub(super) fn sec_kv_map(i: &str) -> IResult<&str, Config<'_>> {
+    sec_kv_list(i).map(|(i, l)| (i, l.into_iter().collect()))
+}
+
+/// Like [sec_kv_map] but keeps the order of sections, and repeated sections.
+/// The "" section always comes first if present.
+pub(super) fn sec_kv_list(i: &str) -> IResult<&str, Sections<'_>> {
     let (i, prelude) = opt(key_value_map).parse(i)?;
+    let mut ret: Sections = prelude.into_iter().map(|p| ("", p)).collect();
     let mut i = iterator(i, (terminated(section, opt(multispace0)), key_value_map));
-    let mut ret: HashMap<_, _> = i.by_ref().collect();
-    if let Some(prelude) = prelude {
-        ret.insert("", prelude);
-    }
+    ret.extend(i.by_ref());
     i.finish().map(|(i, ())| (i, ret))
 }
 
+/// Position of the first thing that could not be parsed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    /// 1-based
+    pub line: usize,
+    /// 1-based, in chars
+    pub column: usize,
+    /// The offending line.
+    pub snippet: String,
+}
+
+impl ParseError {
+    /// rest has to be a suffix of input.
+    fn at(input: &str, rest: &str) -> Self {
+        let offset = input.len() - rest.len();
+        let before = &input[..offset];
+        let line_start = before.rfind('\n').map_or(0, |p| p + 1);
+        let line_end = input[offset..]
+            .find('\n')
+            .map_or(input.len(), |p| offset + p);
+        Self {
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+            snippet: input[line_start..line_end].to_owned(),
+        }
+    }
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "line {}, column {}: {}",
+            self.line, self.column, self.snippet
+        )
+    }
+}
+
+/// Runs parser on all of input, anything left over is an error.
+pub(super) fn parse_all<'c, T>(
+    input: &'c str,
+    parser: fn(&'c str) -> IResult<&'c str, T>,
+) -> Result<T, ParseError> {
+    match parser(input) {
+        Ok((rest, v)) if rest.trim().is_empty() => Ok(v),
+        Ok((rest, _)) => Err(ParseError::at(input, rest)),
+        Err(nom::Err::Error(e) | nom::Err::Failure(e)) => Err(ParseError::at(input, e.input)),
+        Err(nom::Err::Incomplete(_)) => Err(ParseError::at(input, "")),
+    }
+}
+
+#[test]
+fn test_parse_error() {
+    assert!(parse_all("[a]\nb = c\n\n", sec_kv_list).is_ok());
+    let e = parse_all("[a]\nb = c\n  [d\ne = f\n", sec_kv_list).unwrap_err();
+    assert_eq!((e.line, e.column), (3, 3));
+    assert_eq!(e.snippet, "  [d");
+    let e = parse_all("# ä\nServer-x = y", sec_kv_list).unwrap_err();
+    assert_eq!((e.line, e.column), (2, 7));
+    assert_eq!(e.to_string(), "line 2, column 7: Server-x = y");
+}
+
 /// Section -> (Key -> List<Value>)
 pub type Config<'c> = HashMap<&'c str, HashMap<&'c str, Vec<&'c str>>>;
 
+/// (Section, Key -> List<Value>) in order of appearance
+pub type Sections<'c> = Vec<(&'c str, HashMap<&'c str, Vec<&'c str>>)>;
+
 #[test]
 fn test_sec_kv_map() {
     let parse = sec_kv_map("a=0\n#b=9\n\n[a]a=1;b=2;c=3\n[b]a=-1;b=-2;c=-3\n[c]a=1;a=2");
@@ -103,3 +192,11 @@ fn test_sec_kv_map() {
     assert_eq!(parse.1["b"]["c"], vec!("-3"));
     assert_eq!(parse.1["c"]["a"], vec!("1", "2"));
 }
+
+#[test]
+fn test_sec_kv_list() {
+    let (_, parse) = sec_kv_list("a=0\n[b]a=1\n[a]a=2\n[b]a=3").unwrap();
+    let names: Vec<_> = parse.iter().map(|(n, _)| *n).collect();
+    assert_eq!(names, ["", "b", "a", "b"]);
+    assert_eq!(parse[3].1["a"], vec!("3"));
+}
@@ -0,0 +1,103 @@
+/// Whether a signature has to be present.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SigRequirement {
+    Required,
+    Optional,
+    Never,
+}
+
+/// Which keys are accepted to have made the signature.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SigTrust {
+    /// Key has to be in the keyring and be trusted.
+    TrustedOnly,
+    /// Key only has to be in the keyring.
+    TrustAll,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SigCheck {
+    pub requirement: SigRequirement,
+    pub trust: SigTrust,
+}
+
+/// Parsed SigLevel, separately for packages and databases.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SigLevel {
+    pub package: SigCheck,
+    pub database: SigCheck,
+}
+
+/// pacman's builtin `Required DatabaseOptional`.
+impl Default for SigLevel {
+    fn default() -> Self {
+        Self {
+            package: SigCheck {
+                requirement: SigRequirement::Required,
+                trust: SigTrust::TrustedOnly,
+            },
+            database: SigCheck {
+                requirement: SigRequirement::Optional,
+                trust: SigTrust::TrustedOnly,
+            },
+        }
+    }
+}
+
+impl SigLevel {
+    /// Applies the tokens of a SigLevel line on top of self.
+    /// Like pacman, repo sections start from the global SigLevel
+    /// and only override what they mention.
+    /// Returns the offending token on error.
+    pub fn apply<S: AsRef<str>>(mut self, tokens: &[S]) -> Result<Self, String> {
+        for token in tokens {
+            let token = token.as_ref();
+            let (package, database, rest) = if let Some(r) = token.strip_prefix("Package") {
+                (true, false, r)
+            } else if let Some(r) = token.strip_prefix("Database") {
+                (false, true, r)
+            } else {
+                (true, true, token)
+            };
+            let change: fn(&mut SigCheck) = match rest {
+                "Required" => |c| c.requirement = SigRequirement::Required,
+                "Optional" => |c| c.requirement = SigRequirement::Optional,
+                "Never" => |c| c.requirement = SigRequirement::Never,
+                "TrustedOnly" => |c| c.trust = SigTrust::TrustedOnly,
+                "TrustAll" => |c| c.trust = SigTrust::TrustAll,
+                _ => return Err(token.to_owned()),
+            };
+            if package {
+                change(&mut self.package);
+            }
+            if database {
+                change(&mut self.database);
+            }
+        }
+        Ok(self)
+    }
+}
+
+#[test]
+fn test_siglevel() {
+    use SigRequirement::*;
+    use SigTrust::*;
+    let global = SigLevel::default().apply(&["Never"]).unwrap();
+    assert_eq!(global.package.requirement, Never);
+    assert_eq!(global.database.requirement, Never);
+
+    let global = SigLevel::default()
+        .apply(&["Required", "DatabaseOptional"])
+        .unwrap();
+    assert_eq!(global, SigLevel::default());
+
+    let repo = global.apply(&["PackageTrustAll"]).unwrap();
+    assert_eq!(repo.package.trust, TrustAll);
+    assert_eq!(repo.package.requirement, Required);
+    assert_eq!(repo.database, global.database);
+
+    assert_eq!(
+        global.apply(&["Optional", "PackageSometimes"]),
+        Err("PackageSometimes".to_owned())
+    );
+}
@@ -0,0 +1,395 @@
+//! Lossless representation of a pacman.conf for programmatic edits.
+//! Unlike the parser used for reading the config this keeps comments, ordering,
+//! formatting and unknown keys, so unmodified lines are written back byte for byte.
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Kind {
+    /// Blank lines, comments and anything not understood.
+    Other,
+    Section(String),
+    Entry {
+        key: String,
+        value: Option<String>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Line {
+    kind: Kind,
+    /// Original text, None once the line was modified.
+    raw: Option<String>,
+}
+
+impl Line {
+    fn parse(raw: &str) -> Self {
+        let t = raw.trim();
+        let kind = if t.is_empty() || t.starts_with('#') {
+            Kind::Other
+        } else if let Some(name) = t.strip_prefix('[').and_then(|t| t.split_once(']')) {
+            Kind::Section(name.0.to_owned())
+        } else if let Some((key, value)) = t.split_once('=') {
+            Kind::Entry {
+                key: key.trim().to_owned(),
+                value: Some(value.trim().to_owned()),
+            }
+        } else if t.chars().all(|c| c.is_alphanumeric()) {
+            Kind::Entry {
+                key: t.to_owned(),
+                value: None,
+            }
+        } else {
+            Kind::Other
+        };
+        Self {
+            kind,
+            raw: Some(raw.to_owned()),
+        }
+    }
+
+    fn entry(key: &str, value: Option<&str>) -> Self {
+        Self {
+            kind: Kind::Entry {
+                key: key.to_owned(),
+                value: value.map(ToOwned::to_owned),
+            },
+            raw: None,
+        }
+    }
+
+    fn section(name: &str) -> Self {
+        Self {
+            kind: Kind::Section(name.to_owned()),
+            raw: None,
+        }
+    }
+
+    fn other(raw: String) -> Self {
+        Self {
+            kind: Kind::Other,
+            raw: Some(raw),
+        }
+    }
+
+    fn is_entry(&self, k: &str) -> bool {
+        matches!(&self.kind, Kind::Entry { key, .. } if key == k)
+    }
+
+    fn render(&self) -> String {
+        if let Some(raw) = &self.raw {
+            return raw.clone();
+        }
+        match &self.kind {
+            Kind::Other => String::new(),
+            Kind::Section(name) => format!("[{name}]"),
+            Kind::Entry { key, value: None } => key.clone(),
+            Kind::Entry {
+                key,
+                value: Some(value),
+            } => format!("{key} = {value}"),
+        }
+    }
+}
+
+/// Entries before the first section are in the section "".
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Document {
+    lines: Vec<Line>,
+    /// Whether the source ended with a newline.
+    trailing_newline: bool,
+}
+
+impl Document {
+    /// Never fails, lines that are not understood are kept as is.
+    pub fn parse(s: &str) -> Self {
+        Self {
+            lines: s.lines().map(Line::parse).collect(),
+            trailing_newline: s.ends_with('\n') || s.is_empty(),
+        }
+    }
+
+    /// Section names in order of appearance, without the "" section.
+    pub fn sections(&self) -> impl Iterator<Item = &str> {
+        self.lines.iter().filter_map(|l| match &l.kind {
+            Kind::Section(name) => Some(name.as_str()),
+            _ => None,
+        })
+    }
+
+    /// Line indices of the contents of section, excluding its header.
+    fn section_range(&self, section: &str) -> Option<std::ops::Range<usize>> {
+        let start = if section.is_empty() {
+            0
+        } else {
+            self.lines
+                .iter()
+                .position(|l| matches!(&l.kind, Kind::Section(n) if n == section))?
+                + 1
+        };
+        let len = self.lines[start..]
+            .iter()
+            .position(|l| matches!(l.kind, Kind::Section(_)))
+            .unwrap_or(self.lines.len() - start);
+        Some(start..start + len)
+    }
+
+    /// All values of key in section, one per line it occurs on.
+    /// Valueless flags have an empty value.
+    pub fn get(&self, section: &str, key: &str) -> Vec<&str> {
+        let Some(range) = self.section_range(section) else {
+            return Vec::new();
+        };
+        self.lines[range]
+            .iter()
+            .filter_map(|l| match &l.kind {
+                Kind::Entry { key: k, value } if k == key => {
+                    Some(value.as_deref().unwrap_or_default())
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Sets key on its first occurrence and removes all others.
+    /// Otherwise the key is added after the last entry of the section,
+    /// creating the section at the end if needed.
+    /// A value of None sets a flag like CheckSpace.
+    pub fn set(&mut self, section: &str, key: &str, value: Option<&str>) {
+        let range = self.section_range(section).unwrap_or_else(|| {
+            if !self.lines.is_empty() {
+                self.lines.push(Line::other(String::new()));
+            }
+            self.lines.push(Line::section(section));
+            self.lines.len()..self.lines.len()
+        });
+        let mut found = false;
+        let mut idx = range.start;
+        let mut end = range.end;
+        while idx < end {
+            if self.lines[idx].is_entry(key) {
+                if found {
+                    self.lines.remove(idx);
+                    end -= 1;
+                    continue;
+                }
+                found = true;
+                self.lines[idx] = Line::entry(key, value);
+            }
+            idx += 1;
+        }
+        if !found {
+            let at = self.lines[range.start..end]
+                .iter()
+                .rposition(|l| matches!(l.kind, Kind::Entry { .. }))
+                .map(|p| range.start + p + 1)
+                .unwrap_or(range.start);
+            self.lines.insert(at, Line::entry(key, value));
+        }
+    }
+
+    /// Removes every occurrence of key in section.
+    pub fn remove(&mut self, section: &str, key: &str) {
+        if let Some(range) = self.section_range(section) {
+            let mut idx = 0;
+            self.lines.retain(|l| {
+                idx += 1;
+                !(range.contains(&(idx - 1)) && l.is_entry(key))
+            });
+        }
+    }
+
+    /// Adds item to a whitespace separated list like IgnorePkg if it is not already present.
+    pub fn list_add(&mut self, section: &str, key: &str, item: &str) {
+        let values = self.get(section, key);
+        if values
+            .iter()
+            .any(|v| v.split_whitespace().any(|i| i == item))
+        {
+            return;
+        }
+        let Some(last) = values.last() else {
+            return self.set(section, key, Some(item));
+        };
+        let new = if last.is_empty() {
+            item.to_owned()
+        } else {
+            format!("{last} {item}")
+        };
+        let range = self.section_range(section).unwrap();
+        let idx = range.start
+            + self.lines[range]
+                .iter()
+                .rposition(|l| l.is_entry(key))
+                .unwrap();
+        self.lines[idx] = Line::entry(key, Some(&new));
+    }
+
+    /// Removes item from every occurrence of a list like IgnorePkg,
+    /// lines that become empty are removed.
+    pub fn list_remove(&mut self, section: &str, key: &str, item: &str) {
+        let Some(range) = self.section_range(section) else {
+            return;
+        };
+        let mut remove = Vec::new();
+        for idx in range {
+            let line = &mut self.lines[idx];
+            let Kind::Entry {
+                key: k,
+                value: Some(value),
+            } = &line.kind
+            else {
+                continue;
+            };
+            if k != key || !value.split_whitespace().any(|i| i == item) {
+                continue;
+            }
+            let rest: Vec<_> = value.split_whitespace().filter(|i| *i != item).collect();
+            if rest.is_empty() {
+                remove.push(idx);
+            } else {
+                *line = Line::entry(key, Some(&rest.join(" ")));
+            }
+        }
+        for idx in remove.into_iter().rev() {
+            self.lines.remove(idx);
+        }
+    }
+
+    /// Comments out the section header of repo and all of its entries.
+    pub fn disable_repo(&mut self, repo: &str) {
+        let Some(range) = self.section_range(repo) else {
+            return;
+        };
+        for idx in range.start - 1..range.end {
+            let line = &self.lines[idx];
+            if !matches!(line.kind, Kind::Other) {
+                self.lines[idx] = Line::other(format!("#{}", line.render()));
+            }
+        }
+    }
+
+    /// Uncomments a commented out `#[repo]` header and the commented entries directly below it,
+    /// up to the first blank line.
+    /// This is how repos like multilib are shipped in the default pacman.conf.
+    /// Returns false if no such section was found.
+    pub fn enable_repo(&mut self, repo: &str) -> bool {
+        let header = format!("[{repo}]");
+        let Some(start) = self.lines.iter().position(|l| {
+            matches!(l.kind, Kind::Other)
+                && l.raw
+                    .as_deref()
+                    .and_then(|r| r.trim().strip_prefix('#'))
+                    .is_some_and(|r| r.trim() == header)
+        }) else {
+            return false;
+        };
+        for idx in start..self.lines.len() {
+            let line = &self.lines[idx];
+            let Some(uncommented) = line
+                .raw
+                .as_deref()
+                .and_then(|r| r.trim_start().strip_prefix('#'))
+            else {
+                break;
+            };
+            let parsed = Line::parse(uncommented);
+            let is_header = matches!(parsed.kind, Kind::Section(_));
+            if (is_header && idx != start) || matches!(parsed.kind, Kind::Other) {
+                break;
+            }
+            self.lines[idx] = parsed;
+        }
+        true
+    }
+}
+
+impl std::fmt::Display for Document {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (idx, line) in self.lines.iter().enumerate() {
+            f.write_str(&line.render())?;
+            if idx + 1 < self.lines.len() || self.trailing_newline {
+                f.write_str("\n")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+const TEST_CONF: &str = "#
+# /etc/pacman.conf
+#
+[options]
+#RootDir     = /
+HoldPkg     = pacman glibc
+Architecture = auto
+IgnorePkg   = foo
+CheckSpace
+SomeFutureOption = 1
+
+[core]
+Include = /etc/pacman.d/mirrorlist
+
+#[multilib]
+#Include = /etc/pacman.d/mirrorlist
+
+# An example of a custom package repository.
+[custom]
+SigLevel = Optional TrustAll
+Server = file:///home/custompkgs
+";
+
+#[test]
+fn test_roundtrip() {
+    let doc = Document::parse(TEST_CONF);
+    assert_eq!(doc.to_string(), TEST_CONF);
+    assert_eq!(
+        doc.sections().collect::<Vec<_>>(),
+        ["options", "core", "custom"]
+    );
+    assert_eq!(doc.get("options", "CheckSpace"), [""]);
+    assert_eq!(doc.get("options", "SomeFutureOption"), ["1"]);
+    let no_trailing = TEST_CONF.trim_end();
+    assert_eq!(Document::parse(no_trailing).to_string(), no_trailing);
+}
+
+#[test]
+fn test_edit() {
+    let mut doc = Document::parse(TEST_CONF);
+    doc.list_add("options", "IgnorePkg", "bar");
+    doc.list_add("options", "IgnorePkg", "foo");
+    assert_eq!(doc.get("options", "IgnorePkg"), ["foo bar"]);
+    doc.list_remove("options", "IgnorePkg", "foo");
+    doc.list_remove("options", "IgnorePkg", "bar");
+    assert!(doc.get("options", "IgnorePkg").is_empty());
+    doc.list_add("options", "IgnorePkg", "baz");
+    doc.set("options", "ParallelDownloads", Some("5"));
+    doc.remove("options", "CheckSpace");
+    doc.set("core", "Usage", Some("Sync"));
+    doc.remove("custom", "HoldPkg");
+
+    assert!(doc.enable_repo("multilib"));
+    assert!(!doc.enable_repo("nope"));
+    doc.disable_repo("custom");
+    doc.set("new", "Server", Some("https://example.com"));
+
+    let expected = TEST_CONF
+        .replace("IgnorePkg   = foo\nCheckSpace\n", "")
+        .replace(
+            "SomeFutureOption = 1\n",
+            "SomeFutureOption = 1\nIgnorePkg = baz\nParallelDownloads = 5\n",
+        )
+        .replace(
+            "[core]\nInclude = /etc/pacman.d/mirrorlist\n",
+            "[core]\nInclude = /etc/pacman.d/mirrorlist\nUsage = Sync\n",
+        )
+        .replace("#[multilib]\n#Include", "[multilib]\nInclude")
+        .replace(
+            "[custom]\nSigLevel = Optional TrustAll\nServer = file:///home/custompkgs\n",
+            "#[custom]\n#SigLevel = Optional TrustAll\n#Server = file:///home/custompkgs\n\n[new]\nServer = https://example.com\n",
+        );
+    assert_eq!(doc.to_string(), expected);
+    assert_eq!(
+        doc.sections().collect::<Vec<_>>(),
+        ["options", "core", "multilib", "new"]
+    );
+}
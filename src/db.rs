@@ -1,158 +1,857 @@
+mod archive;
+mod builder;
+mod check;
+mod database;
+mod depend;
+mod display;
+pub mod graph;
+pub mod mtree;
+#[cfg(feature = "parallel")]
+mod parallel;
 mod parse;
-use log::debug;
+mod pkgfile;
+pub mod repo;
+mod resolved;
+#[cfg(feature = "serde")]
+mod serialize;
+mod soname;
+mod version;
+pub use builder::PackageBuilder;
+pub use check::{BackupStatus, CheckLevel, Mismatch, check_files};
+pub use database::{
+    Database, Db, DbDiff, LocalDb, LocalPackage, SearchQuery, SyncDb, SyncPackage, dep_name, diff,
+};
+pub use depend::{DepMod, Depend};
+pub use display::PackageInfo;
+use log::{debug, warn};
+#[cfg(feature = "sync")]
+pub use parse::SyncInterner;
 pub use parse::new_interner;
-pub use parse::{Interner, Istr, Package, QuickResolve};
+pub use parse::{
+    Arch, Backup, FileList, InstallReason, Interner, Istr, Package, QuickResolve, Validation,
+};
 pub use parse::{versioncmp, versionparse};
-use std::{collections::HashMap, io::Read};
+pub use pkgfile::{Compression, PkgFile, parse_pkg_filename};
+pub use resolved::ResolvedPackage;
+#[cfg(feature = "serde")]
+pub use serialize::{PackageSeed, SerializePackage};
+pub use soname::Soname;
+use std::{
+    collections::{HashMap, HashSet},
+    io::{BufRead, Read, Write},
+    path::Path,
+};
+pub use version::{InvalidVersion, Version};
 
+use crate::events::EventSink;
+
+pub const DBPATH: &str = "/var/lib/pacman/";
 const LOCAL_DBPATH: &str = "/var/lib/pacman/local/";
 const SYNC_DBPATH: &str = "/var/lib/pacman/sync/";
 
 /// returns name -> package
+/// With the parallel feature the descs are parsed on the rayon thread pool.
 pub fn parse_localdb(i: Interner) -> std::io::Result<HashMap<Istr, Package>> {
-    debug!("parsing localdb");
-    let v = std::fs::read(format!("{LOCAL_DBPATH}/ALPM_DB_VERSION"))?;
-    let e = "invalid version";
-    let v = String::from_utf8(v).expect(e);
-    let v: u64 = v.trim().parse().expect(e);
-    assert_eq!(v, 9, "{e}");
-
-    let mut s = String::with_capacity(32_000);
-    let mut pkgs = HashMap::new();
-    for dir in std::fs::read_dir(LOCAL_DBPATH).unwrap() {
-        let dir = dir.unwrap();
-        if !dir.metadata().unwrap().is_dir() {
-            continue;
-        }
+    parse_localdb_at(i, Path::new(LOCAL_DBPATH))
+}
 
-        let desc = dir.path().join("desc");
-        let mut desc = std::fs::File::open(desc)?;
-        s.clear();
-        desc.read_to_string(&mut s)?;
+/// Like [parse_localdb] but reads the local db from `local_dbpath` (usually `<dbpath>/local`).
+pub fn parse_localdb_at(
+    i: Interner,
+    local_dbpath: &Path,
+) -> std::io::Result<HashMap<Istr, Package>> {
+    debug!("parsing localdb at {}", local_dbpath.display());
+    #[cfg(feature = "parallel")]
+    let pkgs = parallel::localdb(&i, local_dbpath)?;
+    #[cfg(not(feature = "parallel"))]
+    let pkgs = iter_localdb_at(i, local_dbpath)?
+        .map(|p| p.map(|p| (p.name, p)))
+        .collect::<std::io::Result<_>>()?;
+    Ok(pkgs)
+}
 
-        debug!("parsing {}", dir.path().display());
-        let pkg = Package::from_str(i.clone(), &s).unwrap();
+/// Like [parse_localdb_at] but maps the desc files into memory instead of reading them,
+/// saving a read and a copy per package.
+/// The db must not be modified while it is mapped, lock is the [DBLock] of the dbpath
+/// local_dbpath is in, which keeps pacman and this crate from writing to it.
+#[cfg(feature = "mmap")]
+pub fn parse_localdb_mmap_at(
+    i: Interner,
+    local_dbpath: &Path,
+    _lock: &DBLock,
+) -> std::io::Result<HashMap<Istr, Package>> {
+    debug!("mapping localdb at {}", local_dbpath.display());
+    let mut pkgs = HashMap::new();
+    for dir in localdb_dirs(local_dbpath)? {
+        let dir = dir?;
+        let desc = std::fs::File::open(dir.join("desc"))?;
+        // Safety: pacman replaces desc files instead of writing to them,
+        // and concurrent writers are excluded by the lock.
+        let desc = unsafe { memmap2::Mmap::map(&desc)? };
+        let s = std::str::from_utf8(&desc)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+
+        debug!("parsing {}", dir.display());
+        let pkg = Package::from_str(i.clone(), s).map_err(invalid_package)?;
         pkgs.insert(pkg.name, pkg);
     }
     Ok(pkgs)
 }
 
-pub fn parse_syncdb(i: Interner, name: &str) -> std::io::Result<HashMap<Istr, Package>> {
+/// The `ALPM_DB_VERSION` this crate reads.
+pub const LOCALDB_VERSION: u64 = 9;
+
+/// The local db has a version other than [LOCALDB_VERSION].
+/// Returned inside of an [std::io::Error] of kind [std::io::ErrorKind::InvalidData],
+/// get it back with `e.get_ref().and_then(|e| e.downcast_ref::<UnsupportedDbVersion>())`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnsupportedDbVersion {
+    /// Contents of `ALPM_DB_VERSION`.
+    pub found: String,
+}
+
+impl std::fmt::Display for UnsupportedDbVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "unsupported local db version {:?}, expected {LOCALDB_VERSION}",
+            self.found
+        )
+    }
+}
+
+impl std::error::Error for UnsupportedDbVersion {}
+
+/// Checks the local db version and returns its package directories.
+fn localdb_dirs(
+    local_dbpath: &Path,
+) -> std::io::Result<impl Iterator<Item = std::io::Result<std::path::PathBuf>> + use<>> {
+    match std::fs::read_to_string(local_dbpath.join("ALPM_DB_VERSION")) {
+        Ok(v) if v.trim().parse() == Ok(LOCALDB_VERSION) => (),
+        Ok(v) => {
+            let found = v.trim().to_owned();
+            let e = UnsupportedDbVersion { found };
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e));
+        }
+        // Dbs from before pacman 4.2 have no version file.
+        // Their desc and files entries have the same format,
+        // only file lists may go through directories that are symlinks by now, like /lib.
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            warn!(
+                "{} has no ALPM_DB_VERSION, reading it as a pre 4.2 db",
+                local_dbpath.display()
+            );
+        }
+        Err(e) => return Err(e),
+    }
+
+    let dirs = std::fs::read_dir(local_dbpath)?.filter_map(|dir| {
+        dir.and_then(|dir| Ok(dir.metadata()?.is_dir().then(|| dir.path())))
+            .transpose()
+    });
+    Ok(dirs)
+}
+
+/// Reads the files entry of every installed package.
+/// returns name -> files and backups
+pub fn parse_localdb_files(i: Interner) -> std::io::Result<HashMap<Istr, FileList>> {
+    parse_localdb_files_at(i, Path::new(LOCAL_DBPATH))
+}
+
+/// Like [parse_localdb_files] but reads the local db from `local_dbpath`.
+pub fn parse_localdb_files_at(
+    i: Interner,
+    local_dbpath: &Path,
+) -> std::io::Result<HashMap<Istr, FileList>> {
+    debug!("parsing local files at {}", local_dbpath.display());
+    let mut lists = HashMap::new();
+    for dir in localdb_dirs(local_dbpath)? {
+        let dir = dir?;
+        let desc = std::fs::read_to_string(dir.join("desc"))?;
+        let name = i.borrow_mut().get_or_intern(desc_name(&desc)?);
+        // Packages without files, like meta packages, may have no files entry.
+        let list = match std::fs::read_to_string(dir.join("files")) {
+            Ok(s) => FileList::parse(&s),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => FileList::default(),
+            Err(e) => return Err(e),
+        };
+        lists.insert(name, list);
+    }
+    Ok(lists)
+}
+
+/// returns name -> package
+/// With the parallel feature the descs are parsed on the rayon thread pool.
+/// Start and end are reported to events.
+pub fn parse_syncdb(
+    i: Interner,
+    name: &str,
+    events: &dyn EventSink,
+) -> std::io::Result<HashMap<Istr, Package>> {
+    parse_syncdb_at(i, Path::new(SYNC_DBPATH), name, events)
+}
+
+/// Like [parse_syncdb] but reads `<name>.db` from `sync_dbpath` (usually `<dbpath>/sync`).
+pub fn parse_syncdb_at(
+    i: Interner,
+    sync_dbpath: &Path,
+    name: &str,
+    events: &dyn EventSink,
+) -> std::io::Result<HashMap<Istr, Package>> {
     debug!("parsing sync db {name}");
-    let dbfile = format!("{SYNC_DBPATH}/{name}.db");
+    events.db_parse_started(name);
+    #[cfg(feature = "parallel")]
+    let pkgs = parallel::syncdb(&i, &sync_dbpath.join(format!("{name}.db")))?;
+    #[cfg(not(feature = "parallel"))]
+    let pkgs: HashMap<_, _> = iter_syncdb_at(i, sync_dbpath, name)?
+        .map(|p| p.map(|p| (p.name, p)))
+        .collect::<std::io::Result<_>>()?;
+    events.db_parse_finished(name, pkgs.len());
+    Ok(pkgs)
+}
+
+/// Reads `/var/lib/pacman/sync/<name>.files`, as downloaded by `pacman -Fy`.
+/// returns package name -> files
+pub fn parse_files_db(i: Interner, name: &str) -> std::io::Result<HashMap<Istr, FileList>> {
+    parse_files_db_at(i, Path::new(SYNC_DBPATH), name)
+}
+
+/// Like [parse_files_db] but reads `<name>.files` from `sync_dbpath`.
+pub fn parse_files_db_at(
+    i: Interner,
+    sync_dbpath: &Path,
+    name: &str,
+) -> std::io::Result<HashMap<Istr, FileList>> {
+    debug!("parsing files db {name}");
+    // Each package directory contains a desc, for the name, and a files entry.
+    let mut names = HashMap::new();
+    let mut lists = HashMap::new();
+    let mut err = None;
+    read_sync_archive(&sync_dbpath.join(format!("{name}.files")), |path, s| {
+        let Some((dir, file)) = path.rsplit_once('/') else {
+            return;
+        };
+        match file {
+            "desc" => match desc_name(s) {
+                Ok(name) => {
+                    let name = i.borrow_mut().get_or_intern(name);
+                    names.insert(dir.to_owned(), name);
+                }
+                Err(e) => {
+                    err.get_or_insert(e);
+                }
+            },
+            "files" => {
+                lists.insert(dir.to_owned(), FileList::parse(s));
+            }
+            _ => (),
+        }
+    })?;
+    if let Some(e) = err {
+        return Err(e);
+    }
+    Ok(lists
+        .into_iter()
+        .filter_map(|(dir, list)| Some((*names.get(&dir)?, list)))
+        .collect())
+}
+
+/// Wraps r in the decompressor matching its magic bytes,
+/// anything unknown is assumed to be an uncompressed tar.
+pub(crate) fn decompress<'r>(mut r: impl BufRead + 'r) -> std::io::Result<Box<dyn Read + 'r>> {
+    let magic = r.fill_buf()?;
+    Ok(if magic.starts_with(&[0x1f, 0x8b]) {
+        Box::new(flate2::bufread::GzDecoder::new(r))
+    } else if magic.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0]) {
+        Box::new(xz2::bufread::XzDecoder::new(r))
+    } else if magic.starts_with(b"BZh") {
+        Box::new(bzip2::bufread::BzDecoder::new(r))
+    } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        Box::new(zstd::Decoder::with_buffer(r)?)
+    } else {
+        Box::new(r)
+    })
+}
+
+#[test]
+fn test_decompress() {
+    use std::io::Write;
+    let data = b"not really a tar";
+    let decompressed = |compressed: Vec<u8>| {
+        let mut out = Vec::new();
+        decompress(compressed.as_slice())
+            .unwrap()
+            .read_to_end(&mut out)
+            .unwrap();
+        out
+    };
+    let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+    gz.write_all(data).unwrap();
+    assert_eq!(decompressed(gz.finish().unwrap()), data);
+    let mut xz = xz2::write::XzEncoder::new(Vec::new(), 1);
+    xz.write_all(data).unwrap();
+    assert_eq!(decompressed(xz.finish().unwrap()), data);
+    let mut bz = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::fast());
+    bz.write_all(data).unwrap();
+    assert_eq!(decompressed(bz.finish().unwrap()), data);
+    assert_eq!(decompressed(zstd::encode_all(&data[..], 1).unwrap()), data);
+    assert_eq!(decompressed(data.to_vec()), data);
+}
+
+/// path and contents of every file in the db archive, streamed one at a time.
+/// The compression is detected, see [decompress].
+fn sync_archive(
+    dbfile: &Path,
+) -> std::io::Result<impl Iterator<Item = std::io::Result<(String, String)>> + use<>> {
     let dbfile = std::fs::File::open(dbfile)?;
-    let mut dbfile = flate2::read::GzDecoder::new(dbfile);
+    let dbfile = decompress(std::io::BufReader::new(dbfile))?;
+    Ok(archive::Entries::new(dbfile))
+}
 
-    let mut archive = Vec::new();
-    dbfile.read_to_end(&mut archive)?;
-    let seek_archive = std::io::Cursor::new(&archive);
-    let mut seek_archive = tar::Archive::new(seek_archive);
+/// Calls f with the path and contents of every file in the db archive.
+fn read_sync_archive(dbfile: &Path, mut f: impl FnMut(&str, &str)) -> std::io::Result<()> {
+    for entry in sync_archive(dbfile)? {
+        let (path, s) = entry?;
+        f(&path, &s);
+    }
+    Ok(())
+}
 
-    let mut pkgs = HashMap::new();
-    for entry in seek_archive.entries_with_seek()? {
-        let entry = entry?;
-        if !entry.header().entry_type().is_file() {
-            continue;
+fn invalid_package(e: parse::MissingFieldError) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string())
+}
+
+fn invalid_desc(e: nom::Err<nom::error::Error<&str>>) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string())
+}
+
+/// The NAME of the desc s, [std::io::ErrorKind::InvalidData] if it has none.
+fn desc_name(s: &str) -> std::io::Result<&str> {
+    let m = parse::parse_to_map(s).map_err(invalid_desc)?;
+    m.get("NAME")
+        .copied()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "desc without a NAME"))
+}
+
+/// Like [parse_syncdb] but yields the packages one at a time instead of collecting them,
+/// for callers that only need a single pass.
+pub fn iter_syncdb(
+    i: Interner,
+    name: &str,
+) -> std::io::Result<impl Iterator<Item = std::io::Result<Package>> + use<>> {
+    iter_syncdb_at(i, Path::new(SYNC_DBPATH), name)
+}
+
+/// Like [iter_syncdb] but reads `<name>.db` from `sync_dbpath`.
+pub fn iter_syncdb_at(
+    i: Interner,
+    sync_dbpath: &Path,
+    name: &str,
+) -> std::io::Result<impl Iterator<Item = std::io::Result<Package>> + use<>> {
+    let entries = sync_archive(&sync_dbpath.join(format!("{name}.db")))?;
+    Ok(entries.map(move |entry| {
+        let (_, s) = entry?;
+        Package::from_str(i.clone(), &s).map_err(invalid_package)
+    }))
+}
+
+/// Like [parse_localdb] but yields the packages one at a time instead of collecting them.
+pub fn iter_localdb(
+    i: Interner,
+) -> std::io::Result<impl Iterator<Item = std::io::Result<Package>> + use<>> {
+    iter_localdb_at(i, Path::new(LOCAL_DBPATH))
+}
+
+/// Like [iter_localdb] but reads the local db from `local_dbpath`.
+pub fn iter_localdb_at(
+    i: Interner,
+    local_dbpath: &Path,
+) -> std::io::Result<impl Iterator<Item = std::io::Result<Package>> + use<>> {
+    let dirs = localdb_dirs(local_dbpath)?;
+    Ok(dirs.map(move |dir| {
+        let s = std::fs::read_to_string(dir?.join("desc"))?;
+        Package::from_str(i.clone(), &s).map_err(invalid_package)
+    }))
+}
+
+/// What [update_candidates] and [find_upgrades] compare and report.
+/// By default all given sync dbs are compared, nothing is ignored, replacements are offered
+/// and downgrades are not.
+///
+/// Ex: ```UpdateOptions::default().db("core").db("extra").ignore("linux").downgrades(true)```
+#[derive(Clone, Debug)]
+pub struct UpdateOptions {
+    dbs: Vec<String>,
+    ignore: Vec<String>,
+    ignore_groups: Vec<String>,
+    architectures: Vec<Arch>,
+    replaces: bool,
+    downgrades: bool,
+    skip_debug: bool,
+}
+
+impl Default for UpdateOptions {
+    fn default() -> Self {
+        Self {
+            dbs: Vec::new(),
+            ignore: Vec::new(),
+            ignore_groups: Vec::new(),
+            architectures: Vec::new(),
+            replaces: true,
+            downgrades: false,
+            skip_debug: false,
         }
+    }
+}
 
-        // Avoid a copy by indexing into the archive
-        let start = entry.raw_file_position() as usize;
-        let size = entry.size() as usize;
-        let end = start + size;
-        let slice = &archive[start..end];
-        let s = std::str::from_utf8(slice).unwrap();
+impl UpdateOptions {
+    /// Adds a sync db to compare against, in order of priority.
+    /// [update_candidates] parses exactly these,
+    /// [find_upgrades] compares all given sync dbs if none is added.
+    pub fn db(mut self, name: impl Into<String>) -> Self {
+        self.dbs.push(name.into());
+        self
+    }
 
-        let pkg = Package::from_str(i.clone(), s).expect("package parsing failed");
-        pkgs.insert(pkg.name, pkg);
+    /// Skips the local package name, like IgnorePkg.
+    pub fn ignore(mut self, name: impl Into<String>) -> Self {
+        self.ignore.push(name.into());
+        self
     }
 
-    Ok(pkgs)
+    /// Skips the local packages in group, like IgnoreGroup.
+    pub fn ignore_group(mut self, group: impl Into<String>) -> Self {
+        self.ignore_groups.push(group.into());
+        self
+    }
+
+    /// Allows sync packages built for arch, besides any.
+    /// Without any architecture all are allowed, like Architecture.
+    /// An [Arch::Other] has to be interned into the interner of the packages.
+    pub fn architecture(mut self, arch: Arch) -> Self {
+        self.architectures.push(arch);
+        self
+    }
+
+    /// Whether sync packages replacing a local package are offered as its upgrade.
+    pub fn replaces(mut self, replaces: bool) -> Self {
+        self.replaces = replaces;
+        self
+    }
+
+    /// Whether local packages newer than in the sync dbs are offered too,
+    /// to go back to the sync version like `pacman -Suu`.
+    pub fn downgrades(mut self, downgrades: bool) -> Self {
+        self.downgrades = downgrades;
+        self
+    }
+
+    /// Whether local debug packages, see [Package::is_debug], are left out.
+    pub fn skip_debug(mut self, skip_debug: bool) -> Self {
+        self.skip_debug = skip_debug;
+        self
+    }
 }
 
-/// only gets upgrades, no new dependencies
-pub fn update_candidates<'db>(
+/// only gets upgrades, no new dependencies.
+/// Parses the local db and the sync dbs of options, see [find_upgrades] for how they are compared.
+/// Parsing the sync dbs and comparing the local packages is reported to events.
+pub fn update_candidates<'o>(
     i: &Interner,
-    dbs: &'db [&str],
-    ignore: &[Istr],
-) -> Vec<(&'db str, Package, Package)> {
-    let local = parse_localdb(i.clone()).unwrap();
+    options: &'o UpdateOptions,
+    events: &dyn EventSink,
+) -> std::io::Result<Vec<(&'o str, Package, Package)>> {
+    let local = parse_localdb(i.clone())?;
 
-    let syncs: Vec<_> = dbs
+    let syncs = options
+        .dbs
         .iter()
-        .map(|name| (name, parse_syncdb(i.clone(), name).unwrap()))
-        .collect();
+        .map(|name| Ok((name.as_str(), parse_syncdb(i.clone(), name, events)?)))
+        .collect::<std::io::Result<Vec<_>>>()?;
     i.borrow_mut().shrink_to_fit();
-    let i = i.borrow();
+    Ok(find_upgrade_refs(i, &local, &syncs, options, events)
+        .into_iter()
+        .map(|(repo, old, new)| (repo, old.clone(), new.clone()))
+        .collect())
+}
+
+/// The comparison step of [update_candidates], on already parsed databases.
+/// syncs are in order of priority, like pacman a package is only taken from the first one
+/// that contains it or replaces it, so every local package has at most one upgrade.
+/// A replacement wins over a newer version in the same repo,
+/// of several replacements in one repo the first by name is taken.
+/// Local packages ignored by options are skipped, sync packages built for an architecture
+/// options do not allow are skipped too, and reported to events.
+pub fn find_upgrades<'db>(
+    i: &Interner,
+    local: &HashMap<Istr, Package>,
+    syncs: &[(&'db str, HashMap<Istr, Package>)],
+    options: &UpdateOptions,
+    events: &dyn EventSink,
+) -> Vec<(&'db str, Package, Package)> {
+    find_upgrade_refs(i, local, syncs, options, events)
+        .into_iter()
+        .map(|(repo, old, new)| (repo, old.clone(), new.clone()))
+        .collect()
+}
+
+/// Like [find_upgrades], but borrows the packages from the dbs instead of cloning them,
+/// for callers that keep the dbs around anyway, e.g. to only print the upgrades.
+pub fn find_upgrade_refs<'db, 'p>(
+    i: &Interner,
+    local: &'p HashMap<Istr, Package>,
+    syncs: &'p [(&'db str, HashMap<Istr, Package>)],
+    options: &UpdateOptions,
+    events: &dyn EventSink,
+) -> Vec<(&'db str, &'p Package, &'p Package)> {
     let mut upgrades = Vec::new();
-    for (name, package) in local.iter().filter(|(s, _)| !ignore.contains(s)) {
-        let package_version = package.version.r(&i);
-        let package_version = parse::versionparse(package_version).unwrap();
-        for (dbname, db) in &syncs {
-            for (sync_name, sync_package) in db {
-                let is_upgrade = if *sync_name == *name {
-                    let sync_package_version = sync_package.version.r(&i);
-                    let sync_package_version = parse::versionparse(sync_package_version).unwrap();
-                    match package_version.cmp(&sync_package_version) {
-                        std::cmp::Ordering::Less => true,
-                        std::cmp::Ordering::Equal => false,
-                        std::cmp::Ordering::Greater => {
-                            use log;
-                            log::warn!(
-                                "downgrade? {name:?}: {package_version:?} to {sync_package_version:?}",
-                            );
-                            false
-                        }
+    let (ignore, ignore_groups): (HashSet<Istr>, HashSet<Istr>) = {
+        // names that were never interned can not match any package
+        let ii = i.borrow();
+        (
+            options
+                .ignore
+                .iter()
+                .filter_map(|n| ii.get(n.trim()))
+                .collect(),
+            options
+                .ignore_groups
+                .iter()
+                .filter_map(|g| ii.get(g.trim()))
+                .collect(),
+        )
+    };
+    let ignored_group = |p: &Package| p.groups.iter().flatten().any(|g| ignore_groups.contains(g));
+    let compared: Vec<_> = local
+        .iter()
+        .filter(|(s, _)| !ignore.contains(s))
+        .filter(|(_, p)| !ignored_group(p))
+        .filter(|(_, p)| !(options.skip_debug && p.is_debug(i)))
+        .collect();
+    let syncs: Vec<_> = syncs
+        .iter()
+        .filter(|(name, _)| options.dbs.is_empty() || options.dbs.iter().any(|d| d == name))
+        .collect();
+    let architectures = &options.architectures;
+    let arch_allowed = |p: &Package| {
+        let allowed =
+            architectures.is_empty() || p.arch == Arch::Any || architectures.contains(&p.arch);
+        if !allowed {
+            let name = p.name.r(&i.borrow()).to_owned();
+            let arch = p.arch.r(&i.borrow()).to_owned();
+            log::warn!("{name} is built for {arch}");
+            events.arch_mismatch(&name, &arch);
+        }
+        allowed
+    };
+    // per sync db: replaced local name -> the package replacing it, first by name,
+    // so every local package is looked up instead of compared to every sync package
+    let replaces: Vec<HashMap<Istr, &Package>> = syncs
+        .iter()
+        .map(|(_, db)| {
+            let mut index: HashMap<Istr, &Package> = HashMap::new();
+            if !options.replaces {
+                return index;
+            }
+            for p in db.values() {
+                // like pacman by name and version, the provides of the local package do not count
+                let replaced: Vec<Istr> = p
+                    .replaces
+                    .iter()
+                    .flatten()
+                    .filter_map(|r| {
+                        let ii = i.borrow();
+                        let r: Depend = r.r(&ii).parse().ok()?;
+                        let name = ii.get(&r.name)?;
+                        drop(ii);
+                        let old = local.get(&name)?;
+                        r.allows(&r.name, Some(old.parsed_version(i)))
+                            .then_some(name)
+                    })
+                    .collect();
+                if replaced.is_empty() || !arch_allowed(p) {
+                    continue;
+                }
+                let ii = i.borrow();
+                for r in replaced {
+                    let replacing = index.entry(r).or_insert(p);
+                    if p.name.r(&ii) < replacing.name.r(&ii) {
+                        *replacing = p;
+                    }
+                }
+            }
+            index
+        })
+        .collect();
+    for (n, &(name, package)) in compared.iter().enumerate() {
+        events.package_compared(package.name.r(&i.borrow()), n + 1, compared.len());
+        let package_version = package.parsed_version(i);
+        // Like pacman only the first repo containing or replacing the package is considered,
+        // so e.g. core-testing shadows core even if core has a newer version.
+        for ((dbname, db), replaces) in syncs.iter().zip(&replaces) {
+            if let Some(replacement) = replaces.get(name) {
+                upgrades.push((*dbname, package, *replacement));
+                break;
+            }
+            if let Some(sync_package) = db.get(name).filter(|p| arch_allowed(p)) {
+                let sync_package_version = sync_package.parsed_version(i);
+                match package_version.cmp(sync_package_version) {
+                    std::cmp::Ordering::Less => upgrades.push((*dbname, package, sync_package)),
+                    std::cmp::Ordering::Equal => (),
+                    std::cmp::Ordering::Greater if options.downgrades => {
+                        upgrades.push((*dbname, package, sync_package))
+                    }
+                    std::cmp::Ordering::Greater => {
+                        log::warn!(
+                            "downgrade? {name:?}: {package_version} to {sync_package_version}",
+                        );
                     }
-                } else if let Some(r) = &sync_package.replaces {
-                    r.contains(name)
-                } else {
-                    false
-                };
-
-                if is_upgrade {
-                    upgrades.push((**dbname, package.clone(), sync_package.clone()));
                 }
+                break;
             }
         }
     }
     upgrades
 }
 
+/// The opposite of [find_upgrade_refs]: local packages newer than in the first repo
+/// containing them, e.g. after leaving a testing repo, what `pacman -Suu` downgrades.
+/// Replacements are never downgrades.
+pub fn find_downgrade_refs<'db, 'p>(
+    i: &Interner,
+    local: &'p HashMap<Istr, Package>,
+    syncs: &'p [(&'db str, HashMap<Istr, Package>)],
+    options: &UpdateOptions,
+    events: &dyn EventSink,
+) -> Vec<(&'db str, &'p Package, &'p Package)> {
+    let options = options.clone().replaces(false).downgrades(true);
+    find_upgrade_refs(i, local, syncs, &options, events)
+        .into_iter()
+        .filter(|(_, old, new)| old.parsed_version(i) > new.parsed_version(i))
+        .collect()
+}
+
+/// What [search_files] looks for.
+pub enum FileQuery {
+    /// Like `pacman -F`: matches the file name,
+    /// or the whole path if the query contains a /.
+    Exact(String),
+    /// Like `pacman -Fx`: matched anywhere in the whole path.
+    Regex(regex::Regex),
+}
+
+impl FileQuery {
+    pub fn matches(&self, path: &str) -> bool {
+        match self {
+            FileQuery::Exact(q) if q.contains('/') => {
+                q.trim_start_matches('/') == path.trim_start_matches('/')
+            }
+            FileQuery::Exact(q) => path.trim_end_matches('/').rsplit('/').next() == Some(q),
+            FileQuery::Regex(r) => r.is_match(path),
+        }
+    }
+}
+
+/// Searches already parsed files dbs, see [parse_files_db].
+/// returns (repo, package name, matching paths) in the order of dbs
+pub fn search_files<'db>(
+    dbs: &'db [(&'db str, HashMap<Istr, FileList>)],
+    query: &FileQuery,
+) -> Vec<(&'db str, Istr, Vec<&'db str>)> {
+    let mut ret = Vec::new();
+    for (repo, db) in dbs {
+        for (name, list) in db {
+            let matches: Vec<_> = list
+                .files
+                .iter()
+                .map(String::as_str)
+                .filter(|f| query.matches(f))
+                .collect();
+            if !matches.is_empty() {
+                ret.push((*repo, *name, matches));
+            }
+        }
+    }
+    ret
+}
+
+/// When `<name>.db` in sync_dbpath was last written.
+pub fn syncdb_modified(sync_dbpath: &Path, name: &str) -> std::io::Result<std::time::SystemTime> {
+    std::fs::metadata(sync_dbpath.join(format!("{name}.db")))?.modified()
+}
+
+/// The repos whose db in sync_dbpath is older than max_age,
+/// so tools can ask for a `pacman -Sy` instead of silently finding no updates.
+/// returns (name, age), age is None if the db was never downloaded
+pub fn stale_syncdbs<'n>(
+    sync_dbpath: &Path,
+    names: &[&'n str],
+    max_age: std::time::Duration,
+) -> std::io::Result<Vec<(&'n str, Option<std::time::Duration>)>> {
+    let mut stale = Vec::new();
+    for name in names {
+        match syncdb_modified(sync_dbpath, name) {
+            Ok(modified) => {
+                let age = modified.elapsed().unwrap_or_default();
+                if age > max_age {
+                    stale.push((*name, Some(age)));
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => stale.push((*name, None)),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(stale)
+}
+
+#[test]
+fn test_stale_syncdbs() {
+    use std::time::{Duration, SystemTime};
+    let dir = crate::util::test_dir("stale_syncdbs");
+    let sync_fields = [("FILENAME", "foo-1-1-x86_64.pkg.tar.zst"), ("CSIZE", "42")];
+    let pkgs = [("foo-1-1", test_desc("foo", "1-1", &sync_fields))];
+    write_test_dbpath(&dir, &[], &[("core", &pkgs), ("extra", &pkgs)]);
+    let sync = dir.join("sync");
+    let day = Duration::from_secs(24 * 60 * 60);
+    std::fs::File::options()
+        .write(true)
+        .open(sync.join("extra.db"))
+        .unwrap()
+        .set_modified(SystemTime::now() - 20 * day)
+        .unwrap();
+
+    let stale = stale_syncdbs(&sync, &["core", "extra", "multilib"], 7 * day).unwrap();
+    assert_eq!(stale.len(), 2);
+    assert_eq!(stale[0].0, "extra");
+    assert!(stale[0].1.unwrap() >= 20 * day);
+    assert_eq!(stale[1], ("multilib", None));
+
+    let extra = SyncDb::open(new_interner(), &sync, "extra").unwrap();
+    assert!(extra.is_stale(7 * day));
+    assert!(!extra.is_stale(30 * day));
+}
+
 /// auto-unlocks on drop
-pub struct DBLock(#[allow(dead_code)] std::fs::File);
+pub struct DBLock(#[allow(dead_code)] std::fs::File, std::path::PathBuf);
 
 impl DBLock {
-    pub fn new() -> Result<Self, ()> {
-        match std::fs::OpenOptions::new()
+    /// Locks the default database.
+    /// Fails with [std::io::ErrorKind::AlreadyExists] if it is already locked.
+    pub fn new() -> std::io::Result<Self> {
+        Self::at(Path::new(DBPATH))
+    }
+
+    /// Locks the database in `dbpath` by creating `<dbpath>/db.lck`.
+    pub fn at(dbpath: &Path) -> std::io::Result<Self> {
+        let lockfile = dbpath.join("db.lck");
+        std::fs::OpenOptions::new()
             .create_new(true)
             .write(true)
             .read(false)
-            .open("/var/lib/pacman/db.lck")
-        {
-            Ok(f) => Ok(Self(f)),
-            Err(e) => {
-                if e.kind() == std::io::ErrorKind::AlreadyExists {
-                    Err(())
-                } else {
-                    panic!("unexpected error while locking database");
-                }
-            }
-        }
+            .open(&lockfile)
+            .map(|f| Self(f, lockfile))
     }
 }
 
 impl Drop for DBLock {
     fn drop(&mut self) {
-        std::fs::remove_file("/var/lib/pacman/db.lck").expect("error unlocking database")
+        std::fs::remove_file(&self.1).expect("error unlocking database")
     }
 }
 
+/// Like `pacman -D --asdeps/--asexplicit`: changes the install reason of the installed package name
+/// in the local db of dbpath, holding the [DBLock] while its desc is atomically replaced.
+/// Fails with [std::io::ErrorKind::NotFound] if the package is not installed.
+pub fn set_install_reason(dbpath: &Path, name: &str, reason: InstallReason) -> std::io::Result<()> {
+    let _lock = DBLock::at(dbpath)?;
+    let prefix = format!("{name}-");
+    for dir in localdb_dirs(&dbpath.join("local"))? {
+        let dir = dir?;
+        if !dir
+            .file_name()
+            .is_some_and(|d| d.to_string_lossy().starts_with(&prefix))
+        {
+            continue;
+        }
+        let descfile = dir.join("desc");
+        let desc = std::fs::read_to_string(&descfile)?;
+        let map = parse::parse_to_map(&desc).map_err(invalid_desc)?;
+        if map.get("NAME") == Some(&name) {
+            let desc = with_reason(&desc, reason);
+            return crate::util::replace(&descfile, |mut f| f.write_all(desc.as_bytes()));
+        }
+    }
+    Err(std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        format!("{name} is not installed"),
+    ))
+}
+
+/// desc with its REASON replaced, keeping everything else as is.
+/// Like pacman, explicitly installed packages get no REASON.
+fn with_reason(desc: &str, reason: InstallReason) -> String {
+    let mut entries: Vec<_> = desc
+        .split("\n\n")
+        .filter(|e| !e.is_empty() && !e.starts_with("%REASON%\n"))
+        .map(str::to_owned)
+        .collect();
+    if reason != InstallReason::Explicit {
+        // pacman writes it after the size
+        let pos = entries
+            .iter()
+            .position(|e| e.starts_with("%SIZE%\n"))
+            .map_or(entries.len(), |p| p + 1);
+        entries.insert(pos, format!("%REASON%\n{}", reason as u8));
+    }
+    entries.iter().map(|e| format!("{e}\n\n")).collect()
+}
+
+#[test]
+fn test_set_install_reason() {
+    let dir = crate::util::test_dir("set_install_reason");
+    let desc = |name| test_desc(name, "1-1", &[("INSTALLDATE", "1700000000"), ("SIZE", "7")]);
+    write_test_dbpath(
+        &dir,
+        &[("foo-1-1", desc("foo")), ("foo-bar-1-1", desc("foo-bar"))],
+        &[],
+    );
+    let reason = |name| {
+        let local = LocalDb::open(new_interner(), &dir.join("local")).unwrap();
+        local.package(name).unwrap().reason()
+    };
+    set_install_reason(&dir, "foo", InstallReason::Dependency).unwrap();
+    assert_eq!(reason("foo"), InstallReason::Dependency);
+    assert_eq!(reason("foo-bar"), InstallReason::Explicit);
+    let written = std::fs::read_to_string(dir.join("local/foo-1-1/desc")).unwrap();
+    assert!(written.contains("%SIZE%\n7\n\n%REASON%\n1\n\n"));
+
+    set_install_reason(&dir, "foo", InstallReason::Explicit).unwrap();
+    assert_eq!(reason("foo"), InstallReason::Explicit);
+    let written = std::fs::read_to_string(dir.join("local/foo-1-1/desc")).unwrap();
+    assert_eq!(written, desc("foo"));
+
+    let e = set_install_reason(&dir, "baz", InstallReason::Dependency).unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+    assert!(!dir.join("db.lck").exists());
+}
+
+#[test]
+fn test_update_missing_db() {
+    let options = UpdateOptions::default().db("no-such-repo");
+    let candidates = update_candidates(&new_interner(), &options, &crate::events::NoEvents);
+    assert!(candidates.is_err());
+}
+
 #[test]
 fn test_update() {
     use std::time::SystemTime;
     let ts = SystemTime::now();
     let i = new_interner();
-    let vers = update_candidates(&i, &["core", "extra", "multilib"], &[]);
+    let options = UpdateOptions::default()
+        .db("core")
+        .db("extra")
+        .db("multilib");
+    let vers = update_candidates(&i, &options, &crate::events::NoEvents).unwrap();
 
     let i = i.borrow();
     for (dbname, from, to) in vers {
@@ -168,6 +867,227 @@ fn test_update() {
     println!("update_candidates took {passed:?} seconds");
 }
 
+#[test]
+fn test_ignore_group() {
+    let i = new_interner();
+    let parse = |desc: String| Package::from_str(i.clone(), &desc).unwrap();
+    let local = HashMap::from_iter(
+        [
+            parse(test_desc("foo", "1-1", &[("GROUPS", "gnome\nxorg")])),
+            parse(test_desc("bar", "1-1", &[])),
+        ]
+        .map(|p| (p.name, p)),
+    );
+    let sync = HashMap::from_iter(
+        [
+            parse(test_desc("foo", "2-1", &[])),
+            parse(test_desc("bar", "2-1", &[])),
+        ]
+        .map(|p| (p.name, p)),
+    );
+    let syncs = [("core", sync)];
+    let default = UpdateOptions::default();
+    assert_eq!(
+        find_upgrades(&i, &local, &syncs, &default, &crate::events::NoEvents).len(),
+        2
+    );
+    let options = UpdateOptions::default().ignore_group("xorg");
+    let ups = find_upgrades(&i, &local, &syncs, &options, &crate::events::NoEvents);
+    assert_eq!(ups.len(), 1);
+    assert_eq!(ups[0].1.name, i.borrow_mut().get_or_intern("bar"));
+
+    // ignored packages are not compared at all
+    struct Record(std::sync::Mutex<Vec<(String, usize, usize)>>);
+    impl EventSink for Record {
+        fn package_compared(&self, name: &str, done: usize, total: usize) {
+            self.0.lock().unwrap().push((name.to_owned(), done, total));
+        }
+    }
+    let record = Record(Default::default());
+    find_upgrade_refs(&i, &local, &syncs, &options, &record);
+    assert_eq!(record.0.into_inner().unwrap(), [("bar".to_owned(), 1, 1)]);
+}
+
+#[test]
+fn test_repo_priority() {
+    use crate::db::QuickResolve;
+    let i = new_interner();
+    let parse = |desc: String| Package::from_str(i.clone(), &desc).unwrap();
+    let db =
+        |packages: &[Package]| HashMap::from_iter(packages.iter().map(|p| (p.name, p.clone())));
+    let local = db(&[
+        parse(test_desc("foo", "1-1", &[])),
+        parse(test_desc("bar", "1-1", &[])),
+    ]);
+    let testing = db(&[
+        parse(test_desc("foo", "2-1", &[])),
+        parse(test_desc("bar", "1-1", &[])),
+    ]);
+    let core = db(&[
+        parse(test_desc("foo", "1.5-1", &[])),
+        parse(test_desc("bar", "3-1", &[])),
+    ]);
+    let syncs = [("testing", testing), ("core", core)];
+    let options = UpdateOptions::default();
+    let ups = find_upgrades(&i, &local, &syncs, &options, &crate::events::NoEvents);
+    assert_eq!(ups.len(), 1);
+    assert_eq!(ups[0].0, "testing");
+    assert_eq!(ups[0].2.version.r(&i.borrow()), "2-1");
+    // bar is newer locally than in testing, core is not looked at
+    let local = db(&[parse(test_desc("bar", "2-1", &[]))]);
+    let downs = find_downgrade_refs(&i, &local, &syncs, &options, &crate::events::NoEvents);
+    assert_eq!(downs.len(), 1);
+    assert_eq!(downs[0].0, "testing");
+    assert_eq!(downs[0].2.version.r(&i.borrow()), "1-1");
+    assert!(find_upgrade_refs(&i, &local, &syncs, &options, &crate::events::NoEvents).is_empty());
+    let options = options.downgrades(true);
+    assert_eq!(
+        find_upgrade_refs(&i, &local, &syncs, &options, &crate::events::NoEvents).len(),
+        1
+    );
+    // only core is compared, its bar is newer
+    let options = options.db("core");
+    let ups = find_upgrade_refs(&i, &local, &syncs, &options, &crate::events::NoEvents);
+    assert_eq!(ups[0].2.version.r(&i.borrow()), "3-1");
+    assert!(find_downgrade_refs(&i, &local, &syncs, &options, &crate::events::NoEvents).is_empty());
+}
+
+#[test]
+fn test_versioned_replaces() {
+    let i = new_interner();
+    let parse = |desc: String| {
+        let p = Package::from_str(i.clone(), &desc).unwrap();
+        (p.name, p)
+    };
+    let local = HashMap::from([parse(test_desc("foo", "2-1", &[]))]);
+    let replacing = |replaces: &str| {
+        let core = HashMap::from([parse(test_desc("bar", "1-1", &[("REPLACES", replaces)]))]);
+        let syncs = [("core", core)];
+        let options = UpdateOptions::default();
+        find_upgrades(&i, &local, &syncs, &options, &crate::events::NoEvents).len()
+    };
+    assert_eq!(replacing("foo<2"), 0);
+    assert_eq!(replacing("foo<3"), 1);
+    assert_eq!(replacing("foo"), 1);
+    assert_eq!(replacing("foo>=2.1"), 0);
+}
+
+#[test]
+fn test_replaces() {
+    use crate::db::QuickResolve;
+    let i = new_interner();
+    let parse = |desc: String| Package::from_str(i.clone(), &desc).unwrap();
+    let db =
+        |packages: &[Package]| HashMap::from_iter(packages.iter().map(|p| (p.name, p.clone())));
+    let local = db(&[
+        parse(test_desc("old", "1-1", &[])),
+        parse(test_desc("other", "1-1", &[])),
+    ]);
+    let core = db(&[
+        parse(test_desc("other", "2-1", &[])),
+        parse(test_desc("new", "1-1", &[("REPLACES", "old\nold\nother")])),
+        parse(test_desc("alt", "1-1", &[("REPLACES", "other")])),
+    ]);
+    let extra = db(&[
+        parse(test_desc("newer", "1-1", &[("REPLACES", "old")])),
+        parse(test_desc("old", "2-1", &[])),
+    ]);
+    let syncs = [("core", core), ("extra", extra)];
+    let ups = find_upgrade_refs(
+        &i,
+        &local,
+        &syncs,
+        &UpdateOptions::default(),
+        &crate::events::NoEvents,
+    );
+    let plain = find_upgrade_refs(
+        &i,
+        &local,
+        &syncs,
+        &UpdateOptions::default().replaces(false),
+        &crate::events::NoEvents,
+    );
+    let i = i.borrow();
+    let names = |ups: &[(&str, &Package, &Package)]| {
+        let mut names: Vec<_> = ups
+            .iter()
+            .map(|(repo, old, new)| (repo.to_string(), old.name.r(&i), new.name.r(&i)))
+            .collect();
+        names.sort();
+        names
+    };
+    assert_eq!(
+        names(&ups),
+        [
+            ("core".into(), "old", "new"),
+            ("core".into(), "other", "alt")
+        ]
+    );
+    assert_eq!(
+        names(&plain),
+        [
+            ("core".into(), "other", "other"),
+            ("extra".into(), "old", "old")
+        ]
+    );
+}
+
+#[test]
+fn test_arch_filter() {
+    let i = new_interner();
+    let parse = |desc: String| Package::from_str(i.clone(), &desc).unwrap();
+    let db =
+        |packages: &[Package]| HashMap::from_iter(packages.iter().map(|p| (p.name, p.clone())));
+    let local = db(&[
+        parse(test_desc("foo", "1-1", &[])),
+        parse(test_desc("bar", "1-1", &[])),
+    ]);
+    let mut foo = parse(test_desc("foo", "2-1", &[]));
+    foo.arch = Arch::Aarch64;
+    let mut bar = parse(test_desc("bar", "2-1", &[]));
+    bar.arch = Arch::Any;
+    let mut baz = parse(test_desc("baz", "1-1", &[("REPLACES", "bar")]));
+    baz.arch = Arch::Riscv64;
+    let testing = db(&[foo, baz]);
+    let core = db(&[parse(test_desc("foo", "1.5-1", &[])), bar]);
+    let syncs = [("testing", testing), ("core", core)];
+    let default = UpdateOptions::default();
+    assert_eq!(
+        find_upgrades(&i, &local, &syncs, &default, &crate::events::NoEvents).len(),
+        2
+    );
+
+    struct Record(std::sync::Mutex<Vec<(String, String)>>);
+    impl EventSink for Record {
+        fn arch_mismatch(&self, name: &str, arch: &str) {
+            let mut mismatches = self.0.lock().unwrap();
+            mismatches.push((name.to_owned(), arch.to_owned()));
+        }
+    }
+    let record = Record(Default::default());
+    let options = UpdateOptions::default().architecture(Arch::X86_64);
+    let ups = find_upgrade_refs(&i, &local, &syncs, &options, &record);
+    let mut ups: Vec<_> = ups
+        .iter()
+        .map(|(repo, _, new)| (*repo, new.version.r(&i.borrow()).to_owned()))
+        .collect();
+    ups.sort();
+    assert_eq!(ups, [("core", "1.5-1".into()), ("core", "2-1".into())]);
+    let mut mismatches = record.0.into_inner().unwrap();
+    mismatches.sort();
+    let expected = [("baz", "riscv64"), ("foo", "aarch64")];
+    assert_eq!(
+        mismatches,
+        expected.map(|(n, a)| (n.to_owned(), a.to_owned()))
+    );
+
+    // an architecture not listed in Arch still restricts the upgrades
+    let sparc = UpdateOptions::default().architecture(Arch::new("sparc", &i));
+    let ups = find_upgrades(&i, &local, &syncs, &sparc, &crate::events::NoEvents);
+    assert_eq!(ups.len(), 1);
+    assert_eq!(ups[0].2.arch, Arch::Any);
+}
+
 #[test]
 fn test_syncdb() {
     use std::time::SystemTime;
@@ -175,11 +1095,11 @@ fn test_syncdb() {
 
     let i = new_interner();
 
-    let _core = parse_syncdb(i.clone(), "core").unwrap();
+    let _core = parse_syncdb(i.clone(), "core", &crate::events::NoEvents).unwrap();
     println!("core done");
-    let _multilib = parse_syncdb(i.clone(), "multilib").unwrap();
+    let _multilib = parse_syncdb(i.clone(), "multilib", &crate::events::NoEvents).unwrap();
     println!("multilib done");
-    let _extra = parse_syncdb(i.clone(), "extra").unwrap();
+    let _extra = parse_syncdb(i.clone(), "extra", &crate::events::NoEvents).unwrap();
     println!("extra done");
 
     let passed = SystemTime::now().duration_since(ts).unwrap();
@@ -197,3 +1117,214 @@ fn test_local() {
     let passed = SystemTime::now().duration_since(ts).unwrap();
     println!("local took {passed:?} seconds");
 }
+
+/// Minimal but complete desc for tests, `extra` entries are appended as `%KEY%` sections.
+#[cfg(test)]
+pub(crate) fn test_desc(name: &str, version: &str, extra: &[(&str, &str)]) -> String {
+    let mut s = format!(
+        "%NAME%\n{name}\n\n%BASE%\n{name}\n\n%VERSION%\n{version}\n\n%DESC%\ntest package {name}\n\n\
+        %ARCH%\nx86_64\n\n%BUILDDATE%\n1700000000\n\n%PACKAGER%\ntester\n\n%LICENSE%\nMIT\n\n"
+    );
+    for (k, v) in extra {
+        s.push_str(&format!("%{k}%\n{v}\n\n"));
+    }
+    s
+}
+
+/// Lays out a pacman dbpath in `dbpath` containing a local db and gzipped sync dbs.
+#[cfg(test)]
+pub(crate) fn write_test_dbpath(
+    dbpath: &Path,
+    local: &[(&str, String)],
+    syncs: &[(&str, &[(&str, String)])],
+) {
+    let localdir = dbpath.join("local");
+    std::fs::create_dir_all(&localdir).unwrap();
+    std::fs::write(localdir.join("ALPM_DB_VERSION"), "9\n").unwrap();
+    for (dir, desc) in local {
+        std::fs::create_dir_all(localdir.join(dir)).unwrap();
+        std::fs::write(localdir.join(dir).join("desc"), desc).unwrap();
+    }
+
+    let syncdir = dbpath.join("sync");
+    std::fs::create_dir_all(&syncdir).unwrap();
+    for (name, pkgs) in syncs {
+        let entries: Vec<_> = pkgs
+            .iter()
+            .map(|(dir, desc)| (format!("{dir}/desc"), desc.as_str()))
+            .collect();
+        write_test_archive(&syncdir.join(format!("{name}.db")), &entries);
+    }
+}
+
+/// Writes a gzipped tar like a sync db, with a directory entry before each file's directory.
+#[cfg(test)]
+pub(crate) fn write_test_archive(path: &Path, entries: &[(String, &str)]) {
+    let f = std::fs::File::create(path).unwrap();
+    let gz = flate2::write::GzEncoder::new(f, flate2::Compression::fast());
+    let mut tar = tar::Builder::new(gz);
+    let mut last_dir = None;
+    for (path, contents) in entries {
+        let dir = path.rsplit_once('/').map(|(d, _)| d);
+        if let Some(d) = dir
+            && dir != last_dir
+        {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(tar::EntryType::Directory);
+            header.set_size(0);
+            header.set_mode(0o755);
+            tar.append_data(&mut header, format!("{d}/"), std::io::empty())
+                .unwrap();
+            last_dir = dir;
+        }
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        tar.append_data(&mut header, path, contents.as_bytes())
+            .unwrap();
+    }
+    tar.into_inner().unwrap().finish().unwrap();
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn test_localdb_mmap() {
+    let dir = crate::util::test_dir("localdb_mmap");
+    write_test_dbpath(
+        &dir,
+        &[
+            ("foo-1-1", test_desc("foo", "1-1", &[])),
+            ("bar-2-1", test_desc("bar", "2-1", &[])),
+        ],
+        &[],
+    );
+    let i = new_interner();
+    let read = parse_localdb_at(i.clone(), &dir.join("local")).unwrap();
+    let lock = DBLock::at(&dir).unwrap();
+    let mapped = parse_localdb_mmap_at(i.clone(), &dir.join("local"), &lock).unwrap();
+    assert_eq!(mapped.len(), 2);
+    for (name, p) in &read {
+        assert_eq!(mapped[name].version, p.version);
+    }
+
+    std::fs::write(dir.join("local/bar-2-1/desc"), b"%NAME%\n\xff\n").unwrap();
+    let e = parse_localdb_mmap_at(i.clone(), &dir.join("local"), &lock).unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+    std::fs::write(dir.join("local/bar-2-1/desc"), "%NAME%\nbar\n\n").unwrap();
+    let e = parse_localdb_mmap_at(i, &dir.join("local"), &lock).unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn test_iter_db() {
+    let dir = crate::util::test_dir("iter_db");
+    let pkgs = [
+        ("foo-1-1", test_desc("foo", "1-1", &[])),
+        ("bar-2-1", test_desc("bar", "2-1", &[])),
+    ];
+    write_test_dbpath(&dir, &pkgs, &[("core", &pkgs)]);
+    let i = new_interner();
+    let local = iter_localdb_at(i.clone(), &dir.join("local")).unwrap();
+    assert_eq!(local.map(Result::unwrap).count(), 2);
+    let mut sync: Vec<_> = iter_syncdb_at(i.clone(), &dir.join("sync"), "core")
+        .unwrap()
+        .map(|p| p.unwrap().version.r(&i.borrow()).to_owned())
+        .collect();
+    sync.sort();
+    assert_eq!(sync, ["1-1", "2-1"]);
+
+    std::fs::write(dir.join("local/foo-1-1/desc"), "%NAME%\nfoo\n\n").unwrap();
+    let local = iter_localdb_at(i.clone(), &dir.join("local")).unwrap();
+    assert!(local.into_iter().any(|p| p.is_err()));
+    let e = parse_localdb_at(i, &dir.join("local")).unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn test_localdb_version() {
+    let dir = crate::util::test_dir("localdb_version");
+    write_test_dbpath(&dir, &[("foo-1-1", test_desc("foo", "1-1", &[]))], &[]);
+    let local = dir.join("local");
+    let version_error = |v: &str| {
+        std::fs::write(local.join("ALPM_DB_VERSION"), v).unwrap();
+        let e = parse_localdb_at(new_interner(), &local).err().unwrap();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        e.get_ref()
+            .and_then(|e| e.downcast_ref::<UnsupportedDbVersion>())
+            .cloned()
+    };
+    assert_eq!(version_error("10\n").unwrap().found, "10");
+    assert_eq!(version_error("").unwrap().found, "");
+
+    std::fs::remove_file(local.join("ALPM_DB_VERSION")).unwrap();
+    assert_eq!(parse_localdb_at(new_interner(), &local).unwrap().len(), 1);
+}
+
+#[test]
+fn test_localdb_files() {
+    let dir = crate::util::test_dir("localdb_files");
+    write_test_dbpath(
+        &dir,
+        &[
+            ("foo-1-1", test_desc("foo", "1-1", &[])),
+            ("meta-1-1", test_desc("meta", "1-1", &[])),
+        ],
+        &[],
+    );
+    std::fs::write(
+        dir.join("local/foo-1-1/files"),
+        "%FILES%\netc/\netc/foo.conf\n\n%BACKUP%\netc/foo.conf\t0123\n\n",
+    )
+    .unwrap();
+    let i = new_interner();
+    let files = parse_localdb_files_at(i.clone(), &dir.join("local")).unwrap();
+    let foo = i.borrow_mut().get_or_intern("foo");
+    let meta = i.borrow_mut().get_or_intern("meta");
+    assert_eq!(files[&foo].files, ["etc/", "etc/foo.conf"]);
+    assert_eq!(files[&foo].backup[0].path, "etc/foo.conf");
+    assert_eq!(files[&meta], FileList::default());
+
+    std::fs::write(dir.join("local/meta-1-1/desc"), "%VERSION%\n1-1\n\n").unwrap();
+    let e = parse_localdb_files_at(i, &dir.join("local")).unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn test_files_db() {
+    let dir = crate::util::test_dir("files_db");
+    write_test_archive(
+        &dir.join("core.files"),
+        &[
+            ("foo-1-1/desc".to_owned(), &test_desc("foo", "1-1", &[])),
+            (
+                "foo-1-1/files".to_owned(),
+                "%FILES%\nusr/\nusr/bin/\nusr/bin/foo\n",
+            ),
+            ("bar-2-1/desc".to_owned(), &test_desc("bar", "2-1", &[])),
+            ("bar-2-1/files".to_owned(), "%FILES%\n"),
+        ],
+    );
+    let i = new_interner();
+    let files = parse_files_db_at(i.clone(), &dir, "core").unwrap();
+    let foo = i.borrow_mut().get_or_intern("foo");
+    let bar = i.borrow_mut().get_or_intern("bar");
+    assert_eq!(files.len(), 2);
+    assert_eq!(files[&foo].files, ["usr/", "usr/bin/", "usr/bin/foo"]);
+    assert!(files[&bar].files.is_empty());
+
+    let dbs = [("core", files)];
+    let found = search_files(&dbs, &FileQuery::Exact("foo".into()));
+    assert_eq!(found, [("core", foo, vec!["usr/bin/foo"])]);
+    let found = search_files(&dbs, &FileQuery::Exact("/usr/bin/".into()));
+    assert_eq!(found, [("core", foo, vec!["usr/bin/"])]);
+    assert!(search_files(&dbs, &FileQuery::Exact("bin/foo".into())).is_empty());
+    let regex = FileQuery::Regex(regex::Regex::new("^usr/.*/f").unwrap());
+    assert_eq!(search_files(&dbs, &regex).len(), 1);
+
+    write_test_archive(
+        &dir.join("broken.files"),
+        &[("foo-1-1/desc".to_owned(), "%VERSION%\n1-1\n\n")],
+    );
+    let e = parse_files_db_at(i, &dir, "broken").unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+}
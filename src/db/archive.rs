@@ -0,0 +1,196 @@
+//! Minimal streaming tar reader, enough for pacman's databases.
+//! Unlike [tar::Archive] it owns its reader, so it can be returned as an iterator.
+use std::io::{self, Read};
+
+const BLOCK: usize = 512;
+
+/// Entries that are read into memory are descs, files lists and path headers,
+/// anything larger is a corrupt header rather than a real entry.
+const MAX_ENTRY: u64 = 64 << 20;
+
+/// Yields (path, contents) of the regular files in the archive.
+/// Directories and other entry types are skipped.
+pub(super) struct Entries<R> {
+    r: R,
+    /// From a GNU long name or pax header, replaces the name of the next entry.
+    next_path: Option<String>,
+    /// Whether a header was read, an empty file counts as an empty archive.
+    started: bool,
+    done: bool,
+}
+
+impl<R: Read> Entries<R> {
+    pub(super) fn new(r: R) -> Self {
+        Self {
+            r,
+            next_path: None,
+            started: false,
+            done: false,
+        }
+    }
+
+    /// Reads the contents of an entry of size bytes and skips its padding.
+    fn data(&mut self, size: u64) -> io::Result<Vec<u8>> {
+        if size > MAX_ENTRY {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("archive entry of {size} bytes"),
+            ));
+        }
+        // grows with what actually arrives instead of trusting the header
+        let mut data = Vec::new();
+        (&mut self.r).take(size).read_to_end(&mut data)?;
+        if data.len() as u64 != size {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.skip(size.next_multiple_of(BLOCK as u64) - size)?;
+        Ok(data)
+    }
+
+    /// Skips n bytes without keeping them.
+    fn skip(&mut self, n: u64) -> io::Result<()> {
+        if io::copy(&mut (&mut self.r).take(n), &mut io::sink())? != n {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(())
+    }
+
+    fn entry(&mut self) -> io::Result<Option<(String, String)>> {
+        loop {
+            let mut header = [0; BLOCK];
+            // running out before the end of archive blocks means the archive was cut off
+            match self.r.read_exact(&mut header) {
+                Ok(()) => self.started = true,
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && !self.started => {
+                    return Ok(None);
+                }
+                Err(e) => return Err(e),
+            }
+            if header.iter().all(|b| *b == 0) {
+                return Ok(None);
+            }
+
+            let size = octal(&header[124..136])?;
+            match header[156] {
+                b'0' | 0 => {
+                    let data = self.data(size)?;
+                    let path = match self.next_path.take() {
+                        Some(path) => path,
+                        None => header_path(&header),
+                    };
+                    let contents = String::from_utf8(data)
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                    return Ok(Some((path, contents)));
+                }
+                b'L' => {
+                    let data = self.data(size)?;
+                    let name = String::from_utf8_lossy(&data);
+                    self.next_path = Some(name.trim_end_matches('\0').to_owned());
+                }
+                b'x' => {
+                    let data = self.data(size)?;
+                    if let Some(path) = pax_path(&data) {
+                        self.next_path = Some(path);
+                    }
+                }
+                _ => {
+                    self.next_path = None;
+                    self.skip(size.next_multiple_of(BLOCK as u64))?;
+                }
+            }
+        }
+    }
+}
+
+impl<R: Read> Iterator for Entries<R> {
+    type Item = io::Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let entry = self.entry().transpose();
+        if !matches!(entry, Some(Ok(_))) {
+            self.done = true;
+        }
+        entry
+    }
+}
+
+fn nul_terminated(field: &[u8]) -> &[u8] {
+    let end = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    &field[..end]
+}
+
+fn octal(field: &[u8]) -> io::Result<u64> {
+    let s = String::from_utf8_lossy(nul_terminated(field));
+    u64::from_str_radix(s.trim(), 8).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// name, with the ustar prefix if there is one.
+fn header_path(header: &[u8; BLOCK]) -> String {
+    let name = String::from_utf8_lossy(nul_terminated(&header[..100]));
+    let prefix = nul_terminated(&header[345..500]);
+    if &header[257..262] == b"ustar" && !prefix.is_empty() {
+        format!("{}/{name}", String::from_utf8_lossy(prefix))
+    } else {
+        name.into_owned()
+    }
+}
+
+/// The path of a pax extended header, made of `<len> <key>=<value>\n` records.
+fn pax_path(data: &[u8]) -> Option<String> {
+    let s = String::from_utf8_lossy(data);
+    s.lines()
+        .filter_map(|record| record.split_once(' '))
+        .filter_map(|(_, kv)| kv.split_once('='))
+        .find(|(k, _)| *k == "path")
+        .map(|(_, v)| v.to_owned())
+}
+
+#[test]
+fn test_entries() {
+    let long = format!("{}/desc", "a".repeat(150));
+    let mut tar = tar::Builder::new(Vec::new());
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Directory);
+    header.set_size(0);
+    tar.append_data(&mut header, "foo-1-1/", io::empty())
+        .unwrap();
+    for (path, contents) in [("foo-1-1/desc", "%NAME%\nfoo\n\n"), (&long, "x")] {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        tar.append_data(&mut header, path, contents.as_bytes())
+            .unwrap();
+    }
+    let mut header = tar::Header::new_ustar();
+    header.set_size(1);
+    tar.append_pax_extensions([("path", "pax/desc".as_bytes())])
+        .unwrap();
+    tar.append_data(&mut header, "ignored", "y".as_bytes())
+        .unwrap();
+    let archive = tar.into_inner().unwrap();
+
+    let entries: Vec<_> = Entries::new(archive.as_slice())
+        .collect::<io::Result<_>>()
+        .unwrap();
+    assert_eq!(
+        entries,
+        [
+            ("foo-1-1/desc".to_owned(), "%NAME%\nfoo\n\n".to_owned()),
+            (long, "x".to_owned()),
+            ("pax/desc".to_owned(), "y".to_owned()),
+        ]
+    );
+    assert!(Entries::new(&archive[..1100]).last().unwrap().is_err());
+    // cut off right after the directory entry, before the end of archive blocks
+    assert!(Entries::new(&archive[..BLOCK]).last().unwrap().is_err());
+    assert!(Entries::new(io::empty()).next().is_none());
+
+    let mut header = tar::Header::new_gnu();
+    header.set_path("huge/desc").unwrap();
+    header.set_size(1 << 40);
+    header.set_cksum();
+    let e = Entries::new(header.as_bytes().as_slice()).next().unwrap();
+    assert_eq!(e.unwrap_err().kind(), io::ErrorKind::InvalidData);
+}
@@ -0,0 +1,247 @@
+//! [PackageBuilder], for packages that do not come from a desc.
+use super::{Arch, InstallReason, Interner, Istr, Package};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Builds a [Package] field by field, e.g. for tests or to inject virtual packages.
+/// List methods can be called multiple times, each call adds one entry.
+/// Fields that are not set are empty, base defaults to the name and arch to [Arch::Any].
+///
+/// Ex: ```PackageBuilder::new("foo", "1-1").depend("glibc").provide("bar=1").build(&i)```
+#[derive(Clone, Debug)]
+pub struct PackageBuilder {
+    name: String,
+    version: String,
+    base: Option<String>,
+    arch: Arch,
+    reason: Option<InstallReason>,
+    install_date: Option<SystemTime>,
+    packager: String,
+    isize: Option<u64>,
+    csize: Option<u64>,
+    build_date: SystemTime,
+    url: Option<String>,
+    license: Vec<String>,
+    desc: String,
+    filename: Option<String>,
+    provides: Vec<String>,
+    depends: Vec<String>,
+    optdepends: Vec<String>,
+    makedepends: Vec<String>,
+    checkdepends: Vec<String>,
+    groups: Vec<String>,
+    replaces: Vec<String>,
+    conflicts: Vec<String>,
+}
+
+impl PackageBuilder {
+    pub fn new(name: impl Into<String>, version: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            version: version.into(),
+            base: None,
+            arch: Arch::Any,
+            reason: None,
+            install_date: None,
+            packager: String::new(),
+            isize: None,
+            csize: None,
+            build_date: UNIX_EPOCH,
+            url: None,
+            license: Vec::new(),
+            desc: String::new(),
+            filename: None,
+            provides: Vec::new(),
+            depends: Vec::new(),
+            optdepends: Vec::new(),
+            makedepends: Vec::new(),
+            checkdepends: Vec::new(),
+            groups: Vec::new(),
+            replaces: Vec::new(),
+            conflicts: Vec::new(),
+        }
+    }
+
+    pub fn base(mut self, base: impl Into<String>) -> Self {
+        self.base = Some(base.into());
+        self
+    }
+
+    pub fn arch(mut self, arch: Arch) -> Self {
+        self.arch = arch;
+        self
+    }
+
+    /// Makes this a local package.
+    pub fn reason(mut self, reason: InstallReason) -> Self {
+        self.reason = Some(reason);
+        self
+    }
+
+    pub fn install_date(mut self, date: SystemTime) -> Self {
+        self.install_date = Some(date);
+        self
+    }
+
+    pub fn packager(mut self, packager: impl Into<String>) -> Self {
+        self.packager = packager.into();
+        self
+    }
+
+    pub fn isize(mut self, isize: u64) -> Self {
+        self.isize = Some(isize);
+        self
+    }
+
+    pub fn csize(mut self, csize: u64) -> Self {
+        self.csize = Some(csize);
+        self
+    }
+
+    pub fn build_date(mut self, date: SystemTime) -> Self {
+        self.build_date = date;
+        self
+    }
+
+    pub fn url(mut self, url: impl Into<String>) -> Self {
+        self.url = Some(url.into());
+        self
+    }
+
+    pub fn license(mut self, license: impl Into<String>) -> Self {
+        self.license.push(license.into());
+        self
+    }
+
+    pub fn desc(mut self, desc: impl Into<String>) -> Self {
+        self.desc = desc.into();
+        self
+    }
+
+    pub fn filename(mut self, filename: impl Into<String>) -> Self {
+        self.filename = Some(filename.into());
+        self
+    }
+
+    /// Like in the desc, e.g. "bar=1".
+    pub fn provide(mut self, provide: impl Into<String>) -> Self {
+        self.provides.push(provide.into());
+        self
+    }
+
+    /// A depend string like in the desc, e.g. "bar>=1".
+    pub fn depend(mut self, depend: impl Into<String>) -> Self {
+        self.depends.push(depend.into());
+        self
+    }
+
+    /// Like in the desc, e.g. "bar: for baz support".
+    pub fn optdepend(mut self, optdepend: impl Into<String>) -> Self {
+        self.optdepends.push(optdepend.into());
+        self
+    }
+
+    pub fn makedepend(mut self, makedepend: impl Into<String>) -> Self {
+        self.makedepends.push(makedepend.into());
+        self
+    }
+
+    pub fn checkdepend(mut self, checkdepend: impl Into<String>) -> Self {
+        self.checkdepends.push(checkdepend.into());
+        self
+    }
+
+    pub fn group(mut self, group: impl Into<String>) -> Self {
+        self.groups.push(group.into());
+        self
+    }
+
+    pub fn replace(mut self, replace: impl Into<String>) -> Self {
+        self.replaces.push(replace.into());
+        self
+    }
+
+    pub fn conflict(mut self, conflict: impl Into<String>) -> Self {
+        self.conflicts.push(conflict.into());
+        self
+    }
+
+    /// Interns all strings into i. Lists that were never added to are None,
+    /// like for a desc that does not have them.
+    pub fn build(self, i: &Interner) -> Package {
+        let mut ir = i.borrow_mut();
+        let mut intern = |s: &str| -> Istr { ir.get_or_intern(s) };
+        let name = intern(&self.name);
+        let base = self.base.as_deref().map_or(name, &mut intern);
+        let version = intern(&self.version);
+        let packager = intern(&self.packager);
+        let desc = intern(&self.desc);
+        let url = self.url.as_deref().map(&mut intern);
+        let filename = self.filename.as_deref().map(&mut intern);
+        let license = self.license.iter().map(|l| intern(l)).collect();
+        let mut list = |l: Vec<String>| {
+            (!l.is_empty()).then(|| l.iter().map(|s| intern(s)).collect::<Vec<_>>())
+        };
+        Package {
+            base,
+            name,
+            version,
+            arch: self.arch,
+            reason: self.reason,
+            install_date: self.install_date,
+            validation: None,
+            packager,
+            isize: self.isize,
+            csize: self.csize,
+            build_date: self.build_date,
+            url,
+            license,
+            desc,
+            filename,
+            md5sum: None,
+            sha256sum: None,
+            pgpsig: None,
+            provides: list(self.provides),
+            depends: list(self.depends),
+            optdepends: list(self.optdepends),
+            makedepends: list(self.makedepends),
+            checkdepends: list(self.checkdepends),
+            groups: list(self.groups),
+            replaces: list(self.replaces).map(|r| r.into_iter().collect()),
+            conflicts: list(self.conflicts),
+            xdata: None,
+            parsed_version: Default::default(),
+        }
+    }
+}
+
+#[test]
+fn test_builder() {
+    use super::{new_interner, test_desc};
+    use std::time::Duration;
+    let i = new_interner();
+    let desc = test_desc(
+        "foo",
+        "1:2.0-1",
+        &[("DEPENDS", "glibc\nbar>=1"), ("REPLACES", "baz")],
+    );
+    let parsed = Package::from_str(i.clone(), &desc).unwrap();
+    let built = PackageBuilder::new("foo", "1:2.0-1")
+        .arch(Arch::X86_64)
+        .desc("test package foo")
+        .build_date(UNIX_EPOCH + Duration::from_secs(1700000000))
+        .packager("tester")
+        .license("MIT")
+        .depend("glibc")
+        .depend("bar>=1")
+        .replace("baz")
+        .build(&i);
+    assert_eq!(built, parsed);
+    assert_eq!(built.resolve(&i.borrow()), parsed.resolve(&i.borrow()));
+    assert_eq!(built.parsed_version(&i), parsed.parsed_version(&i));
+
+    let virt = PackageBuilder::new("virt", "1").provide("sh").build(&i);
+    let ii = i.borrow();
+    let r = virt.resolve(&ii);
+    assert_eq!((r.base, r.arch, r.provides), ("virt", "any", vec!["sh"]));
+    assert!(virt.depends.is_none());
+}
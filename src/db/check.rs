@@ -0,0 +1,277 @@
+//! Comparing installed files against what the local db recorded about them.
+use super::mtree::{self, EntryType};
+use super::{Backup, FileList, Interner, Package, QuickResolve};
+use md5::{Digest, Md5};
+use sha2::Sha256;
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+/// State of a backup file on disk, like `pacman -Qii` shows it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BackupStatus {
+    /// Same md5 as when it was installed.
+    Unmodified,
+    /// Edited since, pacman keeps it and installs new versions as .pacnew.
+    Modified,
+    Missing,
+}
+
+/// Hex encoded digest of the file at path.
+fn digest_file<D: Digest>(path: &Path) -> io::Result<String> {
+    let mut f = File::open(path)?;
+    let mut d = D::new();
+    let mut buf = vec![0; 1 << 16];
+    loop {
+        let n = f.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        d.update(&buf[..n]);
+    }
+    Ok(d.finalize().iter().map(|b| format!("{b:02x}")).collect())
+}
+
+impl Backup {
+    /// Compares the file below root against the recorded md5.
+    /// Errors if the file exists but can not be read.
+    pub fn status(&self, root: &Path) -> io::Result<BackupStatus> {
+        match digest_file::<Md5>(&root.join(&self.path)) {
+            Ok(md5) if md5 == self.md5sum => Ok(BackupStatus::Unmodified),
+            Ok(_) => Ok(BackupStatus::Modified),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BackupStatus::Missing),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl FileList {
+    /// [Backup::status] of every backup file, in the order the db lists them.
+    pub fn backup_status(&self, root: &Path) -> Vec<(&Backup, io::Result<BackupStatus>)> {
+        self.backup.iter().map(|b| (b, b.status(root))).collect()
+    }
+}
+
+/// How much [check_files] looks at.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CheckLevel {
+    /// Like `pacman -Qk`: whether every file exists.
+    Exists,
+    /// Like `pacman -Qkk`: also type, permissions, owner, size, mtime, link targets and sha256
+    /// against the package's mtree. Contents of backup files are not checked, they are meant to change.
+    Thorough,
+}
+
+/// What is wrong with an installed file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Mismatch {
+    Missing,
+    /// Can not be looked at, e.g. for lack of permissions.
+    Unreadable(io::ErrorKind),
+    /// A directory where a file should be or similar.
+    Type,
+    Mode,
+    Uid,
+    Gid,
+    Size,
+    Mtime,
+    Sha256,
+    LinkTarget,
+}
+
+/// Checks the files of the installed package pkg below root,
+/// reading what they should be like from its entry in local_dbpath (usually `<dbpath>/local`).
+/// returns (path, mismatch) in the order the db lists the files, empty if all is fine.
+pub fn check_files(
+    i: &Interner,
+    root: &Path,
+    local_dbpath: &Path,
+    pkg: &Package,
+    level: CheckLevel,
+) -> io::Result<Vec<(String, Mismatch)>> {
+    let dir = {
+        let i = i.borrow();
+        local_dbpath.join(format!("{}-{}", pkg.name.r(&i), pkg.version.r(&i)))
+    };
+    let files = match std::fs::read_to_string(dir.join("files")) {
+        Ok(s) => FileList::parse(&s),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => FileList::default(),
+        Err(e) => return Err(e),
+    };
+    let mut ret = Vec::new();
+    if level == CheckLevel::Exists {
+        for path in &files.files {
+            if let Err(e) = std::fs::symlink_metadata(root.join(path)) {
+                ret.push((path.trim_end_matches('/').to_owned(), lost(e)));
+            }
+        }
+        return Ok(ret);
+    }
+    for entry in mtree::read(&dir.join("mtree"))? {
+        // .PKGINFO, .BUILDINFO, .INSTALL and such are not installed
+        if !entry.path.contains('/') && entry.path.starts_with('.') {
+            continue;
+        }
+        let backup = files.backup.iter().any(|b| b.path == entry.path);
+        for mismatch in check_entry(root, &entry, backup) {
+            ret.push((entry.path.clone(), mismatch));
+        }
+    }
+    Ok(ret)
+}
+
+fn lost(e: io::Error) -> Mismatch {
+    match e.kind() {
+        io::ErrorKind::NotFound => Mismatch::Missing,
+        kind => Mismatch::Unreadable(kind),
+    }
+}
+
+fn check_entry(root: &Path, entry: &mtree::Entry, backup: bool) -> Vec<Mismatch> {
+    let path = root.join(&entry.path);
+    let meta = match std::fs::symlink_metadata(&path) {
+        Ok(meta) => meta,
+        Err(e) => return vec![lost(e)],
+    };
+    let kind = if meta.is_dir() {
+        EntryType::Dir
+    } else if meta.is_symlink() {
+        EntryType::Link
+    } else if meta.is_file() {
+        EntryType::File
+    } else {
+        EntryType::Other
+    };
+    if kind != entry.kind {
+        return vec![Mismatch::Type];
+    }
+    let mut ret = Vec::new();
+    // link permissions are meaningless on linux
+    if kind != EntryType::Link && entry.mode.is_some_and(|m| m != meta.mode() & 0o7777) {
+        ret.push(Mismatch::Mode);
+    }
+    if entry.uid.is_some_and(|u| u != meta.uid()) {
+        ret.push(Mismatch::Uid);
+    }
+    if entry.gid.is_some_and(|g| g != meta.gid()) {
+        ret.push(Mismatch::Gid);
+    }
+    if kind == EntryType::Link {
+        let target = std::fs::read_link(&path).ok();
+        if entry.link.as_deref() != target.as_deref().and_then(Path::to_str) {
+            ret.push(Mismatch::LinkTarget);
+        }
+    }
+    if kind != EntryType::File || backup {
+        return ret;
+    }
+    if entry.size.is_some_and(|s| s != meta.len()) {
+        ret.push(Mismatch::Size);
+    }
+    let mtime = meta.modified().ok();
+    let secs = |t: std::time::SystemTime| t.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).ok();
+    if let Some(time) = entry.time
+        && secs(time) != mtime.and_then(secs)
+    {
+        ret.push(Mismatch::Mtime);
+    }
+    if let Some(sha256) = &entry.sha256 {
+        match digest_file::<Sha256>(&path) {
+            Ok(d) if &d == sha256 => (),
+            Ok(_) => ret.push(Mismatch::Sha256),
+            Err(e) => ret.push(Mismatch::Unreadable(e.kind())),
+        }
+    }
+    ret
+}
+
+#[test]
+fn test_backup_status() {
+    let root = crate::util::test_dir("backup_status");
+    std::fs::create_dir_all(root.join("etc")).unwrap();
+    std::fs::write(root.join("etc/same.conf"), "").unwrap();
+    std::fs::write(root.join("etc/edited.conf"), "edited").unwrap();
+    let l = FileList::parse(
+        "%BACKUP%\netc/same.conf\td41d8cd98f00b204e9800998ecf8427e\n\
+        etc/edited.conf\td41d8cd98f00b204e9800998ecf8427e\n\
+        etc/gone.conf\td41d8cd98f00b204e9800998ecf8427e\n\n",
+    );
+    let status: Vec<_> = l
+        .backup_status(&root)
+        .into_iter()
+        .map(|(b, s)| (b.path.as_str(), s.unwrap()))
+        .collect();
+    assert_eq!(
+        status,
+        [
+            ("etc/same.conf", BackupStatus::Unmodified),
+            ("etc/edited.conf", BackupStatus::Modified),
+            ("etc/gone.conf", BackupStatus::Missing),
+        ]
+    );
+}
+
+#[test]
+fn test_check_files() {
+    use super::{new_interner, test_desc};
+    use std::os::unix::fs::PermissionsExt;
+    let dir = crate::util::test_dir("check_files");
+    let root = dir.join("root");
+    let local = dir.join("local");
+    let pkgdir = local.join("foo-1-1");
+    std::fs::create_dir_all(root.join("usr/bin")).unwrap();
+    std::fs::create_dir_all(root.join("etc")).unwrap();
+    std::fs::create_dir_all(&pkgdir).unwrap();
+    std::fs::write(
+        pkgdir.join("files"),
+        "%FILES%\netc/\netc/foo.conf\nusr/\nusr/bin/\nusr/bin/foo\nusr/bin/gone\nusr/bin/link\n\n\
+        %BACKUP%\netc/foo.conf\td41d8cd98f00b204e9800998ecf8427e\n\n",
+    )
+    .unwrap();
+    std::fs::write(root.join("etc/foo.conf"), "edited").unwrap();
+    std::fs::write(root.join("usr/bin/foo"), "abc").unwrap();
+    std::fs::set_permissions(root.join("usr/bin/foo"), PermissionsExt::from_mode(0o700)).unwrap();
+    std::os::unix::fs::symlink("elsewhere", root.join("usr/bin/link")).unwrap();
+    let mtime = |p: &str| {
+        let m = std::fs::metadata(root.join(p)).unwrap().modified().unwrap();
+        m.duration_since(UNIX_EPOCH).unwrap().as_secs()
+    };
+    let uid = std::fs::metadata(&root).unwrap().uid();
+    let mtree = format!(
+        "#mtree\n/set type=file uid={uid} gid=0 mode=644\n\
+        ./.PKGINFO size=1\n\
+        ./etc/foo.conf time={}.0 size=0\n\
+        ./usr/bin/foo time={}.0 mode=755 size=3 \
+        sha256digest=ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad\n\
+        ./usr/bin/gone size=1\n\
+        ./usr/bin/link type=link mode=777 link=foo\n",
+        mtime("etc/foo.conf"),
+        mtime("usr/bin/foo"),
+    );
+    let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+    std::io::Write::write_all(&mut gz, mtree.as_bytes()).unwrap();
+    std::fs::write(pkgdir.join("mtree"), gz.finish().unwrap()).unwrap();
+
+    let i = new_interner();
+    let pkg = Package::from_str(i.clone(), &test_desc("foo", "1-1", &[])).unwrap();
+    let exists = check_files(&i, &root, &local, &pkg, CheckLevel::Exists).unwrap();
+    assert_eq!(exists, [("usr/bin/gone".to_owned(), Mismatch::Missing)]);
+    let thorough = check_files(&i, &root, &local, &pkg, CheckLevel::Thorough).unwrap();
+    let gid = |p: &str| std::fs::symlink_metadata(root.join(p)).unwrap().gid() != 0;
+    let mut expected = Vec::new();
+    if gid("etc/foo.conf") {
+        expected.push(("etc/foo.conf".to_owned(), Mismatch::Gid));
+    }
+    expected.push(("usr/bin/foo".to_owned(), Mismatch::Mode));
+    if gid("usr/bin/foo") {
+        expected.push(("usr/bin/foo".to_owned(), Mismatch::Gid));
+    }
+    expected.push(("usr/bin/gone".to_owned(), Mismatch::Missing));
+    if gid("usr/bin/link") {
+        expected.push(("usr/bin/link".to_owned(), Mismatch::Gid));
+    }
+    expected.push(("usr/bin/link".to_owned(), Mismatch::LinkTarget));
+    assert_eq!(thorough, expected);
+}
@@ -0,0 +1,981 @@
+use super::{Depend, InstallReason, Interner, Istr, Package, QuickResolve, Soname};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime};
+
+/// Parsed packages of one database with queries by plain strings,
+/// so callers do not need to resolve symbols themselves.
+#[derive(Clone)]
+pub struct Db {
+    i: Interner,
+    packages: HashMap<Istr, Package>,
+    /// package name -> names of the packages depending on it, built on first use.
+    required_by: OnceLock<HashMap<Istr, Vec<Istr>>>,
+    /// Same for optdepends.
+    optional_for: OnceLock<HashMap<Istr, Vec<Istr>>>,
+    /// group -> member names, see [Db::group_index].
+    groups: OnceLock<HashMap<Istr, Vec<Istr>>>,
+}
+
+/// Name part of a provides or depends entry like `sh=5.1` or `glibc>=2.38`.
+pub fn dep_name(entry: &str) -> &str {
+    entry.split(['=', '<', '>']).next().unwrap_or(entry)
+}
+
+impl Db {
+    /// packages is name -> package, as returned by the parse functions in [crate::db].
+    pub fn new(i: Interner, packages: HashMap<Istr, Package>) -> Self {
+        Self {
+            i,
+            packages,
+            required_by: OnceLock::new(),
+            optional_for: OnceLock::new(),
+            groups: OnceLock::new(),
+        }
+    }
+
+    pub fn interner(&self) -> &Interner {
+        &self.i
+    }
+
+    pub fn into_inner(self) -> HashMap<Istr, Package> {
+        self.packages
+    }
+
+    pub fn as_map(&self) -> &HashMap<Istr, Package> {
+        &self.packages
+    }
+
+    pub fn len(&self) -> usize {
+        self.packages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packages.is_empty()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Package> {
+        let name = self.i.borrow().get(name)?;
+        self.packages.get(&name)
+    }
+
+    /// In no particular order.
+    pub fn packages(&self) -> impl Iterator<Item = &Package> {
+        self.packages.values()
+    }
+
+    /// Like `pacman -Ss` with a single term: packages whose name, description or provides match,
+    /// best matches first: exact names, then names starting with or containing the query,
+    /// then provides, then descriptions. Ties are sorted by name.
+    pub fn search(&self, query: &SearchQuery) -> Vec<&Package> {
+        let i = self.i.borrow();
+        let mut found: Vec<_> = self
+            .packages()
+            .filter_map(|p| {
+                let provides = || {
+                    p.provides
+                        .iter()
+                        .flatten()
+                        .any(|prov| query.rank(dep_name(prov.r(&i))).is_some())
+                };
+                let rank = query
+                    .rank(p.name.r(&i))
+                    .or_else(|| provides().then_some(3))
+                    .or_else(|| query.rank(p.desc.r(&i)).map(|_| 4))?;
+                Some((rank, p))
+            })
+            .collect();
+        found.sort_unstable_by_key(|(rank, p)| (*rank, p.name.r(&i)));
+        found.into_iter().map(|(_, p)| p).collect()
+    }
+
+    /// Packages named name or providing it, ignoring versions.
+    pub fn by_provides(&self, name: &str) -> Vec<&Package> {
+        let i = self.i.borrow();
+        self.packages()
+            .filter(|p| {
+                p.name.r(&i) == name
+                    || p.provides
+                        .iter()
+                        .flatten()
+                        .any(|prov| dep_name(prov.r(&i)) == name)
+            })
+            .collect()
+    }
+
+    /// Packages satisfying dep by name and version or through their provides,
+    /// the one named like dep first, like pacman prefers it, then by name.
+    pub fn satisfiers(&self, dep: &Depend) -> Vec<&Package> {
+        let i = self.i.borrow();
+        let mut found: Vec<_> = self
+            .packages()
+            .filter(|p| dep.satisfied_by(p, &self.i))
+            .collect();
+        found.sort_unstable_by_key(|p| (p.name.r(&i) != dep.name, p.name.r(&i)));
+        found
+    }
+
+    /// Packages in this db depending on pkg, directly or through something it provides,
+    /// like "Required By" in `pacman -Qi`, sorted by name.
+    /// The reverse index is built on the first call, so repeated queries are cheap.
+    pub fn required_by(&self, pkg: &Package) -> Vec<&Package> {
+        let index = self
+            .required_by
+            .get_or_init(|| self.reverse_index(Package::parsed_depends));
+        self.lookup(index, pkg)
+    }
+
+    /// Packages in this db optionally depending on pkg, like "Optional For" in `pacman -Qi`.
+    pub fn optional_for(&self, pkg: &Package) -> Vec<&Package> {
+        let index = self
+            .optional_for
+            .get_or_init(|| self.reverse_index(Package::parsed_optdepends));
+        self.lookup(index, pkg)
+    }
+
+    fn lookup(&self, index: &HashMap<Istr, Vec<Istr>>, pkg: &Package) -> Vec<&Package> {
+        let i = self.i.borrow();
+        let mut found: Vec<_> = index
+            .get(&pkg.name)
+            .into_iter()
+            .flatten()
+            .filter_map(|name| self.packages.get(name))
+            .collect();
+        found.sort_unstable_by_key(|p| p.name.r(&i));
+        found
+    }
+
+    fn reverse_index(
+        &self,
+        deps: fn(&Package, &Interner) -> Vec<Depend>,
+    ) -> HashMap<Istr, Vec<Istr>> {
+        // candidates for each dependency name, checked against the version afterwards
+        let mut providers: HashMap<&str, Vec<&Package>> = HashMap::new();
+        let i = self.i.borrow();
+        for p in self.packages() {
+            providers.entry(p.name.r(&i)).or_default().push(p);
+            for prov in p.provides.iter().flatten() {
+                providers.entry(dep_name(prov.r(&i))).or_default().push(p);
+            }
+        }
+        let mut index: HashMap<Istr, Vec<Istr>> = HashMap::new();
+        for p in self.packages() {
+            for dep in deps(p, &self.i) {
+                let mut satisfiers: Vec<_> = providers
+                    .get(dep.name.as_str())
+                    .into_iter()
+                    .flatten()
+                    .filter(|s| dep.satisfied_by(s, &self.i))
+                    .map(|s| s.name)
+                    .collect();
+                satisfiers.dedup();
+                for s in satisfiers {
+                    let dependents = index.entry(s).or_default();
+                    if !dependents.contains(&p.name) {
+                        dependents.push(p.name);
+                    }
+                }
+            }
+        }
+        index
+    }
+
+    /// Like `pacman -Sg group`: the packages in group, sorted by name.
+    pub fn group_members(&self, group: &str) -> Vec<&Package> {
+        let Some(group) = self.i.borrow().get(group) else {
+            return Vec::new();
+        };
+        self.group_index()
+            .get(&group)
+            .map(|names| names.iter().map(|n| &self.packages[n]).collect())
+            .unwrap_or_default()
+    }
+
+    /// Like `pacman -Qg`: every group and its members, both sorted by name.
+    pub fn groups(&self) -> Vec<(String, Vec<&Package>)> {
+        let i = self.i.borrow();
+        let mut groups: Vec<_> = self
+            .group_index()
+            .iter()
+            .map(|(g, names)| {
+                let members = names.iter().map(|n| &self.packages[n]).collect();
+                (g.r(&i).to_owned(), members)
+            })
+            .collect();
+        groups.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        groups
+    }
+
+    /// Packages grouped by the pkgbase they were built from, both sorted by name.
+    pub fn by_pkgbase(&self) -> Vec<(String, Vec<&Package>)> {
+        let i = self.i.borrow();
+        let mut bases: HashMap<Istr, Vec<&Package>> = HashMap::new();
+        for p in self.packages() {
+            bases.entry(p.base).or_default().push(p);
+        }
+        let mut bases: Vec<_> = bases
+            .into_iter()
+            .map(|(base, mut members)| {
+                members.sort_unstable_by_key(|p| p.name.r(&i));
+                (base.r(&i).to_owned(), members)
+            })
+            .collect();
+        bases.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        bases
+    }
+
+    /// [Db::packages] without debug packages, see [Package::is_debug].
+    pub fn without_debug(&self) -> impl Iterator<Item = &Package> {
+        self.packages().filter(|p| !p.is_debug(&self.i))
+    }
+
+    /// group -> names of its members, sorted.
+    fn group_index(&self) -> &HashMap<Istr, Vec<Istr>> {
+        self.groups.get_or_init(|| {
+            let i = self.i.borrow();
+            let mut index: HashMap<Istr, Vec<Istr>> = HashMap::new();
+            for p in self.packages() {
+                for g in p.groups.iter().flatten() {
+                    index.entry(*g).or_default().push(p.name);
+                }
+            }
+            for names in index.values_mut() {
+                names.sort_unstable_by_key(|n| n.r(&i));
+            }
+            index
+        })
+    }
+
+    /// Packages providing exactly soname, version and bitness included.
+    pub fn soname_providers(&self, soname: &Soname) -> Vec<&Package> {
+        self.packages()
+            .filter(|p| p.provided_sonames(&self.i).contains(soname))
+            .collect()
+    }
+
+    /// Packages linking against soname, e.g. the ones to rebuild after it got bumped.
+    /// Only the version given in soname matches, use [Db::soname_users] to ignore it.
+    pub fn soname_dependents(&self, soname: &Soname) -> Vec<&Package> {
+        self.packages()
+            .filter(|p| p.required_sonames(&self.i).contains(soname))
+            .collect()
+    }
+
+    /// Packages linking against any version of the library name with bitness.
+    pub fn soname_users(&self, name: &str, bitness: u8) -> Vec<&Package> {
+        self.packages()
+            .filter(|p| {
+                p.required_sonames(&self.i)
+                    .iter()
+                    .any(|s| s.name == name && s.bitness == bitness)
+            })
+            .collect()
+    }
+}
+
+/// What [Db::search] looks for, ignoring case.
+#[derive(Clone, Debug)]
+pub enum SearchQuery {
+    Substring(String),
+    /// Like pacman, which treats search terms as regular expressions.
+    Regex(regex::Regex),
+}
+
+impl SearchQuery {
+    /// A case insensitive Regex query.
+    pub fn regex(pattern: &str) -> Result<Self, regex::Error> {
+        regex::RegexBuilder::new(pattern)
+            .case_insensitive(true)
+            .build()
+            .map(Self::Regex)
+    }
+
+    /// How well haystack matches: 0 if all of it, 1 if its start, 2 if anywhere.
+    fn rank(&self, haystack: &str) -> Option<u8> {
+        let (len, start, end) = match self {
+            Self::Substring(q) => {
+                let haystack = haystack.to_lowercase();
+                let q = q.to_lowercase();
+                let start = haystack.find(&q)?;
+                (haystack.len(), start, start + q.len())
+            }
+            Self::Regex(r) => {
+                let m = r.find(haystack)?;
+                (haystack.len(), m.start(), m.end())
+            }
+        };
+        Some(match (start, end) {
+            (0, end) if end == len => 0,
+            (0, _) => 1,
+            _ => 2,
+        })
+    }
+}
+
+impl From<&str> for SearchQuery {
+    fn from(q: &str) -> Self {
+        Self::Substring(q.to_owned())
+    }
+}
+
+/// How a db changed between two snapshots, see [diff].
+/// Each list is sorted by name, version changes are (old, new).
+pub struct DbDiff<'db> {
+    pub added: Vec<&'db Package>,
+    pub removed: Vec<&'db Package>,
+    pub upgraded: Vec<(&'db Package, &'db Package)>,
+    pub downgraded: Vec<(&'db Package, &'db Package)>,
+}
+
+/// Compares two snapshots of a db by package name,
+/// like the same sync db before and after a refresh.
+/// The dbs may use different interners.
+pub fn diff<'db>(old: &'db Db, new: &'db Db) -> DbDiff<'db> {
+    use std::cmp::Ordering;
+    fn by_name(db: &Db) -> Vec<(String, &Package)> {
+        let i = db.i.borrow();
+        let mut v: Vec<_> = db
+            .packages()
+            .map(|p| (p.name.r(&i).to_owned(), p))
+            .collect();
+        v.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        v
+    }
+    let old_pkgs = by_name(old);
+    let new_pkgs = by_name(new);
+    let mut ret = DbDiff {
+        added: Vec::new(),
+        removed: Vec::new(),
+        upgraded: Vec::new(),
+        downgraded: Vec::new(),
+    };
+    let (mut o, mut n) = (old_pkgs.iter().peekable(), new_pkgs.iter().peekable());
+    loop {
+        match (o.peek(), n.peek()) {
+            (None, None) => break,
+            (Some((_, op)), None) => {
+                ret.removed.push(op);
+                o.next();
+            }
+            (None, Some((_, np))) => {
+                ret.added.push(np);
+                n.next();
+            }
+            (Some((oname, op)), Some((nname, np))) => match oname.cmp(nname) {
+                Ordering::Less => {
+                    ret.removed.push(op);
+                    o.next();
+                }
+                Ordering::Greater => {
+                    ret.added.push(np);
+                    n.next();
+                }
+                Ordering::Equal => {
+                    match op.parsed_version(&old.i).cmp(np.parsed_version(&new.i)) {
+                        Ordering::Less => ret.upgraded.push((op, np)),
+                        Ordering::Greater => ret.downgraded.push((op, np)),
+                        Ordering::Equal => (),
+                    }
+                    o.next();
+                    n.next();
+                }
+            },
+        }
+    }
+    ret
+}
+
+/// Queries shared by [LocalDb] and [SyncDb].
+pub trait Database {
+    fn db(&self) -> &Db;
+
+    fn get(&self, name: &str) -> Option<&Package> {
+        self.db().get(name)
+    }
+
+    fn packages(&self) -> impl Iterator<Item = &Package> {
+        self.db().packages()
+    }
+
+    fn search(&self, query: &SearchQuery) -> Vec<&Package> {
+        self.db().search(query)
+    }
+
+    fn by_provides(&self, name: &str) -> Vec<&Package> {
+        self.db().by_provides(name)
+    }
+
+    fn group_members(&self, group: &str) -> Vec<&Package> {
+        self.db().group_members(group)
+    }
+
+    fn groups(&self) -> Vec<(String, Vec<&Package>)> {
+        self.db().groups()
+    }
+
+    fn by_pkgbase(&self) -> Vec<(String, Vec<&Package>)> {
+        self.db().by_pkgbase()
+    }
+
+    fn without_debug(&self) -> impl Iterator<Item = &Package> {
+        self.db().without_debug()
+    }
+
+    fn satisfiers(&self, dep: &Depend) -> Vec<&Package> {
+        self.db().satisfiers(dep)
+    }
+
+    fn required_by(&self, pkg: &Package) -> Vec<&Package> {
+        self.db().required_by(pkg)
+    }
+
+    fn optional_for(&self, pkg: &Package) -> Vec<&Package> {
+        self.db().optional_for(pkg)
+    }
+}
+
+fn invalid(msg: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+/// The installed packages, every package has an install date.
+#[derive(Clone)]
+pub struct LocalDb(Db);
+
+/// A package from a [LocalDb].
+#[derive(Copy, Clone)]
+pub struct LocalPackage<'db>(&'db Package);
+
+impl LocalDb {
+    /// Errors if a package lacks the fields every installed package has.
+    pub fn new(db: Db) -> std::io::Result<Self> {
+        let i = db.i.borrow();
+        if let Some(p) = db.packages().find(|p| p.install_date.is_none()) {
+            return Err(invalid(format!("{} has no install date", p.name.r(&i))));
+        }
+        drop(i);
+        Ok(Self(db))
+    }
+
+    /// Reads the local db from `local_dbpath` (usually `<dbpath>/local`).
+    pub fn open(i: Interner, local_dbpath: &Path) -> std::io::Result<Self> {
+        let packages = super::parse_localdb_at(i.clone(), local_dbpath)?;
+        Self::new(Db::new(i, packages))
+    }
+
+    pub fn package(&self, name: &str) -> Option<LocalPackage<'_>> {
+        self.0.get(name).map(LocalPackage)
+    }
+
+    pub fn local_packages(&self) -> impl Iterator<Item = LocalPackage<'_>> {
+        self.0.packages().map(LocalPackage)
+    }
+
+    /// Like `pacman -Qe`: explicitly installed packages, in no particular order.
+    pub fn explicit(&self) -> impl Iterator<Item = LocalPackage<'_>> {
+        self.local_packages()
+            .filter(|p| p.reason() == InstallReason::Explicit)
+    }
+
+    /// Like `pacman -Qd`: packages installed as dependencies, in no particular order.
+    pub fn dependencies(&self) -> impl Iterator<Item = LocalPackage<'_>> {
+        self.local_packages()
+            .filter(|p| p.reason() == InstallReason::Dependency)
+    }
+
+    /// Like `pacman -Qdt`: packages installed as dependencies
+    /// that no installed package depends or optionally depends on, sorted by name.
+    pub fn orphans(&self) -> Vec<LocalPackage<'_>> {
+        self.orphans_after(&HashSet::new())
+    }
+
+    /// Like [LocalDb::orphans], but also the packages that would become orphans
+    /// once those are removed, repeated until nothing changes.
+    /// What running `pacman -Rns $(pacman -Qdtq)` until it finds nothing would remove.
+    pub fn orphans_recursive(&self) -> Vec<LocalPackage<'_>> {
+        let mut removed = HashSet::new();
+        loop {
+            let orphans = self.orphans_after(&removed);
+            let before = removed.len();
+            removed.extend(orphans.iter().map(|p| p.0.name));
+            if removed.len() == before {
+                return orphans;
+            }
+        }
+    }
+
+    /// Orphans when the packages in removed and only those are gone, including removed.
+    fn orphans_after(&self, removed: &HashSet<Istr>) -> Vec<LocalPackage<'_>> {
+        let gone = |p: &&Package| removed.contains(&p.name);
+        let i = self.0.i.borrow();
+        let mut orphans: Vec<_> = self
+            .dependencies()
+            .filter(|p| {
+                self.0.required_by(p.0).iter().all(gone)
+                    && self.0.optional_for(p.0).iter().all(gone)
+            })
+            .collect();
+        orphans.sort_unstable_by_key(|p| p.0.name.r(&i));
+        orphans
+    }
+}
+
+impl Database for LocalDb {
+    fn db(&self) -> &Db {
+        &self.0
+    }
+}
+
+impl<'db> LocalPackage<'db> {
+    pub fn package(self) -> &'db Package {
+        self.0
+    }
+
+    pub fn install_date(self) -> SystemTime {
+        self.0.install_date.unwrap()
+    }
+
+    /// pacman only writes the reason for dependencies, so a missing one means explicit.
+    pub fn reason(self) -> InstallReason {
+        self.0.reason.unwrap_or(InstallReason::Explicit)
+    }
+}
+
+/// A repo's packages, every package has a file name and compressed size.
+#[derive(Clone)]
+pub struct SyncDb {
+    name: String,
+    db: Db,
+    /// Of the db file, if it was read from one.
+    modified: Option<SystemTime>,
+}
+
+/// A package from a [SyncDb].
+#[derive(Copy, Clone)]
+pub struct SyncPackage<'db>(&'db Package);
+
+impl SyncDb {
+    /// Errors if a package lacks the fields needed to download it.
+    pub fn new(name: impl Into<String>, db: Db) -> std::io::Result<Self> {
+        let i = db.i.borrow();
+        if let Some(p) = db
+            .packages()
+            .find(|p| p.filename.is_none() || p.csize.is_none())
+        {
+            return Err(invalid(format!(
+                "{} has no file name or size",
+                p.name.r(&i)
+            )));
+        }
+        drop(i);
+        Ok(Self {
+            name: name.into(),
+            db,
+            modified: None,
+        })
+    }
+
+    /// Reads `<name>.db` from `sync_dbpath` (usually `<dbpath>/sync`).
+    pub fn open(i: Interner, sync_dbpath: &Path, name: &str) -> std::io::Result<Self> {
+        let modified = super::syncdb_modified(sync_dbpath, name)?;
+        let packages =
+            super::parse_syncdb_at(i.clone(), sync_dbpath, name, &crate::events::NoEvents)?;
+        let db = Self::new(name, Db::new(i, packages))?;
+        Ok(Self {
+            modified: Some(modified),
+            ..db
+        })
+    }
+
+    /// When the db file was last written, so usually the last `pacman -Sy`.
+    /// None if the db was not read from a file.
+    pub fn last_modified(&self) -> Option<SystemTime> {
+        self.modified
+    }
+
+    /// Whether the db file is older than max_age.
+    /// A db not read from a file is never stale.
+    pub fn is_stale(&self, max_age: Duration) -> bool {
+        self.modified
+            .is_some_and(|m| m.elapsed().unwrap_or_default() > max_age)
+    }
+
+    /// The repo name.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn package(&self, name: &str) -> Option<SyncPackage<'_>> {
+        self.db.get(name).map(SyncPackage)
+    }
+
+    pub fn sync_packages(&self) -> impl Iterator<Item = SyncPackage<'_>> {
+        self.db.packages().map(SyncPackage)
+    }
+}
+
+impl Database for SyncDb {
+    fn db(&self) -> &Db {
+        &self.db
+    }
+}
+
+impl<'db> SyncPackage<'db> {
+    pub fn package(self) -> &'db Package {
+        self.0
+    }
+
+    pub fn filename(self) -> Istr {
+        self.0.filename.unwrap()
+    }
+
+    /// Download size.
+    pub fn csize(self) -> u64 {
+        self.0.csize.unwrap()
+    }
+}
+
+#[test]
+fn test_db() {
+    use super::{new_interner, test_desc};
+    let i = new_interner();
+    let parse = |desc: String| Package::from_str(i.clone(), &desc).unwrap();
+    let db = Db::new(
+        i.clone(),
+        [
+            parse(test_desc("bash", "5.2-1", &[("PROVIDES", "sh=5.2")])),
+            parse(test_desc("dash", "0.5-1", &[("PROVIDES", "sh")])),
+            parse(test_desc("xorg-server", "21-1", &[("GROUPS", "xorg")])),
+        ]
+        .into_iter()
+        .map(|p| (p.name, p))
+        .collect(),
+    );
+    let names = |ps: Vec<&Package>| {
+        let mut names: Vec<_> = ps
+            .iter()
+            .map(|p| p.name.r(&i.borrow()).to_owned())
+            .collect();
+        names.sort();
+        names
+    };
+    assert_eq!(db.len(), 3);
+    assert!(db.get("bash").is_some());
+    assert!(db.get("zsh").is_none());
+    assert_eq!(names(db.search(&"ASH".into())), ["bash", "dash"]);
+    assert_eq!(names(db.search(&"package xorg".into())), ["xorg-server"]);
+    let ranked = |q: &SearchQuery| {
+        db.search(q)
+            .iter()
+            .map(|p| p.name.r(&i.borrow()).to_owned())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(ranked(&"sh".into()), ["bash", "dash"]);
+    assert_eq!(ranked(&"dash".into()), ["dash"]);
+    assert_eq!(ranked(&"A".into()), ["bash", "dash", "xorg-server"]);
+    let r = SearchQuery::regex("^(bash|xorg)").unwrap();
+    assert_eq!(ranked(&r), ["bash", "xorg-server"]);
+    assert_eq!(
+        ranked(&SearchQuery::regex("^SH$").unwrap()),
+        ["bash", "dash"]
+    );
+    assert!(SearchQuery::regex("(").is_err());
+    assert_eq!(names(db.by_provides("sh")), ["bash", "dash"]);
+    assert_eq!(names(db.by_provides("bash")), ["bash"]);
+    assert_eq!(names(db.group_members("xorg")), ["xorg-server"]);
+    let sh = |d: &str| db.satisfiers(&d.parse().unwrap());
+    assert_eq!(names(sh("sh")), ["bash", "dash"]);
+    assert_eq!(names(sh("sh>=5")), ["bash"]);
+    assert!(sh("sh>6").is_empty());
+    assert!(db.group_members("gnome").is_empty());
+    let groups = db.groups();
+    assert_eq!(groups.len(), 1);
+    assert_eq!(groups[0].0, "xorg");
+    assert_eq!(names(groups[0].1.clone()), ["xorg-server"]);
+    assert_eq!(dep_name("glibc>=2.38"), "glibc");
+}
+
+#[test]
+fn test_required_by() {
+    use super::{new_interner, test_desc};
+    let i = new_interner();
+    let parse = |desc: String| Package::from_str(i.clone(), &desc).unwrap();
+    let db = Db::new(
+        i.clone(),
+        [
+            parse(test_desc("glibc", "2.39-1", &[])),
+            parse(test_desc(
+                "bash",
+                "5.2-1",
+                &[("PROVIDES", "sh=5.2"), ("DEPENDS", "glibc")],
+            )),
+            parse(test_desc("dash", "0.5-1", &[("PROVIDES", "sh")])),
+            parse(test_desc(
+                "script",
+                "1-1",
+                &[("DEPENDS", "sh>=5\nglibc>=2")],
+            )),
+            parse(test_desc("old", "1-1", &[("DEPENDS", "glibc<2")])),
+        ]
+        .into_iter()
+        .map(|p| (p.name, p))
+        .collect(),
+    );
+    let required_by = |name: &str| {
+        db.required_by(db.get(name).unwrap())
+            .iter()
+            .map(|p| p.name.r(&i.borrow()).to_owned())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(required_by("glibc"), ["bash", "script"]);
+    assert_eq!(required_by("bash"), ["script"]);
+    assert!(required_by("dash").is_empty());
+    assert!(required_by("script").is_empty());
+}
+
+#[test]
+fn test_orphans() {
+    use super::{new_interner, test_desc};
+    let i = new_interner();
+    let installed = |name: &str, reason: &str, extra: &[(&str, &str)]| {
+        let mut extra = extra.to_vec();
+        extra.push(("INSTALLDATE", "1700000000"));
+        if !reason.is_empty() {
+            extra.push(("REASON", reason));
+        }
+        Package::from_str(i.clone(), &test_desc(name, "1-1", &extra)).unwrap()
+    };
+    let local = LocalDb::new(Db::new(
+        i.clone(),
+        [
+            installed(
+                "app",
+                "",
+                &[("DEPENDS", "lib"), ("OPTDEPENDS", "docs: manual")],
+            ),
+            installed("lib", "1", &[]),
+            installed("docs", "1", &[]),
+            installed("tool", "1", &[("DEPENDS", "helper")]),
+            installed("helper", "1", &[("DEPENDS", "base")]),
+            installed("base", "1", &[]),
+            installed("explicit", "", &[]),
+        ]
+        .into_iter()
+        .map(|p| (p.name, p))
+        .collect(),
+    ))
+    .unwrap();
+    let names = |ps: Vec<LocalPackage>| {
+        ps.iter()
+            .map(|p| p.package().name.r(&i.borrow()).to_owned())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(names(local.orphans()), ["tool"]);
+    assert_eq!(names(local.orphans_recursive()), ["base", "helper", "tool"]);
+    let docs = local.get("docs").unwrap();
+    assert_eq!(local.optional_for(docs).len(), 1);
+}
+
+#[test]
+fn test_pkgbase() {
+    use super::{new_interner, test_desc};
+    let i = new_interner();
+    let p = |name: &str, base: &str, xdata: &str| {
+        let mut desc = test_desc(name, "1-1", &[])
+            .replace(&format!("%BASE%\n{name}\n"), &format!("%BASE%\n{base}\n"));
+        if !xdata.is_empty() {
+            desc.push_str(&format!("%XDATA%\npkgtype={xdata}\n\n"));
+        }
+        Package::from_str(i.clone(), &desc).unwrap()
+    };
+    let db = Db::new(
+        i.clone(),
+        [
+            p("gcc", "gcc", "pkg"),
+            p("gcc-libs", "gcc", "split"),
+            p("gcc-debug", "gcc", "debug"),
+            p("old-libs", "old", ""),
+            p("old-debug", "old", ""),
+            p("zlib", "zlib", ""),
+        ]
+        .into_iter()
+        .map(|p| (p.name, p))
+        .collect(),
+    );
+    let bases: Vec<_> = db
+        .by_pkgbase()
+        .into_iter()
+        .map(|(base, ps)| {
+            let names: Vec<_> = ps
+                .iter()
+                .map(|p| p.name.r(&i.borrow()).to_owned())
+                .collect();
+            (base, names)
+        })
+        .collect();
+    assert_eq!(bases.len(), 3);
+    assert_eq!(bases[0].0, "gcc");
+    assert_eq!(bases[0].1, ["gcc", "gcc-debug", "gcc-libs"]);
+    assert_eq!(bases[1].1, ["old-debug", "old-libs"]);
+    assert!(db.get("gcc-debug").unwrap().is_debug(&i));
+    assert!(db.get("old-debug").unwrap().is_debug(&i));
+    assert_eq!(db.without_debug().count(), 4);
+    assert!(db.get("gcc-libs").unwrap().is_split(&i));
+    assert!(db.get("old-libs").unwrap().is_split(&i));
+    assert!(!db.get("zlib").unwrap().is_split(&i));
+    assert!(!db.get("gcc-debug").unwrap().is_split(&i));
+}
+
+#[test]
+fn test_soname_lookup() {
+    use super::{new_interner, test_desc};
+    let i = new_interner();
+    let parse = |desc: String| Package::from_str(i.clone(), &desc).unwrap();
+    let db = Db::new(
+        i.clone(),
+        [
+            parse(test_desc(
+                "openssl",
+                "3.2-1",
+                &[("PROVIDES", "libssl.so=3-64")],
+            )),
+            parse(test_desc(
+                "openssl-1.1",
+                "1.1-1",
+                &[("PROVIDES", "libssl.so=1.1-64")],
+            )),
+            parse(test_desc(
+                "curl",
+                "8-1",
+                &[("DEPENDS", "glibc\nlibssl.so=3-64")],
+            )),
+            parse(test_desc("old", "1-1", &[("DEPENDS", "libssl.so=1.1-64")])),
+        ]
+        .into_iter()
+        .map(|p| (p.name, p))
+        .collect(),
+    );
+    let names = |ps: Vec<&Package>| {
+        let mut names: Vec<_> = ps
+            .iter()
+            .map(|p| p.name.r(&i.borrow()).to_owned())
+            .collect();
+        names.sort();
+        names
+    };
+    let ssl3 = "libssl.so=3-64".parse().unwrap();
+    assert_eq!(names(db.soname_providers(&ssl3)), ["openssl"]);
+    assert_eq!(names(db.soname_dependents(&ssl3)), ["curl"]);
+    assert_eq!(names(db.soname_users("libssl.so", 64)), ["curl", "old"]);
+    assert!(db.soname_users("libssl.so", 32).is_empty());
+}
+
+#[test]
+fn test_diff() {
+    use super::{new_interner, test_desc};
+    let db = |pkgs: &[(&str, &str)]| {
+        let i = new_interner();
+        let packages = pkgs
+            .iter()
+            .map(|(name, version)| {
+                let p = Package::from_str(i.clone(), &test_desc(name, version, &[])).unwrap();
+                (p.name, p)
+            })
+            .collect();
+        Db::new(i, packages)
+    };
+    let old = db(&[
+        ("bash", "5.2-1"),
+        ("gone", "1-1"),
+        ("same", "1-1"),
+        ("zsh", "5.9-2"),
+    ]);
+    let new = db(&[
+        ("bash", "5.2-2"),
+        ("new", "1-1"),
+        ("same", "1-1"),
+        ("zsh", "5.9-1"),
+    ]);
+    let d = diff(&old, &new);
+    let names = |db: &Db, ps: &[&Package]| -> Vec<String> {
+        ps.iter()
+            .map(|p| p.name.r(&db.i.borrow()).to_owned())
+            .collect()
+    };
+    assert_eq!(names(&new, &d.added), ["new"]);
+    assert_eq!(names(&old, &d.removed), ["gone"]);
+    let upgraded: Vec<_> = d
+        .upgraded
+        .iter()
+        .map(|(o, n)| (o.version, n.version))
+        .collect();
+    assert_eq!(upgraded.len(), 1);
+    assert_eq!(upgraded[0].1.r(&new.i.borrow()), "5.2-2");
+    assert_eq!(
+        names(
+            &old,
+            &d.downgraded.iter().map(|(o, _)| *o).collect::<Vec<_>>()
+        ),
+        ["zsh"]
+    );
+    assert!(diff(&old, &old).upgraded.is_empty());
+}
+
+#[test]
+fn test_local_sync_db() {
+    use super::{QuickResolve, test_desc, write_test_dbpath};
+    let dir = crate::util::test_dir("local_sync_db");
+    let installed = |name, reason: &str| {
+        let mut extra = vec![("INSTALLDATE", "1700000000")];
+        if !reason.is_empty() {
+            extra.push(("REASON", reason));
+        }
+        test_desc(name, "1-1", &extra)
+    };
+    let sync = [(
+        "foo-1-1",
+        test_desc(
+            "foo",
+            "1-1",
+            &[("FILENAME", "foo-1-1-x86_64.pkg.tar.zst"), ("CSIZE", "42")],
+        ),
+    )];
+    write_test_dbpath(
+        &dir,
+        &[
+            ("foo-1-1", installed("foo", "")),
+            ("bar-1-1", installed("bar", "1")),
+        ],
+        &[
+            ("core", &sync),
+            ("broken", &[("foo-1-1", test_desc("foo", "1-1", &[]))]),
+        ],
+    );
+    let i = super::new_interner();
+    let local = LocalDb::open(i.clone(), &dir.join("local")).unwrap();
+    assert_eq!(
+        local.package("foo").unwrap().reason(),
+        InstallReason::Explicit
+    );
+    assert_eq!(
+        local.package("bar").unwrap().reason(),
+        InstallReason::Dependency
+    );
+    assert_eq!(local.local_packages().count(), 2);
+    assert_eq!(local.explicit().count(), 1);
+    assert_eq!(local.dependencies().count(), 1);
+    assert!(local.get("bar").is_some());
+
+    let core = SyncDb::open(i.clone(), &dir.join("sync"), "core").unwrap();
+    let foo = core.package("foo").unwrap();
+    assert_eq!(core.name(), "core");
+    assert_eq!(foo.csize(), 42);
+    assert_eq!(foo.filename().r(&i.borrow()), "foo-1-1-x86_64.pkg.tar.zst");
+    assert!(SyncDb::open(i.clone(), &dir.join("sync"), "broken").is_err());
+    assert!(core.last_modified().unwrap() <= SystemTime::now());
+    assert!(!core.is_stale(Duration::from_secs(3600)));
+
+    std::fs::write(dir.join("local/bar-1-1/desc"), test_desc("bar", "1-1", &[])).unwrap();
+    assert!(LocalDb::open(i, &dir.join("local")).is_err());
+}
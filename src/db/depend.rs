@@ -0,0 +1,173 @@
+//! Dependencies with version constraints like `glibc>=2.38`,
+//! and matching them against packages and their provides.
+use super::{Interner, InvalidVersion, Istr, Package, QuickResolve, Version};
+use std::cmp::Ordering;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+/// The comparison of a versioned [Depend].
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum DepMod {
+    Eq,
+    Ge,
+    Le,
+    Gt,
+    Lt,
+}
+
+impl DepMod {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Eq => "=",
+            Self::Ge => ">=",
+            Self::Le => "<=",
+            Self::Gt => ">",
+            Self::Lt => "<",
+        }
+    }
+
+    /// Whether ord, the result of comparing a version against the constraint, passes.
+    fn allows(self, ord: Ordering) -> bool {
+        match self {
+            Self::Eq => ord.is_eq(),
+            Self::Ge => ord.is_ge(),
+            Self::Le => ord.is_le(),
+            Self::Gt => ord.is_gt(),
+            Self::Lt => ord.is_lt(),
+        }
+    }
+}
+
+/// A depends, makedepends, conflicts, … entry.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Depend {
+    pub name: String,
+    pub constraint: Option<(DepMod, Version)>,
+}
+
+impl FromStr for Depend {
+    type Err = InvalidVersion;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some(pos) = s.find(['=', '<', '>']) else {
+            return Ok(Self {
+                name: s.to_owned(),
+                constraint: None,
+            });
+        };
+        let (name, rest) = s.split_at(pos);
+        let (depmod, version) = [">=", "<=", "=", ">", "<"]
+            .iter()
+            .zip([DepMod::Ge, DepMod::Le, DepMod::Eq, DepMod::Gt, DepMod::Lt])
+            .find_map(|(op, m)| rest.strip_prefix(op).map(|v| (m, v)))
+            .unwrap();
+        if version.is_empty() {
+            return Err(InvalidVersion(s.to_owned()));
+        }
+        Ok(Self {
+            name: name.to_owned(),
+            constraint: Some((depmod, version.parse()?)),
+        })
+    }
+}
+
+impl Display for Depend {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)?;
+        if let Some((m, v)) = &self.constraint {
+            write!(f, "{}{v}", m.as_str())?;
+        }
+        Ok(())
+    }
+}
+
+impl Depend {
+    /// Whether a package or provision named name at version fulfills this.
+    /// An unversioned provision only satisfies unversioned dependencies, like in pacman.
+    pub(super) fn allows(&self, name: &str, version: Option<&Version>) -> bool {
+        if name != self.name {
+            return false;
+        }
+        match (&self.constraint, version) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some((m, want)), Some(have)) => m.allows(have.vercmp(want)),
+        }
+    }
+
+    /// By name and version, or by one of the PROVIDES entries.
+    pub fn satisfied_by(&self, p: &Package, i: &Interner) -> bool {
+        let version = p.parsed_version(i);
+        let i = i.borrow();
+        if self.allows(p.name.r(&i), Some(version)) {
+            return true;
+        }
+        p.provides.iter().flatten().any(|prov| {
+            let prov = prov.r(&i);
+            match prov.split_once('=') {
+                Some((name, version)) => version.parse().is_ok_and(|v| self.allows(name, Some(&v))),
+                None => self.allows(prov, None),
+            }
+        })
+    }
+}
+
+impl Package {
+    /// The DEPENDS entries, skipping the ones that do not parse.
+    pub fn parsed_depends(&self, i: &Interner) -> Vec<Depend> {
+        let i = i.borrow();
+        let depends: &[Istr] = self.depends.as_deref().unwrap_or_default();
+        depends
+            .iter()
+            .filter_map(|d| d.r(&i).parse().ok())
+            .collect()
+    }
+
+    /// The OPTDEPENDS entries without their description.
+    pub fn parsed_optdepends(&self, i: &Interner) -> Vec<Depend> {
+        let i = i.borrow();
+        let optdepends: &[Istr] = self.optdepends.as_deref().unwrap_or_default();
+        optdepends
+            .iter()
+            .filter_map(|d| d.r(&i).split(':').next()?.trim().parse().ok())
+            .collect()
+    }
+}
+
+#[test]
+fn test_depend() {
+    use super::{new_interner, test_desc};
+    let d: Depend = "glibc>=2.38".parse().unwrap();
+    assert_eq!(d.name, "glibc");
+    assert_eq!(d.constraint.as_ref().unwrap().0, DepMod::Ge);
+    assert_eq!(d.to_string(), "glibc>=2.38");
+    let d: Depend = "sh".parse().unwrap();
+    assert!(d.constraint.is_none());
+    assert!("foo>=".parse::<Depend>().is_err());
+
+    let i = new_interner();
+    let parse = |desc: String| Package::from_str(i.clone(), &desc).unwrap();
+    let glibc = parse(test_desc("glibc", "2.39-1", &[]));
+    let bash = parse(test_desc("bash", "5.2-1", &[("PROVIDES", "sh=5.2")]));
+    let dash = parse(test_desc(
+        "dash",
+        "0.5-1",
+        &[("PROVIDES", "sh"), ("DEPENDS", "glibc>=2.38\nlibc")],
+    ));
+    let sat = |d: &str, p: &Package| d.parse::<Depend>().unwrap().satisfied_by(p, &i);
+    assert!(sat("glibc>=2.38", &glibc));
+    assert!(sat("glibc=2.39", &glibc));
+    assert!(sat("glibc=2.39-1", &glibc));
+    assert!(!sat("glibc<2.39", &glibc));
+    assert!(!sat("glibc>2.39-1", &glibc));
+    assert!(sat("sh", &bash));
+    assert!(sat("sh>=5", &bash));
+    assert!(!sat("sh>5.2", &bash));
+    assert!(sat("sh", &dash));
+    assert!(!sat("sh>=5", &dash));
+    assert!(!sat("glibc", &bash));
+    let depends = dash.parsed_depends(&i);
+    assert_eq!(depends.len(), 2);
+    assert!(depends[0].satisfied_by(&glibc, &i));
+    assert!(!depends[1].satisfied_by(&glibc, &i));
+}
@@ -0,0 +1,207 @@
+//! `pacman -Qi` / `pacman -Si` style rendering of a [Package].
+use super::parse::Validation;
+use super::{InstallReason, Interner, Package, QuickResolve};
+use std::fmt::{self, Display, Formatter};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Renders the package as the familiar `Name            : foo` block, see [Package::display].
+pub struct PackageInfo<'p> {
+    p: &'p Package,
+    i: &'p Interner,
+    repo: Option<&'p str>,
+}
+
+impl Package {
+    /// Fields pacman only shows for installed packages (install date and reason)
+    /// or for sync packages (download size) are included if the package has them.
+    /// Fields that need other packages, like `Required By`, are left out.
+    pub fn display<'p>(&'p self, i: &'p Interner) -> PackageInfo<'p> {
+        PackageInfo {
+            p: self,
+            i,
+            repo: None,
+        }
+    }
+}
+
+impl<'p> PackageInfo<'p> {
+    /// Adds the leading `Repository` line of `pacman -Si`.
+    pub fn repo(self, repo: &'p str) -> Self {
+        Self {
+            repo: Some(repo),
+            ..self
+        }
+    }
+}
+
+/// Width of the label column, including the separator.
+const INDENT: usize = 18;
+
+fn field(f: &mut Formatter<'_>, label: &str, value: impl Display) -> fmt::Result {
+    writeln!(f, "{label:<15} : {value}")
+}
+
+fn list<'a>(
+    f: &mut Formatter<'_>,
+    label: &str,
+    l: impl IntoIterator<Item = &'a str>,
+) -> fmt::Result {
+    let l: Vec<_> = l.into_iter().collect();
+    if l.is_empty() {
+        field(f, label, "None")
+    } else {
+        field(f, label, l.join("  "))
+    }
+}
+
+/// Like pacman: the largest unit that keeps the value at most 2048.
+fn size(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+    let mut val = bytes as f64;
+    let mut unit = UNITS[0];
+    for u in &UNITS[1..] {
+        if val <= 2048.0 {
+            break;
+        }
+        val /= 1024.0;
+        unit = u;
+    }
+    format!("{val:.2} {unit}")
+}
+
+/// In UTC, like `Tue 14 Nov 2023 22:13:20 UTC`.
+fn date(t: SystemTime) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let secs = t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let (days, secs) = (secs / 86400, secs % 86400);
+    // Civil from days, https://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    format!(
+        "{} {day:02} {} {year} {:02}:{:02}:{:02} UTC",
+        WEEKDAYS[(days % 7) as usize],
+        MONTHS[month as usize - 1],
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+    )
+}
+
+impl<'p> Display for PackageInfo<'p> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let p = self.p;
+        let guard = self.i.borrow();
+        let i = &guard;
+        let strs = |l: &'p Option<Vec<super::Istr>>| l.iter().flatten().map(move |s| s.r(i));
+
+        if let Some(repo) = self.repo {
+            field(f, "Repository", repo)?;
+        }
+        field(f, "Name", p.name.r(i))?;
+        field(f, "Version", p.version.r(i))?;
+        field(f, "Description", p.desc.r(i))?;
+        field(f, "Architecture", p.arch.r(i))?;
+        field(f, "URL", p.url.map_or("None", |u| u.r(i)))?;
+        list(f, "Licenses", p.license.iter().map(|l| l.r(i)))?;
+        list(f, "Groups", strs(&p.groups))?;
+        list(f, "Provides", strs(&p.provides))?;
+        list(f, "Depends On", strs(&p.depends))?;
+
+        let mut optdepends = strs(&p.optdepends);
+        match optdepends.next() {
+            None => field(f, "Optional Deps", "None")?,
+            Some(first) => {
+                field(f, "Optional Deps", first)?;
+                for o in optdepends {
+                    writeln!(f, "{:INDENT$}{o}", "")?;
+                }
+            }
+        }
+
+        list(f, "Conflicts With", strs(&p.conflicts))?;
+        let mut replaces: Vec<_> = p.replaces.iter().flatten().map(|r| r.r(i)).collect();
+        replaces.sort_unstable();
+        list(f, "Replaces", replaces)?;
+        if let Some(csize) = p.csize {
+            field(f, "Download Size", size(csize))?;
+        }
+        field(f, "Installed Size", size(p.isize.unwrap_or(0)))?;
+        field(f, "Packager", p.packager.r(i))?;
+        field(f, "Build Date", date(p.build_date))?;
+        if let Some(install_date) = p.install_date {
+            field(f, "Install Date", date(install_date))?;
+            let reason = match p.reason {
+                None | Some(InstallReason::Explicit) => "Explicitly installed",
+                Some(InstallReason::Dependency) => "Installed as a dependency for another package",
+                Some(InstallReason::Unknown) => "Unknown",
+            };
+            field(f, "Install Reason", reason)?;
+        }
+        let validation = match p.validation {
+            None | Some(Validation::None) => "None",
+            Some(Validation::Md5Sum) => "MD5 Sum",
+            Some(Validation::Sha256Sum) => "SHA-256 Sum",
+            Some(Validation::Signature) => "Signature",
+        };
+        field(f, "Validated By", validation)
+    }
+}
+
+#[test]
+fn test_display() {
+    use super::{new_interner, test_desc};
+    let desc = test_desc(
+        "foo",
+        "1.0-1",
+        &[
+            ("DEPENDS", "glibc\nsh"),
+            ("OPTDEPENDS", "bar: for bar\nbaz: for baz"),
+            ("ISIZE", "3145728"),
+            ("CSIZE", "2048"),
+            ("VALIDATION", "pgp"),
+        ],
+    );
+    let i = new_interner();
+    let p = Package::from_str(i.clone(), &desc).unwrap();
+    let info = p.display(&i).repo("core").to_string();
+    let expected = "\
+Repository      : core
+Name            : foo
+Version         : 1.0-1
+Description     : test package foo
+Architecture    : x86_64
+URL             : None
+Licenses        : MIT
+Groups          : None
+Provides        : None
+Depends On      : glibc  sh
+Optional Deps   : bar: for bar
+                  baz: for baz
+Conflicts With  : None
+Replaces        : None
+Download Size   : 2048.00 B
+Installed Size  : 3.00 MiB
+Packager        : tester
+Build Date      : Tue 14 Nov 2023 22:13:20 UTC
+Validated By    : Signature
+";
+    assert_eq!(info, expected);
+
+    let desc = test_desc("bar", "2-1", &[("INSTALLDATE", "0"), ("REASON", "1")]);
+    let i = new_interner();
+    let p = Package::from_str(i.clone(), &desc).unwrap();
+    let info = p.display(&i).to_string();
+    assert!(info.starts_with("Name            : bar\n"));
+    assert!(info.contains("Install Date    : Thu 01 Jan 1970 00:00:00 UTC\n"));
+    assert!(info.contains("Install Reason  : Installed as a dependency for another package\n"));
+}
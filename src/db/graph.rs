@@ -0,0 +1,390 @@
+//! Dependency graphs over one or more dbs, like pactree, with DOT export for graphviz.
+use super::{Db, Depend, Interner, Package, QuickResolve, dep_name};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::Write;
+
+/// Why an edge exists.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum EdgeKind {
+    /// from depends on to.
+    Depends,
+    /// from optionally depends on to.
+    OptDepends,
+    /// from is a virtual name that to provides, e.g. `sh` -> `bash`.
+    Provides,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Node {
+    pub name: String,
+    /// The db the package is from, None for virtual names only known from provides.
+    pub repo: Option<String>,
+}
+
+/// Packages and virtual names as nodes, see [EdgeKind] for the edges.
+/// Dependencies are resolved like pacman: a package named like the dependency wins,
+/// otherwise the first satisfier in db order.
+/// Dependencies nothing satisfies are left out.
+#[derive(Clone, Debug, Default)]
+pub struct DepGraph {
+    nodes: Vec<Node>,
+    index: HashMap<String, usize>,
+    edges: Vec<(usize, usize, EdgeKind)>,
+    /// For deduplicating edges.
+    edge_set: HashSet<(usize, usize, EdgeKind)>,
+}
+
+impl DepGraph {
+    /// dbs in order of priority as (repo name, db).
+    /// A package in several dbs is only taken from the first.
+    pub fn new(dbs: &[(&str, &Db)]) -> Self {
+        let mut g = Self::default();
+        // dependency name -> candidates in priority order, with the interner of their db
+        let mut providers: HashMap<String, Vec<(&Package, &Interner)>> = HashMap::new();
+        let mut packages = Vec::new();
+        for (repo, db) in dbs {
+            let interner = db.interner();
+            let i = interner.borrow();
+            let mut names: Vec<_> = db.packages().map(|p| (p.name.r(&i), p)).collect();
+            names.sort_unstable_by_key(|(name, _)| *name);
+            for (name, p) in names {
+                if g.index.contains_key(name) {
+                    continue;
+                }
+                g.node(name, Some(repo));
+                packages.push((name.to_owned(), p, interner));
+                providers
+                    .entry(name.to_owned())
+                    .or_default()
+                    .push((p, interner));
+                for prov in p.provides.iter().flatten() {
+                    let prov = dep_name(prov.r(&i));
+                    providers
+                        .entry(prov.to_owned())
+                        .or_default()
+                        .push((p, interner));
+                }
+            }
+        }
+        for (name, p, i) in &packages {
+            let from = g.index[name];
+            let deps = p
+                .parsed_depends(i)
+                .into_iter()
+                .map(|d| (d, EdgeKind::Depends));
+            let optdeps = p.parsed_optdepends(i).into_iter();
+            for (dep, kind) in deps.chain(optdeps.map(|d| (d, EdgeKind::OptDepends))) {
+                let Some((satisfier, i)) = Self::resolve(&providers, &dep) else {
+                    continue;
+                };
+                let to = g.index[satisfier.name.r(&i.borrow())];
+                if g.index.contains_key(&dep.name) {
+                    g.edge(from, to, kind);
+                } else {
+                    let virt = g.node(&dep.name, None);
+                    g.edge(from, virt, kind);
+                    g.edge(virt, to, EdgeKind::Provides);
+                }
+            }
+        }
+        g
+    }
+
+    fn resolve<'p>(
+        providers: &HashMap<String, Vec<(&'p Package, &'p Interner)>>,
+        dep: &Depend,
+    ) -> Option<(&'p Package, &'p Interner)> {
+        let candidates = providers.get(&dep.name)?;
+        let mut satisfying = candidates.iter().filter(|(p, i)| dep.satisfied_by(p, i));
+        let first = *satisfying.clone().next()?;
+        let named = satisfying.find(|(p, i)| p.name.r(&i.borrow()) == dep.name);
+        Some(named.copied().unwrap_or(first))
+    }
+
+    /// Index of the node named name, adding it if needed.
+    fn node(&mut self, name: &str, repo: Option<&str>) -> usize {
+        if let Some(&n) = self.index.get(name) {
+            return n;
+        }
+        self.nodes.push(Node {
+            name: name.to_owned(),
+            repo: repo.map(ToOwned::to_owned),
+        });
+        self.index.insert(name.to_owned(), self.nodes.len() - 1);
+        self.nodes.len() - 1
+    }
+
+    fn edge(&mut self, from: usize, to: usize, kind: EdgeKind) {
+        if from != to && self.edge_set.insert((from, to, kind)) {
+            self.edges.push((from, to, kind));
+        }
+    }
+
+    pub fn nodes(&self) -> &[Node] {
+        &self.nodes
+    }
+
+    pub fn node_by_name(&self, name: &str) -> Option<&Node> {
+        self.index.get(name).map(|&n| &self.nodes[n])
+    }
+
+    /// (from, to, kind) by node name.
+    pub fn edges(&self) -> impl Iterator<Item = (&str, &str, EdgeKind)> {
+        self.edges.iter().map(|&(from, to, kind)| {
+            (
+                self.nodes[from].name.as_str(),
+                self.nodes[to].name.as_str(),
+                kind,
+            )
+        })
+    }
+
+    /// What root pulls in, like `pactree root`,
+    /// or with reverse what pulls in root, which answers why it is installed, like `pactree -r`.
+    /// Optional dependencies are only followed if optional is set.
+    /// Empty if there is no node named root.
+    pub fn subgraph(&self, root: &str, reverse: bool, optional: bool) -> Self {
+        let mut g = Self::default();
+        let Some(&root) = self.index.get(root) else {
+            return g;
+        };
+        let mut adjacent = vec![Vec::new(); self.nodes.len()];
+        for &(a, b, kind) in &self.edges {
+            if optional || kind != EdgeKind::OptDepends {
+                let (here, there) = if reverse { (b, a) } else { (a, b) };
+                adjacent[here].push((there, kind));
+            }
+        }
+        let mut seen = vec![false; self.nodes.len()];
+        seen[root] = true;
+        let mut queue = VecDeque::from([root]);
+        while let Some(n) = queue.pop_front() {
+            let node = &self.nodes[n];
+            let here = g.node(&node.name, node.repo.as_deref());
+            for &(there, kind) in &adjacent[n] {
+                let other = &self.nodes[there];
+                let to = g.node(&other.name, other.repo.as_deref());
+                if reverse {
+                    g.edge(to, here, kind);
+                } else {
+                    g.edge(here, to, kind);
+                }
+                if !seen[there] {
+                    seen[there] = true;
+                    queue.push_back(there);
+                }
+            }
+        }
+        g
+    }
+
+    /// The graph in graphviz' DOT language.
+    /// Optional dependencies are dashed, provides are grey and virtual names are boxes.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph deps {\n");
+        for node in &self.nodes {
+            match &node.repo {
+                Some(repo) => writeln!(dot, "  {:?} [tooltip={repo:?}];", node.name),
+                None => writeln!(dot, "  {:?} [shape=box];", node.name),
+            }
+            .unwrap();
+        }
+        for (from, to, kind) in self.edges() {
+            let attrs = match kind {
+                EdgeKind::Depends => "",
+                EdgeKind::OptDepends => " [style=dashed]",
+                EdgeKind::Provides => " [color=grey]",
+            };
+            writeln!(dot, "  {from:?} -> {to:?}{attrs};").unwrap();
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+/// Orders packages for installation like pacman: dependencies before what depends on them,
+/// only looking at depends satisfied within packages.
+/// Each inner Vec is a single package, or a dependency cycle in the order the packages were given,
+/// which pacman installs in that order after warning about it.
+pub fn sort_by_deps(i: &Interner, packages: &[Package]) -> Vec<Vec<Package>> {
+    let mut providers: HashMap<String, Vec<usize>> = HashMap::new();
+    for (n, p) in packages.iter().enumerate() {
+        let ir = i.borrow();
+        providers
+            .entry(p.name.r(&ir).to_owned())
+            .or_default()
+            .push(n);
+        for prov in p.provides.iter().flatten() {
+            providers
+                .entry(dep_name(prov.r(&ir)).to_owned())
+                .or_default()
+                .push(n);
+        }
+    }
+    let depends: Vec<Vec<usize>> = packages
+        .iter()
+        .map(|p| {
+            let mut to = Vec::new();
+            for dep in p.parsed_depends(i) {
+                let candidates = providers.get(&dep.name).into_iter().flatten();
+                if let Some(&s) = candidates
+                    .into_iter()
+                    .find(|&&s| dep.satisfied_by(&packages[s], i))
+                {
+                    to.push(s);
+                }
+            }
+            to
+        })
+        .collect();
+
+    // Tarjan's algorithm emits each strongly connected component
+    // after everything reachable from it, so dependencies come first.
+    const UNVISITED: usize = usize::MAX;
+    let mut index = vec![UNVISITED; packages.len()];
+    let mut lowlink = vec![0; packages.len()];
+    let mut on_stack = vec![false; packages.len()];
+    let mut stack = Vec::new();
+    let mut next = 0;
+    let mut ret = Vec::new();
+    for root in 0..packages.len() {
+        if index[root] != UNVISITED {
+            continue;
+        }
+        // (node, next edge to look at)
+        let mut work = vec![(root, 0)];
+        while let Some(&mut (n, ref mut edge)) = work.last_mut() {
+            if *edge == 0 && index[n] == UNVISITED {
+                index[n] = next;
+                lowlink[n] = next;
+                next += 1;
+                stack.push(n);
+                on_stack[n] = true;
+            }
+            if let Some(&m) = depends[n].get(*edge) {
+                *edge += 1;
+                if index[m] == UNVISITED {
+                    work.push((m, 0));
+                } else if on_stack[m] {
+                    lowlink[n] = lowlink[n].min(index[m]);
+                }
+                continue;
+            }
+            work.pop();
+            if let Some(&(parent, _)) = work.last() {
+                lowlink[parent] = lowlink[parent].min(lowlink[n]);
+            }
+            if lowlink[n] == index[n] {
+                let pos = stack.iter().rposition(|&s| s == n).unwrap();
+                let mut component: Vec<_> = stack.drain(pos..).collect();
+                for &c in &component {
+                    on_stack[c] = false;
+                }
+                component.sort_unstable();
+                if component.len() > 1 {
+                    let names: Vec<_> = component
+                        .iter()
+                        .map(|&c| packages[c].name.r(&i.borrow()).to_owned())
+                        .collect();
+                    log::warn!("dependency cycle detected: {}", names.join(", "));
+                }
+                ret.push(component.into_iter().map(|c| packages[c].clone()).collect());
+            }
+        }
+    }
+    ret
+}
+
+#[test]
+fn test_dep_graph() {
+    use super::{new_interner, test_desc};
+    let i = new_interner();
+    let db = |pkgs: &[(&str, &[(&str, &str)])]| {
+        let packages = pkgs
+            .iter()
+            .map(|(name, extra)| Package::from_str(i.clone(), &test_desc(name, "1-1", extra)))
+            .map(|p| p.unwrap())
+            .map(|p| (p.name, p))
+            .collect();
+        Db::new(i.clone(), packages)
+    };
+    let core = db(&[
+        ("glibc", &[]),
+        ("bash", &[("PROVIDES", "sh"), ("DEPENDS", "glibc")]),
+        (
+            "script",
+            &[("DEPENDS", "sh\nmissing"), ("OPTDEPENDS", "docs: manual")],
+        ),
+    ]);
+    let extra = db(&[("docs", &[]), ("bash", &[])]);
+    let g = DepGraph::new(&[("core", &core), ("extra", &extra)]);
+    assert_eq!(g.nodes().len(), 5);
+    assert_eq!(
+        g.node_by_name("bash").unwrap().repo.as_deref(),
+        Some("core")
+    );
+    assert_eq!(g.node_by_name("sh").unwrap().repo, None);
+    let mut edges: Vec<_> = g.edges().collect();
+    edges.sort_unstable_by_key(|e| (e.0, e.1));
+    assert_eq!(
+        edges,
+        [
+            ("bash", "glibc", EdgeKind::Depends),
+            ("script", "docs", EdgeKind::OptDepends),
+            ("script", "sh", EdgeKind::Depends),
+            ("sh", "bash", EdgeKind::Provides),
+        ]
+    );
+
+    let why = g.subgraph("glibc", true, false);
+    let mut names: Vec<_> = why.nodes().iter().map(|n| n.name.as_str()).collect();
+    names.sort_unstable();
+    assert_eq!(names, ["bash", "glibc", "script", "sh"]);
+    assert!(g.subgraph("docs", true, false).edges().next().is_none());
+    assert_eq!(g.subgraph("docs", true, true).edges().count(), 1);
+    assert_eq!(g.subgraph("script", false, false).nodes().len(), 4);
+    assert!(g.subgraph("nope", false, false).nodes().is_empty());
+
+    let dot = g.subgraph("bash", false, false).to_dot();
+    assert_eq!(
+        dot,
+        "digraph deps {\n  \"bash\" [tooltip=\"core\"];\n  \"glibc\" [tooltip=\"core\"];\n  \
+        \"bash\" -> \"glibc\";\n}\n"
+    );
+    assert!(g.to_dot().contains("\"sh\" -> \"bash\" [color=grey];"));
+    assert!(g.to_dot().contains("\"sh\" [shape=box];"));
+}
+
+#[test]
+fn test_sort_by_deps() {
+    use super::{new_interner, test_desc};
+    let i = new_interner();
+    let p = |name: &str, extra: &[(&str, &str)]| {
+        Package::from_str(i.clone(), &test_desc(name, "1-1", extra)).unwrap()
+    };
+    let packages = [
+        p("app", &[("DEPENDS", "lib\nsh")]),
+        p("lib", &[("DEPENDS", "glibc")]),
+        p(
+            "bash",
+            &[("DEPENDS", "glibc\nreadline"), ("PROVIDES", "sh")],
+        ),
+        p("readline", &[("DEPENDS", "bash")]),
+        p("glibc", &[("DEPENDS", "missing")]),
+        p("dash", &[]),
+    ];
+    let order: Vec<Vec<String>> = sort_by_deps(&i, &packages)
+        .iter()
+        .map(|c| c.iter().map(|p| p.name.r(&i.borrow()).to_owned()).collect())
+        .collect();
+    assert_eq!(
+        order,
+        [
+            vec!["glibc"],
+            vec!["lib"],
+            vec!["bash", "readline"],
+            vec!["app"],
+            vec!["dash"],
+        ]
+    );
+}
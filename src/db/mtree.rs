@@ -0,0 +1,215 @@
+//! The mtree files makepkg writes as `.MTREE` into packages and pacman copies to the local db,
+//! describing every file of the package.
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EntryType {
+    File,
+    Dir,
+    Link,
+    /// Block or character devices, fifos and sockets, which packages should not contain.
+    Other,
+}
+
+/// One path of an mtree, keywords it does not have are None.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    /// Relative to root without leading `./`, like in [super::FileList::files] but without trailing /.
+    pub path: String,
+    pub kind: EntryType,
+    /// Permission bits, e.g. 0o755.
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub size: Option<u64>,
+    pub time: Option<SystemTime>,
+    /// Hex encoded.
+    pub md5: Option<String>,
+    /// Hex encoded.
+    pub sha256: Option<String>,
+    /// Target of a link.
+    pub link: Option<String>,
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Undoes the `\ooo` octal escapes mtree uses for spaces and other special bytes.
+fn unescape(s: &str) -> io::Result<String> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut pos = 0;
+    while pos < bytes.len() {
+        if bytes[pos] == b'\\' {
+            let octal = s
+                .get(pos + 1..pos + 4)
+                .and_then(|o| u8::from_str_radix(o, 8).ok())
+                .ok_or_else(|| invalid(format!("bad escape in {s:?}")))?;
+            out.push(octal);
+            pos += 4;
+        } else {
+            out.push(bytes[pos]);
+            pos += 1;
+        }
+    }
+    String::from_utf8(out).map_err(|_| invalid(format!("{s:?} is not utf-8")))
+}
+
+fn entry(path: &str, keywords: &HashMap<&str, &str>) -> io::Result<Entry> {
+    let num = |k: &str, radix| {
+        keywords
+            .get(k)
+            .map(|v| u64::from_str_radix(v, radix))
+            .transpose()
+            .map_err(|_| invalid(format!("bad {k} for {path}")))
+    };
+    let time = keywords
+        .get("time")
+        .map(|t| t.parse::<f64>())
+        .transpose()
+        .map_err(|_| invalid(format!("bad time for {path}")))?
+        .map(|t| UNIX_EPOCH + Duration::from_secs_f64(t));
+    let kind = match keywords.get("type").copied().unwrap_or("file") {
+        "file" => EntryType::File,
+        "dir" => EntryType::Dir,
+        "link" => EntryType::Link,
+        _ => EntryType::Other,
+    };
+    let path = unescape(path.trim_start_matches("./"))?;
+    Ok(Entry {
+        kind,
+        mode: num("mode", 8)?.map(|m| m as u32),
+        uid: num("uid", 10)?.map(|u| u as u32),
+        gid: num("gid", 10)?.map(|g| g as u32),
+        size: num("size", 10)?,
+        time,
+        md5: keywords.get("md5digest").map(|s| s.to_string()),
+        sha256: keywords.get("sha256digest").map(|s| s.to_string()),
+        link: keywords.get("link").map(|l| unescape(l)).transpose()?,
+        path,
+    })
+}
+
+/// Parses an uncompressed mtree, applying `/set` and `/unset` to the entries after them.
+/// The `.` entry of the root itself is skipped.
+pub fn parse(s: &str) -> io::Result<Vec<Entry>> {
+    let mut defaults: HashMap<&str, &str> = HashMap::new();
+    let mut entries = Vec::new();
+    for line in s.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut words = line.split_ascii_whitespace();
+        let first = words.next().unwrap();
+        let pairs = words.filter_map(|w| w.split_once('='));
+        match first {
+            "/set" => defaults.extend(pairs),
+            "/unset" => {
+                for k in line.split_ascii_whitespace().skip(1) {
+                    defaults.remove(k);
+                }
+            }
+            "." => (),
+            path => {
+                let mut keywords = defaults.clone();
+                keywords.extend(pairs);
+                entries.push(entry(path, &keywords)?);
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Reads a gzip compressed mtree, like the one in `<dbpath>/local/<name>-<version>/mtree`.
+pub fn read(path: &Path) -> io::Result<Vec<Entry>> {
+    read_from(BufReader::new(File::open(path)?))
+}
+
+fn read_from(r: impl BufRead) -> io::Result<Vec<Entry>> {
+    let mut s = String::new();
+    super::decompress(r)?.read_to_string(&mut s)?;
+    parse(&s)
+}
+
+/// Reads the `.MTREE` of the package file at path, e.g. a `.pkg.tar.zst`.
+/// Errors with [io::ErrorKind::NotFound] if it has none, like packages built before pacman 4.1.
+pub fn from_package(path: &Path) -> io::Result<Vec<Entry>> {
+    read_from(package_mtree(path)?.as_slice())
+}
+
+/// The still compressed `.MTREE` of a package file, which pacman copies to the local db as is.
+pub(crate) fn package_mtree(path: &Path) -> io::Result<Vec<u8>> {
+    let f = super::decompress(BufReader::new(File::open(path)?))?;
+    for entry in tar::Archive::new(f).entries()? {
+        let mut entry = entry?;
+        if entry.path_bytes().as_ref() == b".MTREE" {
+            let mut mtree = Vec::new();
+            entry.read_to_end(&mut mtree)?;
+            return Ok(mtree);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} has no .MTREE", path.display()),
+    ))
+}
+
+#[test]
+fn test_mtree() {
+    let entries = parse(
+        "#mtree\n\
+        /set type=file uid=0 gid=0 mode=644\n\
+        ./.BUILDINFO time=1700000000.5 size=5 md5digest=abc sha256digest=def\n\
+        ./usr time=1700000000.0 mode=755 type=dir\n\
+        ./usr/bin/my\\040tool time=1700000000.0 mode=755 size=3 sha256digest=123\n\
+        /set mode=777 type=link\n\
+        ./usr/lib/libfoo.so time=1700000000.0 link=libfoo.so.1\n\
+        /unset uid\n\
+        ./usr/share/x uid=1000 type=file\n\
+        ./usr/share/y\n",
+    )
+    .unwrap();
+    assert_eq!(entries.len(), 6);
+    let buildinfo = &entries[0];
+    assert_eq!(buildinfo.path, ".BUILDINFO");
+    assert_eq!(buildinfo.kind, EntryType::File);
+    assert_eq!(buildinfo.mode, Some(0o644));
+    assert_eq!(buildinfo.size, Some(5));
+    assert_eq!(buildinfo.md5.as_deref(), Some("abc"));
+    assert_eq!(
+        buildinfo.time,
+        Some(UNIX_EPOCH + Duration::from_millis(1_700_000_000_500))
+    );
+    assert_eq!(entries[1].kind, EntryType::Dir);
+    assert_eq!(entries[1].mode, Some(0o755));
+    assert_eq!(entries[2].path, "usr/bin/my tool");
+    assert_eq!(entries[2].sha256.as_deref(), Some("123"));
+    assert_eq!(entries[3].kind, EntryType::Link);
+    assert_eq!(entries[3].link.as_deref(), Some("libfoo.so.1"));
+    assert_eq!(entries[4].uid, Some(1000));
+    assert_eq!(entries[5].uid, None);
+    assert_eq!(entries[5].kind, EntryType::Link);
+    assert!(parse("./bad\\9 type=file\n").is_err());
+}
+
+#[test]
+fn test_mtree_from_package() {
+    let dir = crate::util::test_dir("mtree_from_package");
+    let pkg = dir.join("foo-1-1-x86_64.pkg.tar.zst");
+    let pkginfo = super::repo::test_pkginfo("foo", "1-1");
+    super::repo::write_test_package(&pkg, &pkginfo, &["usr/", "usr/bin/foo"]);
+    let entries = from_package(&pkg).unwrap();
+    let paths: Vec<_> = entries.iter().map(|e| e.path.as_str()).collect();
+    assert_eq!(paths, [".PKGINFO", "usr", "usr/bin/foo"]);
+    assert_eq!(entries[1].kind, EntryType::Dir);
+    assert_eq!(entries[2].size, Some(8));
+
+    super::write_test_archive(&dir.join("empty.tar.gz"), &[]);
+    let e = from_package(&dir.join("empty.tar.gz")).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::NotFound);
+}
@@ -0,0 +1,36 @@
+//! Parallel versions of the db parsers, used with the parallel feature.
+//! The interner is not thread-safe without the sync feature, so descs are read and split on the thread pool
+//! and only turned into [Package]s on the calling thread.
+use super::{Interner, Istr, Package, parse};
+use rayon::prelude::*;
+use std::{collections::HashMap, path::Path};
+
+fn packages(i: &Interner, descs: &[String]) -> std::io::Result<HashMap<Istr, Package>> {
+    let maps = descs
+        .par_iter()
+        .map(|s| parse::parse_to_map(s).map_err(super::invalid_desc))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    maps.iter()
+        .map(|m| Package::from_map(i.clone(), m).map_err(super::invalid_package))
+        .map(|p| p.map(|p| (p.name, p)))
+        .collect()
+}
+
+pub(super) fn localdb(
+    i: &Interner,
+    local_dbpath: &Path,
+) -> std::io::Result<HashMap<Istr, Package>> {
+    let dirs = super::localdb_dirs(local_dbpath)?.collect::<std::io::Result<Vec<_>>>()?;
+    let descs = dirs
+        .par_iter()
+        .map(|dir| std::fs::read_to_string(dir.join("desc")))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    packages(i, &descs)
+}
+
+pub(super) fn syncdb(i: &Interner, dbfile: &Path) -> std::io::Result<HashMap<Istr, Package>> {
+    // Decompression is sequential, so unlike the serial parser all descs are kept in memory.
+    let mut descs = Vec::new();
+    super::read_sync_archive(dbfile, |_, s| descs.push(s.to_owned()))?;
+    packages(i, &descs)
+}
@@ -1,16 +1,14 @@
-use std::cell::RefCell;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::ops::Deref;
-use std::rc::Rc;
 use std::str::FromStr;
+use std::sync::OnceLock;
 use std::time::Duration;
 use std::time::SystemTime;
 use std::time::UNIX_EPOCH;
 
 use base64::Engine;
 use base64::prelude::BASE64_STANDARD_NO_PAD as B64;
-use nom::Finish;
 use nom::branch::alt;
 use nom::bytes::complete::tag;
 use nom::bytes::complete::take_until;
@@ -30,12 +28,47 @@ use string_interner::DefaultStringInterner;
 pub use string_interner::DefaultSymbol as Istr;
 use string_interner::StringInterner;
 
-type InnerInterner = DefaultStringInterner;
-pub type Interner = Rc<RefCell<InnerInterner>>;
+pub(super) type InnerInterner = DefaultStringInterner;
+#[cfg(not(feature = "sync"))]
+pub type Interner = std::rc::Rc<std::cell::RefCell<InnerInterner>>;
+/// With the sync feature the interner, and so dbs, are Send + Sync,
+/// at the cost of locking the interner on every access.
+#[cfg(feature = "sync")]
+pub type Interner = std::sync::Arc<SyncInterner>;
 pub fn new_interner() -> Interner {
     let i = StringInterner::<_>::new();
-    Rc::new(RefCell::new(i))
+    Interner::new(i.into())
 }
+
+/// The interner behind a RwLock, with the methods of RefCell,
+/// so code using it does not care whether the sync feature is enabled.
+/// Unlike with RefCell, borrowing it mutably while it is borrowed deadlocks instead of panicking.
+#[cfg(feature = "sync")]
+#[derive(Debug, Default)]
+pub struct SyncInterner(std::sync::RwLock<InnerInterner>);
+
+#[cfg(feature = "sync")]
+impl From<InnerInterner> for SyncInterner {
+    fn from(i: InnerInterner) -> Self {
+        Self(std::sync::RwLock::new(i))
+    }
+}
+
+#[cfg(feature = "sync")]
+impl SyncInterner {
+    pub fn borrow(&self) -> std::sync::RwLockReadGuard<'_, InnerInterner> {
+        self.0
+            .read()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    pub fn borrow_mut(&self) -> std::sync::RwLockWriteGuard<'_, InnerInterner> {
+        self.0
+            .write()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
+
 pub trait QuickResolve {
     fn r<I: Deref<Target = InnerInterner>>(self, i: &I) -> &str;
 }
@@ -46,6 +79,25 @@ impl QuickResolve for Istr {
     }
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum InstallReason {
+    Explicit = 0,
+    /// Installed as a dependency of another package.
+    Dependency = 1,
+    /// A REASON pacman does not know either.
+    Unknown = 2,
+}
+
+impl From<u8> for InstallReason {
+    fn from(r: u8) -> Self {
+        match r {
+            0 => Self::Explicit,
+            1 => Self::Dependency,
+            _ => Self::Unknown,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub enum Validation {
     None = 1,
@@ -54,6 +106,17 @@ pub enum Validation {
     Signature = 1 << 3,
 }
 
+impl Validation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Md5Sum => "md5",
+            Self::Sha256Sum => "sha256",
+            Self::Signature => "pgp",
+        }
+    }
+}
+
 impl FromStr for Validation {
     type Err = String;
 
@@ -68,18 +131,41 @@ impl FromStr for Validation {
     }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum Arch {
     X86_64,
+    X86_64V2,
+    X86_64V3,
+    X86_64V4,
+    I686,
+    Pentium4,
+    Aarch64,
+    Armv7h,
+    Armv6h,
+    Riscv64,
+    Loong64,
     Any,
+    /// One not listed here, like the architecture of a custom port.
+    Other(Istr),
 }
 
+/// Only the architectures listed in [Arch], [Arch::new] takes any.
 impl FromStr for Arch {
     type Err = ();
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
             "x86_64" => Ok(Self::X86_64),
+            "x86_64_v2" => Ok(Self::X86_64V2),
+            "x86_64_v3" => Ok(Self::X86_64V3),
+            "x86_64_v4" => Ok(Self::X86_64V4),
+            "i686" => Ok(Self::I686),
+            "pentium4" => Ok(Self::Pentium4),
+            "aarch64" => Ok(Self::Aarch64),
+            "armv7h" => Ok(Self::Armv7h),
+            "armv6h" => Ok(Self::Armv6h),
+            "riscv64" => Ok(Self::Riscv64),
+            "loong64" => Ok(Self::Loong64),
             "any" => Ok(Self::Any),
             _ => Err(()),
         }
@@ -87,14 +173,54 @@ impl FromStr for Arch {
 }
 
 impl Arch {
-    pub fn as_str(self) -> &'static str {
+    /// s as one of the listed architectures, or interned into i as [Arch::Other].
+    pub fn new(s: &str, i: &Interner) -> Self {
+        Self::intern(s, &mut i.borrow_mut())
+    }
+
+    pub(super) fn intern(s: &str, ir: &mut InnerInterner) -> Self {
+        Self::from_str(s).unwrap_or_else(|()| Self::Other(ir.get_or_intern(s)))
+    }
+}
+
+impl QuickResolve for Arch {
+    fn r<I: Deref<Target = InnerInterner>>(self, i: &I) -> &str {
         match self {
             Arch::X86_64 => "x86_64",
+            Arch::X86_64V2 => "x86_64_v2",
+            Arch::X86_64V3 => "x86_64_v3",
+            Arch::X86_64V4 => "x86_64_v4",
+            Arch::I686 => "i686",
+            Arch::Pentium4 => "pentium4",
+            Arch::Aarch64 => "aarch64",
+            Arch::Armv7h => "armv7h",
+            Arch::Armv6h => "armv6h",
+            Arch::Riscv64 => "riscv64",
+            Arch::Loong64 => "loong64",
             Arch::Any => "any",
+            Arch::Other(s) => s.r(i),
         }
     }
 }
 
+#[test]
+fn test_arch() {
+    let i = new_interner();
+    for a in ["x86_64", "x86_64_v3", "aarch64", "riscv64", "any"] {
+        assert_eq!(Arch::from_str(a).unwrap().r(&i.borrow()), a);
+    }
+    assert!(Arch::from_str("x86-64").is_err());
+    let other = Arch::new("x86-64", &i);
+    assert!(matches!(other, Arch::Other(_)));
+    assert_eq!(other.r(&i.borrow()), "x86-64");
+    assert_eq!(Arch::new("x86-64", &i), other);
+    assert_eq!(Arch::new("any", &i), Arch::Any);
+
+    let desc = super::test_desc("foo", "1-1", &[]).replace("x86_64", "sparc");
+    let p = Package::from_str(i.clone(), &desc).unwrap();
+    assert_eq!(p.arch.r(&i.borrow()), "sparc");
+}
+
 #[derive(Clone)]
 // TODO: Possibly just keep this as a string/don't keep it at all
 // its unclear to me what even uses this data.
@@ -104,6 +230,16 @@ pub enum XData {
     Debug,
 }
 
+impl XData {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Pkg => "pkgtype=pkg",
+            Self::Split => "pkgtype=split",
+            Self::Debug => "pkgtype=debug",
+        }
+    }
+}
+
 impl FromStr for XData {
     type Err = String;
 
@@ -117,16 +253,16 @@ impl FromStr for XData {
     }
 }
 
+/// Plain data, its strings are interned and resolved with the interner it was parsed with.
 #[derive(Clone)]
 pub struct Package {
-    pub i: Interner,
     pub base: Istr,
     pub name: Istr,
     pub version: Istr,
     pub arch: Arch,
 
-    // explicit = 0, depend = 1, unknown = 2
-    pub reason: Option<u8>,
+    /// None if the desc has no REASON, which pacman omits for explicitly installed packages.
+    pub reason: Option<InstallReason>,
     pub install_date: Option<SystemTime>,
     pub validation: Option<Validation>,
 
@@ -152,6 +288,9 @@ pub struct Package {
     pub conflicts: Option<Vec<Istr>>,
 
     pub xdata: Option<XData>,
+
+    /// version, parsed on first use by [Package::parsed_version].
+    pub(super) parsed_version: OnceLock<super::Version>,
 }
 
 #[derive(Clone)]
@@ -228,20 +367,98 @@ impl std::fmt::Debug for MissingFieldError {
     }
 }
 
+/// Packages are equal if name, version and arch are, like pacman tells packages apart.
+/// Comparing packages of different interners is meaningless.
+impl PartialEq for Package {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for Package {}
+
+impl std::hash::Hash for Package {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.key().hash(state)
+    }
+}
+
+/// Prints the interned symbol ids of name, version and arch, not the strings,
+/// because the package does not hold its interner.
+/// For the resolved output debug-print [Package::resolve] instead, e.g.
+/// ```p.resolve(&i.borrow())```, which shows every field as a string.
+impl std::fmt::Debug for Package {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Package")
+            .field("name", &self.name)
+            .field("version", &self.version)
+            .field("arch", &self.arch)
+            .finish_non_exhaustive()
+    }
+}
+
 impl Package {
+    fn key(&self) -> (Istr, Istr, Arch) {
+        (self.name, self.version, self.arch)
+    }
+
+    /// By name alphabetically, then by version like pacman, then by arch.
+    pub fn cmp_resolved(&self, other: &Self, i: &Interner) -> std::cmp::Ordering {
+        let by_name = {
+            let i = i.borrow();
+            self.name.r(&i).cmp(other.name.r(&i))
+        };
+        by_name
+            .then_with(|| self.parsed_version(i).cmp(other.parsed_version(i)))
+            .then_with(|| {
+                let i = i.borrow();
+                self.arch.r(&i).cmp(other.arch.r(&i))
+            })
+    }
+
+    /// A `-debug` package with the detached symbols of its pkgbase.
+    /// Packages built before XDATA existed are recognized by their name.
+    pub fn is_debug(&self, i: &Interner) -> bool {
+        match self.xdata {
+            Some(ref x) => matches!(x, XData::Debug),
+            None => {
+                let i = i.borrow();
+                self.name.r(&i).strip_suffix("-debug") == Some(self.base.r(&i))
+            }
+        }
+    }
+
+    /// One of several packages built from the same pkgbase.
+    /// Packages built before XDATA existed count as split if they are not named like their base.
+    pub fn is_split(&self, i: &Interner) -> bool {
+        match self.xdata {
+            Some(ref x) => matches!(x, XData::Split),
+            None => self.name != self.base && !self.is_debug(i),
+        }
+    }
+
+    /// version as a comparable [super::Version], parsed once and then cached.
+    pub fn parsed_version(&self, i: &Interner) -> &super::Version {
+        self.parsed_version.get_or_init(|| {
+            // the parser accepts any string, so this does not fail
+            versionparse(self.version.r(&i.borrow())).unwrap()
+        })
+    }
+
     pub fn from_str(i: Interner, s: &str) -> Result<Self, MissingFieldError> {
-        use std::cell::RefMut;
-        let m = parse_to_map(s).unwrap();
-        //TODO: clone can be avoided if the package construction is done in 2 steps
-        let ii = i.clone();
+        Self::from_map(i, &parse_to_map(s).unwrap())
+    }
+
+    /// Second half of [Package::from_str], for a desc already split by [parse_to_map].
+    /// Splitting does not need the interner, so it can happen on another thread.
+    pub fn from_map(i: Interner, m: &HashMap<&str, &str>) -> Result<Self, MissingFieldError> {
         let mut ir = i.borrow_mut();
         fn str_to_systemtime(s: &&str) -> SystemTime {
             let u: u64 = s.parse().unwrap();
-            UNIX_EPOCH + Duration::from_millis(u)
+            UNIX_EPOCH + Duration::from_secs(u)
         }
-        let intern =
-            |s, ir: &mut RefMut<'_, StringInterner<_>>| m.get(s).map(|s| ir.get_or_intern(s));
-        let intern_list = |s: &str, ir: &mut RefMut<'_, StringInterner<_>>| {
+        let intern = |s, ir: &mut InnerInterner| m.get(s).map(|s| ir.get_or_intern(s));
+        let intern_list = |s: &str, ir: &mut InnerInterner| {
             m.get(s).map(move |s| {
                 s.split('\n')
                     .map(|l| ir.get_or_intern(l))
@@ -263,9 +480,11 @@ impl Package {
             ))?,
             arch: m
                 .get("ARCH")
-                .map(|s| Arch::from_str(s).unwrap())
+                .map(|s| Arch::intern(s, &mut ir))
                 .ok_or(MFE::new(i.clone(), base.into(), MF::Arch))?,
-            reason: m.get("REASON").map(|s| u8::from_str(s).unwrap()),
+            reason: m
+                .get("REASON")
+                .map(|s| u8::from_str(s).map_or(InstallReason::Unknown, InstallReason::from)),
             install_date: m.get("INSTALLDATE").map(str_to_systemtime),
             packager: intern("PACKAGER", &mut ir).ok_or(MFE::new(
                 i.clone(),
@@ -300,7 +519,7 @@ impl Package {
                 .get("MD5SUM")
                 .map(|s| B64.decode(s).unwrap().try_into().unwrap()),
             sha256sum: m
-                .get("SHA265SUM")
+                .get("SHA256SUM")
                 .map(|s| B64.decode(s).unwrap().try_into().unwrap()),
             pgpsig: intern("PGPSIG", &mut ir),
 
@@ -314,11 +533,11 @@ impl Package {
             replaces: intern_list("REPLACES", &mut ir).map(|l| l.into_iter().collect()),
             conflicts: intern_list("CONFLICTS", &mut ir),
             xdata: m.get("XDATA").map(|s| XData::from_str(s).unwrap()),
-            i: ii,
+            parsed_version: OnceLock::new(),
         };
         #[cfg(debug_assertions)]
         {
-            let mut m = m;
+            let mut m = m.clone();
             for token in [
                 "BASE",
                 "NAME",
@@ -355,6 +574,197 @@ impl Package {
         }
         Ok(s)
     }
+
+    /// Inverse of [Package::from_str], in the field order pacman writes.
+    /// The installed size is written as SIZE if the package has an install date
+    /// like in the local db, as ISIZE otherwise.
+    /// Empty lists are left out, the parser can not tell them apart from a missing field.
+    pub fn to_desc_string(&self, i: &Interner) -> String {
+        use std::fmt::Write;
+        let i = i.borrow();
+        let mut s = String::new();
+        let mut field = |name: &str, value: &dyn std::fmt::Display| {
+            write!(s, "%{name}%\n{value}\n\n").unwrap();
+        };
+        let secs = |t: SystemTime| t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let join = |l: &[Istr]| l.iter().map(|e| e.r(&i)).collect::<Vec<_>>().join("\n");
+
+        if let Some(filename) = self.filename {
+            field("FILENAME", &filename.r(&i));
+        }
+        field("NAME", &self.name.r(&i));
+        field("BASE", &self.base.r(&i));
+        field("VERSION", &self.version.r(&i));
+        field("DESC", &self.desc.r(&i));
+        if let Some(url) = self.url {
+            field("URL", &url.r(&i));
+        }
+        field("ARCH", &self.arch.r(&i));
+        field("BUILDDATE", &secs(self.build_date));
+        if let Some(install_date) = self.install_date {
+            field("INSTALLDATE", &secs(install_date));
+        }
+        field("PACKAGER", &self.packager.r(&i));
+        if let Some(csize) = self.csize {
+            field("CSIZE", &csize);
+        }
+        if let Some(isize) = self.isize {
+            let name = if self.install_date.is_some() {
+                "SIZE"
+            } else {
+                "ISIZE"
+            };
+            field(name, &isize);
+        }
+        if let Some(reason) = self.reason {
+            field("REASON", &(reason as u8));
+        }
+        if let Some(validation) = &self.validation {
+            field("VALIDATION", &validation.as_str());
+        }
+        if let Some(md5sum) = self.md5sum {
+            field("MD5SUM", &B64.encode(md5sum));
+        }
+        if let Some(sha256sum) = self.sha256sum {
+            field("SHA256SUM", &B64.encode(sha256sum));
+        }
+        if let Some(pgpsig) = self.pgpsig {
+            field("PGPSIG", &pgpsig.r(&i));
+        }
+        let mut replaces: Vec<_> = self.replaces.iter().flatten().copied().collect();
+        replaces.sort_unstable_by_key(|r| r.r(&i));
+        for (name, list) in [
+            ("LICENSE", Some(&self.license)),
+            ("GROUPS", self.groups.as_ref()),
+            ("REPLACES", Some(&replaces)),
+            ("CONFLICTS", self.conflicts.as_ref()),
+            ("PROVIDES", self.provides.as_ref()),
+            ("DEPENDS", self.depends.as_ref()),
+            ("OPTDEPENDS", self.optdepends.as_ref()),
+            ("MAKEDEPENDS", self.makedepends.as_ref()),
+            ("CHECKDEPENDS", self.checkdepends.as_ref()),
+        ] {
+            if let Some(list) = list.filter(|l| !l.is_empty()) {
+                field(name, &join(list));
+            }
+        }
+        if let Some(xdata) = &self.xdata {
+            field("XDATA", &xdata.as_str());
+        }
+        s
+    }
+}
+
+#[test]
+fn test_to_desc_string() {
+    let desc = super::test_desc(
+        "foo",
+        "1:1.0-1",
+        &[
+            ("FILENAME", "foo-1:1.0-1-x86_64.pkg.tar.zst"),
+            ("URL", "https://example.org"),
+            ("CSIZE", "42"),
+            ("ISIZE", "1024"),
+            ("MD5SUM", "d41d8cd98f00b204e9800998ecf8427e"),
+            (
+                "SHA256SUM",
+                "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            ),
+            ("VALIDATION", "sha256"),
+            ("REPLACES", "bar\nbaz"),
+            ("DEPENDS", "glibc\nsh"),
+            ("OPTDEPENDS", "qux: for qux"),
+            ("XDATA", "pkgtype=pkg"),
+        ],
+    );
+    let i = new_interner();
+    let p = Package::from_str(i.clone(), &desc).unwrap();
+    let written = p.to_desc_string(&i);
+    assert_eq!(
+        parse_to_map(&written).unwrap(),
+        parse_to_map(&desc).unwrap()
+    );
+    assert_eq!(
+        p.sha256sum.map(|s| B64.encode(s)).as_deref(),
+        Some("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855")
+    );
+
+    let local = super::test_desc(
+        "bar",
+        "1-1",
+        &[("INSTALLDATE", "1700000001"), ("SIZE", "7")],
+    );
+    let p = Package::from_str(i.clone(), &local).unwrap();
+    assert_eq!(
+        parse_to_map(&p.to_desc_string(&i)).unwrap(),
+        parse_to_map(&local).unwrap()
+    );
+}
+
+#[test]
+fn test_parsed_version() {
+    let i = new_interner();
+    let p = Package::from_str(i.clone(), &super::test_desc("foo", "1:2.0-3", &[])).unwrap();
+    let v = p.parsed_version(&i);
+    assert_eq!((v.epoch(), v.pkgver(), v.pkgrel()), (1, "2.0", Some("3")));
+    assert!(std::ptr::eq(v, p.parsed_version(&i)));
+}
+
+#[test]
+// the cached parsed_version is interior mutability clippy can not see past,
+// Hash and Eq do not look at it
+#[allow(clippy::mutable_key_type)]
+fn test_package_cmp() {
+    use std::collections::HashSet;
+    let i = new_interner();
+    let pkg = |name: &str, version: &str| {
+        Package::from_str(i.clone(), &super::test_desc(name, version, &[])).unwrap()
+    };
+    let (zsh, zsh2, bash, bash10) = (
+        pkg("zsh", "5.9-1"),
+        pkg("zsh", "5.9-2"),
+        pkg("bash", "5.2-1"),
+        pkg("bash", "5.10-1"),
+    );
+    assert_eq!(zsh, pkg("zsh", "5.9-1"));
+    assert_ne!(zsh, zsh2);
+    let set: HashSet<_> = [zsh.clone(), pkg("zsh", "5.9-1"), bash.clone()].into();
+    assert_eq!(set.len(), 2);
+    let mut sorted = vec![zsh2.clone(), bash10.clone(), zsh.clone(), bash.clone()];
+    sorted.sort_by(|a, b| a.cmp_resolved(b, &i));
+    assert_eq!(sorted, [bash, bash10, zsh, zsh2]);
+    assert!(format!("{sorted:?}").starts_with("[Package { name: "));
+
+    let ii = i.borrow();
+    let mut resolved: Vec<_> = sorted.iter().rev().map(|p| p.resolve(&ii)).collect();
+    resolved.sort();
+    assert_eq!(
+        resolved,
+        sorted.iter().map(|p| p.resolve(&ii)).collect::<Vec<_>>()
+    );
+}
+
+#[cfg(feature = "sync")]
+#[test]
+fn test_sync_interner() {
+    let i = new_interner();
+    let p = Package::from_str(i.clone(), &super::test_desc("foo", "1-1", &[])).unwrap();
+    std::thread::scope(|s| {
+        let (p, i) = (&p, &i);
+        let threads: Vec<_> = (0..4)
+            .map(|n| {
+                s.spawn(move || {
+                    let bar = i.borrow_mut().get_or_intern(format!("bar{n}"));
+                    assert_eq!(p.name.r(&i.borrow()), "foo");
+                    assert_eq!(p.parsed_version(i).pkgver(), "1");
+                    bar
+                })
+            })
+            .collect();
+        for (n, t) in threads.into_iter().enumerate() {
+            assert_eq!(t.join().unwrap().r(&i.borrow()), format!("bar{n}"));
+        }
+    });
 }
 
 fn entry(i: &str) -> IResult<&str, (&str, &str)> {
@@ -373,14 +783,100 @@ pub fn parse_to_map(i: &str) -> Result<HashMap<&str, &str>, nom::Err<Error<&str>
     Ok(h)
 }
 
-type Version<'v> = (Option<u64>, VersionSegment<'v>, Option<VersionSegment<'v>>);
+/// Contents of a `files` entry, from a .files sync db or a local db.
+/// Paths are relative to root, directories end in /.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FileList {
+    pub files: Vec<String>,
+    /// Only in the local db, files listed in the package's backup array.
+    pub backup: Vec<Backup>,
+}
+
+/// A config file as installed, to detect whether it was modified since.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Backup {
+    /// Relative to root, like [FileList::files].
+    pub path: String,
+    /// Hex md5 of the file when it was installed.
+    pub md5sum: String,
+}
+
+impl FileList {
+    /// Unlike desc, sync db files entries do not end in a blank line,
+    /// so this is parsed line by line instead of through [parse_to_map].
+    pub fn parse(s: &str) -> Self {
+        let mut ret = Self::default();
+        let mut section = "";
+        for line in s.lines() {
+            if let Some(name) = line.strip_prefix('%').and_then(|l| l.strip_suffix('%')) {
+                section = name;
+            } else if line.is_empty() {
+                section = "";
+            } else if section == "FILES" {
+                ret.files.push(line.to_owned());
+            } else if section == "BACKUP"
+                && let Some((path, md5sum)) = line.split_once('\t')
+            {
+                ret.backup.push(Backup {
+                    path: path.to_owned(),
+                    md5sum: md5sum.to_owned(),
+                });
+            }
+        }
+        ret
+    }
+
+    /// The files entry of the local db, which ends in a blank line unlike in sync dbs.
+    pub fn to_files_string(&self) -> String {
+        let mut s = String::new();
+        if !self.files.is_empty() {
+            s.push_str("%FILES%\n");
+            for f in &self.files {
+                s.push_str(f);
+                s.push('\n');
+            }
+            s.push('\n');
+        }
+        if !self.backup.is_empty() {
+            s.push_str("%BACKUP%\n");
+            for b in &self.backup {
+                s.push_str(&format!("{}\t{}\n", b.path, b.md5sum));
+            }
+            s.push('\n');
+        }
+        s
+    }
+}
+
+#[test]
+fn test_file_list() {
+    let l = FileList::parse("%FILES%\nusr/\nusr/bin/\nusr/bin/foo\n");
+    assert_eq!(l.files, ["usr/", "usr/bin/", "usr/bin/foo"]);
+    let l = FileList::parse("%FILES%\nusr/\n\n%OTHER%\nx\n\n");
+    assert_eq!(l.files, ["usr/"]);
+    let l = FileList::parse(
+        "%FILES%\netc/\netc/foo.conf\n\n%BACKUP%\netc/foo.conf\td41d8cd98f00b204e9800998ecf8427e\n\n",
+    );
+    assert_eq!(l.files, ["etc/", "etc/foo.conf"]);
+    assert_eq!(
+        l.backup,
+        [Backup {
+            path: "etc/foo.conf".to_owned(),
+            md5sum: "d41d8cd98f00b204e9800998ecf8427e".to_owned(),
+        }]
+    );
+    assert_eq!(FileList::parse(&l.to_files_string()), l);
+    assert_eq!(FileList::default().to_files_string(), "");
+}
+
+type RawVersion<'v> = (Option<u64>, VersionSegment<'v>, Option<VersionSegment<'v>>);
 
 type VersionSegment<'v> = Vec<VersionElement<'v>>;
 type VersionElement<'v> = Result<&'v str, u64>;
 
 //TODO: do not allocate, this is pretty wasteful overall!
 #[inline(always)]
-fn versionparse_(i: &str) -> IResult<&str, Version<'_>, ()> {
+pub(super) fn versionparse_(i: &str) -> IResult<&str, RawVersion<'_>, ()> {
     let epoch = (take_while(|c: char| c.is_numeric()), char(':'))
         .map(|i| i.0)
         .map_res(u64::from_str);
@@ -399,8 +895,8 @@ fn versionparse_(i: &str) -> IResult<&str, Version<'_>, ()> {
     Ok((r_rem.unwrap_or(v_rem), (epoch, version, release)))
 }
 
-pub fn versionparse(i: &str) -> Result<Version<'_>, ()> {
-    versionparse_(i).finish().map(|r| r.1)
+pub fn versionparse(i: &str) -> Result<super::Version, super::InvalidVersion> {
+    i.parse()
 }
 
 #[inline(always)]
@@ -422,7 +918,7 @@ fn version_segment_parse(i: &str) -> IResult<&str, VersionSegment<'_>, ()> {
 #[test]
 fn test_version() {
     let v1 = "2025.Q1.2-1";
-    let (epoch, version, release) = versionparse(v1).unwrap();
+    let (_, (epoch, version, release)) = versionparse_(v1).unwrap();
     println!("{epoch:?} {version:?} {release:?}");
     assert!(epoch.is_none());
     assert_eq!(version.len(), 4);
@@ -436,8 +932,12 @@ fn test_versions() {
     let local = super::parse_localdb(i.clone()).unwrap();
     let local = ("local", local);
 
-    let syncs = ["core", "extra", "multilib"]
-        .map(|name| (name, super::parse_syncdb(i.clone(), name).unwrap()));
+    let syncs = ["core", "extra", "multilib"].map(|name| {
+        (
+            name,
+            super::parse_syncdb(i.clone(), name, &crate::events::NoEvents).unwrap(),
+        )
+    });
 
     let i = i.borrow();
 
@@ -0,0 +1,183 @@
+//! Package files like `foo-1.2.3-1-x86_64.pkg.tar.zst`, as built by makepkg.
+use super::repo::{PkgContents, pkginfo_to_desc_map, read_package};
+use super::{FileList, Interner, Package, Version};
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+/// How a package file is compressed, by its extension after `.pkg.tar`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Compression {
+    /// Plain `.pkg.tar`.
+    None,
+    Gzip,
+    Bzip2,
+    Xz,
+    Zstd,
+    Lzip,
+    Lz4,
+    Lrzip,
+    Lzop,
+    /// `.Z`
+    Compress,
+}
+
+impl Compression {
+    const ALL: [Self; 10] = [
+        Self::None,
+        Self::Gzip,
+        Self::Bzip2,
+        Self::Xz,
+        Self::Zstd,
+        Self::Lzip,
+        Self::Lz4,
+        Self::Lrzip,
+        Self::Lzop,
+        Self::Compress,
+    ];
+
+    /// The extension after `.pkg.tar`, including the dot, empty for [Compression::None].
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::None => "",
+            Self::Gzip => ".gz",
+            Self::Bzip2 => ".bz2",
+            Self::Xz => ".xz",
+            Self::Zstd => ".zst",
+            Self::Lzip => ".lz",
+            Self::Lz4 => ".lz4",
+            Self::Lrzip => ".lrz",
+            Self::Lzop => ".lzo",
+            Self::Compress => ".Z",
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = ();
+
+    /// From an extension like [Compression::extension] returns.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL.into_iter().find(|c| c.extension() == s).ok_or(())
+    }
+}
+
+/// Splits a package file name like `foo-bar-1:1.2.3-1-x86_64.pkg.tar.zst`
+/// into name, version, arch and compression.
+/// Names may contain dashes, versions and arches can not.
+/// None if it is not named like a package, e.g. a `.sig`.
+pub fn parse_pkg_filename(filename: &str) -> Option<(&str, Version, &str, Compression)> {
+    let (rest, ext) = filename.rsplit_once(".pkg.tar")?;
+    let compression = ext.parse().ok()?;
+    let (rest, arch) = rest.rsplit_once('-')?;
+    let (rest, pkgrel) = rest.rsplit_once('-')?;
+    let (name, pkgver) = rest.rsplit_once('-')?;
+    if name.is_empty() || pkgver.is_empty() || pkgrel.is_empty() || arch.is_empty() {
+        return None;
+    }
+    let version = format!("{pkgver}-{pkgrel}").parse().ok()?;
+    Some((name, version, arch, compression))
+}
+
+/// A package file as read by [Package::from_pkg_file].
+pub struct PkgFile {
+    pub package: Package,
+    /// Backup entries carry the md5 of the packaged file, which pacman records on install.
+    pub files: FileList,
+    /// Whether it has an `.MTREE`, see [super::mtree::from_package].
+    pub has_mtree: bool,
+    /// Whether it has an `.INSTALL` scriptlet.
+    pub has_install: bool,
+}
+
+impl Package {
+    /// Reads the `.PKGINFO` and file list of a package file, compressed with zstd, gzip, xz or bzip2.
+    /// Fields only repos know, like FILENAME, CSIZE and the checksums, stay unset.
+    pub fn from_pkg_file(i: Interner, path: &Path) -> io::Result<PkgFile> {
+        let PkgContents {
+            pkginfo,
+            files,
+            backup,
+            has_mtree,
+            has_install,
+        } = read_package(path)?;
+        let m = pkginfo_to_desc_map(&pkginfo);
+        let m: HashMap<&str, &str> = m.iter().map(|(k, v)| (*k, v.as_str())).collect();
+        let package = Package::from_map(i, &m).map_err(super::invalid_package)?;
+        Ok(PkgFile {
+            package,
+            files: FileList { files, backup },
+            has_mtree,
+            has_install,
+        })
+    }
+}
+
+#[test]
+fn test_from_pkg_file() {
+    use super::QuickResolve;
+    use super::repo::{test_pkginfo, write_test_package};
+    let dir = crate::util::test_dir("from_pkg_file");
+    let path = dir.join("foo-1-1-x86_64.pkg.tar.zst");
+    let pkginfo = test_pkginfo("foo", "1-1") + "backup = etc/foo.conf\n";
+    write_test_package(
+        &path,
+        &pkginfo,
+        &["etc/", "etc/foo.conf", "usr/", "usr/bin/foo"],
+    );
+    let i = super::new_interner();
+    let f = Package::from_pkg_file(i.clone(), &path).unwrap();
+    {
+        let ii = i.borrow();
+        assert_eq!(f.package.name.r(&ii), "foo");
+        assert_eq!(f.package.base.r(&ii), "foo");
+        assert_eq!(f.package.version.r(&ii), "1-1");
+    }
+    assert_eq!(f.package.isize, Some(1024));
+    assert_eq!(f.package.parsed_depends(&i).len(), 2);
+    assert!(f.package.filename.is_none());
+    assert_eq!(
+        f.files.files,
+        ["etc/", "etc/foo.conf", "usr/", "usr/bin/foo"]
+    );
+    assert_eq!(f.files.backup.len(), 1);
+    assert_eq!(f.files.backup[0].path, "etc/foo.conf");
+    assert_eq!(f.files.backup[0].md5sum, "98bf7d8c15784f0a3d63204441e1e2aa");
+    assert!(f.has_mtree);
+    assert!(!f.has_install);
+    assert!(Package::from_pkg_file(i, &dir.join("missing.pkg.tar.zst")).is_err());
+}
+
+#[test]
+fn test_parse_pkg_filename() {
+    let (name, version, arch, compression) =
+        parse_pkg_filename("foo-1.2.3-1-x86_64.pkg.tar.zst").unwrap();
+    assert_eq!(name, "foo");
+    assert_eq!(version.as_str(), "1.2.3-1");
+    assert_eq!(arch, "x86_64");
+    assert_eq!(compression, Compression::Zstd);
+    let (name, version, arch, compression) =
+        parse_pkg_filename("python-foo-bar-2:0.1.r5.gabc-2.1-any.pkg.tar").unwrap();
+    assert_eq!(name, "python-foo-bar");
+    assert_eq!(version.epoch(), 2);
+    assert_eq!(version.pkgrel(), Some("2.1"));
+    assert_eq!(arch, "any");
+    assert_eq!(compression, Compression::None);
+    assert_eq!(
+        parse_pkg_filename("lib32-glibc-2.39-1-x86_64.pkg.tar.xz")
+            .unwrap()
+            .0,
+        "lib32-glibc"
+    );
+    assert!(parse_pkg_filename("foo-1.2.3-1-x86_64.pkg.tar.zst.sig").is_none());
+    // custom ports have their own arches
+    assert_eq!(
+        parse_pkg_filename("foo-1.2.3-1-sparc.pkg.tar.zst")
+            .unwrap()
+            .2,
+        "sparc"
+    );
+    assert!(parse_pkg_filename("1.2.3-1-x86_64.pkg.tar.zst").is_none());
+    assert!(parse_pkg_filename("foo.db.tar.gz").is_none());
+}
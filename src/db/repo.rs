@@ -0,0 +1,531 @@
+//! Building and updating sync databases from package files, like repo-add and repo-remove.
+use super::{Backup, Interner, Package, new_interner, parse};
+use crate::util::{replace, tmp_path};
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use md5::{Digest, Md5};
+use sha2::Sha256;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A sync database being edited, together with its `.files` companion.
+/// Changes are only written by [RepoDb::write].
+pub struct RepoDb {
+    /// Like `<dir>/<repo>.db.tar.gz`.
+    path: PathBuf,
+    /// package name -> entry
+    entries: BTreeMap<String, Entry>,
+    i: Interner,
+}
+
+struct Entry {
+    /// `<name>-<version>`, the directory in the archive.
+    dir: String,
+    desc: String,
+    /// The files entry of the `.files` db.
+    files: String,
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// `<repo>.db<ext>` -> `<repo>.files<ext>`
+fn files_path(path: &Path) -> io::Result<PathBuf> {
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+    let Some((repo, ext)) = name.split_once(".db") else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not named like <repo>.db.tar.*", path.display()),
+        ));
+    };
+    Ok(path.with_file_name(format!("{repo}.files{ext}")))
+}
+
+/// .PKGINFO key -> desc field
+const PKGINFO_FIELDS: [(&str, &str); 18] = [
+    ("pkgname", "NAME"),
+    ("pkgbase", "BASE"),
+    ("pkgver", "VERSION"),
+    ("pkgdesc", "DESC"),
+    ("url", "URL"),
+    ("builddate", "BUILDDATE"),
+    ("packager", "PACKAGER"),
+    ("size", "ISIZE"),
+    ("arch", "ARCH"),
+    ("license", "LICENSE"),
+    ("replaces", "REPLACES"),
+    ("group", "GROUPS"),
+    ("conflict", "CONFLICTS"),
+    ("provides", "PROVIDES"),
+    ("depend", "DEPENDS"),
+    ("optdepend", "OPTDEPENDS"),
+    ("makedepend", "MAKEDEPENDS"),
+    ("checkdepend", "CHECKDEPENDS"),
+];
+
+/// .PKGINFO is `key = value` lines, repeated keys make up lists.
+/// BASE defaults to NAME, like in packages built before pkgbase was recorded.
+/// returns desc field -> values joined by newlines
+pub(super) fn pkginfo_to_desc_map(pkginfo: &str) -> HashMap<&'static str, String> {
+    let mut m: HashMap<&str, String> = HashMap::new();
+    for (key, value) in pkginfo_pairs(pkginfo) {
+        let Some((_, field)) = PKGINFO_FIELDS.iter().find(|(k, _)| *k == key) else {
+            continue;
+        };
+        let entry = m.entry(field).or_default();
+        if !entry.is_empty() {
+            entry.push('\n');
+        }
+        entry.push_str(value);
+    }
+    if !m.contains_key("BASE")
+        && let Some(name) = m.get("NAME").cloned()
+    {
+        m.insert("BASE", name);
+    }
+    m
+}
+
+fn pkginfo_pairs(pkginfo: &str) -> impl Iterator<Item = (&str, &str)> {
+    pkginfo
+        .lines()
+        .filter(|l| !l.starts_with('#'))
+        .filter_map(|l| l.split_once(" = "))
+}
+
+/// The paths of the backup array, relative to root.
+pub(crate) fn pkginfo_backup(pkginfo: &str) -> Vec<String> {
+    pkginfo_pairs(pkginfo)
+        .filter(|(k, _)| *k == "backup")
+        .map(|(_, v)| v.to_owned())
+        .collect()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// md5 and sha256 of the file, hex encoded.
+fn checksums(path: &Path) -> io::Result<(String, String)> {
+    let mut f = File::open(path)?;
+    let mut md5 = Md5::new();
+    let mut sha256 = Sha256::new();
+    let mut buf = vec![0; 1 << 16];
+    loop {
+        let n = f.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        md5.update(&buf[..n]);
+        sha256.update(&buf[..n]);
+    }
+    Ok((hex(&md5.finalize()), hex(&sha256.finalize())))
+}
+
+/// What [read_package] found in a package file.
+pub(super) struct PkgContents {
+    pub pkginfo: String,
+    /// The installed paths, directories ending in /.
+    pub files: Vec<String>,
+    /// The files .PKGINFO lists as backup, with the md5 of their packaged contents.
+    pub backup: Vec<Backup>,
+    pub has_mtree: bool,
+    pub has_install: bool,
+}
+
+/// Reads the whole package file.
+/// Backup md5s are only known for files after the .PKGINFO, makepkg always puts it first.
+pub(super) fn read_package(path: &Path) -> io::Result<PkgContents> {
+    let f = super::decompress(BufReader::new(File::open(path)?))?;
+    let mut pkginfo = None;
+    let mut backup_paths = Vec::new();
+    let mut c = PkgContents {
+        pkginfo: String::new(),
+        files: Vec::new(),
+        backup: Vec::new(),
+        has_mtree: false,
+        has_install: false,
+    };
+    for entry in tar::Archive::new(f).entries()? {
+        let mut entry = entry?;
+        let mut p = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+        match p.as_str() {
+            ".PKGINFO" => {
+                let mut s = String::new();
+                entry.read_to_string(&mut s)?;
+                backup_paths = pkginfo_backup(&s);
+                pkginfo = Some(s);
+            }
+            ".MTREE" => c.has_mtree = true,
+            ".INSTALL" => c.has_install = true,
+            _ if p.starts_with('.') => (),
+            _ => {
+                if entry.header().entry_type().is_dir() && !p.ends_with('/') {
+                    p.push('/');
+                }
+                if backup_paths.contains(&p) {
+                    let mut contents = Vec::new();
+                    entry.read_to_end(&mut contents)?;
+                    c.backup.push(Backup {
+                        path: p.clone(),
+                        md5sum: hex(&Md5::digest(&contents)),
+                    });
+                }
+                c.files.push(p);
+            }
+        }
+    }
+    c.pkginfo = pkginfo.ok_or_else(|| invalid(format!("{} has no .PKGINFO", path.display())))?;
+    c.files.sort_unstable();
+    Ok(c)
+}
+
+impl RepoDb {
+    /// An empty database that will be written to path, like `<dir>/<repo>.db.tar.gz`.
+    /// The compression is picked from the extension.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            entries: BTreeMap::new(),
+            i: new_interner(),
+        }
+    }
+
+    /// Reads an existing database, and its `.files` companion if there is one.
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let mut db = Self::new(path);
+        let mut files = HashMap::new();
+        let files_path = files_path(&db.path)?;
+        if files_path.exists() {
+            super::read_sync_archive(&files_path, |path, s| {
+                if let Some(dir) = path.strip_suffix("/files") {
+                    files.insert(dir.to_owned(), s.to_owned());
+                }
+            })?;
+        }
+        let mut err = None;
+        super::read_sync_archive(&db.path, |path, s| {
+            let Some(dir) = path.strip_suffix("/desc") else {
+                return;
+            };
+            let name = match parse::parse_to_map(s) {
+                Ok(m) if m.contains_key("NAME") => m["NAME"].to_owned(),
+                _ => {
+                    err.get_or_insert_with(|| invalid(format!("{path} has no name")));
+                    return;
+                }
+            };
+            let entry = Entry {
+                dir: dir.to_owned(),
+                desc: s.to_owned(),
+                files: files.remove(dir).unwrap_or_default(),
+            };
+            db.entries.insert(name, entry);
+        })?;
+        match err {
+            Some(e) => Err(e),
+            None => Ok(db),
+        }
+    }
+
+    /// Names of the packages in the database, sorted.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.entries.keys().map(String::as_str)
+    }
+
+    /// Drops the package from the database.
+    /// returns whether it was in the database
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.entries.remove(name).is_some()
+    }
+
+    /// Adds the package file, replacing any entry of the same name.
+    /// The file is expected to stay next to the database, only its name is recorded.
+    /// A detached `<pkgfile>.sig` is embedded if present.
+    /// returns the name of the package
+    pub fn add(&mut self, pkgfile: &Path) -> io::Result<String> {
+        let PkgContents { pkginfo, files, .. } = read_package(pkgfile)?;
+        let (md5sum, sha256sum) = checksums(pkgfile)?;
+        let csize = std::fs::metadata(pkgfile)?.len().to_string();
+        let filename = pkgfile
+            .file_name()
+            .and_then(|n| n.to_str())
+            .ok_or_else(|| invalid(format!("bad file name {}", pkgfile.display())))?;
+        let mut sigfile = pkgfile.as_os_str().to_owned();
+        sigfile.push(".sig");
+        let sig = match std::fs::read(sigfile) {
+            Ok(sig) => Some(BASE64_STANDARD.encode(sig)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+
+        let m = pkginfo_to_desc_map(&pkginfo);
+        let mut m: HashMap<&str, &str> = m.iter().map(|(k, v)| (*k, v.as_str())).collect();
+        m.insert("FILENAME", filename);
+        m.insert("CSIZE", &csize);
+        m.insert("MD5SUM", &md5sum);
+        m.insert("SHA256SUM", &sha256sum);
+        if let Some(sig) = &sig {
+            m.insert("PGPSIG", sig);
+        }
+        let p = Package::from_map(self.i.clone(), &m).map_err(super::invalid_package)?;
+
+        let mut list = String::from("%FILES%\n");
+        for f in files {
+            list.push_str(&f);
+            list.push('\n');
+        }
+        let name = m["NAME"].to_owned();
+        let entry = Entry {
+            dir: format!("{name}-{}", m["VERSION"]),
+            desc: p.to_desc_string(&self.i),
+            files: list,
+        };
+        self.entries.insert(name.clone(), entry);
+        Ok(name)
+    }
+
+    /// Writes the database and the `.files` database next to it.
+    /// Each file is replaced atomically, so pacman never downloads a half written db.
+    /// Like repo-add, `<repo>.db` and `<repo>.files` are symlinked to them,
+    /// pacman downloads those names.
+    pub fn write(&self) -> io::Result<()> {
+        let files_path = files_path(&self.path)?;
+        let db = self
+            .entries
+            .values()
+            .map(|e| (&e.dir, vec![("desc", &e.desc)]));
+        write_archive(&self.path, db)?;
+        let files = self
+            .entries
+            .values()
+            .map(|e| (&e.dir, vec![("desc", &e.desc), ("files", &e.files)]));
+        write_archive(&files_path, files)?;
+        for path in [&self.path, &files_path] {
+            link_short_name(path)?;
+        }
+        Ok(())
+    }
+}
+
+/// `<repo>.db.tar.gz` -> symlink `<repo>.db`, if the names differ.
+fn link_short_name(path: &Path) -> io::Result<()> {
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+    let Some(short) = name.find(".tar").map(|end| &name[..end]) else {
+        return Ok(());
+    };
+    let link = path.with_file_name(short);
+    match std::fs::symlink_metadata(&link) {
+        Ok(m) if m.file_type().is_symlink() => (),
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a symlink", link.display()),
+            ));
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e),
+    }
+    let tmp = tmp_path(&link);
+    let _ = std::fs::remove_file(&tmp);
+    std::os::unix::fs::symlink(name, &tmp)?;
+    std::fs::rename(tmp, link)
+}
+
+/// Writes a tar of `dir/name` entries, compressed according to the extension of path.
+fn write_archive<'e>(
+    path: &Path,
+    entries: impl Iterator<Item = (&'e String, Vec<(&'e str, &'e String)>)>,
+) -> io::Result<()> {
+    let mtime = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let mut tar = tar::Builder::new(Vec::new());
+    for (dir, files) in entries {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_size(0);
+        header.set_mode(0o755);
+        header.set_mtime(mtime);
+        tar.append_data(&mut header, format!("{dir}/"), io::empty())?;
+        for (name, contents) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_mtime(mtime);
+            tar.append_data(&mut header, format!("{dir}/{name}"), contents.as_bytes())?;
+        }
+    }
+    let tar = tar.into_inner()?;
+
+    replace(path, |f| {
+        let f = match path.extension().and_then(|e| e.to_str()) {
+            Some("gz") => {
+                let mut e = flate2::write::GzEncoder::new(f, flate2::Compression::default());
+                e.write_all(&tar)?;
+                e.finish()?
+            }
+            Some("xz") => {
+                let mut e = xz2::write::XzEncoder::new(f, 6);
+                e.write_all(&tar)?;
+                e.finish()?
+            }
+            Some("bz2") => {
+                let mut e = bzip2::write::BzEncoder::new(f, bzip2::Compression::default());
+                e.write_all(&tar)?;
+                e.finish()?
+            }
+            Some("zst") => {
+                let mut e = zstd::Encoder::new(f, 0)?;
+                e.write_all(&tar)?;
+                e.finish()?
+            }
+            _ => {
+                (&f).write_all(&tar)?;
+                f
+            }
+        };
+        f.sync_all()
+    })
+}
+
+/// Writes a zstd package with a .PKGINFO and the given files.
+#[cfg(test)]
+pub(crate) fn write_test_package(path: &Path, pkginfo: &str, files: &[&str]) {
+    let f = File::create(path).unwrap();
+    let mut tar = tar::Builder::new(zstd::Encoder::new(f, 0).unwrap());
+    let mut append = |path: &str, contents: &[u8]| {
+        let mut header = tar::Header::new_gnu();
+        header.set_mode(0o644);
+        if path.ends_with('/') {
+            header.set_entry_type(tar::EntryType::Directory);
+            header.set_mode(0o755);
+        }
+        header.set_size(contents.len() as u64);
+        tar.append_data(&mut header, path, contents).unwrap();
+    };
+    let mut mtree = format!(
+        "#mtree\n/set type=file uid=0 gid=0 mode=644\n./.PKGINFO size={}\n",
+        pkginfo.len()
+    );
+    for file in files {
+        match file.strip_suffix('/') {
+            Some(dir) => mtree.push_str(&format!("./{dir} mode=755 type=dir\n")),
+            None => mtree.push_str(&format!("./{file} size=8\n")),
+        }
+    }
+    let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+    gz.write_all(mtree.as_bytes()).unwrap();
+    append(".PKGINFO", pkginfo.as_bytes());
+    append(".MTREE", &gz.finish().unwrap());
+    for file in files {
+        append(file, b"contents");
+    }
+    tar.into_inner().unwrap().finish().unwrap();
+}
+
+#[cfg(test)]
+pub(crate) fn test_pkginfo(name: &str, version: &str) -> String {
+    format!(
+        "# Generated by makepkg\npkgname = {name}\npkgver = {version}\npkgdesc = test package {name}\n\
+        url = https://example.org\nbuilddate = 1700000000\npackager = tester\nsize = 1024\n\
+        arch = x86_64\nlicense = MIT\ndepend = glibc\ndepend = sh\n"
+    )
+}
+
+#[test]
+fn test_repo_add() {
+    use super::QuickResolve;
+    let dir = crate::util::test_dir("repo_add");
+    let pkg = |name: &str, version: &str, files: &[&str]| {
+        let path = dir.join(format!("{name}-{version}-x86_64.pkg.tar.zst"));
+        write_test_package(&path, &test_pkginfo(name, version), files);
+        path
+    };
+    let foo1 = pkg("foo", "1-1", &["usr/", "usr/bin/", "usr/bin/foo"]);
+    std::fs::write(format!("{}.sig", foo1.display()), b"signature").unwrap();
+    let foo2 = pkg("foo", "2-1", &["usr/", "usr/bin/", "usr/bin/foo2"]);
+    let bar = pkg("bar", "1-1", &[]);
+
+    let dbfile = dir.join("test.db.tar.gz");
+    let mut db = RepoDb::new(&dbfile);
+    assert_eq!(db.add(&foo1).unwrap(), "foo");
+    db.write().unwrap();
+
+    let i = new_interner();
+    let packages =
+        super::parse_syncdb_at(i.clone(), &dir, "test", &crate::events::NoEvents).unwrap();
+    assert_eq!(packages.len(), 1);
+    let foo = packages.values().next().unwrap();
+    let ii = i.borrow();
+    assert_eq!(foo.base.r(&ii), "foo");
+    assert_eq!(foo.version.r(&ii), "1-1");
+    assert_eq!(foo.filename.unwrap().r(&ii), "foo-1-1-x86_64.pkg.tar.zst");
+    assert_eq!(foo.csize, Some(std::fs::metadata(&foo1).unwrap().len()));
+    assert_eq!(foo.isize, Some(1024));
+    assert_eq!(
+        foo.pgpsig.unwrap().r(&ii),
+        BASE64_STANDARD.encode("signature")
+    );
+    let depends: Vec<_> = foo.depends.iter().flatten().map(|d| d.r(&ii)).collect();
+    assert_eq!(depends, ["glibc", "sh"]);
+    let (md5sum, _) = checksums(&foo1).unwrap();
+    assert!(foo.to_desc_string(&i).contains(&md5sum));
+    drop(ii);
+
+    let mut db = RepoDb::open(&dbfile).unwrap();
+    db.add(&foo2).unwrap();
+    db.add(&bar).unwrap();
+    db.write().unwrap();
+    let db = RepoDb::open(&dbfile).unwrap();
+    assert_eq!(db.names().collect::<Vec<_>>(), ["bar", "foo"]);
+    assert_eq!(db.entries["foo"].dir, "foo-2-1");
+
+    let files = super::parse_files_db_at(i.clone(), &dir, "test").unwrap();
+    let foo = i.borrow().get("foo").unwrap();
+    assert_eq!(files[&foo].files, ["usr/", "usr/bin/", "usr/bin/foo2"]);
+    assert!(RepoDb::new(dir.join("test.tar.gz")).write().is_err());
+}
+
+#[test]
+fn test_repo_remove() {
+    let dir = crate::util::test_dir("repo_remove");
+    let dbfile = dir.join("test.db.tar.zst");
+    let mut db = RepoDb::new(&dbfile);
+    for name in ["foo", "bar"] {
+        let path = dir.join(format!("{name}-1-1-x86_64.pkg.tar.zst"));
+        write_test_package(&path, &test_pkginfo(name, "1-1"), &["usr/"]);
+        db.add(&path).unwrap();
+    }
+    db.write().unwrap();
+
+    let mut db = RepoDb::open(&dbfile).unwrap();
+    assert!(db.remove("foo"));
+    assert!(!db.remove("foo"));
+    db.write().unwrap();
+
+    let i = new_interner();
+    let packages =
+        super::parse_syncdb_at(i.clone(), &dir, "test", &crate::events::NoEvents).unwrap();
+    let files = super::parse_files_db_at(i.clone(), &dir, "test").unwrap();
+    let ii = i.borrow();
+    assert_eq!(packages.len(), 1);
+    assert_eq!(files.len(), 1);
+    assert!(packages.contains_key(&ii.get("bar").unwrap()));
+    let leftovers = std::fs::read_dir(&dir)
+        .unwrap()
+        .filter(|e| {
+            e.as_ref()
+                .unwrap()
+                .file_name()
+                .to_string_lossy()
+                .ends_with(".tmp")
+        })
+        .count();
+    assert_eq!(leftovers, 0);
+}
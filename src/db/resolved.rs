@@ -0,0 +1,209 @@
+//! [ResolvedPackage], a [Package] with its strings looked up.
+use super::parse::InnerInterner;
+use super::{InstallReason, Istr, Package, QuickResolve, versionparse};
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD_NO_PAD as B64;
+use std::cmp::Ordering;
+use std::fmt::{self, Display, Formatter};
+use std::ops::Deref;
+use std::time::SystemTime;
+
+/// A read-only view of a [Package] with plain strings, borrowed from the interner,
+/// see [Package::resolve]. Lists the desc does not have are empty.
+/// Displays as `<name> <version>` like `pacman -Q`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResolvedPackage<'a> {
+    pub base: &'a str,
+    pub name: &'a str,
+    pub version: &'a str,
+    pub arch: &'a str,
+
+    pub reason: Option<InstallReason>,
+    pub install_date: Option<SystemTime>,
+    /// "none", "md5", "sha256" or "pgp".
+    pub validation: Option<&'static str>,
+
+    pub packager: &'a str,
+    pub isize: Option<u64>,
+    pub csize: Option<u64>,
+    pub build_date: SystemTime,
+    pub url: Option<&'a str>,
+    pub license: Vec<&'a str>,
+    pub desc: &'a str,
+    pub filename: Option<&'a str>,
+    /// As written in the desc.
+    pub md5sum: Option<String>,
+    /// As written in the desc.
+    pub sha256sum: Option<String>,
+    pub pgpsig: Option<&'a str>,
+
+    pub provides: Vec<&'a str>,
+    pub depends: Vec<&'a str>,
+    pub optdepends: Vec<&'a str>,
+    pub makedepends: Vec<&'a str>,
+    pub checkdepends: Vec<&'a str>,
+    pub groups: Vec<&'a str>,
+    /// Sorted, the package keeps them unordered.
+    pub replaces: Vec<&'a str>,
+    pub conflicts: Vec<&'a str>,
+
+    /// "pkgtype=pkg", "pkgtype=split" or "pkgtype=debug".
+    pub xdata: Option<&'static str>,
+}
+
+impl Package {
+    /// Looks up all strings at once, i is the borrowed interner the package was parsed with.
+    ///
+    /// Ex: ```p.resolve(&i.borrow()).depends```
+    pub fn resolve<'a, I: Deref<Target = InnerInterner>>(
+        &'a self,
+        i: &'a I,
+    ) -> ResolvedPackage<'a> {
+        let list = |l: &'a Option<Vec<Istr>>| l.iter().flatten().map(|s| s.r(i)).collect();
+        let mut replaces: Vec<_> = self.replaces.iter().flatten().map(|s| s.r(i)).collect();
+        replaces.sort_unstable();
+        ResolvedPackage {
+            base: self.base.r(i),
+            name: self.name.r(i),
+            version: self.version.r(i),
+            arch: self.arch.r(i),
+            reason: self.reason,
+            install_date: self.install_date,
+            validation: self.validation.as_ref().map(|v| v.as_str()),
+            packager: self.packager.r(i),
+            isize: self.isize,
+            csize: self.csize,
+            build_date: self.build_date,
+            url: self.url.map(|u| u.r(i)),
+            license: self.license.iter().map(|l| l.r(i)).collect(),
+            desc: self.desc.r(i),
+            filename: self.filename.map(|f| f.r(i)),
+            md5sum: self.md5sum.map(|m| B64.encode(m)),
+            sha256sum: self.sha256sum.map(|m| B64.encode(m)),
+            pgpsig: self.pgpsig.map(|s| s.r(i)),
+            provides: list(&self.provides),
+            depends: list(&self.depends),
+            optdepends: list(&self.optdepends),
+            makedepends: list(&self.makedepends),
+            checkdepends: list(&self.checkdepends),
+            groups: list(&self.groups),
+            replaces,
+            conflicts: list(&self.conflicts),
+            xdata: self.xdata.as_ref().map(|x| x.as_str()),
+        }
+    }
+}
+
+impl Display for ResolvedPackage<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.name, self.version)
+    }
+}
+
+/// By name, then by version like pacman, then by arch, like [Package::cmp_resolved].
+/// The remaining fields only break ties, so that the order agrees with `==`.
+impl Ord for ResolvedPackage<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let version = |p: &Self| versionparse(p.version).ok();
+        self.name
+            .cmp(other.name)
+            .then_with(|| version(self).cmp(&version(other)))
+            .then_with(|| self.arch.cmp(other.arch))
+            .then_with(|| self.rest().cmp(&other.rest()))
+    }
+}
+
+impl PartialOrd for ResolvedPackage<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl ResolvedPackage<'_> {
+    #[allow(clippy::type_complexity)]
+    fn rest(
+        &self,
+    ) -> (
+        (
+            &str,
+            &str,
+            Option<InstallReason>,
+            Option<SystemTime>,
+            Option<&str>,
+            &str,
+        ),
+        (
+            Option<u64>,
+            Option<u64>,
+            SystemTime,
+            Option<&str>,
+            &[&str],
+            &str,
+        ),
+        (Option<&str>, Option<&String>, Option<&String>, Option<&str>),
+        (&[&str], &[&str], &[&str], &[&str], &[&str], &[&str]),
+        (&[&str], &[&str], Option<&str>),
+    ) {
+        (
+            (
+                self.version,
+                self.base,
+                self.reason,
+                self.install_date,
+                self.validation,
+                self.packager,
+            ),
+            (
+                self.isize,
+                self.csize,
+                self.build_date,
+                self.url,
+                &self.license,
+                self.desc,
+            ),
+            (
+                self.filename,
+                self.md5sum.as_ref(),
+                self.sha256sum.as_ref(),
+                self.pgpsig,
+            ),
+            (
+                &self.provides,
+                &self.depends,
+                &self.optdepends,
+                &self.makedepends,
+                &self.checkdepends,
+                &self.groups,
+            ),
+            (&self.replaces, &self.conflicts, self.xdata),
+        )
+    }
+}
+
+#[test]
+fn test_resolve() {
+    use super::{new_interner, test_desc};
+    let i = new_interner();
+    let desc = test_desc(
+        "foo",
+        "1-1",
+        &[
+            ("DEPENDS", "glibc\nsh"),
+            ("REPLACES", "baz\nbar"),
+            ("MD5SUM", "d41d8cd98f00b204e9800998ecf8427e"),
+        ],
+    );
+    let p = Package::from_str(i.clone(), &desc).unwrap();
+    let ii = i.borrow();
+    let r = p.resolve(&ii);
+    assert_eq!((r.name, r.version, r.arch), ("foo", "1-1", "x86_64"));
+    assert_eq!(r.depends, ["glibc", "sh"]);
+    assert_eq!(r.replaces, ["bar", "baz"]);
+    assert!(r.provides.is_empty());
+    assert_eq!(
+        r.md5sum.as_deref(),
+        Some("d41d8cd98f00b204e9800998ecf8427e")
+    );
+    assert_eq!(r.to_string(), "foo 1-1");
+    assert!(format!("{r:?}").contains(r#"license: ["MIT"]"#));
+}
@@ -0,0 +1,218 @@
+//! Serde support for [Package], used with the serde feature.
+//! Packages do not know their interner, so serializing goes through [SerializePackage]
+//! or [ResolvedPackage] to resolve their strings,
+//! and deserializing through [PackageSeed] to intern them again.
+use super::parse::{Arch, Validation, XData};
+use super::{InstallReason, Interner, Package, ResolvedPackage};
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD_NO_PAD as B64;
+use serde::de::{DeserializeSeed, Error};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Package with resolved strings, times are unix seconds.
+#[derive(Serialize, Deserialize)]
+struct Owned {
+    base: String,
+    name: String,
+    version: String,
+    arch: String,
+    reason: Option<u8>,
+    install_date: Option<u64>,
+    validation: Option<String>,
+    packager: String,
+    isize: Option<u64>,
+    csize: Option<u64>,
+    build_date: u64,
+    url: Option<String>,
+    license: Vec<String>,
+    desc: String,
+    filename: Option<String>,
+    md5sum: Option<String>,
+    sha256sum: Option<String>,
+    pgpsig: Option<String>,
+    provides: Option<Vec<String>>,
+    depends: Option<Vec<String>>,
+    optdepends: Option<Vec<String>>,
+    makedepends: Option<Vec<String>>,
+    checkdepends: Option<Vec<String>>,
+    groups: Option<Vec<String>>,
+    replaces: Option<Vec<String>>,
+    conflicts: Option<Vec<String>>,
+    xdata: Option<String>,
+}
+
+fn secs(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+impl Owned {
+    fn new(p: &ResolvedPackage) -> Self {
+        let s = |s: &str| s.to_owned();
+        let list = |l: &[&str]| (!l.is_empty()).then(|| l.iter().map(|e| s(e)).collect());
+        Self {
+            base: s(p.base),
+            name: s(p.name),
+            version: s(p.version),
+            arch: s(p.arch),
+            reason: p.reason.map(|r| r as u8),
+            install_date: p.install_date.map(secs),
+            validation: p.validation.map(s),
+            packager: s(p.packager),
+            isize: p.isize,
+            csize: p.csize,
+            build_date: secs(p.build_date),
+            url: p.url.map(s),
+            license: p.license.iter().map(|l| s(l)).collect(),
+            desc: s(p.desc),
+            filename: p.filename.map(s),
+            md5sum: p.md5sum.clone(),
+            sha256sum: p.sha256sum.clone(),
+            pgpsig: p.pgpsig.map(s),
+            provides: list(&p.provides),
+            depends: list(&p.depends),
+            optdepends: list(&p.optdepends),
+            makedepends: list(&p.makedepends),
+            checkdepends: list(&p.checkdepends),
+            groups: list(&p.groups),
+            replaces: list(&p.replaces),
+            conflicts: list(&p.conflicts),
+            xdata: p.xdata.map(s),
+        }
+    }
+
+    fn into_package(self, i: Interner) -> Result<Package, String> {
+        let mut ir = i.borrow_mut();
+        let arch = Arch::intern(&self.arch, &mut ir);
+        let mut s = |s: String| ir.get_or_intern(s);
+        fn list(
+            l: Option<Vec<String>>,
+            s: &mut impl FnMut(String) -> super::Istr,
+        ) -> Option<Vec<super::Istr>> {
+            l.map(|l| l.into_iter().map(s).collect())
+        }
+        let time = |secs: u64| UNIX_EPOCH + Duration::from_secs(secs);
+        fn sum<const N: usize>(sum: Option<String>) -> Result<Option<[u8; N]>, String> {
+            sum.map(|sum| {
+                let bytes = B64.decode(&sum).map_err(|e| e.to_string())?;
+                bytes
+                    .try_into()
+                    .map_err(|_| format!("checksum {sum} has the wrong length"))
+            })
+            .transpose()
+        }
+        let validation = self
+            .validation
+            .as_deref()
+            .map(Validation::from_str)
+            .transpose()?;
+        let xdata = self.xdata.as_deref().map(XData::from_str).transpose()?;
+        let p = Package {
+            base: s(self.base),
+            name: s(self.name),
+            version: s(self.version),
+            arch,
+            reason: self.reason.map(InstallReason::from),
+            install_date: self.install_date.map(time),
+            validation,
+            packager: s(self.packager),
+            isize: self.isize,
+            csize: self.csize,
+            build_date: time(self.build_date),
+            url: self.url.map(&mut s),
+            license: self.license.into_iter().map(&mut s).collect(),
+            desc: s(self.desc),
+            filename: self.filename.map(&mut s),
+            md5sum: sum(self.md5sum)?,
+            sha256sum: sum(self.sha256sum)?,
+            pgpsig: self.pgpsig.map(&mut s),
+            provides: list(self.provides, &mut s),
+            depends: list(self.depends, &mut s),
+            optdepends: list(self.optdepends, &mut s),
+            makedepends: list(self.makedepends, &mut s),
+            checkdepends: list(self.checkdepends, &mut s),
+            groups: list(self.groups, &mut s),
+            replaces: list(self.replaces, &mut s).map(|r| r.into_iter().collect()),
+            conflicts: list(self.conflicts, &mut s),
+            xdata,
+            parsed_version: Default::default(),
+        };
+        Ok(p)
+    }
+}
+
+/// Serializes a [Package], resolving its strings with the contained interner.
+///
+/// Ex: ```serde_json::to_string(&SerializePackage(&p, &i))```
+pub struct SerializePackage<'p>(pub &'p Package, pub &'p Interner);
+
+impl Serialize for SerializePackage<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.resolve(&self.1.borrow()).serialize(serializer)
+    }
+}
+
+/// Like [SerializePackage], absent lists are null.
+impl Serialize for ResolvedPackage<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Owned::new(self).serialize(serializer)
+    }
+}
+
+/// Deserializes a [Package], interning its strings into the contained interner.
+///
+/// Ex: ```PackageSeed(i).deserialize(&mut serde_json::Deserializer::from_str(s))```
+pub struct PackageSeed(pub Interner);
+
+impl<'de> DeserializeSeed<'de> for PackageSeed {
+    type Value = Package;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Package, D::Error> {
+        Owned::deserialize(deserializer)?
+            .into_package(self.0)
+            .map_err(D::Error::custom)
+    }
+}
+
+#[test]
+fn test_serde() {
+    use super::{QuickResolve, new_interner, test_desc};
+    let i = new_interner();
+    let desc = test_desc(
+        "foo",
+        "1-1",
+        &[
+            ("DEPENDS", "glibc\nsh"),
+            ("REPLACES", "bar"),
+            ("MD5SUM", "d41d8cd98f00b204e9800998ecf8427e"),
+            ("XDATA", "pkgtype=pkg"),
+        ],
+    );
+    let p = Package::from_str(i.clone(), &desc).unwrap();
+    let json = serde_json::to_string(&SerializePackage(&p, &i)).unwrap();
+    assert!(json.contains(r#""depends":["glibc","sh"]"#));
+    assert!(json.contains(r#""md5sum":"d41d8cd98f00b204e9800998ecf8427e""#));
+    let resolved = serde_json::to_string(&p.resolve(&i.borrow())).unwrap();
+    assert_eq!(resolved, json);
+
+    let other = new_interner();
+    let back = PackageSeed(other.clone())
+        .deserialize(&mut serde_json::Deserializer::from_str(&json))
+        .unwrap();
+    assert_eq!(back.name.r(&other.borrow()), "foo");
+    let again = serde_json::to_string(&SerializePackage(&back, &other)).unwrap();
+    assert_eq!(again, json);
+
+    let vax = json.replace("x86_64", "vax");
+    let vax = PackageSeed(other.clone())
+        .deserialize(&mut serde_json::Deserializer::from_str(&vax))
+        .unwrap();
+    assert_eq!(vax.arch.r(&other.borrow()), "vax");
+    let bad = json.replace("d41d8cd98f00b204e9800998ecf8427e", "zz");
+    assert!(
+        PackageSeed(other)
+            .deserialize(&mut serde_json::Deserializer::from_str(&bad))
+            .is_err()
+    );
+}
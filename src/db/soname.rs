@@ -0,0 +1,86 @@
+//! Shared library dependencies like `libssl.so=3-64`,
+//! as generated by makepkg for provides and depends.
+use super::{Interner, Package, QuickResolve};
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+/// A soname entry `<name>=<version>-<bitness>`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Soname {
+    /// Library name including `.so`, e.g. `libssl.so`.
+    pub name: String,
+    /// The soname version, e.g. `3` for `libssl.so.3`.
+    pub version: String,
+    /// 32 or 64.
+    pub bitness: u8,
+}
+
+impl FromStr for Soname {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("not a soname: {s}");
+        let (name, rest) = s.split_once('=').ok_or_else(err)?;
+        let (version, bitness) = rest.rsplit_once('-').ok_or_else(err)?;
+        if !name.contains(".so") || version.is_empty() {
+            return Err(err());
+        }
+        let bitness = match bitness {
+            "32" => 32,
+            "64" => 64,
+            _ => return Err(err()),
+        };
+        Ok(Self {
+            name: name.to_owned(),
+            version: version.to_owned(),
+            bitness,
+        })
+    }
+}
+
+impl Display for Soname {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}-{}", self.name, self.version, self.bitness)
+    }
+}
+
+impl Package {
+    /// The provides entries that are sonames.
+    pub fn provided_sonames(&self, i: &Interner) -> Vec<Soname> {
+        Self::sonames(i, &self.provides)
+    }
+
+    /// The depends entries that are sonames.
+    pub fn required_sonames(&self, i: &Interner) -> Vec<Soname> {
+        Self::sonames(i, &self.depends)
+    }
+
+    fn sonames(i: &Interner, entries: &Option<Vec<super::Istr>>) -> Vec<Soname> {
+        let i = i.borrow();
+        entries
+            .iter()
+            .flatten()
+            .filter_map(|e| e.r(&i).parse().ok())
+            .collect()
+    }
+}
+
+#[test]
+fn test_soname() {
+    let s: Soname = "libssl.so=3-64".parse().unwrap();
+    assert_eq!(
+        s,
+        Soname {
+            name: "libssl.so".to_owned(),
+            version: "3".to_owned(),
+            bitness: 64
+        }
+    );
+    assert_eq!(s.to_string(), "libssl.so=3-64");
+    let s: Soname = "libgtk-3.so=0-32".parse().unwrap();
+    assert_eq!((s.name.as_str(), s.version.as_str()), ("libgtk-3.so", "0"));
+    assert!("glibc>=2.38".parse::<Soname>().is_err());
+    assert!("sh=5.2-1".parse::<Soname>().is_err());
+    assert!("libfoo.so".parse::<Soname>().is_err());
+    assert!("libfoo.so=1-16".parse::<Soname>().is_err());
+}
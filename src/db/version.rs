@@ -0,0 +1,170 @@
+//! Owned, comparable package versions, `[epoch:]pkgver[-pkgrel]`.
+use super::parse::versionparse_;
+use std::cmp::Ordering;
+use std::fmt::{self, Display, Formatter};
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+
+/// Alphabetic segments sort before numeric ones, like in pacman's vercmp.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum Segment {
+    Alpha(String),
+    Num(u64),
+}
+
+fn segments(s: Vec<Result<&str, u64>>) -> Vec<Segment> {
+    s.into_iter()
+        .map(|s| match s {
+            Ok(a) => Segment::Alpha(a.to_owned()),
+            Err(n) => Segment::Num(n),
+        })
+        .collect()
+}
+
+/// A parsed version, ordered like `vercmp`.
+/// A missing epoch is 0, separators only split segments,
+/// so `1.0` and `1_0` are equal even though they display differently.
+#[derive(Clone, Debug)]
+pub struct Version {
+    /// As parsed, for Display.
+    raw: String,
+    epoch: Option<u64>,
+    /// Where pkgver is in raw.
+    pkgver: std::ops::Range<usize>,
+    pkgver_segments: Vec<Segment>,
+    pkgrel_segments: Option<Vec<Segment>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InvalidVersion(pub String);
+
+impl Display for InvalidVersion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid version {:?}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidVersion {}
+
+impl FromStr for Version {
+    type Err = InvalidVersion;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (_, (epoch, pkgver, pkgrel)) =
+            versionparse_(s).map_err(|_| InvalidVersion(s.to_owned()))?;
+        let start = s.find(':').filter(|_| epoch.is_some()).map_or(0, |p| p + 1);
+        let end = match pkgrel {
+            Some(_) => s.rfind('-').unwrap(),
+            None => s.len(),
+        };
+        Ok(Self {
+            raw: s.to_owned(),
+            epoch,
+            pkgver: start..end,
+            pkgver_segments: segments(pkgver),
+            pkgrel_segments: pkgrel.map(segments),
+        })
+    }
+}
+
+impl Version {
+    /// 0 if the version has none.
+    pub fn epoch(&self) -> u64 {
+        self.epoch.unwrap_or(0)
+    }
+
+    pub fn pkgver(&self) -> &str {
+        &self.raw[self.pkgver.clone()]
+    }
+
+    /// None for versions without a `-`, like in versioned dependencies `foo>=1.2`.
+    pub fn pkgrel(&self) -> Option<&str> {
+        self.pkgrel_segments
+            .as_ref()
+            .map(|_| &self.raw[self.pkgver.end + 1..])
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.raw
+    }
+
+    /// Like pacman's vercmp, which only compares pkgrel if both versions have one,
+    /// so `1.2` equals `1.2-3`. Used for dependency constraints,
+    /// it is not a total order so Ord does not do this.
+    pub fn vercmp(&self, other: &Self) -> Ordering {
+        let rel = match (&self.pkgrel_segments, &other.pkgrel_segments) {
+            (Some(a), Some(b)) => a.cmp(b),
+            _ => Ordering::Equal,
+        };
+        (self.epoch(), &self.pkgver_segments)
+            .cmp(&(other.epoch(), &other.pkgver_segments))
+            .then(rel)
+    }
+
+    fn key(&self) -> (u64, &[Segment], Option<&[Segment]>) {
+        (
+            self.epoch(),
+            &self.pkgver_segments,
+            self.pkgrel_segments.as_deref(),
+        )
+    }
+}
+
+impl Display for Version {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.raw)
+    }
+}
+
+impl PartialEq for Version {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for Version {}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+impl Hash for Version {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key().hash(state)
+    }
+}
+
+#[test]
+fn test_version_type() {
+    let v: Version = "2:1.2.3-4".parse().unwrap();
+    assert_eq!(v.epoch(), 2);
+    assert_eq!(v.pkgver(), "1.2.3");
+    assert_eq!(v.pkgrel(), Some("4"));
+    assert_eq!(v.to_string(), "2:1.2.3-4");
+
+    let v: Version = "2025.Q1.2".parse().unwrap();
+    assert_eq!(v.epoch(), 0);
+    assert_eq!(v.pkgver(), "2025.Q1.2");
+    assert_eq!(v.pkgrel(), None);
+
+    let parse = |s: &str| s.parse::<Version>().unwrap();
+    assert!(parse("1.4-1") > parse("1.1b-1"));
+    assert!(parse("0.15.1-2") < parse("0.15.1b-10"));
+    assert!(parse("1:0.1-1") > parse("9.9-1"));
+    assert_eq!(parse("0:1.0-1"), parse("1.0-1"));
+    assert_eq!(parse("1.0-1"), parse("1_0-1"));
+    let mut versions = [parse("1.10-1"), parse("1.9-1"), parse("1.9-2")];
+    versions.sort();
+    let sorted: Vec<_> = versions.iter().map(Version::as_str).collect();
+    assert_eq!(sorted, ["1.9-1", "1.9-2", "1.10-1"]);
+    assert_eq!(parse("1.2").vercmp(&parse("1.2-3")), Ordering::Equal);
+    assert_eq!(parse("1.2-2").vercmp(&parse("1.2-3")), Ordering::Less);
+}
@@ -0,0 +1,1904 @@
+//! Fetching sync dbs and packages from mirrors, used with the download feature.
+use crate::config::{PacmanConfig, Repo, SigRequirement};
+use crate::db::{DBLock, Interner, Package, QuickResolve};
+use crate::events::EventSink;
+use log::{debug, warn};
+use std::fs::File;
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// An HTTP status a mirror answered with instead of the file,
+/// the [io::Error]s of failed requests wrap it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct HttpStatus(pub u16);
+
+impl std::fmt::Display for HttpStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "http status: {}", self.0)
+    }
+}
+
+impl std::error::Error for HttpStatus {}
+
+impl HttpStatus {
+    /// The status error is wrapped in, if any.
+    pub fn of(e: &io::Error) -> Option<Self> {
+        e.get_ref()?.downcast_ref().copied()
+    }
+}
+
+fn http_error(e: ureq::Error) -> io::Error {
+    match e {
+        ureq::Error::StatusCode(404) => io::Error::new(io::ErrorKind::NotFound, HttpStatus(404)),
+        ureq::Error::StatusCode(status) => io::Error::other(HttpStatus(status)),
+        ureq::Error::Timeout(_) => io::Error::new(io::ErrorKind::TimedOut, e.to_string()),
+        ureq::Error::Io(e) => e,
+        e => io::Error::other(e.to_string()),
+    }
+}
+
+/// How downloads behave beyond what pacman.conf configures.
+#[derive(Clone, Debug, Default)]
+pub struct DownloadOptions {
+    /// Bytes per second all downloads of one call share, unlimited if None.
+    pub rate_limit: Option<u64>,
+    /// Bytes per second of each single download, unlimited if None.
+    pub connection_rate_limit: Option<u64>,
+    /// Proxy url, the ALL_PROXY, HTTPS_PROXY and HTTP_PROXY environment variables are used if None.
+    pub proxy: Option<String>,
+    /// Sent with every HTTP request, like an Authorization token for a private repo.
+    pub headers: Vec<(String, String)>,
+    pub retry: RetryPolicy,
+    /// Download only the bytes appended to a changed sync db since the last refresh,
+    /// see [refresh_syncdbs].
+    pub delta: bool,
+}
+
+/// When to try a mirror again and when to move on to the next one.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Tries per mirror, at least 1.
+    pub attempts: u32,
+    /// Wait before the second try of a mirror, doubling with each further one.
+    pub backoff: Duration,
+    /// HTTP statuses worth trying the same mirror again for,
+    /// on others, like a 404, the next mirror is tried right away.
+    /// Timeouts and dropped connections are always tried again.
+    pub retry_statuses: Vec<u16>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            backoff: Duration::from_secs(1),
+            retry_statuses: vec![408, 429, 500, 502, 503, 504],
+        }
+    }
+}
+
+impl RetryPolicy {
+    fn retries(&self, e: &io::Error) -> bool {
+        use io::ErrorKind::*;
+        match HttpStatus::of(e) {
+            Some(HttpStatus(status)) => self.retry_statuses.contains(&status),
+            None => matches!(
+                e.kind(),
+                TimedOut | ConnectionReset | ConnectionAborted | UnexpectedEof | Interrupted
+            ),
+        }
+    }
+}
+
+/// Paces bytes to a rate, allowing bursts of at most one second worth of them.
+struct Throttle {
+    rate: u64,
+    allowance: f64,
+    last: Instant,
+}
+
+impl Throttle {
+    fn new(rate: u64) -> Self {
+        Self {
+            rate: rate.max(1),
+            allowance: 0.,
+            last: Instant::now(),
+        }
+    }
+
+    /// Takes n bytes, returns how long to wait before taking more.
+    fn take(&mut self, n: usize) -> Duration {
+        let rate = self.rate as f64;
+        let now = Instant::now();
+        let earned = now.duration_since(self.last).as_secs_f64() * rate;
+        self.last = now;
+        self.allowance = (self.allowance + earned).min(rate) - n as f64;
+        Duration::from_secs_f64((-self.allowance).max(0.) / rate)
+    }
+}
+
+/// What the downloads of one call share.
+struct Session {
+    agent: ureq::Agent,
+    headers: Vec<(String, String)>,
+    retry: RetryPolicy,
+    rate_limit: Option<Mutex<Throttle>>,
+    connection_rate_limit: Option<u64>,
+    delta: bool,
+}
+
+impl Session {
+    /// Gives up on mirrors that do not answer within 10 seconds, like pacman,
+    /// so the next one gets tried.
+    /// Fails on an invalid proxy url.
+    fn new(options: &DownloadOptions) -> io::Result<Self> {
+        let timeout = Some(std::time::Duration::from_secs(10));
+        let mut config = ureq::Agent::config_builder()
+            .timeout_connect(timeout)
+            .timeout_recv_response(timeout);
+        if let Some(proxy) = &options.proxy {
+            let proxy = ureq::Proxy::new(proxy).map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidInput, format!("proxy {proxy}: {e}"))
+            })?;
+            config = config.proxy(Some(proxy));
+        }
+        Ok(Self {
+            agent: config.build().into(),
+            headers: options.headers.clone(),
+            retry: options.retry.clone(),
+            rate_limit: options
+                .rate_limit
+                .map(|rate| Mutex::new(Throttle::new(rate))),
+            connection_rate_limit: options.connection_rate_limit,
+            delta: options.delta,
+        })
+    }
+
+    fn get(&self, url: &str) -> ureq::RequestBuilder<ureq::typestate::WithoutBody> {
+        let (url, auth) = basic_auth(url);
+        self.with_headers(self.agent.get(url), auth)
+    }
+
+    fn head(&self, url: &str) -> ureq::RequestBuilder<ureq::typestate::WithoutBody> {
+        let (url, auth) = basic_auth(url);
+        self.with_headers(self.agent.head(url), auth)
+    }
+
+    /// Adds the custom headers and auth, which the custom headers may override.
+    fn with_headers(
+        &self,
+        mut req: ureq::RequestBuilder<ureq::typestate::WithoutBody>,
+        auth: Option<String>,
+    ) -> ureq::RequestBuilder<ureq::typestate::WithoutBody> {
+        if let Some(auth) = auth {
+            req = req.header("Authorization", auth);
+        }
+        for (name, value) in &self.headers {
+            req = req.header(name, value);
+        }
+        req
+    }
+
+    /// Wraps the body of one download in the rate limits.
+    fn throttled<R>(&self, inner: R) -> Throttled<'_, R> {
+        Throttled {
+            inner,
+            session: self,
+            connection: self.connection_rate_limit.map(Throttle::new),
+        }
+    }
+}
+
+/// Reads no faster than the rate limits of its session allow.
+struct Throttled<'s, R> {
+    inner: R,
+    session: &'s Session,
+    connection: Option<Throttle>,
+}
+
+impl<R: io::Read> io::Read for Throttled<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        let mut wait = self
+            .connection
+            .as_mut()
+            .map_or(Duration::ZERO, |t| t.take(n));
+        if let Some(global) = &self.session.rate_limit {
+            wait = wait.max(global.lock().unwrap().take(n));
+        }
+        std::thread::sleep(wait);
+        Ok(n)
+    }
+}
+
+/// Splits `user:password@` off the host of url, like in a Server with basic auth.
+/// returns the url without it and the Authorization header for it
+fn basic_auth(url: &str) -> (String, Option<String>) {
+    use base64::Engine;
+    use base64::prelude::BASE64_STANDARD as B64;
+    if let Some((scheme, rest)) = url.split_once("://") {
+        let host_end = rest.find('/').unwrap_or(rest.len());
+        if let Some(at) = rest[..host_end].rfind('@') {
+            let auth = format!("Basic {}", B64.encode(&rest[..at]));
+            return (format!("{scheme}://{}", &rest[at + 1..]), Some(auth));
+        }
+    }
+    (url.to_owned(), None)
+}
+
+/// `<dest>.part`, downloads go there until they are complete.
+fn part_path(dest: &Path) -> PathBuf {
+    let mut part = dest.as_os_str().to_owned();
+    part.push(".part");
+    part.into()
+}
+
+/// HTTP cache validators of a downloaded db, kept in `<db>.validators`
+/// so the next refresh can ask the mirror whether it changed.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct Validators {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+impl Validators {
+    fn path(dest: &Path) -> PathBuf {
+        let mut path = dest.as_os_str().to_owned();
+        path.push(".validators");
+        path.into()
+    }
+
+    fn of(resp: &ureq::http::Response<ureq::Body>) -> Self {
+        let header = |name| {
+            let value = resp.headers().get(name)?.to_str().ok()?;
+            Some(value.to_owned())
+        };
+        Self {
+            etag: header("ETag"),
+            last_modified: header("Last-Modified"),
+        }
+    }
+
+    /// Missing or unreadable validators just mean an unconditional download.
+    fn load(dest: &Path) -> Self {
+        let s = std::fs::read_to_string(Self::path(dest)).unwrap_or_default();
+        let mut v = Self::default();
+        for (k, val) in s.lines().filter_map(|l| l.split_once(": ")) {
+            match k {
+                "ETag" => v.etag = Some(val.to_owned()),
+                "Last-Modified" => v.last_modified = Some(val.to_owned()),
+                _ => (),
+            }
+        }
+        v
+    }
+
+    fn store(&self, dest: &Path) -> io::Result<()> {
+        let path = Self::path(dest);
+        if *self == Self::default() {
+            return match std::fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            };
+        }
+        let mut s = String::new();
+        if let Some(etag) = &self.etag {
+            s.push_str(&format!("ETag: {etag}\n"));
+        }
+        if let Some(last_modified) = &self.last_modified {
+            s.push_str(&format!("Last-Modified: {last_modified}\n"));
+        }
+        std::fs::write(path, s)
+    }
+}
+
+/// Calls report with the bytes written through it so far and the expected ones.
+struct Counting<'r, W> {
+    inner: W,
+    written: u64,
+    expected: Option<u64>,
+    report: &'r dyn Fn(u64, Option<u64>),
+}
+
+impl<W: Write> Write for Counting<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.written += n as u64;
+        (self.report)(self.written, self.expected);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+enum Fetched {
+    /// The part file and the validators the server sent with it.
+    Part(PathBuf, Validators),
+    /// The server reported the file unchanged since validators were taken.
+    NotModified,
+}
+
+/// Downloads url into `<dest>.part`, file:// urls are copied.
+/// The request is conditional on validators if they are not empty.
+/// With resume, an existing part file is continued with a Range request
+/// and kept on errors for the next try, otherwise it is replaced and removed again on errors.
+/// Fails if less than the announced Content-Length arrived.
+/// HTTP bodies are read within the rate limits of session, local copies are not.
+/// progress gets the bytes received so far and the expected ones, if known.
+fn fetch(
+    session: &Session,
+    url: &str,
+    dest: &Path,
+    validators: &Validators,
+    resume: bool,
+    progress: &dyn Fn(u64, Option<u64>),
+) -> io::Result<Fetched> {
+    // without the credentials of the url, they do not belong in logs
+    debug!("downloading {}", basic_auth(url).0);
+    let part = part_path(dest);
+    let offset = match std::fs::metadata(&part) {
+        Ok(m) if resume => m.len(),
+        _ => 0,
+    };
+    // continues the part file at offset if given, otherwise starts it over
+    let create = |offset: Option<u64>, expected| {
+        let inner = match offset {
+            Some(_) => File::options().append(true).open(&part)?,
+            None => File::create(&part)?,
+        };
+        Ok::<_, io::Error>(Counting {
+            inner,
+            written: offset.unwrap_or(0),
+            expected,
+            report: progress,
+        })
+    };
+    let download = || {
+        let (expected, out, validators) = if let Some(path) = url.strip_prefix("file://") {
+            let mut f = File::open(path)?;
+            let len = f.metadata()?.len();
+            let offset = (offset > 0 && offset <= len).then_some(offset);
+            if let Some(offset) = offset {
+                f.seek(io::SeekFrom::Start(offset))?;
+            }
+            let mut out = create(offset, Some(len))?;
+            io::copy(&mut f, &mut out)?;
+            (Some(len), out, Validators::default())
+        } else {
+            let mut req = session.get(url);
+            if let Some(etag) = &validators.etag {
+                req = req.header("If-None-Match", etag);
+            }
+            if let Some(last_modified) = &validators.last_modified {
+                req = req.header("If-Modified-Since", last_modified);
+            }
+            if offset > 0 {
+                req = req.header("Range", &format!("bytes={offset}-"));
+            }
+            let mut resp = match req.call() {
+                // the part file already has everything, verifying it is up to the caller
+                Err(ureq::Error::StatusCode(416)) if offset > 0 => {
+                    return Ok(Fetched::Part(part.clone(), Validators::default()));
+                }
+                r => r.map_err(http_error)?,
+            };
+            if resp.status() == 304 {
+                return Ok(Fetched::NotModified);
+            }
+            let validators = Validators::of(&resp);
+            // servers not supporting ranges send everything with a 200
+            let offset = (resp.status() == 206).then_some(offset);
+            let length = resp.body().content_length();
+            let expected = length.map(|l| l + offset.unwrap_or(0));
+            let mut out = create(offset, expected)?;
+            io::copy(
+                &mut session.throttled(resp.body_mut().as_reader()),
+                &mut out,
+            )?;
+            (expected, out, validators)
+        };
+        match expected {
+            Some(expected) if expected != out.written => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{url}: got {} of {expected} bytes", out.written),
+            )),
+            _ => Ok(Fetched::Part(part.clone(), validators)),
+        }
+    };
+    let fetched = download();
+    if fetched.is_err() && !resume {
+        let _ = std::fs::remove_file(&part);
+    }
+    fetched
+}
+
+/// Bytes before the end of the old db that [fetch_delta] downloads again,
+/// to check that the new one continues it.
+const DELTA_OVERLAP: u64 = 4096;
+
+/// Like [fetch], but downloads only what was appended to the file at dest:
+/// requests it from [DELTA_OVERLAP] bytes before the old end, and if those match the old file
+/// and more follows, writes the old file with the new bytes after it into `<dest>.part`.
+/// Ok(None) if that does not work out, because the file was rewritten instead of appended to,
+/// did not grow, or the server ignores ranges, so the caller has to download it whole.
+fn fetch_delta(
+    session: &Session,
+    url: &str,
+    dest: &Path,
+    validators: &Validators,
+    progress: &dyn Fn(u64, Option<u64>),
+) -> io::Result<Option<Fetched>> {
+    let old_len = match std::fs::metadata(dest) {
+        Ok(m) if m.len() > 0 && !url.starts_with("file://") => m.len(),
+        _ => return Ok(None),
+    };
+    let start = old_len - old_len.min(DELTA_OVERLAP);
+    let shown = basic_auth(url).0;
+    debug!("downloading {shown} from byte {start}");
+    let mut req = session.get(url);
+    if let Some(etag) = &validators.etag {
+        req = req.header("If-None-Match", etag);
+    }
+    if let Some(last_modified) = &validators.last_modified {
+        req = req.header("If-Modified-Since", last_modified);
+    }
+    req = req.header("Range", &format!("bytes={start}-"));
+    let mut resp = match req.call() {
+        // it shrank
+        Err(ureq::Error::StatusCode(416)) => return Ok(None),
+        r => r.map_err(http_error)?,
+    };
+    match resp.status().as_u16() {
+        304 => return Ok(Some(Fetched::NotModified)),
+        206 => (),
+        _ => return Ok(None),
+    }
+    let validators = Validators::of(&resp);
+    let expected = resp.body().content_length().map(|l| start + l);
+    let mut body = session.throttled(resp.body_mut().as_reader());
+    let mut old_tail = vec![0; (old_len - start) as usize];
+    let mut new_tail = old_tail.clone();
+    let mut old = File::open(dest)?;
+    old.seek(io::SeekFrom::Start(start))?;
+    old.read_exact(&mut old_tail)?;
+    match body.read_exact(&mut new_tail) {
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        r => r?,
+    }
+    if new_tail != old_tail {
+        debug!("{shown} was rewritten, not appended to");
+        return Ok(None);
+    }
+    let part = part_path(dest);
+    let mut appended = || {
+        std::fs::copy(dest, &part)?;
+        let mut out = Counting {
+            inner: File::options().append(true).open(&part)?,
+            written: old_len,
+            expected,
+            report: progress,
+        };
+        io::copy(&mut body, &mut out)?;
+        match expected {
+            Some(expected) if expected != out.written => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{url}: got {} of {expected} bytes", out.written),
+            )),
+            _ => Ok(out.written > old_len),
+        }
+    };
+    match appended() {
+        Ok(true) => Ok(Some(Fetched::Part(part, validators))),
+        r => {
+            let _ = std::fs::remove_file(&part);
+            r.map(|_| None)
+        }
+    }
+}
+
+/// Like [fetch] but unconditional, and a missing file is Ok(None).
+fn fetch_optional(session: &Session, url: &str, dest: &Path) -> io::Result<Option<PathBuf>> {
+    match fetch(
+        session,
+        url,
+        dest,
+        &Validators::default(),
+        false,
+        &|_, _| (),
+    ) {
+        Ok(Fetched::Part(part, _)) => Ok(Some(part)),
+        Ok(Fetched::NotModified) => unreachable!("unconditional request"),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Checks the downloaded db against its downloaded signature, if the SigLevel asks for it.
+#[cfg(feature = "pgp")]
+fn check_signature(
+    config: &PacmanConfig,
+    requirement: SigRequirement,
+    db: &Path,
+    sig: Option<&Path>,
+) -> io::Result<()> {
+    use crate::pgp::{Keyring, Verification};
+    let v = match sig {
+        Some(sig) => {
+            let keyring = Keyring::open(&config.gpg_dir)?;
+            keyring.verify_detached(&std::fs::read(db)?, &std::fs::read(sig)?)?
+        }
+        None => Verification::Missing,
+    };
+    if v.satisfies(requirement) {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("bad signature for {}: {v:?}", db.display()),
+        ))
+    }
+}
+
+#[cfg(not(feature = "pgp"))]
+fn check_signature(
+    _config: &PacmanConfig,
+    requirement: SigRequirement,
+    db: &Path,
+    _sig: Option<&Path>,
+) -> io::Result<()> {
+    match requirement {
+        SigRequirement::Required => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "{} requires a signature, but the pgp feature is disabled",
+                db.display()
+            ),
+        )),
+        _ => {
+            warn!("not checking the signature of {}", db.display());
+            Ok(())
+        }
+    }
+}
+
+/// What refreshing a repo did.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Refreshed {
+    Downloaded,
+    /// The mirror reported the db unchanged, nothing was downloaded.
+    UpToDate,
+}
+
+/// Downloads `<file>` from url and, if signatures are not disabled, `<file>.sig` next to it into sync,
+/// replacing the old ones only if everything arrived and checks out.
+/// Skips the download if the server reports the db unchanged since the last refresh.
+/// With the delta option of session, tries [fetch_delta] first, and downloads the db whole
+/// if that does not work out or the result fails the signature check.
+fn refresh_file(
+    session: &Session,
+    config: &PacmanConfig,
+    repo: &Repo,
+    url: &str,
+    sync: &Path,
+    file: &str,
+    progress: &dyn Fn(u64, Option<u64>),
+) -> io::Result<Refreshed> {
+    let dest = sync.join(file);
+    let sig_dest = sync.join(format!("{file}.sig"));
+    let requirement = repo.sig_level.database.requirement;
+    // Validators without the db they belong to would make the refresh a no-op.
+    let validators = if dest.exists() {
+        Validators::load(&dest)
+    } else {
+        Validators::default()
+    };
+    let mut delta = session.delta;
+    let (db, sig, validators) = loop {
+        let delta_fetched = if delta {
+            fetch_delta(session, url, &dest, &validators, progress)?
+        } else {
+            None
+        };
+        delta = delta_fetched.is_some();
+        let fetched = match delta_fetched {
+            Some(fetched) => fetched,
+            None => fetch(session, url, &dest, &validators, false, progress)?,
+        };
+        let (db, validators) = match fetched {
+            Fetched::Part(db, validators) => (db, validators),
+            Fetched::NotModified => {
+                debug!("{file} is up to date");
+                // Mark the db as checked, for [crate::db::stale_syncdbs].
+                File::options()
+                    .write(true)
+                    .open(&dest)?
+                    .set_modified(std::time::SystemTime::now())?;
+                return Ok(Refreshed::UpToDate);
+            }
+        };
+        let sig = if requirement == SigRequirement::Never {
+            None
+        } else {
+            match fetch_optional(session, &format!("{url}.sig"), &sig_dest) {
+                Ok(sig) => sig,
+                Err(e) => {
+                    let _ = std::fs::remove_file(&db);
+                    return Err(e);
+                }
+            }
+        };
+        let checked = check_signature(config, requirement, &db, sig.as_deref());
+        if let Err(e) = checked {
+            let _ = std::fs::remove_file(&db);
+            if let Some(sig) = &sig {
+                let _ = std::fs::remove_file(sig);
+            }
+            if delta {
+                debug!("{file} put together from a delta failed its check: {e}");
+                delta = false;
+                continue;
+            }
+            return Err(e);
+        }
+        break (db, sig, validators);
+    };
+    match &sig {
+        Some(sig) => std::fs::rename(sig, &sig_dest)?,
+        None => match std::fs::remove_file(&sig_dest) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => (),
+        },
+    }
+    std::fs::rename(&db, &dest)?;
+    validators.store(&dest)?;
+    Ok(Refreshed::Downloaded)
+}
+
+/// Like `pacman -Sy`, or `pacman -Fy` with files:
+/// downloads the db of every repo with Sync usage into `<DBPath>/sync`,
+/// trying its Servers in order. CacheServers are never used for dbs.
+/// Signatures are checked according to the repo's database SigLevel.
+/// Requests are conditional on the ETag and Last-Modified of the previous download,
+/// so unchanged dbs are not downloaded again.
+/// With the delta option, changed dbs that only grew at the end, like uncompressed ones,
+/// have just the new bytes downloaded, others, like the ones repo-add compresses, are downloaded whole.
+/// Holds the [DBLock] while writing, an error locking it is returned right away,
+/// errors of single repos are returned next to them so the other repos still get refreshed.
+/// Mirrors are tried again or in turn following the retry policy of options,
+/// their failures are reported to events, which decides whether to go on.
+/// The progress of each db download is reported to events.
+/// returns (repo, result) in repo order
+pub fn refresh_syncdbs(
+    config: &PacmanConfig,
+    files: bool,
+    options: &DownloadOptions,
+    events: &dyn EventSink,
+) -> io::Result<Vec<(String, io::Result<Refreshed>)>> {
+    let _lock = DBLock::at(&config.db_path)?;
+    let sync = config.db_path.join("sync");
+    std::fs::create_dir_all(&sync)?;
+    let session = Session::new(options)?;
+    let ext = if files { "files" } else { "db" };
+    let mut ret = Vec::new();
+    for repo in config.repos.iter().filter(|r| r.usage.sync) {
+        let file = format!("{}.{ext}", repo.name);
+        let result = refresh_repo(&session, config, repo, &sync, &file, events);
+        ret.push((repo.name.clone(), result));
+    }
+    Ok(ret)
+}
+
+/// [refresh_file] from the Servers of repo with [with_mirrors].
+fn refresh_repo(
+    session: &Session,
+    config: &PacmanConfig,
+    repo: &Repo,
+    sync: &Path,
+    file: &str,
+    events: &dyn EventSink,
+) -> io::Result<Refreshed> {
+    let urls = repo.servers.iter().map(|server| format!("{server}/{file}"));
+    let progress = |received, total| events.download_progress(file, received, total);
+    let failed = |url: &str, e: &io::Error| events.mirror_failed(file, url, e);
+    with_mirrors(&session.retry, file, urls, &failed, |url| {
+        refresh_file(session, config, repo, url, sync, file, &progress)
+    })
+}
+
+/// Every failed try of a file that no mirror delivered,
+/// the [io::Error] of [refresh_syncdbs] and [download_packages] wraps it.
+#[derive(Debug)]
+pub struct MirrorFailures {
+    pub file: String,
+    /// (url, error) in the order they were tried, empty if there were no mirrors.
+    pub failures: Vec<(String, io::Error)>,
+}
+
+impl std::fmt::Display for MirrorFailures {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.failures.is_empty() {
+            return write!(f, "no mirrors to download {} from", self.file);
+        }
+        write!(f, "failed to download {}", self.file)?;
+        for (url, e) in &self.failures {
+            write!(f, "\n  {url}: {e}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for MirrorFailures {}
+
+impl MirrorFailures {
+    /// The failures error is wrapped in, if any.
+    pub fn of(e: &io::Error) -> Option<&Self> {
+        e.get_ref()?.downcast_ref()
+    }
+}
+
+/// Runs attempt on each of urls in order until it works.
+/// A url is tried again after errors policy retries, otherwise the next one is tried.
+/// Each failure is logged and reported to failed, which decides whether to go on.
+/// The error has the kind of the last failure and wraps all of them in [MirrorFailures].
+fn with_mirrors<T>(
+    policy: &RetryPolicy,
+    file: &str,
+    urls: impl IntoIterator<Item = String>,
+    failed: &dyn Fn(&str, &io::Error) -> bool,
+    mut attempt: impl FnMut(&str) -> io::Result<T>,
+) -> io::Result<T> {
+    let mut failures = Vec::new();
+    'mirrors: for url in urls {
+        for n in 0..policy.attempts.max(1) {
+            if n > 0 {
+                std::thread::sleep(policy.backoff * 2u32.saturating_pow(n - 1));
+            }
+            let e = match attempt(&url) {
+                Ok(t) => return Ok(t),
+                Err(e) => e,
+            };
+            warn!("failed to download {file} from {}: {e}", basic_auth(&url).0);
+            let go_on = failed(&url, &e);
+            let retry = policy.retries(&e);
+            failures.push((url.clone(), e));
+            if !go_on {
+                break 'mirrors;
+            }
+            if !retry {
+                break;
+            }
+        }
+    }
+    let kind = failures
+        .last()
+        .map_or(io::ErrorKind::NotFound, |(_, e)| e.kind());
+    let failures = MirrorFailures {
+        file: file.to_owned(),
+        failures,
+    };
+    Err(io::Error::new(kind, failures))
+}
+
+/// Downloads `<repo>.files` for the repos named in repos that do not have one yet,
+/// from the same mirrors and with the same checks as [refresh_syncdbs] with files,
+/// so file searches work without running `pacman -Fy` first.
+/// Files dbs that are present are left alone, even if outdated.
+/// Repos without Sync usage or not in config are skipped.
+/// returns (repo, result) for the repos whose files db was missing
+pub fn fetch_missing_files_dbs(
+    config: &PacmanConfig,
+    repos: &[&str],
+    options: &DownloadOptions,
+    events: &dyn EventSink,
+) -> io::Result<Vec<(String, io::Result<Refreshed>)>> {
+    let sync = config.db_path.join("sync");
+    let missing: Vec<_> = config
+        .repos
+        .iter()
+        .filter(|r| r.usage.sync && repos.contains(&r.name.as_str()))
+        .filter(|r| !sync.join(format!("{}.files", r.name)).exists())
+        .collect();
+    if missing.is_empty() {
+        return Ok(Vec::new());
+    }
+    let _lock = DBLock::at(&config.db_path)?;
+    std::fs::create_dir_all(&sync)?;
+    let session = Session::new(options)?;
+    Ok(missing
+        .into_iter()
+        .map(|repo| {
+            let file = format!("{}.files", repo.name);
+            let result = refresh_repo(&session, config, repo, &sync, &file, events);
+            (repo.name.clone(), result)
+        })
+        .collect())
+}
+
+/// How a mirror did in [rank_mirrors].
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MirrorStats {
+    /// Until the mirror answered a HEAD request of the db, zero for file:// urls.
+    pub latency: Duration,
+    /// Bytes per second while downloading the db.
+    pub throughput: f64,
+}
+
+fn probe(session: &Session, url: &str) -> io::Result<MirrorStats> {
+    let start = Instant::now();
+    let (latency, start, received) = if let Some(path) = url.strip_prefix("file://") {
+        let received = io::copy(&mut File::open(path)?, &mut io::sink())?;
+        (Duration::ZERO, start, received)
+    } else {
+        session.head(url).call().map_err(http_error)?;
+        let latency = start.elapsed();
+        let start = Instant::now();
+        let mut resp = session.get(url).call().map_err(http_error)?;
+        let received = io::copy(&mut resp.body_mut().as_reader(), &mut io::sink())?;
+        (latency, start, received)
+    };
+    // a tiny db on a fast mirror may take no measurable time at all
+    let elapsed = start.elapsed().as_secs_f64().max(1e-6);
+    Ok(MirrorStats {
+        latency,
+        throughput: received as f64 / elapsed,
+    })
+}
+
+/// Like a small reflector: probes every Server of repo by requesting its db,
+/// first with a HEAD for the latency, then downloading it for the throughput.
+/// Nothing is written, the db is only measured.
+/// The proxy and headers of options are used, its rate limits are not, they would only be measured.
+/// returns (server, stats) with the highest throughput first,
+/// followed by the mirrors that failed in config order.
+pub fn rank_mirrors(
+    repo: &Repo,
+    options: &DownloadOptions,
+) -> io::Result<Vec<(String, io::Result<MirrorStats>)>> {
+    let session = Session::new(options)?;
+    let mut ret: Vec<_> = repo
+        .servers
+        .iter()
+        .map(|server| {
+            let stats = probe(&session, &format!("{server}/{}.db", repo.name));
+            if let Err(e) = &stats {
+                warn!("mirror {server} is unhealthy: {e}");
+            }
+            (server.clone(), stats)
+        })
+        .collect();
+    ret.sort_by(|(_, a), (_, b)| match (a, b) {
+        (Ok(a), Ok(b)) => b.throughput.total_cmp(&a.throughput),
+        (Ok(_), Err(_)) => std::cmp::Ordering::Less,
+        (Err(_), Ok(_)) => std::cmp::Ordering::Greater,
+        (Err(_), Err(_)) => std::cmp::Ordering::Equal,
+    });
+    Ok(ret)
+}
+
+/// Writes the servers ranked by [rank_mirrors] of repo as a mirrorlist to path,
+/// replacing it atomically. Failed mirrors are kept commented out.
+/// Path segments that are the repo name or arch become $repo and $arch again,
+/// so the mirrorlist can be included by other repos too.
+pub fn write_mirrorlist(
+    path: &Path,
+    repo: &str,
+    arch: &str,
+    ranked: &[(String, io::Result<MirrorStats>)],
+) -> io::Result<()> {
+    let template = |server: &str| {
+        // the host is never templated
+        let (scheme, rest) = match server.split_once("://") {
+            Some((scheme, rest)) => (format!("{scheme}://"), rest),
+            None => (String::new(), server),
+        };
+        let mut segments = rest.split('/');
+        let host = segments.next().unwrap_or_default();
+        let path = segments.map(|s| match s {
+            s if s == repo => "$repo",
+            s if s == arch => "$arch",
+            s => s,
+        });
+        let path: Vec<_> = std::iter::once(host).chain(path).collect();
+        format!("{scheme}{}", path.join("/"))
+    };
+    let mut s = String::new();
+    for (server, stats) in ranked {
+        match stats {
+            Ok(_) => s.push_str(&format!("Server = {}\n", template(server))),
+            Err(e) => s.push_str(&format!("# {e}\n#Server = {}\n", template(server))),
+        }
+    }
+    crate::util::replace(path, |mut f| f.write_all(s.as_bytes()))
+}
+
+/// A downloaded package that does not match its sync db entry,
+/// the [io::Error] of [download_packages] wraps it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChecksumMismatch {
+    pub file: String,
+    /// The mirror the file came from.
+    pub url: String,
+    /// What did not match: "size", "sha256" or "md5".
+    pub checksum: &'static str,
+}
+
+impl std::fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} from {}: {} does not match the sync db",
+            self.file, self.url, self.checksum
+        )
+    }
+}
+
+impl std::error::Error for ChecksumMismatch {}
+
+impl ChecksumMismatch {
+    /// The mismatch error is wrapped in, if any,
+    /// through [MirrorFailures] the one of the last try.
+    pub fn of(e: &io::Error) -> Option<&Self> {
+        if let Some(failures) = MirrorFailures::of(e) {
+            return Self::of(&failures.failures.last()?.1);
+        }
+        e.get_ref()?.downcast_ref()
+    }
+}
+
+/// Checks a downloaded package against the size and checksum its sync db announced, if any.
+/// Like pacman, md5 is only checked for packages without sha256.
+fn verify_package(part: &Path, job: &PackageJob, url: &str) -> io::Result<()> {
+    use crate::util::digest_matches;
+    let len = std::fs::metadata(part)?.len();
+    let mismatch = |checksum| {
+        let mismatch = ChecksumMismatch {
+            file: job.file.clone(),
+            url: url.to_owned(),
+            checksum,
+        };
+        Err(io::Error::new(io::ErrorKind::InvalidData, mismatch))
+    };
+    if job.size.is_some_and(|size| size != len) {
+        return mismatch("size");
+    }
+    match (&job.sha256sum, &job.md5sum) {
+        (Some(sha256sum), _) if !digest_matches::<sha2::Sha256>(sha256sum, part)? => {
+            mismatch("sha256")
+        }
+        (None, Some(md5sum)) if !digest_matches::<md5::Md5>(md5sum, part)? => mismatch("md5"),
+        _ => Ok(()),
+    }
+}
+
+/// A package file to download, with what its sync db says about it.
+struct PackageJob<'c> {
+    repo: &'c Repo,
+    file: String,
+    size: Option<u64>,
+    sha256sum: Option<[u8; 48]>,
+    md5sum: Option<[u8; 24]>,
+}
+
+/// Downloads `<file>` of job from the urls of its repo into `<cachedir>/<file>`
+/// with [with_mirrors], reporting each failure to failed.
+/// A `.part` file left over from an earlier, interrupted try is resumed,
+/// it only gets renamed once size and sha256sum, if known, match.
+/// A part file that does not match is removed, so the next mirror starts over.
+fn download_package(
+    session: &Session,
+    cachedir: &Path,
+    job: &PackageJob,
+    progress: &dyn Fn(u64, Option<u64>),
+    failed: &dyn Fn(&str, &io::Error) -> bool,
+) -> io::Result<PathBuf> {
+    let PackageJob { repo, file, .. } = job;
+    let dest = cachedir.join(file);
+    let urls = repo.urls().map(|server| format!("{server}/{file}"));
+    let part = with_mirrors(&session.retry, file, urls, failed, |url| {
+        match fetch(session, url, &dest, &Validators::default(), true, progress)? {
+            Fetched::Part(part, _) => {
+                let verified = verify_package(&part, job, url);
+                if verified.is_err() {
+                    let _ = std::fs::remove_file(&part);
+                }
+                verified.map(|()| part)
+            }
+            Fetched::NotModified => unreachable!("unconditional request"),
+        }
+    })?;
+    std::fs::rename(part, &dest)?;
+    Ok(dest)
+}
+
+/// Like the download step of `pacman -Su`: makes the new package of every
+/// (repo, old, new) in upgrades, as returned by update_candidates, available in a cache dir.
+/// Packages already in cachedir or one of the CacheDirs are not downloaded again,
+/// the others are tried from the repo's CacheServers, then its Servers,
+/// and moved into cachedir once complete and matching their sync db entry.
+/// Interrupted downloads are resumed from their `.part` file on the next call.
+/// Mirrors that fail, by missing the file, timing out or sending a corrupt one,
+/// are reported to events, which decides whether to go on,
+/// and tried again or in turn following the retry policy of options.
+/// A package no mirror delivered has an error wrapping the [MirrorFailures],
+/// a [ChecksumMismatch] of the last mirror can be gotten from it directly.
+/// Up to ParallelDownloads packages are downloaded at once, reporting to events.
+/// The rate limit of options is shared by all of them.
+/// Errors of single packages are returned next to them so the others still get downloaded.
+/// returns (filename, path in the cache) in the order of upgrades
+pub fn download_packages(
+    i: &Interner,
+    upgrades: &[(&str, Package, Package)],
+    config: &PacmanConfig,
+    cachedir: &Path,
+    options: &DownloadOptions,
+    events: &dyn EventSink,
+) -> io::Result<Vec<(String, io::Result<PathBuf>)>> {
+    std::fs::create_dir_all(cachedir)?;
+    let session = Session::new(options)?;
+    let cache_dirs: Vec<PathBuf> = std::iter::once(cachedir.to_owned())
+        .chain(config.cache_dirs.iter().cloned())
+        .collect();
+    let mut ret = Vec::new();
+    // (index into ret, job)
+    let mut jobs = Vec::new();
+    for (repo, _, new) in upgrades {
+        let Some(file) = new.filename.map(|f| f.r(&i.borrow()).to_owned()) else {
+            let name = new.name.r(&i.borrow()).to_owned();
+            let e = io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{name} has no filename"),
+            );
+            ret.push((name, Err(e)));
+            continue;
+        };
+        let result = if let Some(cached) = crate::find_cached(&cache_dirs, &file) {
+            Ok(cached)
+        } else if let Some(repo) = config.repo(repo) {
+            let job = PackageJob {
+                repo,
+                file: file.clone(),
+                size: new.csize,
+                sha256sum: new.sha256sum,
+                md5sum: new.md5sum,
+            };
+            jobs.push((ret.len(), job));
+            // replaced by the download
+            Ok(PathBuf::new())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{repo} is not configured"),
+            ))
+        };
+        ret.push((file, result));
+    }
+
+    let total: u64 = jobs.iter().filter_map(|(_, job)| job.size).sum();
+    let received: Vec<AtomicU64> = jobs.iter().map(|_| AtomicU64::new(0)).collect();
+    let queue = Mutex::new(jobs.iter().enumerate());
+    let results = Mutex::new(Vec::new());
+    let done = AtomicUsize::new(0);
+    let threads = (config.parallel_downloads.max(1) as usize).min(jobs.len());
+    std::thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| {
+                loop {
+                    // in its own statement, so the lock is not held during the download
+                    let next = queue.lock().unwrap().next();
+                    let Some((n, (index, job))) = next else {
+                        break;
+                    };
+                    let report = |bytes, file_total| {
+                        events.download_progress(&job.file, bytes, file_total);
+                        received[n].store(bytes, Ordering::Relaxed);
+                        let sum = received.iter().map(|r| r.load(Ordering::Relaxed)).sum();
+                        events.download_aggregate(sum, total);
+                    };
+                    let failed = |url: &str, e: &io::Error| events.mirror_failed(&job.file, url, e);
+                    let result = download_package(&session, cachedir, job, &report, &failed);
+                    let done = done.fetch_add(1, Ordering::Relaxed) + 1;
+                    events.verify_progress(&job.file, done, jobs.len());
+                    results.lock().unwrap().push((*index, result));
+                }
+            });
+        }
+    });
+    for (index, result) in results.into_inner().unwrap() {
+        ret[index].1 = result;
+    }
+    Ok(ret)
+}
+
+/// Serves files over HTTP on localhost, one request per connection, until the test ends.
+/// Each file has an ETag, requests with a matching If-None-Match get a 304.
+/// Range requests of the form `bytes=<start>-` get a 206 with the rest of the file.
+/// Files under /private/ need the basic auth of user:pass, or get a 401.
+/// Files under /flaky/ get a 503 on their first request.
+/// returns the base url
+#[cfg(test)]
+pub(crate) fn test_server(files: std::collections::HashMap<String, Vec<u8>>) -> String {
+    use std::io::{BufRead, Write};
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    std::thread::spawn(move || {
+        let mut flaked = std::collections::HashSet::new();
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = io::BufReader::new(&stream);
+            let mut request = String::new();
+            reader.read_line(&mut request).unwrap();
+            let mut if_none_match = None;
+            let mut authorization = None;
+            let mut start = 0;
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                if let Some((k, v)) = line.trim_end().split_once(": ") {
+                    if k.eq_ignore_ascii_case("If-None-Match") {
+                        if_none_match = Some(v.to_owned());
+                    } else if k.eq_ignore_ascii_case("Authorization") {
+                        authorization = Some(v.to_owned());
+                    } else if k.eq_ignore_ascii_case("Range") {
+                        let range = v.strip_prefix("bytes=").and_then(|r| r.strip_suffix('-'));
+                        start = range.unwrap().parse().unwrap();
+                    }
+                }
+                line.clear();
+            }
+            let path = request.split(' ').nth(1).unwrap_or("/");
+            let response = match files.get(path) {
+                Some(_)
+                    if path.starts_with("/private/")
+                        && authorization.as_deref() != Some("Basic dXNlcjpwYXNz") =>
+                {
+                    b"HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                        .to_vec()
+                }
+                Some(_) if path.starts_with("/flaky/") && flaked.insert(path.to_owned()) => {
+                    b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                        .to_vec()
+                }
+                Some(body) if start > 0 && start >= body.len() => {
+                    b"HTTP/1.1 416 Range Not Satisfiable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                        .to_vec()
+                }
+                Some(body) => {
+                    let etag = format!("\"{}-{}\"", body.len(), body.first().unwrap_or(&0));
+                    let status = if if_none_match.as_ref() == Some(&etag) {
+                        "304 Not Modified"
+                    } else if start > 0 {
+                        "206 Partial Content"
+                    } else {
+                        "200 OK"
+                    };
+                    let mut r = format!("HTTP/1.1 {status}\r\nETag: {etag}\r\n").into_bytes();
+                    let body = &body[start..];
+                    if status != "304 Not Modified" {
+                        r.extend(format!("Content-Length: {}\r\n", body.len()).bytes());
+                    }
+                    r.extend_from_slice(b"Connection: close\r\n\r\n");
+                    if status != "304 Not Modified" {
+                        r.extend_from_slice(body);
+                    }
+                    r
+                }
+                None => b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    .to_vec(),
+            };
+            let _ = stream.write_all(&response);
+        }
+    });
+    url
+}
+
+#[test]
+fn test_refresh_syncdbs() {
+    let dir = crate::util::test_dir("refresh_syncdbs");
+    let mirror = dir.join("mirror");
+    std::fs::create_dir_all(&mirror).unwrap();
+    std::fs::write(mirror.join("core.db"), b"core db").unwrap();
+    let url = test_server(
+        [
+            ("/extra.db".to_owned(), b"extra db".to_vec()),
+            ("/extra.files".to_owned(), b"extra files".to_vec()),
+        ]
+        .into(),
+    );
+    let dbpath = dir.join("db");
+    std::fs::create_dir_all(&dbpath).unwrap();
+    let config = crate::config::test_config(&format!(
+        "[options]\nDBPath = {}\nSigLevel = Never\n\
+        [core]\nServer = file://{}\n\
+        [extra]\nServer = {url}/missing\nServer = {url}\n\
+        [gone]\nServer = {url}/gone\n",
+        dbpath.display(),
+        mirror.display(),
+    ));
+    let results = refresh_syncdbs(
+        &config,
+        false,
+        &Default::default(),
+        &crate::events::NoEvents,
+    )
+    .unwrap();
+    let names: Vec<_> = results.iter().map(|(n, _)| n.as_str()).collect();
+    assert_eq!(names, ["core", "extra", "gone"]);
+    assert!(results[0].1.is_ok());
+    assert!(results[1].1.is_ok());
+    assert_eq!(
+        results[2].1.as_ref().unwrap_err().kind(),
+        io::ErrorKind::NotFound
+    );
+    let sync = dbpath.join("sync");
+    assert_eq!(std::fs::read(sync.join("core.db")).unwrap(), b"core db");
+    assert_eq!(std::fs::read(sync.join("extra.db")).unwrap(), b"extra db");
+    assert!(!sync.join("gone.db").exists());
+    assert!(!sync.join("gone.db.part").exists());
+
+    let results =
+        refresh_syncdbs(&config, true, &Default::default(), &crate::events::NoEvents).unwrap();
+    assert!(results[1].1.is_ok());
+    assert_eq!(
+        std::fs::read(sync.join("extra.files")).unwrap(),
+        b"extra files"
+    );
+
+    let _lock = DBLock::at(&dbpath).unwrap();
+    assert!(
+        refresh_syncdbs(
+            &config,
+            false,
+            &Default::default(),
+            &crate::events::NoEvents
+        )
+        .is_err()
+    );
+}
+
+#[test]
+fn test_fetch_missing_files_dbs() {
+    let dir = crate::util::test_dir("fetch_missing_files_dbs");
+    let mirror = dir.join("mirror");
+    std::fs::create_dir_all(&mirror).unwrap();
+    std::fs::write(mirror.join("core.files"), b"core files").unwrap();
+    std::fs::write(mirror.join("extra.files"), b"extra files").unwrap();
+    let dbpath = dir.join("db");
+    std::fs::create_dir_all(dbpath.join("sync")).unwrap();
+    std::fs::write(dbpath.join("sync/extra.files"), b"old extra files").unwrap();
+    let config = crate::config::test_config(&format!(
+        "[options]\nDBPath = {}\nSigLevel = Never\n\
+        [core]\nServer = file://{mirror}\n[extra]\nServer = file://{mirror}\n\
+        [other]\nServer = file://{mirror}\n",
+        dbpath.display(),
+        mirror = mirror.display(),
+    ));
+    let events = crate::events::NoEvents;
+    let results =
+        fetch_missing_files_dbs(&config, &["core", "extra"], &Default::default(), &events).unwrap();
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].0, "core");
+    assert_eq!(*results[0].1.as_ref().unwrap(), Refreshed::Downloaded);
+    let sync = dbpath.join("sync");
+    assert_eq!(
+        std::fs::read(sync.join("core.files")).unwrap(),
+        b"core files"
+    );
+    assert_eq!(
+        std::fs::read(sync.join("extra.files")).unwrap(),
+        b"old extra files"
+    );
+    assert!(!sync.join("other.files").exists());
+    let again =
+        fetch_missing_files_dbs(&config, &["core", "extra"], &Default::default(), &events).unwrap();
+    assert!(again.is_empty());
+}
+
+#[test]
+fn test_conditional_refresh() {
+    let dir = crate::util::test_dir("conditional_refresh");
+    let url = test_server([("/core.db".to_owned(), b"core db".to_vec())].into());
+    let dbpath = dir.join("db");
+    std::fs::create_dir_all(&dbpath).unwrap();
+    let config = crate::config::test_config(&format!(
+        "[options]\nDBPath = {}\nSigLevel = Never\n[core]\nServer = {url}\n",
+        dbpath.display(),
+    ));
+    let refresh = || {
+        refresh_syncdbs(
+            &config,
+            false,
+            &Default::default(),
+            &crate::events::NoEvents,
+        )
+        .unwrap()
+        .remove(0)
+        .1
+        .unwrap()
+    };
+    assert_eq!(refresh(), Refreshed::Downloaded);
+    let validators = Validators::load(&dbpath.join("sync/core.db"));
+    assert_eq!(validators.etag.as_deref(), Some("\"7-99\""));
+    assert_eq!(refresh(), Refreshed::UpToDate);
+
+    // A db that went missing is downloaded again.
+    std::fs::remove_file(dbpath.join("sync/core.db")).unwrap();
+    assert_eq!(refresh(), Refreshed::Downloaded);
+    assert_eq!(
+        std::fs::read(dbpath.join("sync/core.db")).unwrap(),
+        b"core db"
+    );
+}
+
+#[test]
+fn test_delta_refresh() {
+    let dir = crate::util::test_dir("delta_refresh");
+    let dbpath = dir.join("db");
+    std::fs::create_dir_all(&dbpath).unwrap();
+    struct Record(Mutex<Vec<u64>>);
+    impl EventSink for Record {
+        fn download_progress(&self, _file: &str, received: u64, _total: Option<u64>) {
+            self.0.lock().unwrap().push(received);
+        }
+    }
+    let options = DownloadOptions {
+        delta: true,
+        ..Default::default()
+    };
+    // returns the smallest progress reported
+    let refresh = |db: &[u8]| {
+        let url = test_server([("/core.db".to_owned(), db.to_vec())].into());
+        let config = crate::config::test_config(&format!(
+            "[options]\nDBPath = {}\nSigLevel = Never\n[core]\nServer = {url}\n",
+            dbpath.display(),
+        ));
+        let events = Record(Mutex::new(Vec::new()));
+        let refreshed = refresh_syncdbs(&config, false, &options, &events).unwrap();
+        assert_eq!(*refreshed[0].1.as_ref().unwrap(), Refreshed::Downloaded);
+        assert_eq!(std::fs::read(dbpath.join("sync/core.db")).unwrap(), db);
+        events.0.into_inner().unwrap().into_iter().min().unwrap()
+    };
+    let old: Vec<u8> = (0..20_000u32).map(|n| (n % 251) as u8).collect();
+    refresh(&old);
+
+    // only the appended bytes arrive
+    let mut appended = old.clone();
+    appended.extend_from_slice(&[7; 1000]);
+    assert!(refresh(&appended) > old.len() as u64);
+
+    // a rewritten db is downloaded whole
+    let mut rewritten = appended.clone();
+    rewritten[appended.len() - 1500] = 0xff;
+    rewritten.push(1);
+    assert!(refresh(&rewritten) < old.len() as u64);
+}
+
+#[cfg(not(feature = "pgp"))]
+#[test]
+fn test_refresh_requires_pgp() {
+    let dir = crate::util::test_dir("refresh_requires_pgp");
+    std::fs::write(dir.join("core.db"), b"core db").unwrap();
+    let config = crate::config::test_config(&format!(
+        "[options]\nDBPath = {}\n[core]\nSigLevel = DatabaseRequired\nServer = file://{}\n",
+        dir.join("db").display(),
+        dir.display(),
+    ));
+    std::fs::create_dir_all(dir.join("db")).unwrap();
+    let results = refresh_syncdbs(
+        &config,
+        false,
+        &Default::default(),
+        &crate::events::NoEvents,
+    )
+    .unwrap();
+    let e = results[0].1.as_ref().unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::Unsupported);
+    assert!(!dir.join("db/sync/core.db").exists());
+}
+
+#[cfg(feature = "pgp")]
+#[test]
+fn test_refresh_checks_signature() {
+    use crate::pgp::{test_cert, test_keyring, test_sign};
+    let dir = crate::util::test_dir("refresh_checks_signature");
+    let packager = test_cert("packager");
+    let gpgdir = dir.join("gnupg");
+    std::fs::create_dir_all(&gpgdir).unwrap();
+    std::fs::write(gpgdir.join("pubring.gpg"), test_keyring(&[&packager])).unwrap();
+    let url = test_server(
+        [
+            ("/core.db".to_owned(), b"core db".to_vec()),
+            ("/core.db.sig".to_owned(), test_sign(&packager, b"core db")),
+            ("/extra.db".to_owned(), b"extra db".to_vec()),
+            ("/extra.db.sig".to_owned(), test_sign(&packager, b"other")),
+        ]
+        .into(),
+    );
+    let dbpath = dir.join("db");
+    std::fs::create_dir_all(&dbpath).unwrap();
+    let config = crate::config::test_config(&format!(
+        "[options]\nDBPath = {}\nGPGDir = {}\nSigLevel = DatabaseRequired\n\
+        [core]\nServer = {url}\n[extra]\nServer = {url}\n",
+        dbpath.display(),
+        gpgdir.display(),
+    ));
+    let results = refresh_syncdbs(
+        &config,
+        false,
+        &Default::default(),
+        &crate::events::NoEvents,
+    )
+    .unwrap();
+    assert!(results[0].1.is_ok(), "{:?}", results[0].1);
+    assert!(results[1].1.is_err());
+    let sync = dbpath.join("sync");
+    assert!(sync.join("core.db.sig").exists());
+    assert!(!sync.join("extra.db").exists());
+    assert!(!sync.join("extra.db.sig").exists());
+}
+
+#[test]
+fn test_download_packages() {
+    use crate::db::{new_interner, test_desc};
+    let dir = crate::util::test_dir("download_packages");
+    let url = test_server(
+        [
+            (
+                "/core/foo-1-1-x86_64.pkg.tar.zst".to_owned(),
+                b"foo".to_vec(),
+            ),
+            (
+                "/core/bar-1-1-x86_64.pkg.tar.zst".to_owned(),
+                b"bar".to_vec(),
+            ),
+        ]
+        .into(),
+    );
+    let cachedir = dir.join("cache");
+    std::fs::create_dir_all(&cachedir).unwrap();
+    std::fs::write(cachedir.join("bar-1-1-x86_64.pkg.tar.zst"), b"cached").unwrap();
+    let config = crate::config::test_config(&format!(
+        "[options]\nCacheDir = {}\n\
+        ParallelDownloads = 2\n\
+        [core]\nCacheServer = {url}/missing\nServer = {url}/core\n",
+        cachedir.display(),
+    ));
+    let i = new_interner();
+    let pkg = |name: &str| {
+        let file = format!("{name}-1-1-x86_64.pkg.tar.zst");
+        let desc = test_desc(name, "1-1", &[("FILENAME", &file), ("CSIZE", "3")]);
+        Package::from_str(i.clone(), &desc).unwrap()
+    };
+    let old = pkg("old");
+    let upgrades = [
+        ("core", old.clone(), pkg("foo")),
+        ("core", old.clone(), pkg("bar")),
+        ("core", old.clone(), pkg("baz")),
+        ("extra", old, pkg("qux")),
+    ];
+    struct Record(Mutex<Vec<(u64, u64)>>, Mutex<Vec<String>>);
+    impl EventSink for Record {
+        fn download_aggregate(&self, received: u64, total: u64) {
+            self.0.lock().unwrap().push((received, total));
+        }
+
+        fn mirror_failed(&self, _file: &str, url: &str, error: &io::Error) -> bool {
+            assert_eq!(error.kind(), io::ErrorKind::NotFound);
+            self.1.lock().unwrap().push(url.to_owned());
+            true
+        }
+    }
+    let record = Record(Mutex::new(Vec::new()), Mutex::new(Vec::new()));
+    let results = download_packages(
+        &i,
+        &upgrades,
+        &config,
+        &cachedir,
+        &Default::default(),
+        &record,
+    )
+    .unwrap();
+    let foo = cachedir.join("foo-1-1-x86_64.pkg.tar.zst");
+    assert_eq!(results[0].1.as_ref().unwrap(), &foo);
+    assert_eq!(std::fs::read(&foo).unwrap(), b"foo");
+    assert!(!part_path(&foo).exists());
+    let bar = std::fs::read(results[1].1.as_ref().unwrap()).unwrap();
+    assert_eq!(bar, b"cached");
+    assert_eq!(results[2].0, "baz-1-1-x86_64.pkg.tar.zst");
+    assert_eq!(
+        results[2].1.as_ref().unwrap_err().kind(),
+        io::ErrorKind::NotFound
+    );
+    assert!(!cachedir.join("baz-1-1-x86_64.pkg.tar.zst.part").exists());
+    assert!(results[3].1.is_err());
+    // foo and baz are tried from both mirrors
+    let mut failed = record.1.into_inner().unwrap();
+    failed.sort();
+    assert_eq!(
+        failed,
+        [
+            format!("{url}/core/baz-1-1-x86_64.pkg.tar.zst"),
+            format!("{url}/missing/baz-1-1-x86_64.pkg.tar.zst"),
+            format!("{url}/missing/foo-1-1-x86_64.pkg.tar.zst"),
+        ]
+    );
+    // foo and baz are downloaded, and baz never arrives
+    let record = record.0.into_inner().unwrap();
+    assert!(record.iter().all(|(_, total)| *total == 6));
+    assert_eq!(record.iter().map(|(r, _)| *r).max(), Some(3));
+}
+
+#[test]
+fn test_resume_download() {
+    use crate::db::{new_interner, test_desc};
+    let dir = crate::util::test_dir("resume_download");
+    let files = ["foo", "bar", "baz"].map(|n| format!("{n}-1-1-x86_64.pkg.tar.zst"));
+    let url = test_server(
+        files
+            .iter()
+            .map(|f| (format!("/core/{f}"), b"package contents".to_vec()))
+            .collect(),
+    );
+    let cachedir = dir.join("cache");
+    std::fs::create_dir_all(&cachedir).unwrap();
+    let part = |f: &str| part_path(&cachedir.join(f));
+    // interrupted, already complete, and from a different file
+    std::fs::write(part(&files[0]), b"package").unwrap();
+    std::fs::write(part(&files[1]), b"package contents").unwrap();
+    std::fs::write(part(&files[2]), b"garbage").unwrap();
+    let config = crate::config::test_config(&format!(
+        "[options]\nCacheDir = {}\n[core]\nServer = {url}/core\n",
+        cachedir.display(),
+    ));
+    let i = new_interner();
+    // sha256 of "package contents"
+    let sha256 = "b9e2b98ba957e07c86e3bdab8f9d3bc4d15d4fd29ed0d02824af172924c0b651";
+    let upgrades = files.clone().map(|file| {
+        let name = file.split('-').next().unwrap();
+        let desc = test_desc(
+            name,
+            "1-1",
+            &[("FILENAME", &file), ("CSIZE", "16"), ("SHA256SUM", sha256)],
+        );
+        let pkg = Package::from_str(i.clone(), &desc).unwrap();
+        ("core", pkg.clone(), pkg)
+    });
+    let results = download_packages(
+        &i,
+        &upgrades,
+        &config,
+        &cachedir,
+        &Default::default(),
+        &crate::events::NoEvents,
+    )
+    .unwrap();
+    for (_, result) in &results[..2] {
+        assert_eq!(
+            std::fs::read(result.as_ref().unwrap()).unwrap(),
+            b"package contents"
+        );
+    }
+    let e = results[2].1.as_ref().unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    let mismatch = ChecksumMismatch::of(e).unwrap();
+    assert_eq!(mismatch.url, format!("{url}/core/{}", files[2]));
+    assert_eq!(mismatch.checksum, "sha256");
+    assert!(files.iter().all(|f| !part(f).exists()));
+}
+
+#[test]
+fn test_rank_mirrors() {
+    let dir = crate::util::test_dir("rank_mirrors");
+    let url = test_server([("/core/os/x86_64/core.db".to_owned(), b"core db".to_vec())].into());
+    let local = dir.join("local");
+    std::fs::create_dir_all(local.join("core/os/x86_64")).unwrap();
+    std::fs::write(local.join("core/os/x86_64/core.db"), b"core db").unwrap();
+    let config = crate::config::test_config(&format!(
+        "[options]\nArchitecture = x86_64\n[core]\n\
+        Server = {url}/missing/$repo/os/$arch\n\
+        Server = {url}/$repo/os/$arch\n\
+        Server = file://{}/$repo/os/$arch\n",
+        local.display(),
+    ));
+    let ranked = rank_mirrors(config.repo("core").unwrap(), &Default::default()).unwrap();
+    assert_eq!(ranked.len(), 3);
+    assert!(ranked[..2].iter().all(|(_, stats)| stats.is_ok()));
+    assert_eq!(ranked[2].0, format!("{url}/missing/core/os/x86_64"));
+    assert_eq!(
+        ranked[2].1.as_ref().unwrap_err().kind(),
+        io::ErrorKind::NotFound
+    );
+    let http = ranked.iter().find(|(s, _)| s.starts_with(&url)).unwrap();
+    assert!(http.1.as_ref().unwrap().latency > Duration::ZERO);
+
+    let mirrorlist = dir.join("mirrorlist");
+    write_mirrorlist(&mirrorlist, "core", "x86_64", &ranked).unwrap();
+    let written = std::fs::read_to_string(&mirrorlist).unwrap();
+    let servers: Vec<_> = written.lines().filter(|l| !l.starts_with("# ")).collect();
+    assert!(servers.contains(&format!("Server = {url}/$repo/os/$arch").as_str()));
+    assert_eq!(
+        servers[2],
+        format!("#Server = {url}/missing/$repo/os/$arch")
+    );
+}
+
+#[test]
+fn test_checksum_mismatch() {
+    use crate::db::{new_interner, test_desc};
+    let dir = crate::util::test_dir("checksum_mismatch");
+    let file = "foo-1-1-x86_64.pkg.tar.zst";
+    let url = test_server(
+        [
+            (format!("/bad/{file}"), b"package contentz".to_vec()),
+            (format!("/good/{file}"), b"package contents".to_vec()),
+        ]
+        .into(),
+    );
+    let cachedir = dir.join("cache");
+    let config = crate::config::test_config(&format!(
+        "[options]\nCacheDir = {}\n[core]\nServer = {url}/bad\nServer = {url}/good\n",
+        cachedir.display(),
+    ));
+    let i = new_interner();
+    // md5 of "package contents", without a sha256
+    let desc = test_desc(
+        "foo",
+        "1-1",
+        &[
+            ("FILENAME", file),
+            ("MD5SUM", "9c72341d2c43306fc84cae343f2fc023"),
+        ],
+    );
+    let pkg = Package::from_str(i.clone(), &desc).unwrap();
+    let upgrades = [("core", pkg.clone(), pkg)];
+
+    struct GiveUp;
+    impl EventSink for GiveUp {
+        fn mirror_failed(&self, _file: &str, _url: &str, _error: &io::Error) -> bool {
+            false
+        }
+    }
+    let results = download_packages(
+        &i,
+        &upgrades,
+        &config,
+        &cachedir,
+        &Default::default(),
+        &GiveUp,
+    )
+    .unwrap();
+    let e = results[0].1.as_ref().unwrap_err();
+    let mismatch = ChecksumMismatch::of(e).unwrap();
+    assert_eq!(mismatch.url, format!("{url}/bad/{file}"));
+    assert_eq!(mismatch.checksum, "md5");
+
+    let results = download_packages(
+        &i,
+        &upgrades,
+        &config,
+        &cachedir,
+        &Default::default(),
+        &crate::events::NoEvents,
+    )
+    .unwrap();
+    let path = results[0].1.as_ref().unwrap();
+    assert_eq!(std::fs::read(path).unwrap(), b"package contents");
+}
+
+#[test]
+fn test_throttle() {
+    let mut throttle = Throttle::new(1000);
+    let wait = throttle.take(500);
+    assert!(wait > Duration::from_millis(450) && wait <= Duration::from_millis(500));
+    std::thread::sleep(Duration::from_millis(600));
+    // only the 100ms after the debt was paid off count
+    assert_eq!(throttle.take(100), Duration::ZERO);
+    assert!(throttle.take(100) > Duration::ZERO);
+}
+
+#[test]
+fn test_rate_limit() {
+    use crate::db::{new_interner, test_desc};
+    let dir = crate::util::test_dir("rate_limit");
+    let files = ["foo", "bar"].map(|n| format!("{n}-1-1-x86_64.pkg.tar.zst"));
+    let url = test_server(
+        files
+            .iter()
+            .map(|f| (format!("/core/{f}"), vec![0; 20_000]))
+            .collect(),
+    );
+    let cachedir = dir.join("cache");
+    let config = crate::config::test_config(&format!(
+        "[options]\nCacheDir = {}\nParallelDownloads = 2\n[core]\nServer = {url}/core\n",
+        cachedir.display(),
+    ));
+    let i = new_interner();
+    let upgrades = files.clone().map(|file| {
+        let name = file.split('-').next().unwrap();
+        let desc = test_desc(name, "1-1", &[("FILENAME", &file)]);
+        let pkg = Package::from_str(i.clone(), &desc).unwrap();
+        ("core", pkg.clone(), pkg)
+    });
+    let options = DownloadOptions {
+        rate_limit: Some(80_000),
+        ..Default::default()
+    };
+    let start = Instant::now();
+    let results = download_packages(
+        &i,
+        &upgrades,
+        &config,
+        &cachedir,
+        &options,
+        &crate::events::NoEvents,
+    )
+    .unwrap();
+    assert!(results.iter().all(|(_, r)| r.is_ok()));
+    // both share the 80kB/s, so the 40kB take half a second
+    assert!(start.elapsed() >= Duration::from_millis(400));
+}
+
+#[test]
+fn test_parallel_downloads() {
+    use crate::db::{new_interner, test_desc};
+    use std::io::{BufRead, Write};
+    let dir = crate::util::test_dir("parallel_downloads");
+    let files = ["foo", "bar"].map(|n| format!("{n}-1-1-x86_64.pkg.tar.zst"));
+    // answers only once both requests are in, so serialized downloads get a 404
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    std::thread::spawn(move || {
+        listener.set_nonblocking(true).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut streams = Vec::new();
+        while streams.len() < 2 && Instant::now() < deadline {
+            match listener.accept() {
+                Ok((stream, _)) => streams.push(stream),
+                Err(_) => std::thread::sleep(Duration::from_millis(10)),
+            }
+        }
+        let status = if streams.len() == 2 {
+            "200 OK"
+        } else {
+            "404 Not Found"
+        };
+        for mut stream in streams {
+            stream.set_nonblocking(false).unwrap();
+            let mut reader = io::BufReader::new(&stream);
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            let r =
+                format!("HTTP/1.1 {status}\r\nContent-Length: 4\r\nConnection: close\r\n\r\npkg!");
+            stream.write_all(r.as_bytes()).unwrap();
+        }
+    });
+    let cachedir = dir.join("cache");
+    let config = crate::config::test_config(&format!(
+        "[options]\nCacheDir = {}\nParallelDownloads = 2\n[core]\nServer = {url}/core\n",
+        cachedir.display(),
+    ));
+    let i = new_interner();
+    let upgrades = files.clone().map(|file| {
+        let name = file.split('-').next().unwrap();
+        let desc = test_desc(name, "1-1", &[("FILENAME", &file)]);
+        let pkg = Package::from_str(i.clone(), &desc).unwrap();
+        ("core", pkg.clone(), pkg)
+    });
+    let results = download_packages(
+        &i,
+        &upgrades,
+        &config,
+        &cachedir,
+        &Default::default(),
+        &crate::events::NoEvents,
+    )
+    .unwrap();
+    for (_, r) in results {
+        assert_eq!(std::fs::read(r.unwrap()).unwrap(), b"pkg!");
+    }
+}
+
+#[test]
+fn test_auth() {
+    let dir = crate::util::test_dir("auth");
+    let url = test_server([("/private/core.db".to_owned(), b"core db".to_vec())].into());
+    let dbpath = dir.join("db");
+    std::fs::create_dir_all(&dbpath).unwrap();
+    let config = |server: &str| {
+        crate::config::test_config(&format!(
+            "[options]\nDBPath = {}\nSigLevel = Never\n[core]\nServer = {server}\n",
+            dbpath.display(),
+        ))
+    };
+    let refresh = |config: &PacmanConfig, options: &DownloadOptions| {
+        refresh_syncdbs(config, false, options, &crate::events::NoEvents)
+            .unwrap()
+            .remove(0)
+            .1
+    };
+    let private = config(&format!("{url}/private"));
+    assert!(refresh(&private, &Default::default()).is_err());
+    let options = DownloadOptions {
+        headers: vec![("Authorization".into(), "Basic dXNlcjpwYXNz".into())],
+        ..Default::default()
+    };
+    assert!(refresh(&private, &options).is_ok());
+    std::fs::remove_dir_all(dbpath.join("sync")).unwrap();
+    let with_credentials = url.replace("http://", "http://user:pass@");
+    let credentials = config(&format!("{with_credentials}/private"));
+    assert!(refresh(&credentials, &Default::default()).is_ok());
+
+    let options = DownloadOptions {
+        proxy: Some("no proxy".into()),
+        ..Default::default()
+    };
+    let e = refresh_syncdbs(&private, false, &options, &crate::events::NoEvents).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn test_retry() {
+    let dir = crate::util::test_dir("retry");
+    let url = test_server(
+        [
+            ("/flaky/core.db".to_owned(), b"core db".to_vec()),
+            ("/flaky/extra.db".to_owned(), b"extra db".to_vec()),
+        ]
+        .into(),
+    );
+    let dbpath = dir.join("db");
+    std::fs::create_dir_all(&dbpath).unwrap();
+    let config = |repo: &str| {
+        crate::config::test_config(&format!(
+            "[options]\nDBPath = {}\nSigLevel = Never\n\
+            [{repo}]\nServer = {url}/missing\nServer = {url}/flaky\n",
+            dbpath.display(),
+        ))
+    };
+    struct Record(Mutex<Vec<String>>);
+    impl EventSink for Record {
+        fn mirror_failed(&self, _file: &str, url: &str, _error: &io::Error) -> bool {
+            self.0.lock().unwrap().push(url.to_owned());
+            true
+        }
+    }
+    let once = DownloadOptions {
+        retry: RetryPolicy {
+            attempts: 1,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let events = crate::events::NoEvents;
+    let results = refresh_syncdbs(&config("core"), false, &once, &events).unwrap();
+    let e = results[0].1.as_ref().unwrap_err();
+    let failures = &MirrorFailures::of(e).unwrap().failures;
+    assert_eq!(failures.len(), 2);
+    assert_eq!(failures[0].0, format!("{url}/missing/core.db"));
+    assert_eq!(HttpStatus::of(&failures[0].1), Some(HttpStatus(404)));
+    assert_eq!(HttpStatus::of(&failures[1].1), Some(HttpStatus(503)));
+
+    let retry = DownloadOptions {
+        retry: RetryPolicy {
+            backoff: Duration::from_millis(10),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let record = Record(Mutex::new(Vec::new()));
+    let results = refresh_syncdbs(&config("extra"), false, &retry, &record).unwrap();
+    assert!(results[0].1.is_ok());
+    // the 404 is not tried again, the 503 is
+    assert_eq!(
+        record.0.into_inner().unwrap(),
+        [
+            format!("{url}/missing/extra.db"),
+            format!("{url}/flaky/extra.db")
+        ]
+    );
+}
@@ -0,0 +1,63 @@
+//! Progress of long running operations, for frontends showing progress bars.
+use std::io;
+
+/// Receives events of parsing, upgrade checks, refreshes and downloads.
+/// All methods do nothing by default, implement the ones of interest.
+/// Downloads report from their download threads, hence Sync.
+pub trait EventSink: Sync {
+    /// A sync db is about to be parsed.
+    fn db_parse_started(&self, name: &str) {
+        let _ = name;
+    }
+
+    /// A sync db was parsed into packages.
+    fn db_parse_finished(&self, name: &str, packages: usize) {
+        let _ = (name, packages);
+    }
+
+    /// The local package name was compared against the sync dbs,
+    /// done out of total local packages are compared.
+    fn package_compared(&self, name: &str, done: usize, total: usize) {
+        let _ = (name, done, total);
+    }
+
+    /// The sync package name is built for arch, which is not one of the configured
+    /// Architectures, so it is not offered as an upgrade.
+    fn arch_mismatch(&self, name: &str, arch: &str) {
+        let _ = (name, arch);
+    }
+
+    /// The bytes of file received so far, out of total if the server announced it.
+    /// Starts at the size of a resumed part file, and again at 0
+    /// when file is tried from the next mirror after a corrupt download.
+    fn download_progress(&self, file: &str, received: u64, total: Option<u64>) {
+        let _ = (file, received, total);
+    }
+
+    /// The bytes received so far of all packages to download,
+    /// out of the sum of their sizes in the sync dbs.
+    fn download_aggregate(&self, received: u64, total: u64) {
+        let _ = (received, total);
+    }
+
+    /// Downloading file from url failed,
+    /// `download::ChecksumMismatch::of` tells corrupt downloads apart.
+    /// returns whether to go on, trying url again or the next mirror
+    /// as the retry policy says, which is the default
+    fn mirror_failed(&self, file: &str, url: &str, error: &io::Error) -> bool {
+        let _ = (file, url, error);
+        true
+    }
+
+    /// Downloading file is over, it was checked against its sync db entry
+    /// or failed to arrive, done out of total files are over.
+    fn verify_progress(&self, file: &str, done: usize, total: usize) {
+        let _ = (file, done, total);
+    }
+}
+
+/// Ignores all events.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct NoEvents;
+
+impl EventSink for NoEvents {}
@@ -0,0 +1,537 @@
+use crate::config::PacmanConfig;
+use crate::db::{
+    self, Arch, DBLock, FileList, InstallReason, Interner, Istr, Package, QuickResolve,
+    UpdateOptions,
+};
+use crate::events::EventSink;
+use crate::log::{LogEntry, LogEvent};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Owns the interner and knows where the databases, caches and logs live.
+/// Use this instead of the free functions in [db] to operate on anything but the host system,
+/// e.g. a chroot or a container image.
+///
+/// Ex: ```Handle::builder().root("/mnt").build().localdb()```
+pub struct Handle {
+    i: Interner,
+    root: PathBuf,
+    dbpath: PathBuf,
+    cachedirs: Vec<PathBuf>,
+    logfile: PathBuf,
+    syncdbs: Vec<String>,
+    config: Option<PacmanConfig>,
+    #[cfg(feature = "mmap")]
+    mmap: bool,
+}
+
+/// Paths that are not set explicitly are taken from the config if one is given,
+/// otherwise they default to pacman's locations inside of root.
+/// Setting root keeps the config's DBPath and LogFile only if it sets them explicitly.
+#[derive(Default)]
+pub struct HandleBuilder {
+    root: Option<PathBuf>,
+    dbpath: Option<PathBuf>,
+    cachedirs: Vec<PathBuf>,
+    logfile: Option<PathBuf>,
+    syncdbs: Vec<String>,
+    config: Option<PacmanConfig>,
+    interner: Option<Interner>,
+    #[cfg(feature = "mmap")]
+    mmap: bool,
+}
+
+impl HandleBuilder {
+    pub fn root(mut self, root: impl Into<PathBuf>) -> Self {
+        self.root = Some(root.into());
+        self
+    }
+
+    pub fn dbpath(mut self, dbpath: impl Into<PathBuf>) -> Self {
+        self.dbpath = Some(dbpath.into());
+        self
+    }
+
+    /// Can be called multiple times, cache directories are searched in order.
+    pub fn cachedir(mut self, cachedir: impl Into<PathBuf>) -> Self {
+        self.cachedirs.push(cachedir.into());
+        self
+    }
+
+    pub fn logfile(mut self, logfile: impl Into<PathBuf>) -> Self {
+        self.logfile = Some(logfile.into());
+        self
+    }
+
+    /// Adds a sync db to operate on, in addition to the repos from the config.
+    pub fn register_syncdb(mut self, name: impl Into<String>) -> Self {
+        self.syncdbs.push(name.into());
+        self
+    }
+
+    pub fn config(mut self, config: PacmanConfig) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    /// Share an existing interner, e.g. to compare packages parsed through different handles.
+    pub fn interner(mut self, i: Interner) -> Self {
+        self.interner = Some(i);
+        self
+    }
+
+    /// Map the local db into memory instead of reading it, see [db::parse_localdb_mmap_at].
+    /// [Handle::localdb] then locks the db while parsing and fails if it is already locked.
+    #[cfg(feature = "mmap")]
+    pub fn mmap(mut self, mmap: bool) -> Self {
+        self.mmap = mmap;
+        self
+    }
+
+    pub fn build(self) -> Handle {
+        let config = self.config.as_ref();
+        // like `pacman -r`, a root overrides the dbpath and logfile the config did not set
+        let root_set = self.root.is_some();
+        let root = self
+            .root
+            .or_else(|| config.map(|c| c.root_dir.clone()))
+            .unwrap_or_else(|| "/".into());
+        let dbpath = self
+            .dbpath
+            .or_else(|| {
+                let c = config.filter(|c| c.db_path_explicit || !root_set);
+                c.map(|c| c.db_path.clone())
+            })
+            .unwrap_or_else(|| root.join("var/lib/pacman/"));
+        let mut cachedirs = self.cachedirs;
+        if cachedirs.is_empty() {
+            if let Some(c) = config {
+                cachedirs.clone_from(&c.cache_dirs);
+            } else {
+                cachedirs.push(root.join("var/cache/pacman/pkg/"));
+            }
+        }
+        let logfile = self
+            .logfile
+            .or_else(|| {
+                let c = config.filter(|c| c.log_file_explicit || !root_set);
+                c.map(|c| c.log_file.clone())
+            })
+            .unwrap_or_else(|| root.join("var/log/pacman.log"));
+        let mut syncdbs: Vec<String> = config
+            .map(|c| c.repos.iter().map(|r| r.name.clone()).collect())
+            .unwrap_or_default();
+        // registering a repo of the config again does not change its priority
+        for name in self.syncdbs {
+            if !syncdbs.contains(&name) {
+                syncdbs.push(name);
+            }
+        }
+
+        Handle {
+            i: self.interner.unwrap_or_else(db::new_interner),
+            root,
+            dbpath,
+            cachedirs,
+            logfile,
+            syncdbs,
+            config: self.config,
+            #[cfg(feature = "mmap")]
+            mmap: self.mmap,
+        }
+    }
+}
+
+impl Handle {
+    pub fn builder() -> HandleBuilder {
+        HandleBuilder::default()
+    }
+
+    /// Operates on the host system, paths are taken from config.
+    pub fn from_config(config: PacmanConfig) -> Self {
+        Self::builder().config(config).build()
+    }
+
+    pub fn interner(&self) -> &Interner {
+        &self.i
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn dbpath(&self) -> &Path {
+        &self.dbpath
+    }
+
+    pub fn cachedirs(&self) -> &[PathBuf] {
+        &self.cachedirs
+    }
+
+    pub fn logfile(&self) -> &Path {
+        &self.logfile
+    }
+
+    /// The config's hook dirs, without config pacman's defaults inside of root.
+    pub fn hook_dirs(&self) -> Vec<PathBuf> {
+        match &self.config {
+            Some(c) => c.hook_dirs.clone(),
+            None => vec![
+                self.root.join("usr/share/libalpm/hooks/"),
+                self.root.join("etc/pacman.d/hooks/"),
+            ],
+        }
+    }
+
+    /// names of the registered sync dbs
+    pub fn syncdbs(&self) -> &[String] {
+        &self.syncdbs
+    }
+
+    pub fn config(&self) -> Option<&PacmanConfig> {
+        self.config.as_ref()
+    }
+
+    /// returns name -> package
+    pub fn localdb(&self) -> std::io::Result<HashMap<Istr, Package>> {
+        #[cfg(feature = "mmap")]
+        if self.mmap {
+            let lock = self.lock()?;
+            return db::parse_localdb_mmap_at(self.i.clone(), &self.dbpath.join("local"), &lock);
+        }
+        db::parse_localdb_at(self.i.clone(), &self.dbpath.join("local"))
+    }
+
+    /// returns name -> installed files and backups
+    pub fn localdb_files(&self) -> std::io::Result<HashMap<Istr, FileList>> {
+        db::parse_localdb_files_at(self.i.clone(), &self.dbpath.join("local"))
+    }
+
+    /// returns name -> package
+    pub fn syncdb(&self, name: &str) -> std::io::Result<HashMap<Istr, Package>> {
+        db::parse_syncdb_at(
+            self.i.clone(),
+            &self.dbpath.join("sync"),
+            name,
+            &crate::events::NoEvents,
+        )
+    }
+
+    /// returns name -> files, from `<name>.files`
+    pub fn files_db(&self, name: &str) -> std::io::Result<HashMap<Istr, FileList>> {
+        db::parse_files_db_at(self.i.clone(), &self.dbpath.join("sync"), name)
+    }
+
+    /// Upgrades of local packages from all registered sync dbs,
+    /// honoring IgnorePkg, IgnoreGroup, Architecture and Usage.
+    /// Sync packages built for another architecture are reported to events.
+    /// Like [db::update_candidates] only gets upgrades, no new dependencies.
+    pub fn update_candidates(
+        &self,
+        events: &dyn EventSink,
+    ) -> std::io::Result<Vec<(&str, Package, Package)>> {
+        let local = self.localdb()?;
+        let syncs = self.upgrade_syncdbs()?;
+        Ok(self
+            .upgrades_in(&local, &syncs, events)
+            .into_iter()
+            .map(|(repo, old, new)| (repo, old.clone(), new.clone()))
+            .collect())
+    }
+
+    /// The registered sync dbs whose Usage allows upgrades,
+    /// what [Handle::update_candidates] compares the local db to.
+    /// returns (repo, name -> package) in order of priority
+    pub fn upgrade_syncdbs(&self) -> std::io::Result<Vec<(&str, HashMap<Istr, Package>)>> {
+        self.syncdbs
+            .iter()
+            .filter(|name| {
+                self.config
+                    .as_ref()
+                    .and_then(|c| c.repo(name))
+                    .is_none_or(|o| o.usage.upgrade)
+            })
+            .map(|name| Ok((name.as_str(), self.syncdb(name)?)))
+            .collect()
+    }
+
+    /// Like [Handle::update_candidates] on the local db and [Handle::upgrade_syncdbs],
+    /// borrowing the packages from them instead of cloning every upgrade.
+    pub fn upgrades_in<'db, 'p>(
+        &self,
+        local: &'p HashMap<Istr, Package>,
+        syncs: &'p [(&'db str, HashMap<Istr, Package>)],
+        events: &dyn EventSink,
+    ) -> Vec<(&'db str, &'p Package, &'p Package)> {
+        let options = self.update_options();
+        db::find_upgrade_refs(&self.i, local, syncs, &options, events)
+    }
+
+    /// Installed packages newer than in the registered sync dbs, what `pacman -Suu` downgrades,
+    /// see [db::find_downgrade_refs]. Honors the same options as [Handle::update_candidates].
+    pub fn downgrade_candidates(
+        &self,
+        events: &dyn EventSink,
+    ) -> std::io::Result<Vec<(&str, Package, Package)>> {
+        let local = self.localdb()?;
+        let syncs = self.upgrade_syncdbs()?;
+        Ok(self
+            .downgrades_in(&local, &syncs, events)
+            .into_iter()
+            .map(|(repo, old, new)| (repo, old.clone(), new.clone()))
+            .collect())
+    }
+
+    /// Like [Handle::downgrade_candidates] on the local db and [Handle::upgrade_syncdbs],
+    /// borrowing the packages from them.
+    pub fn downgrades_in<'db, 'p>(
+        &self,
+        local: &'p HashMap<Istr, Package>,
+        syncs: &'p [(&'db str, HashMap<Istr, Package>)],
+        events: &dyn EventSink,
+    ) -> Vec<(&'db str, &'p Package, &'p Package)> {
+        let options = self.update_options();
+        db::find_downgrade_refs(&self.i, local, syncs, &options, events)
+    }
+
+    /// The IgnorePkg, IgnoreGroup and Architecture of the config as [UpdateOptions],
+    /// for comparing with [db::find_upgrades] directly.
+    pub fn update_options(&self) -> UpdateOptions {
+        let mut options = UpdateOptions::default();
+        if let Some(c) = &self.config {
+            for name in &c.ignores {
+                options = options.ignore(name);
+            }
+            for group in &c.ignore_groups {
+                options = options.ignore_group(group);
+            }
+            for arch in &c.architectures {
+                options = options.architecture(Arch::new(arch, &self.i));
+            }
+        }
+        options
+    }
+
+    /// Like `pacman -Ss`: [db::Db::search] over the registered repos, honoring Usage.
+    /// returns (repo, package) in repo order, each repo's results ranked.
+    pub fn search(&self, query: &db::SearchQuery) -> std::io::Result<Vec<(String, Package)>> {
+        let mut ret = Vec::new();
+        for name in self.syncdbs.iter().filter(|name| self.searchable(name)) {
+            let db = db::Db::new(self.i.clone(), self.syncdb(name)?);
+            ret.extend(
+                db.search(query)
+                    .into_iter()
+                    .map(|p| (name.clone(), p.clone())),
+            );
+        }
+        Ok(ret)
+    }
+
+    /// Whether the repo name may be searched according to its Usage.
+    fn searchable(&self, name: &str) -> bool {
+        self.config
+            .as_ref()
+            .and_then(|c| c.repo(name))
+            .is_none_or(|r| r.usage.search)
+    }
+
+    /// Which packages in the registered repos contain matching files, honoring Usage.
+    /// Needs the files dbs downloaded by `pacman -Fy`.
+    /// returns (repo, package name, matching paths)
+    pub fn search_files(
+        &self,
+        query: &db::FileQuery,
+    ) -> std::io::Result<Vec<(String, Istr, Vec<String>)>> {
+        let dbs = self
+            .syncdbs
+            .iter()
+            .filter(|name| self.searchable(name))
+            .map(|name| Ok((name.as_str(), self.files_db(name)?)))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        Ok(db::search_files(&dbs, query)
+            .into_iter()
+            .map(|(repo, name, paths)| {
+                let paths = paths.into_iter().map(ToOwned::to_owned).collect();
+                (repo.to_owned(), name, paths)
+            })
+            .collect())
+    }
+
+    /// Like [Handle::search_files], but first downloads the files dbs of the searched repos
+    /// that are missing, see [crate::download::fetch_missing_files_dbs].
+    /// Needs a config to know the mirrors, failed downloads are only logged.
+    #[cfg(feature = "download")]
+    pub fn search_files_fetching(
+        &self,
+        query: &db::FileQuery,
+        options: &crate::download::DownloadOptions,
+        events: &dyn crate::events::EventSink,
+    ) -> std::io::Result<Vec<(String, Istr, Vec<String>)>> {
+        if let Some(config) = &self.config {
+            let repos: Vec<&str> = self
+                .syncdbs
+                .iter()
+                .filter(|name| self.searchable(name))
+                .map(String::as_str)
+                .collect();
+            let fetched =
+                crate::download::fetch_missing_files_dbs(config, &repos, options, events)?;
+            for (repo, result) in fetched {
+                if let Err(e) = result {
+                    log::warn!("could not download the files db of {repo}: {e}");
+                }
+            }
+        }
+        self.search_files(query)
+    }
+
+    /// [crate::cache::clean] of the cache dirs, keeping the installed packages of the local db.
+    pub fn clean_cache(
+        &self,
+        options: crate::cache::CleanOptions,
+    ) -> std::io::Result<crate::cache::Cleaned> {
+        let local = self.localdb()?;
+        let i = self.i.borrow();
+        let installed: Vec<&str> = local.keys().map(|name| name.r(&i)).collect();
+        crate::cache::clean(&self.cachedirs, &installed, options)
+    }
+
+    /// [db::check_files] of the installed package pkg.
+    pub fn check_files(
+        &self,
+        pkg: &Package,
+        level: db::CheckLevel,
+    ) -> std::io::Result<Vec<(String, db::Mismatch)>> {
+        db::check_files(&self.i, &self.root, &self.dbpath.join("local"), pkg, level)
+    }
+
+    /// See [db::set_install_reason].
+    pub fn set_install_reason(&self, name: &str, reason: InstallReason) -> std::io::Result<()> {
+        db::set_install_reason(&self.dbpath, name, reason)
+    }
+
+    /// Appends event to the log file as libalpm does, with source `ALPM`.
+    pub fn log(&self, event: LogEvent) -> std::io::Result<()> {
+        crate::log::append(&self.logfile, &LogEntry::new("ALPM", event))
+    }
+
+    /// Locks the database in dbpath, auto-unlocks on drop.
+    pub fn lock(&self) -> std::io::Result<DBLock> {
+        DBLock::at(&self.dbpath)
+    }
+}
+
+#[test]
+fn test_handle_paths() {
+    let h = Handle::builder().root("/mnt").build();
+    assert_eq!(h.dbpath(), Path::new("/mnt/var/lib/pacman/"));
+    assert_eq!(
+        h.cachedirs(),
+        &[PathBuf::from("/mnt/var/cache/pacman/pkg/")]
+    );
+    assert_eq!(h.logfile(), Path::new("/mnt/var/log/pacman.log"));
+
+    let h = Handle::builder()
+        .root("/mnt")
+        .dbpath("/db")
+        .cachedir("/c1")
+        .cachedir("/c2")
+        .build();
+    assert_eq!(h.dbpath(), Path::new("/db"));
+    assert_eq!(h.cachedirs().len(), 2);
+
+    let host = crate::config::test_config("[options]\nLogFile = /log\n");
+    let h = Handle::builder().root("/mnt").config(host.clone()).build();
+    assert_eq!(h.dbpath(), Path::new("/mnt/var/lib/pacman/"));
+    assert_eq!(h.logfile(), Path::new("/log"));
+    let h = Handle::builder().config(host).build();
+    assert_eq!(h.dbpath(), Path::new("/var/lib/pacman/"));
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn test_handle_mmap() {
+    let dir = crate::util::test_dir("handle_mmap");
+    let pkgs = [("foo-1.0-1", db::test_desc("foo", "1.0-1", &[]))];
+    db::write_test_dbpath(&dir, &pkgs, &[]);
+    let h = Handle::builder().dbpath(&dir).mmap(true).build();
+    assert_eq!(h.localdb().unwrap().len(), 1);
+    assert!(!dir.join("db.lck").exists());
+    let _lock = h.lock().unwrap();
+    assert!(h.localdb().is_err());
+}
+
+#[test]
+fn test_handle_update_candidates() {
+    use crate::events::NoEvents;
+    use db::test_desc;
+    let dir = crate::util::test_dir("handle");
+    db::write_test_dbpath(
+        &dir,
+        &[
+            ("foo-1.0-1", test_desc("foo", "1.0-1", &[])),
+            ("bar-2.0-1", test_desc("bar", "2.0-1", &[])),
+        ],
+        &[(
+            "core",
+            &[
+                ("foo-1.1-1", test_desc("foo", "1.1-1", &[])),
+                ("bar-2.0-1", test_desc("bar", "2.0-1", &[])),
+            ],
+        )],
+    );
+    let h = Handle::builder()
+        .dbpath(&dir)
+        .register_syncdb("core")
+        .build();
+    assert_eq!(h.localdb().unwrap().len(), 2);
+    let ups = h.update_candidates(&NoEvents).unwrap();
+    assert_eq!(ups.len(), 1);
+    let i = h.interner().borrow();
+    assert_eq!(ups[0].0, "core");
+    assert_eq!(ups[0].1.version.r(&i), "1.0-1");
+    assert_eq!(ups[0].2.version.r(&i), "1.1-1");
+    drop(i);
+    let (local, syncs) = (h.localdb().unwrap(), h.upgrade_syncdbs().unwrap());
+    let refs = h.upgrades_in(&local, &syncs, &NoEvents);
+    assert_eq!(refs.len(), 1);
+    assert!(std::ptr::eq(refs[0].2, &syncs[0].1[&ups[0].2.name]));
+    assert_eq!(refs[0].1, &ups[0].1);
+    assert!(h.downgrades_in(&local, &syncs, &NoEvents).is_empty());
+    let found = h.search(&"FO".into()).unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].0, "core");
+
+    let config = crate::config::test_config(
+        "[options]\nArchitecture = auto\nIgnorePkg = bar\n[core]\nServer = x\nUsage = Sync Install\n",
+    );
+    let h = Handle::builder()
+        .dbpath(&dir)
+        .config(config)
+        .register_syncdb("core")
+        .build();
+    assert_eq!(h.syncdbs(), ["core"]);
+    assert!(h.update_candidates(&NoEvents).unwrap().is_empty());
+    assert!(h.search(&"foo".into()).unwrap().is_empty());
+
+    // packages built for another architecture are reported
+    struct Record(std::sync::Mutex<Vec<String>>);
+    impl EventSink for Record {
+        fn arch_mismatch(&self, name: &str, arch: &str) {
+            self.0.lock().unwrap().push(format!("{name} {arch}"));
+        }
+    }
+    let config =
+        crate::config::test_config("[options]\nArchitecture = sparc\n[core]\nServer = x\n");
+    let sparc = Handle::builder().dbpath(&dir).config(config).build();
+    let record = Record(Default::default());
+    assert!(sparc.update_candidates(&record).unwrap().is_empty());
+    let mut mismatches = record.0.into_inner().unwrap();
+    mismatches.sort();
+    assert_eq!(mismatches, ["bar x86_64", "foo x86_64"]);
+
+    let lock = h.lock().unwrap();
+    assert!(h.lock().is_err());
+    drop(lock);
+    assert!(h.lock().is_ok());
+}
@@ -0,0 +1,447 @@
+//! alpm-hooks(5): `.hook` files describing commands to run before or after transactions.
+use crate::util::match_patterns;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Operation {
+    Install,
+    Upgrade,
+    Remove,
+}
+
+impl FromStr for Operation {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Install" => Ok(Self::Install),
+            "Upgrade" => Ok(Self::Upgrade),
+            "Remove" => Ok(Self::Remove),
+            _ => Err(()),
+        }
+    }
+}
+
+/// What the targets of a [Trigger] are matched against.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TriggerType {
+    /// Paths relative to root, without leading /.
+    Path,
+    /// Package names.
+    Package,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum When {
+    PreTransaction,
+    PostTransaction,
+}
+
+/// A `[Trigger]` section.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Trigger {
+    pub operations: Vec<Operation>,
+    pub kind: TriggerType,
+    /// Globs, later ones win and a leading ! negates, like NoExtract.
+    pub targets: Vec<String>,
+}
+
+/// A parsed hook file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Hook {
+    /// The file name without `.hook`, hooks run ordered by it.
+    pub name: String,
+    pub triggers: Vec<Trigger>,
+    pub description: Option<String>,
+    pub when: When,
+    /// The command line, split like a shell would split it but without expansions.
+    pub exec: Vec<String>,
+    /// Packages the command needs, the hook is not run without them.
+    pub depends: Vec<String>,
+    /// Only for PreTransaction hooks, a failure cancels the transaction.
+    pub abort_on_fail: bool,
+    /// The matched targets are passed on stdin, one per line.
+    pub needs_targets: bool,
+}
+
+/// Splits Exec like a shell: whitespace separates words, quotes and backslashes escape.
+fn split_command(s: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => words.extend(word.take()),
+            '\\' => {
+                let escaped = chars.next().ok_or("trailing backslash")?;
+                word.get_or_insert_default().push(escaped);
+            }
+            '\'' | '"' => {
+                let w = word.get_or_insert_default();
+                loop {
+                    match chars.next() {
+                        Some(q) if q == c => break,
+                        Some('\\') if c == '"' => w.push(chars.next().ok_or("unterminated quote")?),
+                        Some(other) => w.push(other),
+                        None => return Err("unterminated quote".to_owned()),
+                    }
+                }
+            }
+            c => word.get_or_insert_default().push(c),
+        }
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+/// Operations, type and targets of a `[Trigger]` being parsed.
+type PartialTrigger = (Vec<Operation>, Option<TriggerType>, Vec<String>);
+
+fn finish(trigger: Option<PartialTrigger>) -> Result<Option<Trigger>, String> {
+    let Some((operations, kind, targets)) = trigger else {
+        return Ok(None);
+    };
+    match kind {
+        Some(kind) if !operations.is_empty() && !targets.is_empty() => Ok(Some(Trigger {
+            operations,
+            kind,
+            targets,
+        })),
+        _ => Err("trigger needs Operation, Type and Target".to_owned()),
+    }
+}
+
+impl Hook {
+    /// Parses the contents of `<name>.hook`.
+    pub fn parse(name: &str, s: &str) -> Result<Self, String> {
+        let err = |msg: String| format!("hook {name}: {msg}");
+        let mut triggers = Vec::new();
+        let mut description = None;
+        let mut when = None;
+        let mut exec = None;
+        let mut depends = Vec::new();
+        let mut abort_on_fail = false;
+        let mut needs_targets = false;
+        let mut section = "";
+        let mut trigger: Option<PartialTrigger> = None;
+        for line in s.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                triggers.extend(finish(trigger.take()).map_err(err)?);
+                section = match name {
+                    "Trigger" => {
+                        trigger = Some(Default::default());
+                        "Trigger"
+                    }
+                    "Action" => "Action",
+                    _ => return Err(err(format!("unknown section [{name}]"))),
+                };
+                continue;
+            }
+            let (key, value) = match line.split_once('=') {
+                Some((k, v)) => (k.trim(), Some(v.trim())),
+                None => (line, None),
+            };
+            let value = || value.ok_or_else(|| err(format!("{key} needs a value")));
+            match (section, key) {
+                ("Trigger", "Operation") => {
+                    let op = value()?;
+                    let op = op
+                        .parse()
+                        .map_err(|()| err(format!("bad Operation {op}")))?;
+                    trigger.as_mut().unwrap().0.push(op);
+                }
+                ("Trigger", "Type") => {
+                    let kind = match value()? {
+                        // File is the deprecated name of Path
+                        "Path" | "File" => TriggerType::Path,
+                        "Package" => TriggerType::Package,
+                        t => return Err(err(format!("bad Type {t}"))),
+                    };
+                    trigger.as_mut().unwrap().1 = Some(kind);
+                }
+                ("Trigger", "Target") => trigger.as_mut().unwrap().2.push(value()?.to_owned()),
+                ("Action", "Description") => description = Some(value()?.to_owned()),
+                ("Action", "When") => {
+                    when = Some(match value()? {
+                        "PreTransaction" => When::PreTransaction,
+                        "PostTransaction" => When::PostTransaction,
+                        w => return Err(err(format!("bad When {w}"))),
+                    });
+                }
+                ("Action", "Exec") => exec = Some(split_command(value()?).map_err(err)?),
+                ("Action", "Depends") => depends.push(value()?.to_owned()),
+                ("Action", "AbortOnFail") => abort_on_fail = true,
+                ("Action", "NeedsTargets") => needs_targets = true,
+                (section, key) => return Err(err(format!("unknown key {key} in [{section}]"))),
+            }
+        }
+        triggers.extend(finish(trigger).map_err(err)?);
+        let when = when.ok_or_else(|| err("missing When".to_owned()))?;
+        let exec = exec
+            .filter(|e| !e.is_empty())
+            .ok_or_else(|| err("missing Exec".to_owned()))?;
+        if triggers.is_empty() {
+            return Err(err("missing [Trigger]".to_owned()));
+        }
+        Ok(Self {
+            name: name.to_owned(),
+            triggers,
+            description,
+            when,
+            exec,
+            depends,
+            abort_on_fail: abort_on_fail && when == When::PreTransaction,
+            needs_targets,
+        })
+    }
+
+    /// The targets of changes that trigger this hook, sorted and deduplicated.
+    /// None if it is not triggered.
+    pub fn matches(&self, changes: &Changes) -> Option<Vec<String>> {
+        let mut ret = BTreeSet::new();
+        let mut triggered = false;
+        for t in &self.triggers {
+            let candidates = match t.kind {
+                TriggerType::Path => &changes.paths,
+                TriggerType::Package => &changes.packages,
+            };
+            for (target, op) in candidates {
+                if t.operations.contains(op) && match_patterns(&t.targets, target) {
+                    triggered = true;
+                    ret.insert(target.clone());
+                }
+            }
+        }
+        triggered.then(|| ret.into_iter().collect())
+    }
+}
+
+/// Reads all `.hook` files of dirs, ordered by name.
+/// A hook in a later directory replaces the one of the same name in earlier ones,
+/// a symlink to /dev/null disables it. Directories that do not exist are skipped.
+pub fn read_hooks(dirs: &[PathBuf]) -> io::Result<Vec<Hook>> {
+    let mut files: BTreeMap<String, PathBuf> = BTreeMap::new();
+    for dir in dirs {
+        let entries = match std::fs::read_dir(dir) {
+            Ok(e) => e,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            let path = entry?.path();
+            if let Some(name) = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .and_then(|n| n.strip_suffix(".hook"))
+            {
+                files.insert(name.to_owned(), path);
+            }
+        }
+    }
+    let mut hooks = Vec::new();
+    for (name, path) in files {
+        if std::fs::read_link(&path).is_ok_and(|t| t == Path::new("/dev/null")) {
+            continue;
+        }
+        let s = std::fs::read_to_string(&path)?;
+        hooks.push(
+            Hook::parse(&name, &s).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+        );
+    }
+    Ok(hooks)
+}
+
+/// What a transaction does, to match hook triggers against.
+#[derive(Clone, Debug, Default)]
+pub struct Changes {
+    /// (package name, operation)
+    pub packages: Vec<(String, Operation)>,
+    /// (path relative to root, operation), directories end in /.
+    pub paths: Vec<(String, Operation)>,
+}
+
+impl Changes {
+    /// Records a package with the file lists of its installed (old) and new version,
+    /// at least one of them must be Some.
+    /// Paths in both are upgraded, like pacman does.
+    pub fn package(&mut self, name: &str, old: Option<&[String]>, new: Option<&[String]>) {
+        let op = match (old, new) {
+            (Some(_), Some(_)) => Operation::Upgrade,
+            (None, _) => Operation::Install,
+            (_, None) => Operation::Remove,
+        };
+        self.packages.push((name.to_owned(), op));
+        let old: BTreeSet<&String> = old.into_iter().flatten().collect();
+        let new: BTreeSet<&String> = new.into_iter().flatten().collect();
+        for path in new.union(&old) {
+            let op = match (old.contains(*path), new.contains(*path)) {
+                (true, true) => Operation::Upgrade,
+                (false, _) => Operation::Install,
+                (_, false) => Operation::Remove,
+            };
+            self.paths.push(((*path).clone(), op));
+        }
+    }
+}
+
+/// The hooks of when that changes trigger, in the order they run, with their matched targets.
+pub fn triggered<'h>(
+    hooks: &'h [Hook],
+    changes: &Changes,
+    when: When,
+) -> Vec<(&'h Hook, Vec<String>)> {
+    hooks
+        .iter()
+        .filter(|h| h.when == when)
+        .filter_map(|h| Some((h, h.matches(changes)?)))
+        .collect()
+}
+
+/// Executes hooks, so embedders decide how, or whether, they run.
+pub trait HookRunner {
+    /// Runs hook for a system installed at root, targets are what [Hook::matches] returned.
+    fn run(&mut self, root: &Path, hook: &Hook, targets: &[String]) -> io::Result<()>;
+}
+
+/// Runs Exec directly without a shell like pacman, chrooting into root unless it is `/`.
+pub struct ExecRunner;
+
+impl HookRunner for ExecRunner {
+    fn run(&mut self, root: &Path, hook: &Hook, targets: &[String]) -> io::Result<()> {
+        let mut command = if root == Path::new("/") {
+            Command::new(&hook.exec[0])
+        } else {
+            let mut c = Command::new("chroot");
+            c.arg(root).arg(&hook.exec[0]);
+            c
+        };
+        command.args(&hook.exec[1..]).current_dir(root);
+        command.stdin(if hook.needs_targets {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        });
+        let mut child = command.spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            for t in targets {
+                writeln!(stdin, "{t}")?;
+            }
+        }
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(io::Error::other(format!(
+                "hook {} failed with {status}",
+                hook.name
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_split_command() {
+    assert_eq!(
+        split_command("/bin/sh -c 'echo \"$1\"' x\\ y \"a\\\"b\"").unwrap(),
+        ["/bin/sh", "-c", "echo \"$1\"", "x y", "a\"b"]
+    );
+    assert!(split_command("echo 'open").is_err());
+}
+
+#[test]
+fn test_hooks() {
+    let dir = crate::util::test_dir("hooks");
+    let (sys, etc) = (dir.join("sys"), dir.join("etc"));
+    std::fs::create_dir_all(&sys).unwrap();
+    std::fs::create_dir_all(&etc).unwrap();
+    let out = dir.join("out");
+    std::fs::write(
+        sys.join("20-icons.hook"),
+        format!(
+            "[Trigger]\nOperation = Install\nOperation = Upgrade\nOperation = Remove\n\
+            Type = Path\nTarget = usr/share/icons/*\nTarget = !usr/share/icons/skip/*\n\n\
+            [Action]\nDescription = Updating icons\nWhen = PostTransaction\n\
+            Exec = /bin/sh -c 'cat > {}'\nNeedsTargets\n",
+            out.display()
+        ),
+    )
+    .unwrap();
+    std::fs::write(
+        sys.join("10-kernel.hook"),
+        "[Trigger]\nOperation = Upgrade\nType = Package\nTarget = linux\n\
+        [Trigger]\nOperation = Remove\nType = File\nTarget = usr/lib/modules/*\n\
+        [Action]\nWhen = PreTransaction\nExec = /bin/true\nAbortOnFail\nDepends = mkinitcpio\n",
+    )
+    .unwrap();
+    std::fs::write(sys.join("30-disabled.hook"), "broken").unwrap();
+    std::os::unix::fs::symlink("/dev/null", etc.join("30-disabled.hook")).unwrap();
+    std::fs::write(sys.join("README"), "not a hook").unwrap();
+    let hooks = read_hooks(&[sys.clone(), etc.clone(), dir.join("missing")]).unwrap();
+    let names: Vec<_> = hooks.iter().map(|h| h.name.as_str()).collect();
+    assert_eq!(names, ["10-kernel", "20-icons"]);
+    let kernel = &hooks[0];
+    assert_eq!(kernel.triggers.len(), 2);
+    assert_eq!(kernel.triggers[1].kind, TriggerType::Path);
+    assert!(kernel.abort_on_fail && !kernel.needs_targets);
+    assert_eq!(kernel.depends, ["mkinitcpio"]);
+    assert_eq!(hooks[1].description.as_deref(), Some("Updating icons"));
+    assert_eq!(hooks[1].exec.len(), 3);
+
+    let mut changes = Changes::default();
+    let strings = |s: &[&str]| s.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+    changes.package(
+        "hicolor",
+        None,
+        Some(&strings(&[
+            "usr/share/icons/a.png",
+            "usr/share/icons/skip/b.png",
+        ])),
+    );
+    changes.package(
+        "linux",
+        Some(&strings(&["usr/lib/modules/6.1/", "usr/share/icons/a.png"])),
+        Some(&strings(&["usr/lib/modules/6.2/"])),
+    );
+    assert!(
+        changes
+            .paths
+            .contains(&("usr/lib/modules/6.1/".to_owned(), Operation::Remove))
+    );
+    assert!(
+        changes
+            .paths
+            .contains(&("usr/lib/modules/6.2/".to_owned(), Operation::Install))
+    );
+    let pre = triggered(&hooks, &changes, When::PreTransaction);
+    assert_eq!(pre.len(), 1);
+    assert_eq!(pre[0].1, ["linux", "usr/lib/modules/6.1/"]);
+    let post = triggered(&hooks, &changes, When::PostTransaction);
+    assert_eq!(post.len(), 1);
+    assert_eq!(post[0].1, ["usr/share/icons/a.png"]);
+    ExecRunner
+        .run(Path::new("/"), post[0].0, &post[0].1)
+        .unwrap();
+    assert_eq!(
+        std::fs::read_to_string(&out).unwrap(),
+        "usr/share/icons/a.png\n"
+    );
+
+    let mut only_hicolor = Changes::default();
+    only_hicolor.package("hicolor", Some(&[]), Some(&[]));
+    assert!(triggered(&hooks, &only_hicolor, When::PreTransaction).is_empty());
+
+    let action = "[Action]\nWhen = PostTransaction\nExec = /bin/true\n";
+    assert!(Hook::parse("x", action).is_err());
+    let no_operation = format!("[Trigger]\nType = Package\nTarget = x\n{action}");
+    assert!(Hook::parse("x", &no_operation).is_err());
+    let trigger = "[Trigger]\nOperation = Install\nType = Package\nTarget = x\n";
+    assert!(Hook::parse("x", &format!("{trigger}{action}")).is_ok());
+    let bad_when = format!("{trigger}[Action]\nWhen = Sometimes\nExec = /bin/true\n");
+    assert!(Hook::parse("x", &bad_when).is_err());
+}
@@ -0,0 +1,15 @@
+//! Putting packages onto a system, the parts of pacman -S and -U that change files.
+mod decisions;
+mod extract;
+mod provider;
+mod removal;
+mod scriptlet;
+mod space;
+mod transaction;
+pub use decisions::{Decisions, NonInteractive};
+pub use extract::{Extraction, Extractor};
+pub use provider::ProviderStrategy;
+pub use removal::{Removal, RemovalCause, RemovalPlan, RemoveOptions, plan_removal};
+pub use scriptlet::{Scriptlet, ScriptletHook, ScriptletRunner, ShellRunner, SkipScriptlets};
+pub use space::{MountPoint, mount_points};
+pub use transaction::{Install, Plan, Source, Transaction, TransactionError};
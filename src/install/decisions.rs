@@ -0,0 +1,48 @@
+//! The questions pacman asks while resolving a transaction.
+use crate::db::{Depend, Package};
+
+/// Answers the questions of [super::Transaction] that pacman prompts for,
+/// so frontends can ask their users. Every method defaults to [NonInteractive]'s answer.
+pub trait Decisions {
+    /// Which of several packages satisfying dep to install when none is named like it,
+    /// as an index into providers, which are (repo, package) in repo order.
+    fn choose_provider(&mut self, dep: &Depend, providers: &[(&str, &Package)]) -> usize {
+        let _ = (dep, providers);
+        0
+    }
+
+    /// Whether to replace the installed package old with new from repo, during a sysupgrade.
+    fn replace(&mut self, old: &Package, new: &Package, repo: &str) -> bool {
+        let _ = (old, new, repo);
+        true
+    }
+
+    /// Whether to remove the installed package installed, which is in conflict with new.
+    /// Declining makes preparing fail with a conflict.
+    fn remove_conflict(&mut self, new: &Package, installed: &Package) -> bool {
+        let _ = (new, installed);
+        false
+    }
+
+    /// Whether to remove the installed package held, which matches HoldPkg,
+    /// as a target, replaced or in conflict. Declining makes preparing fail.
+    fn remove_held(&mut self, held: &Package) -> bool {
+        let _ = held;
+        false
+    }
+
+    /// Whether to import the key with fingerprint, owned by uid, into the keyring,
+    /// to verify a signature by a key the keyring does not have.
+    fn import_key(&mut self, fingerprint: &str, uid: &str) -> bool {
+        let _ = (fingerprint, uid);
+        false
+    }
+}
+
+/// Answers like `pacman --noconfirm`: the first provider, replacing packages
+/// and refusing conflict removal and removal of held packages.
+/// Unlike pacman it never imports keys, that needs an explicit decision.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct NonInteractive;
+
+impl Decisions for NonInteractive {}
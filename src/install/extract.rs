@@ -0,0 +1,311 @@
+//! Unpacking package files below a root.
+use crate::config::PacmanConfig;
+use crate::db::repo::pkginfo_backup;
+use crate::db::{Backup, FileList};
+use crate::util::match_patterns;
+use md5::{Digest, Md5};
+use std::fs::{File, Permissions};
+use std::io::{self, BufReader, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+/// Unpacks package files like pacman does,
+/// honoring NoExtract, NoUpgrade and the backup array of the package.
+///
+/// Ex: ```Extractor::new("/mnt").upgrade_from(&old_files).extract(pkgfile)```
+#[derive(Clone, Debug)]
+pub struct Extractor {
+    root: PathBuf,
+    no_extract: Vec<String>,
+    no_upgrade: Vec<String>,
+    old_backup: Vec<Backup>,
+    preserve_ownership: bool,
+}
+
+/// What [Extractor::extract] did.
+#[derive(Clone, Debug, Default)]
+pub struct Extraction {
+    /// Every path of the package as the local db lists them, including skipped ones.
+    /// Backup md5s are of the packaged files.
+    pub files: FileList,
+    /// Paths written below root, in archive order. Directories end in /.
+    pub extracted: Vec<String>,
+    /// Modified config files that were kept, the packaged version was written to `<path>.pacnew`.
+    pub pacnew: Vec<String>,
+    /// Paths not extracted because of NoExtract.
+    pub skipped: Vec<String>,
+}
+
+fn hex_md5(contents: &[u8]) -> String {
+    Md5::digest(contents)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Removes whatever non-directory is at path, so it can be replaced.
+fn clear(path: &Path) -> io::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(m) if m.is_dir() => Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("not replacing directory {} with a file", path.display()),
+        )),
+        Ok(_) => std::fs::remove_file(path),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+fn pacnew_path(path: &Path) -> PathBuf {
+    let mut p = path.as_os_str().to_owned();
+    p.push(".pacnew");
+    p.into()
+}
+
+impl Extractor {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            no_extract: Vec::new(),
+            no_upgrade: Vec::new(),
+            old_backup: Vec::new(),
+            preserve_ownership: false,
+        }
+    }
+
+    /// Root, NoExtract and NoUpgrade from the config.
+    pub fn from_config(config: &PacmanConfig) -> Self {
+        Self::new(&config.root_dir)
+            .no_extract(&config.no_extract)
+            .no_upgrade(&config.no_upgrade)
+    }
+
+    /// Patterns as [match_patterns] takes them, matching paths are neither extracted nor replaced.
+    pub fn no_extract<S: AsRef<str>>(mut self, patterns: &[S]) -> Self {
+        self.no_extract = patterns.iter().map(|p| p.as_ref().to_owned()).collect();
+        self
+    }
+
+    /// Existing files matching these are never replaced, the new version goes to `.pacnew`.
+    pub fn no_upgrade<S: AsRef<str>>(mut self, patterns: &[S]) -> Self {
+        self.no_upgrade = patterns.iter().map(|p| p.as_ref().to_owned()).collect();
+        self
+    }
+
+    /// The files of the installed version, whose backup md5s tell modified config files apart.
+    /// Without it every existing config file that differs from the packaged one counts as modified.
+    pub fn upgrade_from(mut self, old: &FileList) -> Self {
+        self.old_backup.clone_from(&old.backup);
+        self
+    }
+
+    /// Sets owner and group from the archive, which needs root.
+    pub fn preserve_ownership(mut self, preserve: bool) -> Self {
+        self.preserve_ownership = preserve;
+        self
+    }
+
+    /// Unpacks the package file below root.
+    /// Stops at the first error, leaving what was extracted so far in place.
+    pub fn extract(&self, pkgfile: &Path) -> io::Result<Extraction> {
+        let f = crate::db::decompress(BufReader::new(File::open(pkgfile)?))?;
+        let mut archive = tar::Archive::new(f);
+        archive.set_preserve_permissions(true);
+        archive.set_preserve_ownerships(self.preserve_ownership);
+        let mut backup_paths = Vec::new();
+        let mut ret = Extraction::default();
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let mut path = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+            if path == ".PKGINFO" {
+                let mut pkginfo = String::new();
+                entry.read_to_string(&mut pkginfo)?;
+                backup_paths = pkginfo_backup(&pkginfo);
+                continue;
+            }
+            // .MTREE, .INSTALL, .BUILDINFO and such are metadata
+            if path.starts_with('.') && !path.contains('/') {
+                continue;
+            }
+            if !Path::new(&path)
+                .components()
+                .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} contains unsafe path {path}", pkgfile.display()),
+                ));
+            }
+            let is_dir = entry.header().entry_type().is_dir();
+            if is_dir && !path.ends_with('/') {
+                path.push('/');
+            }
+            ret.files.files.push(path.clone());
+            if match_patterns(&self.no_extract, &path) {
+                ret.skipped.push(path);
+                continue;
+            }
+            let target = self.root.join(&path);
+            if let Some(parent) = target.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            if is_dir {
+                // existing directories, or symlinks to them, keep their permissions like in pacman
+                if !target.is_dir() {
+                    entry.unpack(&target)?;
+                    ret.extracted.push(path);
+                }
+                continue;
+            }
+            let exists = std::fs::symlink_metadata(&target).is_ok();
+            if backup_paths.contains(&path) {
+                let mut contents = Vec::new();
+                entry.read_to_end(&mut contents)?;
+                let md5 = hex_md5(&contents);
+                if let Some(dest) = self.backup_target(&path, &target, &md5)? {
+                    self.write_file(&dest, &contents, entry.header())?;
+                    if dest == target {
+                        ret.extracted.push(path.clone());
+                    } else {
+                        ret.pacnew.push(path.clone());
+                    }
+                }
+                ret.files.backup.push(Backup { path, md5sum: md5 });
+            } else if exists && match_patterns(&self.no_upgrade, &path) {
+                let dest = pacnew_path(&target);
+                clear(&dest)?;
+                entry.unpack(&dest)?;
+                ret.pacnew.push(path);
+            } else {
+                clear(&target)?;
+                entry.unpack(&target)?;
+                ret.extracted.push(path);
+            }
+        }
+        ret.files.files.sort_unstable();
+        Ok(ret)
+    }
+
+    /// Where a backup file with the packaged md5 goes, None if the file on disk stays as is.
+    /// Like pacman: unmodified files are replaced,
+    /// modified ones are kept and get a .pacnew unless the package did not change them.
+    fn backup_target(&self, path: &str, target: &Path, new: &str) -> io::Result<Option<PathBuf>> {
+        let current = match std::fs::read(target) {
+            Ok(c) => hex_md5(&c),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Some(target.to_owned())),
+            Err(e) => return Err(e),
+        };
+        if current == new {
+            return Ok(None);
+        }
+        let old = self
+            .old_backup
+            .iter()
+            .find(|b| b.path == path)
+            .map(|b| b.md5sum.as_str());
+        if match_patterns(&self.no_upgrade, path) {
+            return Ok(Some(pacnew_path(target)));
+        }
+        Ok(match old {
+            Some(old) if old == current => Some(target.to_owned()),
+            Some(old) if old == new => None,
+            _ => Some(pacnew_path(target)),
+        })
+    }
+
+    fn write_file(&self, path: &Path, contents: &[u8], header: &tar::Header) -> io::Result<()> {
+        clear(path)?;
+        let mut f = File::create(path)?;
+        f.write_all(contents)?;
+        if let Ok(mode) = header.mode() {
+            f.set_permissions(Permissions::from_mode(mode & 0o7777))?;
+        }
+        if let Ok(mtime) = header.mtime() {
+            f.set_modified(UNIX_EPOCH + Duration::from_secs(mtime))?;
+        }
+        if self.preserve_ownership {
+            std::os::unix::fs::chown(
+                path,
+                header.uid().ok().map(|u| u as u32),
+                header.gid().ok().map(|g| g as u32),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_extract() {
+    use crate::db::repo::{test_pkginfo, write_test_package};
+    let dir = crate::util::test_dir("extract");
+    let root = dir.join("root");
+    std::fs::create_dir_all(root.join("etc")).unwrap();
+    let pkg = dir.join("foo-2-1-x86_64.pkg.tar.zst");
+    let pkginfo = test_pkginfo("foo", "2-1")
+        + "backup = etc/modified.conf\nbackup = etc/unmodified.conf\nbackup = etc/new.conf\n";
+    write_test_package(
+        &pkg,
+        &pkginfo,
+        &[
+            "etc/",
+            "etc/modified.conf",
+            "etc/new.conf",
+            "etc/unmodified.conf",
+            "usr/",
+            "usr/bin/",
+            "usr/bin/foo",
+            "usr/share/locale/de/foo.mo",
+        ],
+    );
+    std::fs::write(root.join("etc/modified.conf"), "mine").unwrap();
+    std::fs::write(root.join("etc/unmodified.conf"), "").unwrap();
+    let old = FileList::parse(
+        "%BACKUP%\netc/modified.conf\td41d8cd98f00b204e9800998ecf8427e\n\
+        etc/unmodified.conf\td41d8cd98f00b204e9800998ecf8427e\n\n",
+    );
+
+    let e = Extractor::new(&root)
+        .no_extract(&["usr/share/locale/*"])
+        .upgrade_from(&old)
+        .extract(&pkg)
+        .unwrap();
+    assert_eq!(
+        e.extracted,
+        [
+            "etc/new.conf",
+            "etc/unmodified.conf",
+            "usr/",
+            "usr/bin/",
+            "usr/bin/foo"
+        ]
+    );
+    assert_eq!(e.pacnew, ["etc/modified.conf"]);
+    assert_eq!(e.skipped, ["usr/share/locale/de/foo.mo"]);
+    assert_eq!(e.files.files.len(), 8);
+    assert_eq!(e.files.backup.len(), 3);
+    assert!(
+        e.files
+            .backup
+            .iter()
+            .all(|b| b.md5sum == "98bf7d8c15784f0a3d63204441e1e2aa")
+    );
+    let read = |p: &str| std::fs::read_to_string(root.join(p)).unwrap();
+    assert_eq!(read("etc/modified.conf"), "mine");
+    assert_eq!(read("etc/modified.conf.pacnew"), "contents");
+    assert_eq!(read("etc/unmodified.conf"), "contents");
+    assert_eq!(read("usr/bin/foo"), "contents");
+    assert!(!root.join("usr/share/locale").exists());
+
+    // reinstalling changes nothing but the non-backup files
+    std::fs::remove_file(root.join("etc/modified.conf.pacnew")).unwrap();
+    let e = Extractor::new(&root)
+        .no_extract(&["usr/share/locale/*"])
+        .no_upgrade(&["usr/bin/*"])
+        .upgrade_from(&e.files)
+        .extract(&pkg)
+        .unwrap();
+    assert!(e.extracted.is_empty());
+    assert_eq!(e.pacnew, ["usr/bin/foo"]);
+    assert!(!root.join("etc/modified.conf.pacnew").exists());
+}
@@ -0,0 +1,41 @@
+//! Picking among several packages providing a dependency.
+use crate::db::{Interner, Package, QuickResolve};
+
+/// The order [super::Transaction] offers providers to [super::Decisions::choose_provider] in,
+/// whose default takes the first.
+/// A package named like the dependency is always picked without asking, like in pacman.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ProviderStrategy {
+    /// Like pacman: by repo priority, then by name.
+    #[default]
+    RepoOrder,
+    /// By name regardless of repo.
+    Alphabetical,
+    /// Like RepoOrder, but only offers providers that, together with the dependencies
+    /// they pull in, can be installed without conflicts,
+    /// found by a backtracking search over the provider choices.
+    #[cfg(feature = "solver")]
+    Solver,
+}
+
+impl ProviderStrategy {
+    /// Sorts providers, (repo, package) in repo order, into the order of self.
+    pub fn order(self, i: &Interner, providers: &mut [(&str, &Package)]) {
+        if self == Self::Alphabetical {
+            providers.sort_by_cached_key(|(_, p)| p.name.r(&i.borrow()).to_owned());
+        }
+    }
+}
+
+#[test]
+fn test_provider_strategy() {
+    use crate::db::{new_interner, test_desc};
+    let i = new_interner();
+    let pkg = |name: &str| Package::from_str(i.clone(), &test_desc(name, "1-1", &[])).unwrap();
+    let (zsh, bash) = (pkg("zsh"), pkg("bash"));
+    let mut providers = [("core", &zsh), ("extra", &bash)];
+    ProviderStrategy::RepoOrder.order(&i, &mut providers);
+    assert_eq!(providers[0].0, "core");
+    ProviderStrategy::Alphabetical.order(&i, &mut providers);
+    assert_eq!(providers[0].0, "extra");
+}
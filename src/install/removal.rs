@@ -0,0 +1,364 @@
+//! Computing what `pacman -R` removes.
+use super::{Plan, TransactionError};
+use crate::config::PacmanConfig;
+use crate::db::{Database, Depend, InstallReason, LocalDb, Package, QuickResolve};
+use std::collections::{HashMap, HashSet};
+
+/// The flags of `pacman -R` that change what gets removed.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RemoveOptions {
+    /// `-s`: also remove dependencies installed as such that nothing else needs anymore.
+    pub recursive: bool,
+    /// `-c`: also remove the packages that depend on the targets, recursively.
+    pub cascade: bool,
+    /// `-n`: delete modified backup files instead of keeping them as `.pacsave`.
+    pub nosave: bool,
+    /// `-dd`: remove even if remaining packages lose a dependency.
+    pub nodeps: bool,
+    /// Remove packages matching HoldPkg, pacman asks for confirmation first.
+    pub held: bool,
+}
+
+/// Why a package is in a [RemovalPlan].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RemovalCause {
+    Target,
+    /// Depends on something removed, added by cascade.
+    Dependent,
+    /// A dependency nothing needs anymore, added by recursive.
+    Unneeded,
+}
+
+#[derive(Clone)]
+pub struct Removal {
+    pub package: Package,
+    pub cause: RemovalCause,
+}
+
+/// A dependency of an installed package, with the installed packages satisfying it.
+struct Edge<'a> {
+    name: String,
+    package: &'a Package,
+    dep: Depend,
+    satisfiers: Vec<String>,
+}
+
+/// What [plan_removal] decided, in the order the packages are removed,
+/// packages before the dependencies they are removed with.
+#[derive(Clone, Default)]
+pub struct RemovalPlan {
+    pub remove: Vec<Removal>,
+    pub nosave: bool,
+}
+
+impl From<RemovalPlan> for Plan {
+    fn from(r: RemovalPlan) -> Self {
+        Self {
+            install: Vec::new(),
+            remove: r.remove.into_iter().map(|r| r.package).collect(),
+            nosave: r.nosave,
+        }
+    }
+}
+
+/// Like `pacman -R` with options: the targets and what options add to them.
+/// Errors if a target is not installed,
+/// unless options has nodeps, if a remaining package loses a dependency,
+/// and unless options has held, if the plan removes a package matching the HoldPkg of config.
+pub fn plan_removal(
+    local: &LocalDb,
+    targets: &[&str],
+    options: RemoveOptions,
+    config: Option<&PacmanConfig>,
+) -> Result<RemovalPlan, TransactionError> {
+    let i = local.db().interner();
+    let name = |p: &Package| p.name.r(&i.borrow()).to_owned();
+    let mut remove: Vec<Removal> = Vec::new();
+    let mut removed: HashSet<String> = HashSet::new();
+    for t in targets {
+        let package = local
+            .get(t)
+            .ok_or_else(|| TransactionError::TargetNotFound(t.to_string()))?;
+        if removed.insert(t.to_string()) {
+            remove.push(Removal {
+                package: package.clone(),
+                cause: RemovalCause::Target,
+            });
+        }
+    }
+    // built once, so removing a package only rechecks what depends on it
+    let mut providers: HashMap<String, Vec<&Package>> = HashMap::new();
+    for p in local.packages() {
+        let ii = i.borrow();
+        providers
+            .entry(p.name.r(&ii).to_owned())
+            .or_default()
+            .push(p);
+        for prov in p.provides.iter().flatten() {
+            let prov = prov.r(&ii);
+            let prov = prov.split_once('=').map_or(prov, |(name, _)| name);
+            providers.entry(prov.to_owned()).or_default().push(p);
+        }
+    }
+    let satisfiers = |dep: &Depend| {
+        let mut found: Vec<&Package> = providers
+            .get(&dep.name)
+            .into_iter()
+            .flatten()
+            .copied()
+            .filter(|q| dep.satisfied_by(q, i))
+            .collect();
+        found.sort_by_cached_key(|q| {
+            let n = name(q);
+            (n != dep.name, n)
+        });
+        found.dedup_by(|a, b| a.name == b.name);
+        found
+    };
+    let mut edges = Vec::new();
+    // name -> indices into edges of the dependencies it satisfies
+    let mut dependents: HashMap<String, Vec<usize>> = HashMap::new();
+    for p in local.packages() {
+        for dep in p.parsed_depends(i) {
+            let satisfiers: Vec<String> = satisfiers(&dep).into_iter().map(name).collect();
+            // already broken before, nothing this removal changes
+            if satisfiers.is_empty() {
+                continue;
+            }
+            for s in &satisfiers {
+                dependents.entry(s.clone()).or_default().push(edges.len());
+            }
+            edges.push(Edge {
+                name: name(p),
+                package: p,
+                dep,
+                satisfiers,
+            });
+        }
+    }
+    let broken = |e: &Edge, removed: &HashSet<String>| {
+        !removed.contains(&e.name) && e.satisfiers.iter().all(|s| removed.contains(s))
+    };
+
+    if options.cascade {
+        let mut gone: Vec<String> = remove.iter().map(|r| name(&r.package)).collect();
+        while let Some(g) = gone.pop() {
+            for &n in dependents.get(&g).into_iter().flatten() {
+                let e = &edges[n];
+                if broken(e, &removed) {
+                    removed.insert(e.name.clone());
+                    gone.push(e.name.clone());
+                    remove.push(Removal {
+                        package: e.package.clone(),
+                        cause: RemovalCause::Dependent,
+                    });
+                }
+            }
+        }
+    }
+
+    if options.recursive {
+        let mut next = 0;
+        while next < remove.len() {
+            let depends = remove[next].package.parsed_depends(i);
+            next += 1;
+            for dep in depends {
+                let unneeded: Vec<&Package> = satisfiers(&dep)
+                    .into_iter()
+                    .filter(|s| {
+                        let n = name(s);
+                        s.reason == Some(InstallReason::Dependency)
+                            && !removed.contains(&n)
+                            && dependents
+                                .get(&n)
+                                .into_iter()
+                                .flatten()
+                                .all(|&e| removed.contains(&edges[e].name))
+                    })
+                    .collect();
+                for s in unneeded {
+                    removed.insert(name(s));
+                    remove.push(Removal {
+                        package: s.clone(),
+                        cause: RemovalCause::Unneeded,
+                    });
+                }
+            }
+        }
+    }
+
+    if !options.nodeps
+        && let Some(e) = edges.iter().find(|e| broken(e, &removed))
+    {
+        return Err(TransactionError::UnsatisfiedDependency(
+            e.name.clone(),
+            e.dep.to_string(),
+        ));
+    }
+
+    if !options.held
+        && let Some(config) = config
+        && let Some(r) = remove.iter().find(|r| config.is_held(&name(&r.package)))
+    {
+        return Err(TransactionError::HeldPackage(name(&r.package)));
+    }
+
+    let packages: Vec<Package> = remove.iter().map(|r| r.package.clone()).collect();
+    let order = crate::db::graph::sort_by_deps(i, &packages);
+    let mut ordered = Vec::with_capacity(remove.len());
+    for p in order.into_iter().rev().flatten() {
+        let n = remove
+            .iter()
+            .position(|r| r.package.name == p.name)
+            .unwrap();
+        ordered.push(remove.swap_remove(n));
+    }
+    Ok(RemovalPlan {
+        remove: ordered,
+        nosave: options.nosave,
+    })
+}
+
+#[test]
+fn test_plan_removal() {
+    use crate::db::{Db, new_interner, test_desc};
+    let i = new_interner();
+    let installed = |name: &str, reason: &str, extra: &[(&str, &str)]| {
+        let mut extra = extra.to_vec();
+        extra.push(("INSTALLDATE", "1700000000"));
+        if !reason.is_empty() {
+            extra.push(("REASON", reason));
+        }
+        Package::from_str(i.clone(), &test_desc(name, "1-1", &extra)).unwrap()
+    };
+    let local = LocalDb::new(Db::new(
+        i.clone(),
+        [
+            installed("app", "", &[("DEPENDS", "lib")]),
+            installed("lib", "1", &[("DEPENDS", "base")]),
+            installed("base", "1", &[]),
+            installed("tool", "", &[("DEPENDS", "base")]),
+            installed("plugin", "", &[("DEPENDS", "app")]),
+        ]
+        .into_iter()
+        .map(|p| (p.name, p))
+        .collect(),
+    ))
+    .unwrap();
+    let names = |plan: &RemovalPlan| {
+        plan.remove
+            .iter()
+            .map(|r| (r.package.name.r(&i.borrow()).to_owned(), r.cause))
+            .collect::<Vec<_>>()
+    };
+    let plan = |options| plan_removal(&local, &["app"], options, None);
+
+    assert!(matches!(
+        plan_removal(&local, &["nothing"], RemoveOptions::default(), None),
+        Err(TransactionError::TargetNotFound(_))
+    ));
+    assert!(matches!(
+        plan(RemoveOptions::default()),
+        Err(TransactionError::UnsatisfiedDependency(p, d)) if p == "plugin" && d == "app"
+    ));
+    let nodeps = RemoveOptions {
+        nodeps: true,
+        nosave: true,
+        ..Default::default()
+    };
+    let p = plan(nodeps).unwrap();
+    assert_eq!(names(&p), [("app".to_owned(), RemovalCause::Target)]);
+    assert!(Plan::from(p).nosave);
+
+    let cascade = RemoveOptions {
+        cascade: true,
+        ..Default::default()
+    };
+    let p = plan(cascade).unwrap();
+    assert_eq!(
+        names(&p),
+        [
+            ("plugin".to_owned(), RemovalCause::Dependent),
+            ("app".to_owned(), RemovalCause::Target)
+        ]
+    );
+    let p = plan(RemoveOptions {
+        recursive: true,
+        ..cascade
+    })
+    .unwrap();
+    // base stays for tool
+    assert_eq!(
+        names(&p),
+        [
+            ("plugin".to_owned(), RemovalCause::Dependent),
+            ("app".to_owned(), RemovalCause::Target),
+            ("lib".to_owned(), RemovalCause::Unneeded)
+        ]
+    );
+    let p = plan_removal(
+        &local,
+        &["tool", "plugin", "app"],
+        RemoveOptions {
+            recursive: true,
+            ..Default::default()
+        },
+        None,
+    )
+    .unwrap();
+    assert_eq!(p.remove.len(), 5);
+    assert_eq!(p.remove[4].package.name.r(&i.borrow()), "base");
+
+    // lib is held, and only removed when confirmed
+    let config = crate::config::test_config("[options]\nHoldPkg = li*\n");
+    let recursive = RemoveOptions {
+        recursive: true,
+        ..Default::default()
+    };
+    assert!(plan_removal(&local, &["plugin"], recursive, Some(&config)).is_ok());
+    assert!(matches!(
+        plan_removal(&local, &["plugin", "app"], recursive, Some(&config)),
+        Err(TransactionError::HeldPackage(p)) if p == "lib"
+    ));
+    let held = RemoveOptions {
+        held: true,
+        ..recursive
+    };
+    assert!(plan_removal(&local, &["plugin", "app"], held, Some(&config)).is_ok());
+}
+
+#[test]
+fn test_removal_provides() {
+    use crate::db::{Db, new_interner, test_desc};
+    let i = new_interner();
+    let installed = |name: &str, extra: &[(&str, &str)]| {
+        let mut extra = extra.to_vec();
+        extra.push(("INSTALLDATE", "1700000000"));
+        Package::from_str(i.clone(), &test_desc(name, "1-1", &extra)).unwrap()
+    };
+    let local = LocalDb::new(Db::new(
+        i.clone(),
+        [
+            installed("bash", &[("PROVIDES", "sh")]),
+            installed("dash", &[("PROVIDES", "sh=1")]),
+            installed("script", &[("DEPENDS", "sh")]),
+        ]
+        .into_iter()
+        .map(|p| (p.name, p))
+        .collect(),
+    ))
+    .unwrap();
+    let options = RemoveOptions::default();
+    assert!(plan_removal(&local, &["bash"], options, None).is_ok());
+    assert!(matches!(
+        plan_removal(&local, &["bash", "dash"], options, None),
+        Err(TransactionError::UnsatisfiedDependency(p, d)) if p == "script" && d == "sh"
+    ));
+    let cascade = RemoveOptions {
+        cascade: true,
+        ..options
+    };
+    let p = plan_removal(&local, &["bash", "dash"], cascade, None).unwrap();
+    assert_eq!(p.remove.len(), 3);
+    assert_eq!(p.remove[0].cause, RemovalCause::Dependent);
+}
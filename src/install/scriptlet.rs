@@ -0,0 +1,212 @@
+//! `.INSTALL` scriptlets, shell scripts defining functions pacman calls around a transaction.
+use crate::db::{Interner, Package, QuickResolve};
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The scriptlet functions, in the order pacman calls them for a package.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ScriptletHook {
+    /// Called with the new version.
+    PreInstall,
+    /// Called with the new version.
+    PostInstall,
+    /// Called with the new and the old version.
+    PreUpgrade,
+    /// Called with the new and the old version.
+    PostUpgrade,
+    /// Called with the old version.
+    PreRemove,
+    /// Called with the old version.
+    PostRemove,
+}
+
+impl ScriptletHook {
+    /// The function name, e.g. `pre_install`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::PreInstall => "pre_install",
+            Self::PostInstall => "post_install",
+            Self::PreUpgrade => "pre_upgrade",
+            Self::PostUpgrade => "post_upgrade",
+            Self::PreRemove => "pre_remove",
+            Self::PostRemove => "post_remove",
+        }
+    }
+}
+
+/// The contents of an install scriptlet.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Scriptlet {
+    pub script: String,
+}
+
+impl Scriptlet {
+    /// The `.INSTALL` of a package file, None if it has none.
+    pub fn from_package(path: &Path) -> io::Result<Option<Self>> {
+        let f = crate::db::decompress(BufReader::new(File::open(path)?))?;
+        for entry in tar::Archive::new(f).entries()? {
+            let mut entry = entry?;
+            if entry.path_bytes().as_ref() == b".INSTALL" {
+                let mut script = String::new();
+                entry.read_to_string(&mut script)?;
+                return Ok(Some(Self { script }));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The scriptlet pacman stored for the installed package pkg in local_dbpath
+    /// (usually `<dbpath>/local`), None if it has none.
+    pub fn from_localdb(
+        i: &Interner,
+        local_dbpath: &Path,
+        pkg: &Package,
+    ) -> io::Result<Option<Self>> {
+        let dir = {
+            let i = i.borrow();
+            local_dbpath.join(format!("{}-{}", pkg.name.r(&i), pkg.version.r(&i)))
+        };
+        match std::fs::read_to_string(dir.join("install")) {
+            Ok(script) => Ok(Some(Self { script })),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Whether the script defines the function for hook.
+    /// Like pacman this only looks at the text, functions that are not defined are not called.
+    pub fn defines(&self, hook: ScriptletHook) -> bool {
+        let name = hook.as_str();
+        self.script.lines().any(|l| {
+            let l = l.trim_start();
+            let l = l.strip_prefix("function ").map_or(l, str::trim_start);
+            l.strip_prefix(name)
+                .is_some_and(|rest| rest.trim_start().starts_with('('))
+        })
+    }
+}
+
+/// Executes scriptlet functions, so embedders decide how, or whether, scripts run.
+pub trait ScriptletRunner {
+    /// Calls the hook function of scriptlet with args, for a system installed at root.
+    /// Callers check [Scriptlet::defines] first, like pacman.
+    fn run(
+        &mut self,
+        root: &Path,
+        scriptlet: &Scriptlet,
+        hook: ScriptletHook,
+        args: &[&str],
+    ) -> io::Result<()>;
+}
+
+/// Does not run anything, like `pacman --noscriptlet`.
+pub struct SkipScriptlets;
+
+impl ScriptletRunner for SkipScriptlets {
+    fn run(&mut self, _: &Path, _: &Scriptlet, _: ScriptletHook, _: &[&str]) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Runs scriptlets with `/bin/sh` like pacman,
+/// chrooting into root unless it is `/`, which needs root permissions.
+/// The script is written to a temporary file inside of root's `tmp/`.
+pub struct ShellRunner;
+
+impl ScriptletRunner for ShellRunner {
+    fn run(
+        &mut self,
+        root: &Path,
+        scriptlet: &Scriptlet,
+        hook: ScriptletHook,
+        args: &[&str],
+    ) -> io::Result<()> {
+        static RUNS: AtomicUsize = AtomicUsize::new(0);
+        let run = RUNS.fetch_add(1, Ordering::Relaxed);
+        let tmpdir = root.join(format!("tmp/alpm_{}_{run}", std::process::id()));
+        std::fs::create_dir_all(&tmpdir)?;
+        let script = tmpdir.join(".INSTALL");
+        std::fs::write(&script, &scriptlet.script)?;
+        let inside = Path::new("/").join(script.strip_prefix(root).unwrap());
+        let cmd = format!(". \"{}\"; {} \"$@\"", inside.display(), hook.as_str());
+        let mut command = if root == Path::new("/") {
+            Command::new("/bin/sh")
+        } else {
+            let mut c = Command::new("chroot");
+            c.arg(root).arg("/bin/sh");
+            c
+        };
+        let status = command
+            .arg("-c")
+            .arg(cmd)
+            .arg("sh")
+            .args(args)
+            .current_dir(root)
+            .status();
+        let _ = std::fs::remove_dir_all(&tmpdir);
+        let status = status?;
+        if !status.success() {
+            return Err(io::Error::other(format!(
+                "scriptlet {} failed with {status}",
+                hook.as_str()
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_scriptlet() {
+    use crate::db::repo::{test_pkginfo, write_test_package};
+    let dir = crate::util::test_dir("scriptlet");
+    let pkg = dir.join("foo-1-1-x86_64.pkg.tar.zst");
+    write_test_package(&pkg, &test_pkginfo("foo", "1-1"), &[".INSTALL", "usr/"]);
+    let s = Scriptlet::from_package(&pkg).unwrap().unwrap();
+    assert_eq!(s.script, "contents");
+    let bare = dir.join("bar-1-1-x86_64.pkg.tar.zst");
+    write_test_package(&bare, &test_pkginfo("bar", "1-1"), &[]);
+    assert_eq!(Scriptlet::from_package(&bare).unwrap(), None);
+
+    let local = dir.join("local");
+    std::fs::create_dir_all(local.join("foo-1-1")).unwrap();
+    let out = dir.join("out");
+    let script = format!(
+        "post_install() {{\n\techo \"$1\" > {}\n}}\n\
+        post_upgrade () {{\n\techo \"$1 $2\" > {0}\n}}\n\
+        pre_remove() {{ false; }}\n# pre_install() {{ }}\n",
+        out.display()
+    );
+    std::fs::write(local.join("foo-1-1/install"), &script).unwrap();
+    let i = crate::db::new_interner();
+    let foo = Package::from_str(i.clone(), &crate::db::test_desc("foo", "1-1", &[])).unwrap();
+    let bar = Package::from_str(i.clone(), &crate::db::test_desc("bar", "1-1", &[])).unwrap();
+    assert_eq!(Scriptlet::from_localdb(&i, &local, &bar).unwrap(), None);
+    let s = Scriptlet::from_localdb(&i, &local, &foo).unwrap().unwrap();
+    assert!(s.defines(ScriptletHook::PostInstall));
+    assert!(s.defines(ScriptletHook::PostUpgrade));
+    assert!(s.defines(ScriptletHook::PreRemove));
+    assert!(!s.defines(ScriptletHook::PreInstall));
+    assert!(!s.defines(ScriptletHook::PostRemove));
+    let bash = Scriptlet {
+        script: "function  post_remove() {\n\ttrue\n}\n".to_owned(),
+    };
+    assert!(bash.defines(ScriptletHook::PostRemove));
+
+    let root = Path::new("/");
+    SkipScriptlets
+        .run(root, &s, ScriptletHook::PostInstall, &["1-1"])
+        .unwrap();
+    assert!(!out.exists());
+    ShellRunner
+        .run(root, &s, ScriptletHook::PostUpgrade, &["2-1", "1-1"])
+        .unwrap();
+    assert_eq!(std::fs::read_to_string(&out).unwrap(), "2-1 1-1\n");
+    assert!(
+        ShellRunner
+            .run(root, &s, ScriptletHook::PreRemove, &["1-1"])
+            .is_err()
+    );
+}
@@ -0,0 +1,166 @@
+//! Free space per filesystem, for `CheckSpace`.
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+/// A mounted filesystem and its size in bytes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MountPoint {
+    pub dir: PathBuf,
+    pub total: u64,
+    /// Free to unprivileged users, like pacman counts it.
+    pub available: u64,
+}
+
+/// The mounted filesystems from `/proc/self/mounts`, skipping those statvfs fails on.
+pub fn mount_points() -> io::Result<Vec<MountPoint>> {
+    let mounts = std::fs::read_to_string("/proc/self/mounts")?;
+    Ok(parse_mounts(&mounts)
+        .into_iter()
+        .filter_map(|dir| {
+            statvfs(&dir).ok().map(|(total, available)| MountPoint {
+                dir,
+                total,
+                available,
+            })
+        })
+        .collect())
+}
+
+/// The mount directories of a mounts file, unescaping the `\040` style octal escapes.
+fn parse_mounts(mounts: &str) -> Vec<PathBuf> {
+    let unescape = |s: &str| {
+        let mut out = Vec::with_capacity(s.len());
+        let mut bytes = s.bytes();
+        while let Some(b) = bytes.next() {
+            if b != b'\\' {
+                out.push(b);
+                continue;
+            }
+            let digits: Vec<u8> = bytes.by_ref().take(3).collect();
+            let code = std::str::from_utf8(&digits)
+                .ok()
+                .and_then(|d| u8::from_str_radix(d, 8).ok());
+            match code {
+                Some(c) => out.push(c),
+                None => out.extend([b'\\'].iter().chain(&digits)),
+            }
+        }
+        PathBuf::from(std::ffi::OsStr::from_bytes(&out))
+    };
+    mounts
+        .lines()
+        .filter_map(|l| l.split(' ').nth(1))
+        .map(unescape)
+        .collect()
+}
+
+/// (total, available) bytes of the filesystem path is on.
+// the field types differ between platforms
+#[allow(clippy::useless_conversion)]
+fn statvfs(path: &Path) -> io::Result<(u64, u64)> {
+    let c = CString::new(path.as_os_str().as_bytes())?;
+    let mut s = MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: c is nul terminated and s is only read after statvfs filled it
+    if unsafe { libc::statvfs(c.as_ptr(), s.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let s = unsafe { s.assume_init() };
+    let frsize = u64::from(s.f_frsize);
+    Ok((
+        u64::from(s.f_blocks) * frsize,
+        u64::from(s.f_bavail) * frsize,
+    ))
+}
+
+/// Bytes a transaction adds to or frees from each mount point.
+pub(crate) struct SpaceUsage<'m> {
+    mounts: &'m [MountPoint],
+    delta: Vec<i64>,
+}
+
+impl<'m> SpaceUsage<'m> {
+    pub fn new(mounts: &'m [MountPoint]) -> Self {
+        Self {
+            mounts,
+            delta: vec![0; mounts.len()],
+        }
+    }
+
+    /// Counts bytes, negative for freed ones, on the mount point path is on,
+    /// the one with the longest directory containing it.
+    pub fn add(&mut self, path: &Path, bytes: i64) {
+        let mount = self
+            .mounts
+            .iter()
+            .enumerate()
+            .filter(|(_, m)| path.starts_with(&m.dir))
+            .max_by_key(|(_, m)| m.dir.as_os_str().len());
+        if let Some((n, _)) = mount {
+            self.delta[n] += bytes;
+        }
+    }
+
+    /// Counts the files of a package file as extracted below root, by their size in the archive.
+    pub fn add_archive(&mut self, root: &Path, pkgfile: &Path) -> io::Result<()> {
+        let f = crate::db::decompress(BufReader::new(File::open(pkgfile)?))?;
+        for entry in tar::Archive::new(f).entries()? {
+            let entry = entry?;
+            let path = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+            let metadata = path.starts_with('.') && !path.contains('/');
+            if entry.header().entry_type().is_file() && !metadata {
+                self.add(&root.join(path), entry.size() as i64);
+            }
+        }
+        Ok(())
+    }
+
+    /// The first mount point that would be left with less than pacman's cushion,
+    /// 5% of its size but at most 20 MiB, and the bytes needed there including the cushion.
+    pub fn insufficient(&self) -> Option<(&'m MountPoint, u64)> {
+        self.mounts.iter().zip(&self.delta).find_map(|(m, &delta)| {
+            let cushion = (m.total / 20).min(20 << 20);
+            let needed = u64::try_from(delta).ok().filter(|d| *d > 0)? + cushion;
+            (needed > m.available).then_some((m, needed))
+        })
+    }
+}
+
+#[test]
+fn test_space() {
+    let mounts = parse_mounts(
+        "/dev/sda2 / ext4 rw,relatime 0 0\nproc /proc proc rw 0 0\n\
+        /dev/sda3 /usr ext4 rw 0 0\n/dev/sdb1 /mnt/my\\040disk vfat rw 0 0\n",
+    );
+    assert_eq!(
+        mounts,
+        ["/", "/proc", "/usr", "/mnt/my disk"].map(PathBuf::from)
+    );
+    let (total, available) = statvfs(Path::new("/")).unwrap();
+    assert!(total >= available);
+
+    let mount = |dir: &str, available| MountPoint {
+        dir: dir.into(),
+        total: 1 << 40,
+        available,
+    };
+    let mounts = [
+        mount("/", 1 << 30),
+        mount("/usr", 10 << 20),
+        mount("/var", 0),
+    ];
+    let mut usage = SpaceUsage::new(&mounts);
+    usage.add(Path::new("/usr/bin/foo"), 5 << 20);
+    usage.add(Path::new("/usr2/bin/foo"), 1 << 20);
+    usage.add(Path::new("/var/cache/foo"), -1);
+    assert!(
+        usage
+            .insufficient()
+            .is_some_and(|(m, needed)| m.dir == Path::new("/usr") && needed == 25 << 20)
+    );
+    usage.add(Path::new("/usr/share/foo"), -(5 << 20));
+    assert_eq!(usage.insufficient(), None);
+}
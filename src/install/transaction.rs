@@ -0,0 +1,1534 @@
+//! Installing, upgrading and removing packages as one unit, like a pacman transaction.
+use super::space::{self, SpaceUsage};
+use super::{
+    Decisions, Extractor, NonInteractive, ProviderStrategy, Scriptlet, ScriptletHook,
+    ScriptletRunner,
+};
+use crate::db::mtree::package_mtree;
+use crate::db::{
+    BackupStatus, Database, Db, Depend, FileList, InstallReason, Interner, LocalDb, Package,
+    QuickResolve, SyncDb, Validation, versioncmp,
+};
+use crate::events::EventSink;
+use crate::handle::Handle;
+use crate::hooks::{self, Changes, HookRunner, When};
+use crate::log::LogEvent;
+use log::{debug, warn};
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Where a package to install comes from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Source {
+    /// A sync db, the package file is looked up in the cache dirs.
+    Repo(String),
+    /// A package file, like `pacman -U`.
+    File(PathBuf),
+}
+
+/// A package the transaction installs, upgrades or reinstalls.
+#[derive(Clone)]
+pub struct Install {
+    pub package: Package,
+    pub source: Source,
+    /// Upgrades keep the reason of the installed version.
+    pub reason: InstallReason,
+    /// The installed version it replaces.
+    pub old: Option<Package>,
+}
+
+impl Install {
+    fn name(&self, interner: &Interner) -> String {
+        self.package.name.r(&interner.borrow()).to_owned()
+    }
+}
+
+/// What [Transaction::prepare] decided should happen.
+#[derive(Clone, Default)]
+pub struct Plan {
+    /// In the order they are installed, dependencies first.
+    pub install: Vec<Install>,
+    /// Installed packages that are removed.
+    pub remove: Vec<Package>,
+    /// Delete modified backup files of removed packages instead of keeping them as `.pacsave`.
+    pub nosave: bool,
+}
+
+#[derive(Debug)]
+pub enum TransactionError {
+    /// A target none of the sync dbs has.
+    TargetNotFound(String),
+    /// (package, dependency) that nothing satisfies after the transaction.
+    UnsatisfiedDependency(String, String),
+    /// (new package, package it conflicts with)
+    Conflict(String, String),
+    /// (path, new package, owner) of a file that would be overwritten,
+    /// the owner is None if the file exists but belongs to no package.
+    FileConflict(String, String, Option<String>),
+    /// A package file whose checksum does not match its repo.
+    Corrupt(PathBuf),
+    /// (package file, why) for a signature the package SigLevel does not accept.
+    BadSignature(PathBuf, String),
+    /// (mount point, bytes needed, bytes available), see [Transaction::check_space].
+    InsufficientSpace(PathBuf, u64, u64),
+    /// A package matching HoldPkg that would be removed without a confirmation.
+    HeldPackage(String),
+    /// The file of a repo package that is in none of the cache dirs.
+    /// With the download feature [Transaction::commit] downloads it first,
+    /// so this means it could not be, e.g. for lack of a config.
+    NotCached(String),
+    Io(io::Error),
+}
+
+impl Display for TransactionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TargetNotFound(t) => write!(f, "target not found: {t}"),
+            Self::UnsatisfiedDependency(p, d) => {
+                write!(f, "unable to satisfy dependency '{d}' required by {p}")
+            }
+            Self::Conflict(a, b) => write!(f, "{a} and {b} are in conflict"),
+            Self::FileConflict(path, p, Some(owner)) => {
+                write!(f, "{p}: /{path} exists in both '{p}' and '{owner}'")
+            }
+            Self::FileConflict(path, p, None) => write!(f, "{p}: /{path} exists in filesystem"),
+            Self::Corrupt(path) => write!(f, "{} is corrupted", path.display()),
+            Self::BadSignature(path, why) => {
+                write!(f, "{} has an invalid signature: {why}", path.display())
+            }
+            Self::InsufficientSpace(mount, needed, available) => write!(
+                f,
+                "insufficient space on {}: {needed} bytes needed, {available} available",
+                mount.display()
+            ),
+            Self::HeldPackage(p) => write!(f, "{p} is designated as a HoldPkg"),
+            Self::NotCached(file) => write!(f, "{file} is not in the cache"),
+            Self::Io(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for TransactionError {}
+
+impl From<io::Error> for TransactionError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// Collects targets, then computes a [Plan] with [Transaction::prepare]
+/// that [Transaction::commit] carries out.
+///
+/// Ex: ```let mut t = Transaction::new(&handle)?; t.sysupgrade(&NoEvents)?; t.commit(&t.prepare()?, ..)```
+pub struct Transaction<'h> {
+    handle: &'h Handle,
+    local: LocalDb,
+    /// The repos with Install usage, in priority order.
+    syncs: Vec<SyncDb>,
+    targets: Vec<(Package, Source)>,
+    removals: Vec<String>,
+    /// replacement name -> install reason of what it replaces
+    replacements: HashMap<String, InstallReason>,
+    decisions: RefCell<Box<dyn Decisions + 'h>>,
+    strategy: ProviderStrategy,
+}
+
+impl<'h> Transaction<'h> {
+    /// Reads the local db and the registered sync dbs of handle.
+    pub fn new(handle: &'h Handle) -> io::Result<Self> {
+        let i = handle.interner();
+        let local = LocalDb::new(Db::new(i.clone(), handle.localdb()?))?;
+        let syncs = handle
+            .syncdbs()
+            .iter()
+            .filter(|name| {
+                handle
+                    .config()
+                    .and_then(|c| c.repo(name))
+                    .is_none_or(|r| r.usage.install)
+            })
+            .map(|name| SyncDb::new(name.as_str(), Db::new(i.clone(), handle.syncdb(name)?)))
+            .collect::<io::Result<_>>()?;
+        Ok(Self {
+            handle,
+            local,
+            syncs,
+            targets: Vec::new(),
+            removals: Vec::new(),
+            replacements: HashMap::new(),
+            decisions: RefCell::new(Box::new(NonInteractive)),
+            strategy: ProviderStrategy::default(),
+        })
+    }
+
+    /// How providers are offered to the decisions, [ProviderStrategy::RepoOrder] by default.
+    pub fn provider_strategy(mut self, strategy: ProviderStrategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// Who answers the questions pacman would prompt for, [NonInteractive] by default.
+    pub fn decisions(mut self, decisions: impl Decisions + 'h) -> Self {
+        self.decisions = RefCell::new(Box::new(decisions));
+        self
+    }
+
+    pub fn localdb(&self) -> &LocalDb {
+        &self.local
+    }
+
+    pub fn syncdbs(&self) -> &[SyncDb] {
+        &self.syncs
+    }
+
+    /// Adds a package to install from the sync dbs, like `pacman -S`.
+    /// target is a name, `<repo>/<name>` or a dependency like `sh>=5` that a provider satisfies.
+    pub fn add(&mut self, target: &str) -> Result<(), TransactionError> {
+        let (repo, dep) = match target.split_once('/') {
+            Some((repo, dep)) => (Some(repo), dep),
+            None => (None, target),
+        };
+        let not_found = || TransactionError::TargetNotFound(target.to_owned());
+        let dep: Depend = dep.parse().map_err(|_| not_found())?;
+        let syncs = self
+            .syncs
+            .iter()
+            .filter(|s| repo.is_none_or(|r| s.name() == r));
+        let candidates = candidates(self.handle.interner(), syncs, &dep, self.strategy);
+        let decisions = &mut **self.decisions.get_mut();
+        let (repo, pkg) = choose(decisions, &dep, &candidates).ok_or_else(not_found)?;
+        self.targets.push((pkg, Source::Repo(repo)));
+        Ok(())
+    }
+
+    /// Adds a package file to install, like `pacman -U`.
+    pub fn add_file(&mut self, path: &Path) -> io::Result<()> {
+        let f = Package::from_pkg_file(self.handle.interner().clone(), path)?;
+        self.targets
+            .push((f.package, Source::File(path.to_owned())));
+        Ok(())
+    }
+
+    /// Adds the upgrades [Handle::update_candidates] finds, like `pacman -Su`,
+    /// and sync packages whose REPLACES names an installed package if [Decisions::replace] agrees,
+    /// which is removed and whose install reason the replacement keeps.
+    /// Like pacman, IgnorePkg and IgnoreGroup also hold back replacements.
+    /// [Transaction::prepare] then pulls in new dependencies.
+    /// Upgrades built for another architecture are reported to events.
+    pub fn sysupgrade(&mut self, events: &dyn EventSink) -> io::Result<()> {
+        let interner = self.handle.interner();
+        let (local, syncs) = (self.handle.localdb()?, self.handle.upgrade_syncdbs()?);
+        // replacements are only added below, once decisions agreed
+        let options = self.handle.update_options().replaces(false);
+        let mut upgrades = crate::db::find_upgrade_refs(interner, &local, &syncs, &options, events);
+        upgrades.sort_by_cached_key(|(_, _, new)| new.name.r(&interner.borrow()).to_owned());
+        for (repo, _, new) in upgrades {
+            self.targets
+                .push((new.clone(), Source::Repo(repo.to_owned())));
+        }
+        let config = self.handle.config();
+        let ignored = |p: &Package| {
+            let i = interner.borrow();
+            config.is_some_and(|c| {
+                c.ignores.iter().any(|n| n.trim() == p.name.r(&i))
+                    || p.groups
+                        .iter()
+                        .flatten()
+                        .any(|g| c.ignore_groups.iter().any(|n| n.trim() == g.r(&i)))
+            })
+        };
+        let mut replaced = HashSet::new();
+        for sync in &self.syncs {
+            if config
+                .and_then(|c| c.repo(sync.name()))
+                .is_some_and(|r| !r.usage.upgrade)
+            {
+                continue;
+            }
+            for new in sorted(interner, sync.packages().filter(|p| p.replaces.is_some())) {
+                let i = interner.borrow();
+                let replaces: Vec<Depend> = new
+                    .replaces
+                    .iter()
+                    .flatten()
+                    .filter_map(|r| r.r(&i).parse().ok())
+                    .collect();
+                let new_name = new.name.r(&i).to_owned();
+                drop(i);
+                for old in sorted(interner, self.local.packages()) {
+                    let old_name = old.name.r(&interner.borrow()).to_owned();
+                    let replaces_old = replaces
+                        .iter()
+                        .any(|r| r.name == old_name && r.satisfied_by(old, interner));
+                    if !replaces_old || old_name == new_name || ignored(old) || ignored(new) {
+                        continue;
+                    }
+                    if replaced.contains(&old_name)
+                        || !self.decisions.get_mut().replace(old, new, sync.name())
+                    {
+                        continue;
+                    }
+                    replaced.insert(old_name.clone());
+                    debug!("{new_name} replaces {old_name}");
+                    let reason = old.reason.unwrap_or(InstallReason::Explicit);
+                    self.replacements.insert(new_name.clone(), reason);
+                    self.removals.push(old_name);
+                    if !self.targets.iter().any(|(p, _)| p.name == new.name) {
+                        let source = Source::Repo(sync.name().to_owned());
+                        self.targets.push((new.clone(), source));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds an installed package to remove, like `pacman -R`.
+    pub fn remove(&mut self, name: &str) -> Result<(), TransactionError> {
+        if self.local.get(name).is_none() {
+            return Err(TransactionError::TargetNotFound(name.to_owned()));
+        }
+        self.removals.push(name.to_owned());
+        Ok(())
+    }
+
+    /// Resolves dependencies from the sync dbs, checks for conflicts
+    /// and that no remaining package loses a dependency, and orders the installs.
+    /// Asks decisions for providers, whether to remove conflicting installed packages
+    /// and whether to remove packages matching HoldPkg.
+    pub fn prepare(&self) -> Result<Plan, TransactionError> {
+        let interner = self.handle.interner();
+        let mut install: Vec<Install> = Vec::new();
+        for (package, source) in &self.targets {
+            let name = package.name.r(&interner.borrow()).to_owned();
+            let reason = self.replacements.get(&name).copied();
+            let install_target = self.install_of(package.clone(), source.clone(), reason);
+            install.retain(|i| i.name(interner) != name);
+            install.push(install_target);
+        }
+        let mut remove: Vec<Package> = self
+            .removals
+            .iter()
+            .filter_map(|name| self.local.get(name).cloned())
+            .collect();
+        let removed: HashSet<String> = self
+            .removals
+            .iter()
+            .cloned()
+            .chain(install.iter().map(|i| i.name(interner)))
+            .collect();
+
+        // install grows while its dependencies are resolved
+        let mut next = 0;
+        while next < install.len() {
+            let needed = install[next].package.parsed_depends(interner);
+            let dependent = install[next].name(interner);
+            next += 1;
+            for dep in needed {
+                if self.satisfied(&dep, &install, &removed) {
+                    continue;
+                }
+                #[allow(unused_mut)]
+                let mut candidates = candidates(interner, self.syncs.iter(), &dep, self.strategy);
+                #[cfg(feature = "solver")]
+                if self.strategy == ProviderStrategy::Solver {
+                    let viable: Vec<_> = candidates
+                        .iter()
+                        .copied()
+                        .filter(|(_, p)| self.solvable(p, &install, &removed))
+                        .collect();
+                    // without a viable one the checks below report why
+                    if !viable.is_empty() {
+                        candidates = viable;
+                    }
+                }
+                let decisions = &mut **self.decisions.borrow_mut();
+                let found = choose(decisions, &dep, &candidates);
+                let (repo, pkg) = found.ok_or_else(|| {
+                    TransactionError::UnsatisfiedDependency(dependent.clone(), dep.to_string())
+                })?;
+                debug!("pulling in {} for {dep}", pkg.name.r(&interner.borrow()));
+                let dep_install =
+                    self.install_of(pkg, Source::Repo(repo), Some(InstallReason::Dependency));
+                install.push(dep_install);
+            }
+        }
+        let mut removed: HashSet<String> = removed
+            .into_iter()
+            .chain(install.iter().map(|i| i.name(interner)))
+            .collect();
+
+        // installed packages in conflict with a new one are removed if decisions agree,
+        // otherwise check_conflicts fails below
+        for p in sorted(interner, self.local.packages()) {
+            let name = p.name.r(&interner.borrow()).to_owned();
+            let new = install
+                .iter()
+                .find(|i| in_conflict(interner, &i.package, p));
+            if !removed.contains(&name)
+                && let Some(new) = new
+                && self.decisions.borrow_mut().remove_conflict(&new.package, p)
+            {
+                debug!("removing {name}, it is in conflict");
+                removed.insert(name);
+                remove.push(p.clone());
+            }
+        }
+        if let Some(config) = self.handle.config() {
+            for p in &remove {
+                let name = p.name.r(&interner.borrow()).to_owned();
+                if config.is_held(&name) && !self.decisions.borrow_mut().remove_held(p) {
+                    return Err(TransactionError::HeldPackage(name));
+                }
+            }
+        }
+        let remaining: Vec<&Package> = self
+            .local
+            .packages()
+            .filter(|p| !removed.contains(p.name.r(&interner.borrow())))
+            .collect();
+        let installed = remaining
+            .iter()
+            .copied()
+            .chain(install.iter().map(|i| &i.package));
+        for p in installed {
+            for dep in p.parsed_depends(interner) {
+                let before = self.local.packages().any(|q| dep.satisfied_by(q, interner));
+                let new = install.iter().any(|i| i.package.name == p.name);
+                if (before || new) && !self.satisfied(&dep, &install, &removed) {
+                    let name = p.name.r(&interner.borrow()).to_owned();
+                    return Err(TransactionError::UnsatisfiedDependency(
+                        name,
+                        dep.to_string(),
+                    ));
+                }
+            }
+        }
+        check_conflicts(interner, &install, &remaining)?;
+
+        let packages: Vec<Package> = install.iter().map(|i| i.package.clone()).collect();
+        let order = crate::db::graph::sort_by_deps(interner, &packages);
+        let mut by_name: HashMap<String, Install> =
+            install.into_iter().map(|i| (i.name(interner), i)).collect();
+        let install = order
+            .into_iter()
+            .flatten()
+            .filter_map(|p| by_name.remove(p.name.r(&interner.borrow())))
+            .collect();
+        Ok(Plan {
+            install,
+            remove,
+            nosave: false,
+        })
+    }
+
+    /// Like `pacman --print`: a url for every install of plan in order,
+    /// `file://` for package files and cached packages, otherwise on the repo's first server,
+    /// followed by `<name>-<version>` of every removed package.
+    /// Packages of repos without a configured server are listed by file name.
+    pub fn print(&self, plan: &Plan) -> Vec<String> {
+        let interner = self.handle.interner();
+        let mut lines = Vec::new();
+        for install in &plan.install {
+            let i = interner.borrow();
+            let filename = install
+                .package
+                .filename
+                .map(|f| f.r(&i))
+                .unwrap_or_default();
+            let cached = crate::find_cached(self.handle.cachedirs(), filename);
+            let line = match (&install.source, cached.as_ref()) {
+                (Source::File(path), _) | (_, Some(path)) => format!("file://{}", path.display()),
+                (Source::Repo(repo), None) => {
+                    let server = self
+                        .handle
+                        .config()
+                        .and_then(|c| c.repo(repo)?.urls().next());
+                    match server {
+                        Some(server) => format!("{server}/{filename}"),
+                        None => filename.to_owned(),
+                    }
+                }
+            };
+            lines.push(line);
+        }
+        for p in &plan.remove {
+            let i = interner.borrow();
+            lines.push(format!("{}-{}", p.name.r(&i), p.version.r(&i)));
+        }
+        lines
+    }
+
+    fn install_of(
+        &self,
+        package: Package,
+        source: Source,
+        reason: Option<InstallReason>,
+    ) -> Install {
+        let interner = self.handle.interner();
+        let old = self.local.get(package.name.r(&interner.borrow())).cloned();
+        let reason = match &old {
+            Some(old) => old.reason.unwrap_or(InstallReason::Explicit),
+            None => reason.unwrap_or(InstallReason::Explicit),
+        };
+        Install {
+            package,
+            source,
+            reason,
+            old,
+        }
+    }
+
+    /// Whether candidate and what its dependencies pull in
+    /// can be installed next to install and the packages not in removed without conflicts.
+    #[cfg(feature = "solver")]
+    fn solvable(
+        &self,
+        candidate: &Package,
+        install: &[Install],
+        removed: &HashSet<String>,
+    ) -> bool {
+        let interner = self.handle.interner();
+        let fixed: Vec<&Package> = install
+            .iter()
+            .map(|i| &i.package)
+            .chain(
+                self.local
+                    .packages()
+                    .filter(|p| !removed.contains(p.name.r(&interner.borrow()))),
+            )
+            .collect();
+        !fixed.iter().any(|p| in_conflict(interner, candidate, p))
+            && solve(
+                interner,
+                &self.syncs,
+                &fixed,
+                vec![candidate.clone()],
+                candidate.parsed_depends(interner),
+            )
+    }
+
+    /// Whether dep is satisfied after installing install and removing removed.
+    fn satisfied(&self, dep: &Depend, install: &[Install], removed: &HashSet<String>) -> bool {
+        let interner = self.handle.interner();
+        install
+            .iter()
+            .any(|i| dep.satisfied_by(&i.package, interner))
+            || self
+                .local
+                .packages()
+                .filter(|p| !removed.contains(p.name.r(&interner.borrow())))
+                .any(|p| dep.satisfied_by(p, interner))
+    }
+
+    /// Like CheckSpace: errors if a filesystem would run out of space, keeping pacman's cushion.
+    /// Files of cached packages count with their size in the archive,
+    /// packages still to be downloaded with their download size in the first cache dir
+    /// and their installed size in root.
+    /// Files of removed and replaced packages count as freed.
+    /// [Transaction::commit] checks this when the config sets CheckSpace.
+    pub fn check_space(&self, plan: &Plan) -> Result<(), TransactionError> {
+        let interner = self.handle.interner();
+        let mounts = space::mount_points()?;
+        let mut usage = SpaceUsage::new(&mounts);
+        let root = std::fs::canonicalize(self.handle.root())?;
+        for install in &plan.install {
+            match self.locate(install) {
+                Ok(path) => usage.add_archive(&root, &path)?,
+                Err(TransactionError::Io(e)) if e.kind() == io::ErrorKind::NotFound => {
+                    let p = &install.package;
+                    if let Some(cache) = self.handle.cachedirs().first() {
+                        let cache = std::fs::canonicalize(cache).unwrap_or_else(|_| cache.clone());
+                        usage.add(&cache, p.csize.unwrap_or(0) as i64);
+                    }
+                    usage.add(&root, p.isize.unwrap_or(0) as i64);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        let local_files = self.local_files()?;
+        let gone = plan
+            .remove
+            .iter()
+            .chain(plan.install.iter().filter_map(|i| i.old.as_ref()));
+        for p in gone {
+            let name = p.name.r(&interner.borrow()).to_owned();
+            let files = local_files.get(&name).into_iter().flat_map(|l| &l.files);
+            for path in files.filter(|f| !f.ends_with('/')).map(|f| root.join(f)) {
+                if let Ok(m) = std::fs::symlink_metadata(&path) {
+                    usage.add(&path, -(m.len() as i64));
+                }
+            }
+        }
+        match usage.insufficient() {
+            Some((m, needed)) => Err(TransactionError::InsufficientSpace(
+                m.dir.clone(),
+                needed,
+                m.available,
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// name -> installed files
+    fn local_files(&self) -> io::Result<HashMap<String, FileList>> {
+        let files = self.handle.localdb_files()?;
+        let i = self.handle.interner().borrow();
+        Ok(files
+            .into_iter()
+            .map(|(name, files)| (name.r(&i).to_owned(), files))
+            .collect())
+    }
+
+    /// Downloads the repo packages of plan that are in none of the cache dirs into the first one,
+    /// from the mirrors of the config, see [crate::download::download_packages].
+    /// [Transaction::commit] does this itself with the default options,
+    /// call it first to choose the options and to follow the progress.
+    /// Without a config nothing is downloaded.
+    #[cfg(feature = "download")]
+    pub fn download(
+        &self,
+        plan: &Plan,
+        options: &crate::download::DownloadOptions,
+        events: &dyn EventSink,
+    ) -> Result<(), TransactionError> {
+        let (Some(config), Some(cachedir)) =
+            (self.handle.config(), self.handle.cachedirs().first())
+        else {
+            return Ok(());
+        };
+        // (repo, old, new) like upgrades, download_packages only looks at the new package
+        let missing: Vec<_> = plan
+            .install
+            .iter()
+            .filter_map(|install| match &install.source {
+                Source::Repo(repo)
+                    if matches!(self.locate(install), Err(TransactionError::NotCached(_))) =>
+                {
+                    let p = install.package.clone();
+                    Some((repo.as_str(), p.clone(), p))
+                }
+                _ => None,
+            })
+            .collect();
+        if missing.is_empty() {
+            return Ok(());
+        }
+        let interner = self.handle.interner();
+        let downloaded = crate::download::download_packages(
+            interner, &missing, config, cachedir, options, events,
+        )?;
+        for (_, result) in downloaded {
+            result?;
+        }
+        Ok(())
+    }
+
+    /// Carries out plan while holding the [crate::db::DBLock]:
+    /// finds the package files in the cache dirs, with the download feature
+    /// downloads the missing ones like `Transaction::download`, verifies their checksums
+    /// and, with the pgp feature, their signatures,
+    /// checks for file conflicts, runs PreTransaction hooks, removes and extracts packages
+    /// with their scriptlets, updates the local db and runs PostTransaction hooks.
+    /// Hook failures are only logged unless the hook has AbortOnFail.
+    pub fn commit(
+        &self,
+        plan: &Plan,
+        scriptlets: &mut dyn ScriptletRunner,
+        hook_runner: &mut dyn HookRunner,
+    ) -> Result<(), TransactionError> {
+        let interner = self.handle.interner();
+        let _lock = self.handle.lock()?;
+        let root = self.handle.root();
+        let local_dbpath = self.handle.dbpath().join("local");
+        if self.handle.config().is_some_and(|c| c.check_space) {
+            self.check_space(plan)?;
+        }
+        #[cfg(feature = "download")]
+        self.download(plan, &Default::default(), &crate::events::NoEvents)?;
+        let mut files = Vec::new();
+        // per install, whether a valid signature was found
+        let mut signed = Vec::new();
+        let mut keyring = None;
+        for install in &plan.install {
+            let path = self.locate(install)?;
+            if let Source::Repo(_) = install.source {
+                verify_checksum(&install.package, &path)?;
+            }
+            signed.push(self.verify_signature(install, &path, &mut keyring)?);
+            let pkgfile = Package::from_pkg_file(self.handle.interner().clone(), &path)?;
+            files.push((path, pkgfile));
+        }
+        let local_files = self.local_files()?;
+        let no_files = FileList::default();
+        let old_files = |p: &Package| {
+            let name = p.name.r(&interner.borrow()).to_owned();
+            local_files.get(&name).unwrap_or(&no_files)
+        };
+        self.check_file_conflicts(plan, &files, &local_files)?;
+
+        let hooks = hooks::read_hooks(&self.handle.hook_dirs())?;
+        let mut changes = Changes::default();
+        for (install, (_, pkgfile)) in plan.install.iter().zip(&files) {
+            let old = install.old.as_ref().map(|o| old_files(o).files.as_slice());
+            changes.package(&install.name(interner), old, Some(&pkgfile.files.files));
+        }
+        for p in &plan.remove {
+            let name = p.name.r(&interner.borrow()).to_owned();
+            changes.package(&name, Some(&old_files(p).files), None);
+        }
+        self.run_hooks(&hooks, &changes, When::PreTransaction, hook_runner)?;
+        self.log(LogEvent::TransactionStarted);
+
+        // paths the new packages bring along must survive removing the old ones
+        let kept: HashSet<&str> = files
+            .iter()
+            .flat_map(|(_, f)| f.files.files.iter().map(String::as_str))
+            .collect();
+        for p in &plan.remove {
+            let (name, version) = {
+                let i = interner.borrow();
+                (p.name.r(&i).to_owned(), p.version.r(&i).to_owned())
+            };
+            let scriptlet = Scriptlet::from_localdb(interner, &local_dbpath, p)?;
+            run_scriptlet(
+                scriptlets,
+                root,
+                &scriptlet,
+                ScriptletHook::PreRemove,
+                &[&version],
+            )?;
+            remove_files(root, old_files(p), &kept, plan.nosave)?;
+            remove_dir_all_existing(&local_dbpath.join(format!("{name}-{version}")))?;
+            self.log(LogEvent::Removed {
+                name: name.clone(),
+                version: version.clone(),
+            });
+            run_scriptlet(
+                scriptlets,
+                root,
+                &scriptlet,
+                ScriptletHook::PostRemove,
+                &[&version],
+            )?;
+        }
+
+        let config = self.handle.config();
+        for ((install, (path, pkgfile)), signed) in plan.install.iter().zip(files).zip(signed) {
+            let (name, version) = {
+                let i = interner.borrow();
+                let p = &install.package;
+                (p.name.r(&i).to_owned(), p.version.r(&i).to_owned())
+            };
+            let old_version = install
+                .old
+                .as_ref()
+                .map(|o| o.version.r(&interner.borrow()).to_owned());
+            let scriptlet = if pkgfile.has_install {
+                Scriptlet::from_package(&path)?
+            } else {
+                None
+            };
+            let (pre, post, args) = match &old_version {
+                Some(old) => (
+                    ScriptletHook::PreUpgrade,
+                    ScriptletHook::PostUpgrade,
+                    vec![version.as_str(), old.as_str()],
+                ),
+                None => (
+                    ScriptletHook::PreInstall,
+                    ScriptletHook::PostInstall,
+                    vec![version.as_str()],
+                ),
+            };
+            run_scriptlet(scriptlets, root, &scriptlet, pre, &args)?;
+
+            let previous = install.old.as_ref().map(old_files);
+            let mut extractor = Extractor::new(root)
+                .no_extract(config.map_or(&[][..], |c| &c.no_extract))
+                .no_upgrade(config.map_or(&[][..], |c| &c.no_upgrade));
+            if let Some(previous) = previous {
+                extractor = extractor.upgrade_from(previous);
+            }
+            let extraction = extractor.extract(&path)?;
+            if let Some(previous) = previous {
+                let new: HashSet<&str> =
+                    extraction.files.files.iter().map(String::as_str).collect();
+                remove_files(root, previous, &new, false)?;
+            }
+
+            let mut desc = pkgfile.package;
+            desc.install_date = Some(SystemTime::now());
+            desc.reason = Some(install.reason).filter(|r| *r != InstallReason::Explicit);
+            desc.validation = match install.source {
+                _ if signed => Some(Validation::Signature),
+                Source::Repo(_) if install.package.sha256sum.is_some() => {
+                    Some(Validation::Sha256Sum)
+                }
+                _ => Some(Validation::None),
+            };
+            let dir = local_dbpath.join(format!("{name}-{version}"));
+            if let Some(old) = &old_version
+                && *old != version
+            {
+                remove_dir_all_existing(&local_dbpath.join(format!("{name}-{old}")))?;
+            }
+            std::fs::create_dir_all(&dir)?;
+            let desc = desc.to_desc_string(interner);
+            crate::util::replace(&dir.join("desc"), |mut f| f.write_all(desc.as_bytes()))?;
+            let files = extraction.files.to_files_string();
+            crate::util::replace(&dir.join("files"), |mut f| f.write_all(files.as_bytes()))?;
+            match package_mtree(&path) {
+                Ok(mtree) => std::fs::write(dir.join("mtree"), mtree)?,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e.into()),
+            }
+            if let Some(s) = &scriptlet {
+                std::fs::write(dir.join("install"), &s.script)?;
+            }
+            for pacnew in &extraction.pacnew {
+                warn!("/{pacnew} installed as /{pacnew}.pacnew");
+            }
+            run_scriptlet(scriptlets, root, &scriptlet, post, &args)?;
+            self.log(match old_version {
+                None => LogEvent::Installed { name, version },
+                Some(old) => match versioncmp(&version, &old) {
+                    Ordering::Greater => LogEvent::Upgraded {
+                        name,
+                        old,
+                        new: version,
+                    },
+                    Ordering::Less => LogEvent::Downgraded {
+                        name,
+                        old,
+                        new: version,
+                    },
+                    Ordering::Equal => LogEvent::Reinstalled { name, version },
+                },
+            });
+        }
+
+        self.run_hooks(&hooks, &changes, When::PostTransaction, hook_runner)?;
+        self.log(LogEvent::TransactionCompleted);
+        Ok(())
+    }
+
+    /// Like pacman, failing to write the log does not fail the transaction.
+    fn log(&self, event: LogEvent) {
+        if let Err(e) = self.handle.log(event) {
+            warn!(
+                "could not write to {}: {e}",
+                self.handle.logfile().display()
+            );
+        }
+    }
+
+    /// The package file of install, cached downloads for repo packages.
+    fn locate(&self, install: &Install) -> Result<PathBuf, TransactionError> {
+        let interner = self.handle.interner();
+        match &install.source {
+            Source::File(path) => Ok(path.clone()),
+            Source::Repo(_) => {
+                let i = interner.borrow();
+                let filename = install.package.filename.map(|f| f.r(&i)).ok_or_else(|| {
+                    TransactionError::Corrupt(PathBuf::from(install.package.name.r(&i)))
+                })?;
+                crate::find_cached(self.handle.cachedirs(), filename)
+                    .ok_or_else(|| TransactionError::NotCached(filename.to_owned()))
+            }
+        }
+    }
+
+    /// Checks the signature of a repo package against the keyring in GPGDir,
+    /// if the package SigLevel of its repo asks for it. Without a config nothing is checked.
+    /// keyring is opened on first use.
+    /// returns whether a valid signature was found
+    #[cfg(feature = "pgp")]
+    fn verify_signature(
+        &self,
+        install: &Install,
+        path: &Path,
+        keyring: &mut Option<crate::pgp::Keyring>,
+    ) -> Result<bool, TransactionError> {
+        let interner = self.handle.interner();
+        use crate::config::SigRequirement;
+        use crate::pgp::{Keyring, Verification};
+        let (Source::Repo(repo), Some(config)) = (&install.source, self.handle.config()) else {
+            return Ok(false);
+        };
+        let requirement = config
+            .repo(repo)
+            .map_or(config.sig_level, |r| r.sig_level)
+            .package
+            .requirement;
+        if requirement == SigRequirement::Never {
+            return Ok(false);
+        }
+        if keyring.is_none() {
+            *keyring = Some(Keyring::open(&config.gpg_dir)?);
+        }
+        let keyring = keyring.as_ref().unwrap();
+        let p = &install.package;
+        let pgpsig = p.pgpsig.map(|s| s.r(&interner.borrow()).to_owned());
+        let v = keyring.verify_package(path, pgpsig.as_deref())?;
+        if v.satisfies(requirement) {
+            Ok(matches!(v, Verification::Valid { .. }))
+        } else {
+            Err(TransactionError::BadSignature(
+                path.to_owned(),
+                format!("{v:?}"),
+            ))
+        }
+    }
+
+    /// Signatures are only checked with the pgp feature.
+    #[cfg(not(feature = "pgp"))]
+    fn verify_signature(
+        &self,
+        _install: &Install,
+        _path: &Path,
+        _keyring: &mut Option<()>,
+    ) -> Result<bool, TransactionError> {
+        Ok(false)
+    }
+
+    fn check_file_conflicts(
+        &self,
+        plan: &Plan,
+        files: &[(PathBuf, crate::db::PkgFile)],
+        local_files: &HashMap<String, FileList>,
+    ) -> Result<(), TransactionError> {
+        let interner = self.handle.interner();
+        let replaced: HashSet<String> = plan
+            .install
+            .iter()
+            .map(|i| i.name(interner))
+            .chain(
+                plan.remove
+                    .iter()
+                    .map(|p| p.name.r(&interner.borrow()).to_owned()),
+            )
+            .collect();
+        let mut owners: HashMap<&str, &str> = HashMap::new();
+        for (name, list) in local_files {
+            if replaced.contains(name) {
+                continue;
+            }
+            for f in list.files.iter().filter(|f| !f.ends_with('/')) {
+                owners.insert(f, name);
+            }
+        }
+        let previously_owned: HashSet<&str> = replaced
+            .iter()
+            .filter_map(|name| local_files.get(name))
+            .flat_map(|l| l.files.iter().map(String::as_str))
+            .collect();
+        let mut new_owners: HashMap<&str, String> = HashMap::new();
+        for (install, (_, pkgfile)) in plan.install.iter().zip(files) {
+            let name = install.name(interner);
+            for f in pkgfile.files.files.iter().filter(|f| !f.ends_with('/')) {
+                let conflict =
+                    |owner| TransactionError::FileConflict(f.clone(), name.clone(), owner);
+                if let Some(owner) = owners.get(f.as_str()) {
+                    return Err(conflict(Some(owner.to_string())));
+                }
+                if let Some(owner) = new_owners.insert(f, name.clone()) {
+                    return Err(conflict(Some(owner)));
+                }
+                let exists = std::fs::symlink_metadata(self.handle.root().join(f))
+                    .is_ok_and(|m| !m.is_dir());
+                if exists && !previously_owned.contains(f.as_str()) {
+                    return Err(conflict(None));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn run_hooks(
+        &self,
+        hooks: &[hooks::Hook],
+        changes: &Changes,
+        when: When,
+        runner: &mut dyn HookRunner,
+    ) -> Result<(), TransactionError> {
+        let installed = |name: &str| {
+            self.local.get(name).is_some() || changes.packages.iter().any(|(p, _)| p == name)
+        };
+        for (hook, targets) in hooks::triggered(hooks, changes, when) {
+            if let Some(missing) = hook.depends.iter().find(|d| !installed(d)) {
+                warn!("not running hook {}, {missing} is missing", hook.name);
+                continue;
+            }
+            if let Err(e) = runner.run(self.handle.root(), hook, &targets) {
+                if hook.abort_on_fail {
+                    return Err(e.into());
+                }
+                warn!("hook {} failed: {e}", hook.name);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// (repo, package) satisfying dep: the first package named like it,
+/// otherwise the providers of all sync dbs in the order of strategy.
+fn candidates<'s>(
+    interner: &Interner,
+    syncs: impl Iterator<Item = &'s SyncDb> + Clone,
+    dep: &Depend,
+    strategy: ProviderStrategy,
+) -> Vec<(&'s str, &'s Package)> {
+    let by_name = syncs.clone().find_map(|s| {
+        let p = s.get(&dep.name).filter(|p| dep.satisfied_by(p, interner))?;
+        Some((s.name(), p))
+    });
+    if let Some(p) = by_name {
+        return vec![p];
+    }
+    let mut providers: Vec<(&str, &Package)> = syncs
+        .flat_map(|s| s.satisfiers(dep).into_iter().map(move |p| (s.name(), p)))
+        .collect();
+    strategy.order(interner, &mut providers);
+    providers
+}
+
+/// The only candidate, or the one decisions choose.
+/// returns (repo, package)
+fn choose(
+    decisions: &mut dyn Decisions,
+    dep: &Depend,
+    candidates: &[(&str, &Package)],
+) -> Option<(String, Package)> {
+    let chosen = match candidates.len() {
+        0 => return None,
+        1 => 0,
+        _ => decisions.choose_provider(dep, candidates),
+    };
+    let (repo, p) = candidates.get(chosen).or(candidates.first())?;
+    Some((repo.to_string(), (*p).clone()))
+}
+
+/// Whether the dependencies in pending can be satisfied by fixed, chosen and sync packages
+/// without conflicts, trying providers in repo order depth-first.
+#[cfg(feature = "solver")]
+fn solve(
+    interner: &Interner,
+    syncs: &[SyncDb],
+    fixed: &[&Package],
+    chosen: Vec<Package>,
+    mut pending: Vec<Depend>,
+) -> bool {
+    let Some(dep) = pending.pop() else {
+        return true;
+    };
+    let all = || fixed.iter().copied().chain(&chosen);
+    if all().any(|p| dep.satisfied_by(p, interner)) {
+        return solve(interner, syncs, fixed, chosen, pending);
+    }
+    for (_, c) in candidates(interner, syncs.iter(), &dep, ProviderStrategy::RepoOrder) {
+        if all().any(|p| in_conflict(interner, c, p)) {
+            continue;
+        }
+        let mut chosen = chosen.clone();
+        chosen.push(c.clone());
+        let mut pending = pending.clone();
+        pending.extend(c.parsed_depends(interner));
+        if solve(interner, syncs, fixed, chosen, pending) {
+            return true;
+        }
+    }
+    false
+}
+
+/// By name, for results that do not depend on hash order.
+fn sorted<'p>(
+    interner: &Interner,
+    packages: impl Iterator<Item = &'p Package>,
+) -> Vec<&'p Package> {
+    let mut packages: Vec<_> = packages.collect();
+    packages.sort_by_cached_key(|p| p.name.r(&interner.borrow()).to_owned());
+    packages
+}
+
+/// Whether the CONFLICTS of a or b match the other.
+fn in_conflict(interner: &Interner, a: &Package, b: &Package) -> bool {
+    let conflicts = |p: &Package, other: &Package| {
+        let i = interner.borrow();
+        p.conflicts
+            .iter()
+            .flatten()
+            .filter_map(|c| c.r(&i).parse::<Depend>().ok())
+            .any(|c| c.satisfied_by(other, interner))
+    };
+    a.name != b.name && (conflicts(a, b) || conflicts(b, a))
+}
+
+/// Checks the new packages against each other and the packages that stay installed.
+fn check_conflicts(
+    interner: &Interner,
+    install: &[Install],
+    remaining: &[&Package],
+) -> Result<(), TransactionError> {
+    let everything: Vec<&Package> = install
+        .iter()
+        .map(|i| &i.package)
+        .chain(remaining.iter().copied())
+        .collect();
+    for new in install.iter().map(|i| &i.package) {
+        if let Some(other) = everything
+            .iter()
+            .find(|other| in_conflict(interner, new, other))
+        {
+            let name = |p: &Package| p.name.r(&interner.borrow()).to_owned();
+            return Err(TransactionError::Conflict(name(new), name(other)));
+        }
+    }
+    Ok(())
+}
+
+fn verify_checksum(package: &Package, path: &Path) -> Result<(), TransactionError> {
+    match package.sha256sum {
+        Some(expected) if !crate::util::digest_matches::<sha2::Sha256>(&expected, path)? => {
+            Err(TransactionError::Corrupt(path.to_owned()))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn run_scriptlet(
+    runner: &mut dyn ScriptletRunner,
+    root: &Path,
+    scriptlet: &Option<Scriptlet>,
+    hook: ScriptletHook,
+    args: &[&str],
+) -> io::Result<()> {
+    match scriptlet {
+        Some(s) if s.defines(hook) => runner.run(root, s, hook, args),
+        _ => Ok(()),
+    }
+}
+
+fn remove_dir_all_existing(path: &Path) -> io::Result<()> {
+    match std::fs::remove_dir_all(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Removes the files of a package below root, except the paths in keep,
+/// deepest first so directories are empty by the time they are reached.
+/// Directories still in use by other packages are left alone,
+/// modified backup files are kept as `.pacsave` unless nosave.
+fn remove_files(
+    root: &Path,
+    files: &FileList,
+    keep: &HashSet<&str>,
+    nosave: bool,
+) -> io::Result<()> {
+    for path in files.files.iter().rev() {
+        if keep.contains(path.as_str()) {
+            continue;
+        }
+        let full = root.join(path);
+        if path.ends_with('/') {
+            // fails if not empty, which is fine
+            let _ = std::fs::remove_dir(&full);
+            continue;
+        }
+        let backup = files.backup.iter().find(|b| b.path == *path);
+        if let Some(b) = backup
+            && !nosave
+            && b.status(root)? == BackupStatus::Modified
+        {
+            let mut pacsave = full.clone().into_os_string();
+            pacsave.push(".pacsave");
+            warn!("/{path} saved as /{path}.pacsave");
+            std::fs::rename(&full, pacsave)?;
+            continue;
+        }
+        match std::fs::remove_file(&full) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+/// Builds package files in cache and adds them to the sync db `<dbpath>/sync/core.db`.
+/// packages are (name, .PKGINFO lines, files), all of version 1-1 and without dependencies.
+#[cfg(test)]
+fn write_test_repo(dbpath: &Path, cache: &Path, packages: &[(&str, &str, &[&str])]) {
+    use crate::db::repo::{RepoDb, test_pkginfo, write_test_package};
+    std::fs::create_dir_all(cache).unwrap();
+    std::fs::create_dir_all(dbpath.join("sync")).unwrap();
+    let mut repo = RepoDb::new(dbpath.join("sync/core.db.tar.gz"));
+    for (name, extra, files) in packages {
+        let path = cache.join(format!("{name}-1-1-x86_64.pkg.tar.zst"));
+        let pkginfo = test_pkginfo(name, "1-1").replace("depend = glibc\ndepend = sh\n", extra);
+        write_test_package(&path, &pkginfo, files);
+        repo.add(&path).unwrap();
+    }
+    repo.write().unwrap();
+}
+
+#[test]
+fn test_transaction() {
+    let dir = crate::util::test_dir("transaction");
+    let root = dir.join("root");
+    let dbpath = root.join("var/lib/pacman");
+    let cache = root.join("var/cache/pacman/pkg");
+    let bin = ["usr/", "usr/bin/", "usr/bin/foo"];
+    let lib = ["usr/", "usr/lib/", "usr/lib/libbar.so"];
+    let stray = ["usr/", "usr/bin/", "usr/bin/stray"];
+    write_test_repo(
+        &dbpath,
+        &cache,
+        &[
+            ("foo", "depend = libbar\n", &bin),
+            ("bar", "provides = libbar\n", &lib),
+            ("baz", "conflict = foo\n", &bin[..2]),
+            ("qux", "", &stray),
+        ],
+    );
+
+    let local = dbpath.join("local");
+    crate::db::write_test_dbpath(
+        &dbpath,
+        &[(
+            "foo-0.9-1",
+            crate::db::test_desc("foo", "0.9-1", &[("INSTALLDATE", "1700000000")]),
+        )],
+        &[],
+    );
+    let old_files = "%FILES%\nusr/\nusr/bin/\nusr/bin/foo\nusr/bin/old\n\n";
+    std::fs::write(local.join("foo-0.9-1/files"), old_files).unwrap();
+    std::fs::create_dir_all(root.join("usr/bin")).unwrap();
+    std::fs::write(root.join("usr/bin/foo"), "old").unwrap();
+    std::fs::write(root.join("usr/bin/old"), "old").unwrap();
+    std::fs::write(root.join("usr/bin/stray"), "mine").unwrap();
+    std::fs::create_dir_all(root.join("var/log")).unwrap();
+    let hooks = root.join("etc/pacman.d/hooks");
+    std::fs::create_dir_all(&hooks).unwrap();
+    std::fs::write(
+        hooks.join("foo.hook"),
+        "[Trigger]\nOperation = Upgrade\nType = Package\nTarget = foo\n\n\
+        [Action]\nWhen = PostTransaction\nExec = /bin/true\nNeedsTargets\n",
+    )
+    .unwrap();
+
+    struct Record(Vec<(String, Vec<String>)>);
+    impl HookRunner for Record {
+        fn run(&mut self, _: &Path, hook: &hooks::Hook, targets: &[String]) -> io::Result<()> {
+            self.0.push((hook.name.clone(), targets.to_vec()));
+            Ok(())
+        }
+    }
+    let handle = Handle::builder()
+        .root(&root)
+        .register_syncdb("core")
+        .build();
+
+    let mut t = Transaction::new(&handle).unwrap();
+    assert!(matches!(
+        t.add("nothing"),
+        Err(TransactionError::TargetNotFound(_))
+    ));
+    t.add("baz").unwrap();
+    t.add("core/foo").unwrap();
+    assert!(matches!(t.prepare(), Err(TransactionError::Conflict(..))));
+
+    // removing a held package needs a confirmation
+    let held = Handle::builder()
+        .root(&root)
+        .dbpath(&dbpath)
+        .register_syncdb("core")
+        .config(crate::config::test_config("[options]\nHoldPkg = fo*\n"))
+        .build();
+    let mut t = Transaction::new(&held).unwrap();
+    t.remove("foo").unwrap();
+    assert!(matches!(
+        t.prepare(),
+        Err(TransactionError::HeldPackage(p)) if p == "foo"
+    ));
+    struct Confirm;
+    impl Decisions for Confirm {
+        fn remove_held(&mut self, _: &Package) -> bool {
+            true
+        }
+    }
+    let mut t = Transaction::new(&held).unwrap().decisions(Confirm);
+    t.remove("foo").unwrap();
+    assert_eq!(t.prepare().unwrap().remove.len(), 1);
+
+    let mut t = Transaction::new(&handle).unwrap();
+    t.add("qux").unwrap();
+    let plan = t.prepare().unwrap();
+    let mut record = Record(Vec::new());
+    let conflict = t.commit(&plan, &mut super::SkipScriptlets, &mut record);
+    assert!(matches!(
+        conflict,
+        Err(TransactionError::FileConflict(path, _, None)) if path == "usr/bin/stray"
+    ));
+
+    let mut t = Transaction::new(&handle).unwrap();
+    t.sysupgrade(&crate::events::NoEvents).unwrap();
+    let plan = t.prepare().unwrap();
+    let names: Vec<_> = plan
+        .install
+        .iter()
+        .map(|i| i.name(handle.interner()))
+        .collect();
+    assert_eq!(names, ["bar", "foo"]);
+    assert_eq!(plan.install[0].reason, InstallReason::Dependency);
+    assert_eq!(plan.install[1].reason, InstallReason::Explicit);
+    assert!(plan.install[1].old.is_some());
+    t.check_space(&plan).unwrap();
+    t.commit(&plan, &mut super::SkipScriptlets, &mut record)
+        .unwrap();
+    assert_eq!(record.0, [("foo".to_owned(), vec!["foo".to_owned()])]);
+
+    assert_eq!(
+        std::fs::read_to_string(root.join("usr/bin/foo")).unwrap(),
+        "contents"
+    );
+    assert!(root.join("usr/lib/libbar.so").exists());
+    assert!(!root.join("usr/bin/old").exists());
+    assert!(!local.join("foo-0.9-1").exists());
+    let localdb = LocalDb::open(handle.interner().clone(), &local).unwrap();
+    let foo = localdb.package("foo").unwrap();
+    assert_eq!(foo.reason(), InstallReason::Explicit);
+    assert!(matches!(
+        foo.package().validation,
+        Some(Validation::Sha256Sum)
+    ));
+    let bar = localdb.package("bar").unwrap();
+    assert_eq!(bar.reason(), InstallReason::Dependency);
+    let files = handle.localdb_files().unwrap();
+    let i = handle.interner().borrow();
+    let bar_files = files.iter().find(|(n, _)| n.r(&i) == "bar").unwrap().1;
+    assert_eq!(bar_files.files, ["usr/", "usr/lib/", "usr/lib/libbar.so"]);
+    assert!(local.join("foo-1-1/mtree").exists());
+    let log: Vec<_> = crate::log::read_log(handle.logfile())
+        .unwrap()
+        .into_iter()
+        .map(|e| e.event.to_string())
+        .collect();
+    let expected = [
+        "transaction started",
+        "installed bar (1-1)",
+        "upgraded foo (0.9-1 -> 1-1)",
+        "transaction completed",
+    ];
+    assert_eq!(log, expected);
+}
+
+#[test]
+fn test_commit_download() {
+    let dir = crate::util::test_dir("commit_download");
+    let root = dir.join("root");
+    let dbpath = root.join("var/lib/pacman");
+    let cache = root.join("var/cache/pacman/pkg");
+    let mirror = dir.join("mirror");
+    write_test_repo(
+        &dbpath,
+        &mirror,
+        &[("foo", "", &["usr/", "usr/bin/", "usr/bin/foo"])],
+    );
+    crate::db::write_test_dbpath(&dbpath, &[], &[]);
+    std::fs::create_dir_all(&cache).unwrap();
+    std::fs::create_dir_all(root.join("var/log")).unwrap();
+    let file = "foo-1-1-x86_64.pkg.tar.zst";
+    #[cfg(feature = "download")]
+    let url = crate::download::test_server(
+        [(
+            format!("/core/{file}"),
+            std::fs::read(mirror.join(file)).unwrap(),
+        )]
+        .into(),
+    );
+    #[cfg(not(feature = "download"))]
+    let url = "http://127.0.0.1:1";
+    let config = crate::config::test_config(&format!(
+        "[options]\nSigLevel = Never\nHookDir = {}\n[core]\nServer = {url}/core\n",
+        root.join("etc/pacman.d/hooks").display(),
+    ));
+    let handle = Handle::builder()
+        .root(&root)
+        .dbpath(&dbpath)
+        .cachedir(&cache)
+        .logfile(root.join("var/log/pacman.log"))
+        .config(config)
+        .build();
+    let mut t = Transaction::new(&handle).unwrap();
+    t.add("foo").unwrap();
+    let plan = t.prepare().unwrap();
+    struct NoHooks;
+    impl HookRunner for NoHooks {
+        fn run(&mut self, _: &Path, _: &hooks::Hook, _: &[String]) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    let committed = t.commit(&plan, &mut super::SkipScriptlets, &mut NoHooks);
+    #[cfg(feature = "download")]
+    {
+        committed.unwrap();
+        assert!(cache.join(file).exists());
+        assert!(root.join("usr/bin/foo").exists());
+        assert!(dbpath.join("local/foo-1-1/desc").exists());
+    }
+    #[cfg(not(feature = "download"))]
+    assert!(matches!(committed, Err(TransactionError::NotCached(f)) if f == file));
+}
+
+#[test]
+fn test_sysupgrade() {
+    let dir = crate::util::test_dir("sysupgrade");
+    let dbpath = dir.join("var/lib/pacman");
+    let cache = dir.join("var/cache/pacman/pkg");
+    write_test_repo(
+        &dbpath,
+        &cache,
+        &[
+            ("app", "depend = newlib\n", &[]),
+            ("newlib", "conflict = oldlib\n", &[]),
+            ("newfoo", "replaces = oldfoo\nprovides = oldfoo\n", &[]),
+            ("virt-a", "provides = virt\n", &[]),
+            ("virt-b", "provides = virt\n", &[]),
+        ],
+    );
+    let local = |name: &str, version: &str, extra: &[(&str, &str)]| {
+        let mut extra = extra.to_vec();
+        extra.push(("INSTALLDATE", "1700000000"));
+        let desc = crate::db::test_desc(name, version, &extra);
+        (format!("{name}-{version}"), desc)
+    };
+    let app = local("app", "0.9-1", &[("DEPENDS", "oldlib")]);
+    let oldlib = local("oldlib", "1-1", &[("REASON", "1")]);
+    let oldfoo = local("oldfoo", "1-1", &[("REASON", "1")]);
+    let user = local("user", "1-1", &[("DEPENDS", "oldfoo")]);
+    let installed = [app, oldlib, oldfoo, user];
+    let installed: Vec<_> = installed
+        .iter()
+        .map(|(d, s)| (d.as_str(), s.clone()))
+        .collect();
+    crate::db::write_test_dbpath(&dbpath, &installed, &[]);
+    let handle = Handle::builder().root(&dir).register_syncdb("core").build();
+
+    // the default refuses to remove oldlib
+    let mut t = Transaction::new(&handle).unwrap();
+    t.sysupgrade(&crate::events::NoEvents).unwrap();
+    assert!(matches!(t.prepare(), Err(TransactionError::Conflict(..))));
+
+    struct Answers;
+    impl Decisions for Answers {
+        fn choose_provider(&mut self, _: &Depend, providers: &[(&str, &Package)]) -> usize {
+            providers.len() - 1
+        }
+        fn remove_conflict(&mut self, _: &Package, _: &Package) -> bool {
+            true
+        }
+    }
+    let mut t = Transaction::new(&handle).unwrap().decisions(Answers);
+    t.add("virt").unwrap();
+    assert_eq!(t.targets[0].0.name.r(&handle.interner().borrow()), "virt-b");
+    struct Keep;
+    impl Decisions for Keep {
+        fn replace(&mut self, _: &Package, _: &Package, _: &str) -> bool {
+            false
+        }
+        fn remove_conflict(&mut self, _: &Package, _: &Package) -> bool {
+            true
+        }
+    }
+    let mut t = Transaction::new(&handle).unwrap().decisions(Keep);
+    t.sysupgrade(&crate::events::NoEvents).unwrap();
+    let plan = t.prepare().unwrap();
+    let names: Vec<_> = plan
+        .install
+        .iter()
+        .map(|i| i.name(handle.interner()))
+        .collect();
+    assert_eq!(names, ["newlib", "app"]);
+    assert!(
+        plan.remove
+            .iter()
+            .all(|p| p.name.r(&handle.interner().borrow()) != "oldfoo")
+    );
+
+    let mut t = Transaction::new(&handle).unwrap().decisions(Answers);
+    t.sysupgrade(&crate::events::NoEvents).unwrap();
+    let plan = t.prepare().unwrap();
+    let reasons: Vec<_> = plan
+        .install
+        .iter()
+        .map(|i| (i.name(handle.interner()), i.reason))
+        .collect();
+    let expected = [
+        ("newlib".to_owned(), InstallReason::Dependency),
+        ("app".to_owned(), InstallReason::Explicit),
+        ("newfoo".to_owned(), InstallReason::Dependency),
+    ];
+    assert_eq!(reasons, expected);
+    let printed = t.print(&plan);
+    let file = |name: &str| {
+        format!(
+            "file://{}",
+            cache
+                .join(format!("{name}-1-1-x86_64.pkg.tar.zst"))
+                .display()
+        )
+    };
+    assert_eq!(
+        printed,
+        [
+            file("newlib"),
+            file("app"),
+            file("newfoo"),
+            "oldfoo-1-1".to_owned(),
+            "oldlib-1-1".to_owned()
+        ]
+    );
+}
+
+#[cfg(feature = "solver")]
+#[test]
+fn test_solver() {
+    let dir = crate::util::test_dir("solver");
+    let dbpath = dir.join("var/lib/pacman");
+    let cache = dir.join("var/cache/pacman/pkg");
+    write_test_repo(
+        &dbpath,
+        &cache,
+        &[
+            ("app", "depend = sh\n", &[]),
+            ("ash", "provides = sh\ndepend = libash\n", &[]),
+            ("libash", "conflict = legacy\n", &[]),
+            ("dash", "provides = sh\n", &[]),
+        ],
+    );
+    let legacy = crate::db::test_desc("legacy", "1-1", &[("INSTALLDATE", "1700000000")]);
+    crate::db::write_test_dbpath(&dbpath, &[("legacy-1-1", legacy)], &[]);
+    let handle = Handle::builder().root(&dir).register_syncdb("core").build();
+    let names = |plan: &Plan| {
+        plan.install
+            .iter()
+            .map(|i| i.name(handle.interner()))
+            .collect::<Vec<_>>()
+    };
+
+    // ash comes first but pulls in libash, which conflicts with legacy
+    let mut t = Transaction::new(&handle).unwrap();
+    t.add("app").unwrap();
+    assert!(matches!(t.prepare(), Err(TransactionError::Conflict(..))));
+    let mut t = Transaction::new(&handle)
+        .unwrap()
+        .provider_strategy(ProviderStrategy::Solver);
+    t.add("app").unwrap();
+    assert_eq!(names(&t.prepare().unwrap()), ["dash", "app"]);
+}
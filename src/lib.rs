@@ -1,54 +1,289 @@
+pub mod cache;
 pub mod config;
 pub mod db;
+#[cfg(feature = "download")]
+pub mod download;
+pub mod events;
+pub mod handle;
+pub mod hooks;
+pub mod install;
+pub mod log;
+#[cfg(feature = "tokio")]
+pub mod nonblocking;
+#[cfg(feature = "pgp")]
+pub mod pgp;
 pub mod util;
 
+/// An upgrade found by [upgrade_urls].
+#[derive(Clone, Debug)]
+pub struct UpgradeInfo {
+    /// Where to get the new package file from: a `file://` url if it is cached,
+    /// otherwise on the first mirror. None if the repo has no mirror.
+    pub url: Option<String>,
+    /// The urls on the other mirrors to fall back to, in the order to try them.
+    pub mirrors: Vec<String>,
+    pub repo: String,
+    /// The installed package.
+    pub from: db::Package,
+    /// The package to upgrade to.
+    pub to: db::Package,
+    /// csize of the new package, 0 if it is cached.
+    pub download_size: u64,
+    /// isize of the new package minus isize of the old one.
+    pub install_delta: i64,
+    /// Whether the new package is in one of the CacheDirs already.
+    pub cached: bool,
+}
+
 /// Calculates which packages need upgrades,
-/// limited to the databases passed in with db_filter.
-/// Currently just panics when anything goes wrong.
-/// Ex: ```upgrade_urls(&["core", "extra", "multilib"])```
-///
-/// (upgrade_url, (old_name, old_version, old_arch), (new_name, new_version, new_filename))
+/// limited to the databases passed in with db_filter and to repos with Upgrade usage,
+/// skipping packages built for none of the configured Architectures.
+/// The dbs are read from the DBPath of config, see [handle::Handle::from_config].
+/// Packages already present in one of the CacheDirs get a file:// url,
+/// otherwise the urls of all mirrors in the order to try them,
+/// the repo's CacheServers before its Servers.
+/// The packages are interned into i.
+/// Errors if a db can not be read or an upgrade has no filename.
+/// With the download feature, `download::download_packages` fetches them.
+/// Ex: ```upgrade_urls(&new_interner(), &config, &["core", "extra", "multilib"])?```
 pub fn upgrade_urls(
+    i: &db::Interner,
     config: &config::PacmanConfig,
     db_filter: &[&str],
-) -> Vec<(String, db::Package, db::Package)> {
+) -> std::io::Result<Vec<UpgradeInfo>> {
     use db::QuickResolve;
-    let repo_names: Vec<&str> = config
-        .repo_urls
-        .keys()
-        .map(String::as_str)
-        .filter(|r| db_filter.contains(r))
-        .collect();
-    let i = db::new_interner();
-    let ignore: Vec<_> = config
-        .ignores
+    let handle = handle::Handle::builder()
+        .config(config.clone())
+        .interner(i.clone())
+        .build();
+    let local = handle.localdb()?;
+    let syncs = handle
+        .syncdbs()
         .iter()
-        .map(|s| i.borrow_mut().get_or_intern(s.trim()))
-        .collect();
-    let ups = db::update_candidates(&i, &repo_names, &ignore);
+        .filter(|name| db_filter.contains(&name.as_str()))
+        .filter(|name| config.repo(name).is_some_and(|r| r.usage.upgrade))
+        .map(|name| Ok((name.as_str(), handle.syncdb(name)?)))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    let upgrades = handle.upgrades_in(&local, &syncs, &events::NoEvents);
     let i = i.borrow();
     let mut ret = Vec::new();
-    for (dbname, from, to) in ups.into_iter() {
-        let filename = to.filename.unwrap().r(&i);
-        let cache_file = config.cache_dir.join(filename);
-        let url = if std::fs::exists(&cache_file).unwrap() {
-            format!("file://{}", cache_file.to_string_lossy())
+    for (dbname, from, to) in upgrades {
+        let filename = to.filename.map(|f| f.r(&i)).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{} in {dbname} has no filename", to.name.r(&i)),
+            )
+        })?;
+        let cache_file = find_cached(handle.cachedirs(), filename);
+        let mut urls: Vec<String> = if let Some(cache_file) = &cache_file {
+            vec![format!("file://{}", cache_file.to_string_lossy())]
         } else {
-            format!("{}/{filename}", config.repo_urls[dbname])
+            config
+                .repo(dbname)
+                .into_iter()
+                .flat_map(|repo| repo.urls())
+                .map(|server| format!("{server}/{filename}"))
+                .collect()
         };
-        ret.push((url, from, to));
+        let url = (!urls.is_empty()).then(|| urls.remove(0));
+        let cached = cache_file.is_some();
+        ret.push(UpgradeInfo {
+            url,
+            mirrors: urls,
+            repo: dbname.to_owned(),
+            download_size: if cached { 0 } else { to.csize.unwrap_or(0) },
+            install_delta: to.isize.unwrap_or(0) as i64 - from.isize.unwrap_or(0) as i64,
+            cached,
+            from: from.clone(),
+            to: to.clone(),
+        });
     }
-    ret
+    Ok(ret)
+}
+
+/// Sizes of a set of upgrades, like the totals pacman prints before asking to proceed.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct UpgradeSummary {
+    /// "Total Download Size": csize of the new packages that are not cached yet.
+    pub total_download: u64,
+    /// "Net Upgrade Size": isize of the new packages minus isize of the old ones,
+    /// negative if the upgrade frees space.
+    pub total_installed_delta: i64,
+    pub package_count: usize,
+    /// Packages already in one of the cache dirs, they count towards no download.
+    pub cached_count: usize,
+}
+
+/// Sums up (repo, old, new) upgrades, as returned by [db::update_candidates],
+/// looking for the new package files in cache_dirs.
+/// Packages without csize or isize in their db count as 0.
+pub fn summarize(
+    i: &db::Interner,
+    upgrades: &[(&str, db::Package, db::Package)],
+    cache_dirs: &[std::path::PathBuf],
+) -> UpgradeSummary {
+    use db::QuickResolve;
+    let mut summary = UpgradeSummary {
+        package_count: upgrades.len(),
+        ..Default::default()
+    };
+    for (_, old, new) in upgrades {
+        let filename = new.filename.map(|f| f.r(&i.borrow()).to_owned());
+        if filename.is_some_and(|f| find_cached(cache_dirs, &f).is_some()) {
+            summary.cached_count += 1;
+        } else {
+            summary.total_download += new.csize.unwrap_or(0);
+        }
+        summary.total_installed_delta +=
+            new.isize.unwrap_or(0) as i64 - old.isize.unwrap_or(0) as i64;
+    }
+    summary
+}
+
+#[test]
+fn test_summarize() {
+    use db::{Package, new_interner, test_desc};
+    let dir = util::test_dir("summarize");
+    std::fs::write(dir.join("bar-2-1-x86_64.pkg.tar.zst"), "").unwrap();
+    let i = new_interner();
+    let pkg = |name: &str, version: &str, csize: &str, isize: &str| {
+        let file = format!("{name}-{version}-x86_64.pkg.tar.zst");
+        let extra = [
+            ("FILENAME", file.as_str()),
+            ("CSIZE", csize),
+            ("ISIZE", isize),
+        ];
+        Package::from_str(i.clone(), &test_desc(name, version, &extra)).unwrap()
+    };
+    let upgrades = [
+        (
+            "core",
+            pkg("foo", "1-1", "10", "100"),
+            pkg("foo", "2-1", "20", "150"),
+        ),
+        (
+            "core",
+            pkg("bar", "1-1", "30", "300"),
+            pkg("bar", "2-1", "40", "200"),
+        ),
+    ];
+    assert_eq!(
+        summarize(&i, &upgrades, &[dir]),
+        UpgradeSummary {
+            total_download: 20,
+            total_installed_delta: -50,
+            package_count: 2,
+            cached_count: 1,
+        }
+    );
+    assert_eq!(summarize(&i, &[], &[]), UpgradeSummary::default());
+}
+
+/// Path to filename in the first cache directory that contains it.
+/// Directories that can not be read are skipped.
+pub fn find_cached(
+    cache_dirs: &[std::path::PathBuf],
+    filename: &str,
+) -> Option<std::path::PathBuf> {
+    cache_dirs
+        .iter()
+        .map(|dir| dir.join(filename))
+        .find(|f| std::fs::exists(f).is_ok_and(|e| e))
+}
+
+#[test]
+fn test_find_cached() {
+    let dir = util::test_dir("cached");
+    let dirs = [dir.join("a"), dir.join("b"), dir.join("c")];
+    for d in &dirs {
+        std::fs::create_dir(d).unwrap();
+    }
+    std::fs::write(dirs[1].join("foo.pkg.tar.zst"), "").unwrap();
+    std::fs::write(dirs[2].join("foo.pkg.tar.zst"), "").unwrap();
+    assert_eq!(
+        find_cached(&dirs, "foo.pkg.tar.zst"),
+        Some(dirs[1].join("foo.pkg.tar.zst"))
+    );
+    assert_eq!(find_cached(&dirs, "bar.pkg.tar.zst"), None);
+
+    // a file where a cache dir should be can not be looked into
+    let file = dir.join("file");
+    std::fs::write(&file, "").unwrap();
+    let dirs = [file, dirs[1].clone()];
+    assert_eq!(
+        find_cached(&dirs, "foo.pkg.tar.zst"),
+        Some(dirs[1].join("foo.pkg.tar.zst"))
+    );
 }
 
 #[test]
 fn test_upgrade_urls() {
     let ts = std::time::SystemTime::now();
-    let config = config::extract_relevant_config();
+    let config = config::extract_relevant_config().unwrap();
 
-    for (u, _, _) in upgrade_urls(&config, &["core", "extra", "multilib"]) {
-        println!("{}", u);
+    let i = db::new_interner();
+    for u in upgrade_urls(&i, &config, &["core", "extra", "multilib"]).unwrap() {
+        println!("{} {}", u.url.unwrap_or_default(), u.mirrors.join(" "));
     }
     let passed = std::time::SystemTime::now().duration_since(ts).unwrap();
     println!("finding upgrades took {passed:?}")
 }
+
+#[test]
+fn test_upgrade_urls_dbpath() {
+    let dir = util::test_dir("upgrade_urls_dbpath");
+    let dbpath = dir.join("db");
+    let cache = dir.join("cache");
+    std::fs::create_dir_all(&cache).unwrap();
+    std::fs::write(cache.join("bar-2-1-x86_64.pkg.tar.zst"), "").unwrap();
+    let local = |name: &str| {
+        let desc = db::test_desc(name, "1-1", &[("INSTALLDATE", "1700000000")]);
+        (format!("{name}-1-1"), desc)
+    };
+    let sync = |name: &str| {
+        let file = format!("{name}-2-1-x86_64.pkg.tar.zst");
+        let desc = db::test_desc(name, "2-1", &[("FILENAME", &file), ("CSIZE", "10")]);
+        (format!("{name}-2-1"), desc)
+    };
+    let installed = [local("foo"), local("bar"), local("baz")];
+    let installed: Vec<_> = installed
+        .iter()
+        .map(|(d, s)| (d.as_str(), s.clone()))
+        .collect();
+    let core = [sync("foo"), sync("bar")];
+    let core: Vec<_> = core.iter().map(|(d, s)| (d.as_str(), s.clone())).collect();
+    let extra = [sync("baz")];
+    let extra: Vec<_> = extra.iter().map(|(d, s)| (d.as_str(), s.clone())).collect();
+    db::write_test_dbpath(&dbpath, &installed, &[("core", &core), ("extra", &extra)]);
+    let config = config::test_config(&format!(
+        "[options]\nDBPath = {}\nCacheDir = {}\nArchitecture = x86_64\n\
+        [core]\nServer = http://a/core\nServer = http://b/core\n[extra]\nServer = http://a/extra\n",
+        dbpath.display(),
+        cache.display(),
+    ));
+    let i = db::new_interner();
+    let mut ups = upgrade_urls(&i, &config, &["core"]).unwrap();
+    ups.sort_by_key(|u| u.url.clone());
+    let urls: Vec<_> = ups
+        .iter()
+        .map(|u| (u.url.clone().unwrap(), u.mirrors.clone()))
+        .collect();
+    let cached = format!(
+        "file://{}",
+        cache.join("bar-2-1-x86_64.pkg.tar.zst").display()
+    );
+    assert_eq!(
+        urls,
+        [
+            (cached, vec![]),
+            (
+                "http://a/core/foo-2-1-x86_64.pkg.tar.zst".to_owned(),
+                vec!["http://b/core/foo-2-1-x86_64.pkg.tar.zst".to_owned()]
+            ),
+        ]
+    );
+    assert_eq!((ups[0].download_size, ups[1].download_size), (0, 10));
+
+    let missing = config::test_config("[options]\nDBPath = /nonexistent\n[core]\n");
+    assert!(upgrade_urls(&i, &missing, &["core"]).is_err());
+}
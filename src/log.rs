@@ -0,0 +1,302 @@
+//! pacman.log, the history of transactions pacman and libalpm append to.
+use std::fmt::{self, Display, Formatter};
+use std::fs::OpenOptions;
+use std::io::{self, BufRead, BufReader, Write};
+use std::mem::MaybeUninit;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The message of a log entry, typed for the ones libalpm writes about transactions.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LogEvent {
+    Installed {
+        name: String,
+        version: String,
+    },
+    Upgraded {
+        name: String,
+        old: String,
+        new: String,
+    },
+    Downgraded {
+        name: String,
+        old: String,
+        new: String,
+    },
+    Reinstalled {
+        name: String,
+        version: String,
+    },
+    Removed {
+        name: String,
+        version: String,
+    },
+    TransactionStarted,
+    TransactionCompleted,
+    TransactionInterrupted,
+    /// Anything else, like command lines, warnings and scriptlet output.
+    Other(String),
+}
+
+impl Display for LogEvent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Installed { name, version } => write!(f, "installed {name} ({version})"),
+            Self::Upgraded { name, old, new } => write!(f, "upgraded {name} ({old} -> {new})"),
+            Self::Downgraded { name, old, new } => {
+                write!(f, "downgraded {name} ({old} -> {new})")
+            }
+            Self::Reinstalled { name, version } => write!(f, "reinstalled {name} ({version})"),
+            Self::Removed { name, version } => write!(f, "removed {name} ({version})"),
+            Self::TransactionStarted => f.write_str("transaction started"),
+            Self::TransactionCompleted => f.write_str("transaction completed"),
+            Self::TransactionInterrupted => f.write_str("transaction interrupted"),
+            Self::Other(s) => f.write_str(s),
+        }
+    }
+}
+
+impl From<&str> for LogEvent {
+    fn from(msg: &str) -> Self {
+        // "<verb> <name> (<version>)" or "<verb> <name> (<old> -> <new>)"
+        let package = msg.split_once(' ').and_then(|(verb, rest)| {
+            let (name, versions) = rest.split_once(" (")?;
+            Some((verb, name.to_owned(), versions.strip_suffix(')')?))
+        });
+        let parsed = package.and_then(|(verb, name, versions)| {
+            let version = versions.to_owned();
+            match (verb, versions.split_once(" -> ")) {
+                ("installed", None) => Some(Self::Installed { name, version }),
+                ("reinstalled", None) => Some(Self::Reinstalled { name, version }),
+                ("removed", None) => Some(Self::Removed { name, version }),
+                ("upgraded", Some((old, new))) => Some(Self::Upgraded {
+                    name,
+                    old: old.to_owned(),
+                    new: new.to_owned(),
+                }),
+                ("downgraded", Some((old, new))) => Some(Self::Downgraded {
+                    name,
+                    old: old.to_owned(),
+                    new: new.to_owned(),
+                }),
+                _ => None,
+            }
+        });
+        let parsed = parsed.or(match msg {
+            "transaction started" => Some(Self::TransactionStarted),
+            "transaction completed" => Some(Self::TransactionCompleted),
+            "transaction interrupted" => Some(Self::TransactionInterrupted),
+            _ => None,
+        });
+        parsed.unwrap_or_else(|| Self::Other(msg.to_owned()))
+    }
+}
+
+/// One line of pacman.log: `[2024-01-15T10:23:45+0100] [ALPM] upgraded foo (1-1 -> 1-2)`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogEntry {
+    pub time: SystemTime,
+    /// Seconds east of UTC of the timezone the entry was written in.
+    pub utc_offset: i32,
+    /// Who wrote the entry, e.g. `ALPM`, `PACMAN` or `ALPM-SCRIPTLET`.
+    pub source: String,
+    pub event: LogEvent,
+}
+
+impl LogEntry {
+    /// An entry for now in the local timezone.
+    pub fn new(source: impl Into<String>, event: LogEvent) -> Self {
+        let time = SystemTime::now();
+        Self {
+            time,
+            utc_offset: local_offset(time),
+            source: source.into(),
+            event,
+        }
+    }
+}
+
+impl Display for LogEntry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let secs = self.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let local = secs.as_secs() as i64 + i64::from(self.utc_offset);
+        let (y, mo, d) = civil_from_days(local.div_euclid(86400));
+        let t = local.rem_euclid(86400);
+        let sign = if self.utc_offset < 0 { '-' } else { '+' };
+        let offset = self.utc_offset.unsigned_abs() / 60;
+        write!(
+            f,
+            "[{y:04}-{mo:02}-{d:02}T{:02}:{:02}:{:02}{sign}{:02}{:02}] [{}] {}",
+            t / 3600,
+            t / 60 % 60,
+            t % 60,
+            offset / 60,
+            offset % 60,
+            self.source,
+            self.event
+        )
+    }
+}
+
+impl FromStr for LogEntry {
+    type Err = String;
+
+    /// Also reads the `[2019-03-01 12:00]` timestamps of pacman before 5.2,
+    /// which have no timezone, as UTC.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid log entry: {s}");
+        let (time, rest) = s
+            .strip_prefix('[')
+            .and_then(|s| s.split_once("] "))
+            .ok_or_else(invalid)?;
+        let (source, message) = match rest.strip_prefix('[').and_then(|r| r.split_once("] ")) {
+            Some((source, message)) => (source, message),
+            // pacman before 4.0 did not log a source
+            None => ("", rest),
+        };
+        let (time, utc_offset) = parse_time(time).ok_or_else(invalid)?;
+        Ok(Self {
+            time,
+            utc_offset,
+            source: source.to_owned(),
+            event: message.into(),
+        })
+    }
+}
+
+fn parse_time(s: &str) -> Option<(SystemTime, i32)> {
+    let num = |s: &str| s.parse::<i64>().ok();
+    let (date, time) = s.split_once(['T', ' '])?;
+    let mut date = date.splitn(3, '-').map(num);
+    let (y, mo, d) = (date.next()??, date.next()??, date.next()??);
+    let (time, offset) = match time.find(['+', '-']) {
+        Some(n) => time.split_at(n),
+        None => (time, "+0000"),
+    };
+    let mut hms = time.split(':').map(num);
+    let (h, mi) = (hms.next()??, hms.next()??);
+    let sec = hms.next().unwrap_or(Some(0))?;
+    let offset_digits = offset.get(1..).filter(|o| o.len() == 4)?;
+    let offset_minutes = num(&offset_digits[..2])? * 60 + num(&offset_digits[2..])?;
+    let offset_secs = offset_minutes * 60 * if offset.starts_with('-') { -1 } else { 1 };
+    let secs = days_from_civil(y, mo, d) * 86400 + h * 3600 + mi * 60 + sec - offset_secs;
+    let time = UNIX_EPOCH + Duration::from_secs(u64::try_from(secs).ok()?);
+    Some((time, i32::try_from(offset_secs).ok()?))
+}
+
+/// Days since 1970-01-01 of a proleptic gregorian date.
+fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * (m + if m > 2 { -3 } else { 9 }) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Inverse of [days_from_civil].
+fn civil_from_days(z: i64) -> (i64, i64, i64) {
+    let z = z + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    (yoe + era * 400 + i64::from(m <= 2), m, d)
+}
+
+/// Seconds east of UTC of the local timezone at time.
+fn local_offset(time: SystemTime) -> i32 {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as libc::time_t;
+    let mut tm = MaybeUninit::<libc::tm>::uninit();
+    // SAFETY: localtime_r only writes to tm, which is only read if it succeeded
+    if unsafe { libc::localtime_r(&secs, tm.as_mut_ptr()) }.is_null() {
+        return 0;
+    }
+    unsafe { tm.assume_init() }.tm_gmtoff as i32
+}
+
+/// Appends entry as a line to the log file, creating it if needed.
+pub fn append(logfile: &Path, entry: &LogEntry) -> io::Result<()> {
+    let mut f = OpenOptions::new().create(true).append(true).open(logfile)?;
+    writeln!(f, "{entry}")
+}
+
+/// All entries of a log file in order, lines that are no entry are skipped.
+pub fn read_log(logfile: &Path) -> io::Result<Vec<LogEntry>> {
+    let mut entries = Vec::new();
+    for line in BufReader::new(std::fs::File::open(logfile)?).lines() {
+        match line?.parse() {
+            Ok(entry) => entries.push(entry),
+            Err(e) => log::debug!("{e}"),
+        }
+    }
+    Ok(entries)
+}
+
+#[test]
+fn test_log() {
+    let upgraded = LogEntry {
+        time: UNIX_EPOCH + Duration::from_secs(1705310625),
+        utc_offset: 3600,
+        source: "ALPM".to_owned(),
+        event: LogEvent::Upgraded {
+            name: "foo".to_owned(),
+            old: "1-1".to_owned(),
+            new: "1-2".to_owned(),
+        },
+    };
+    let line = "[2024-01-15T10:23:45+0100] [ALPM] upgraded foo (1-1 -> 1-2)";
+    assert_eq!(upgraded.to_string(), line);
+    assert_eq!(line.parse::<LogEntry>().unwrap(), upgraded);
+    let west = LogEntry {
+        utc_offset: -4 * 3600 - 1800,
+        ..upgraded.clone()
+    };
+    assert_eq!(west.to_string().parse::<LogEntry>().unwrap(), west);
+
+    let old: LogEntry = "[2019-03-01 12:00] [PACMAN] Running 'pacman -Syu'"
+        .parse()
+        .unwrap();
+    assert_eq!(old.time, UNIX_EPOCH + Duration::from_secs(1551441600));
+    assert_eq!(old.source, "PACMAN");
+    assert_eq!(
+        old.event,
+        LogEvent::Other("Running 'pacman -Syu'".to_owned())
+    );
+    assert!("no entry".parse::<LogEntry>().is_err());
+    for msg in [
+        "installed bar (2-1)",
+        "downgraded foo (2-1 -> 1-1)",
+        "reinstalled foo (1-1)",
+        "removed bar (2-1)",
+        "transaction started",
+        "transaction completed",
+    ] {
+        let event = LogEvent::from(msg);
+        assert!(!matches!(event, LogEvent::Other(_)), "{msg}");
+        assert_eq!(event.to_string(), msg);
+    }
+    assert!(matches!(
+        LogEvent::from("installed bar"),
+        LogEvent::Other(_)
+    ));
+
+    let dir = crate::util::test_dir("log");
+    let logfile = dir.join("pacman.log");
+    append(&logfile, &upgraded).unwrap();
+    let now = LogEntry::new("ALPM", LogEvent::TransactionCompleted);
+    append(&logfile, &now).unwrap();
+    let read = read_log(&logfile).unwrap();
+    assert_eq!(read[0], upgraded);
+    // the log only has second precision
+    assert_eq!(read[1].event, now.event);
+    assert_eq!(read[1].utc_offset, now.utc_offset);
+    assert!(now.time.duration_since(read[1].time).unwrap() < Duration::from_secs(1));
+}
@@ -0,0 +1,143 @@
+//! Async variants of refreshing, upgrade checks and downloads, used with the tokio feature.
+//! The blocking functions run on tokio's blocking thread pool.
+//! Without the sync feature, the interner is shared through an Rc and can not leave the thread it was created on,
+//! so upgrades come back as plain [Upgrade]s and parsers run inside of [with_interner].
+use crate::config::PacmanConfig;
+use crate::db::{Interner, Package, QuickResolve};
+use crate::download::{self, DownloadOptions, Refreshed};
+use crate::events::EventSink;
+use crate::handle::Handle;
+use std::io;
+use std::path::PathBuf;
+
+/// Runs f on the blocking thread pool, panics in f are passed on.
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+    match tokio::task::spawn_blocking(f).await {
+        Ok(t) => t,
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
+/// Runs f with a fresh interner on the blocking thread pool,
+/// for parsing dbs and keeping only what is needed from the packages.
+///
+/// Ex: ```with_interner(|i| Ok(parse_syncdb(i, "core", &NoEvents)?.len())).await```
+pub async fn with_interner<T: Send + 'static>(f: impl FnOnce(Interner) -> T + Send + 'static) -> T {
+    blocking(|| f(crate::db::new_interner())).await
+}
+
+/// An upgrade [Handle::update_candidates] found, detached from the interner.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Upgrade {
+    pub repo: String,
+    pub name: String,
+    pub old_version: String,
+    pub new_version: String,
+    /// The package file of the new version, None if the sync db lacks it.
+    pub filename: Option<String>,
+}
+
+impl Upgrade {
+    fn new(i: &Interner, repo: &str, old: &Package, new: &Package) -> Self {
+        let i = i.borrow();
+        Self {
+            repo: repo.to_owned(),
+            name: old.name.r(&i).to_owned(),
+            old_version: old.version.r(&i).to_owned(),
+            new_version: new.version.r(&i).to_owned(),
+            filename: new.filename.map(|f| f.r(&i).to_owned()),
+        }
+    }
+}
+
+/// Like [download::refresh_syncdbs].
+pub async fn refresh_syncdbs(
+    config: PacmanConfig,
+    files: bool,
+    options: DownloadOptions,
+    events: impl EventSink + Send + 'static,
+) -> io::Result<Vec<(String, io::Result<Refreshed>)>> {
+    blocking(move || download::refresh_syncdbs(&config, files, &options, &events)).await
+}
+
+/// Like [Handle::update_candidates] on the system config describes.
+pub async fn update_candidates(
+    config: PacmanConfig,
+    events: impl EventSink + Send + 'static,
+) -> io::Result<Vec<Upgrade>> {
+    blocking(move || {
+        let handle = Handle::from_config(config);
+        let upgrades = handle.update_candidates(&events)?;
+        Ok(upgrades
+            .iter()
+            .map(|(repo, old, new)| Upgrade::new(handle.interner(), repo, old, new))
+            .collect())
+    })
+    .await
+}
+
+/// Like [download::download_packages] for the upgrades of
+/// [Handle::update_candidates] on the system config describes.
+/// returns (filename, path in the cache) in the order of the upgrades
+pub async fn download_upgrades(
+    config: PacmanConfig,
+    cachedir: PathBuf,
+    options: DownloadOptions,
+    events: impl EventSink + Send + 'static,
+) -> io::Result<Vec<(String, io::Result<PathBuf>)>> {
+    blocking(move || {
+        let handle = Handle::from_config(config.clone());
+        let upgrades = handle.update_candidates(&events)?;
+        download::download_packages(
+            handle.interner(),
+            &upgrades,
+            &config,
+            &cachedir,
+            &options,
+            &events,
+        )
+    })
+    .await
+}
+
+#[test]
+fn test_nonblocking() {
+    use crate::events::NoEvents;
+    let dir = crate::util::test_dir("nonblocking");
+    let mirror = dir.join("mirror");
+    std::fs::create_dir_all(&mirror).unwrap();
+    // an empty archive, so nothing is in the repo
+    std::fs::write(mirror.join("core.db"), []).unwrap();
+    let dbpath = dir.join("db");
+    std::fs::create_dir_all(dbpath.join("local")).unwrap();
+    let cachedir = dir.join("cache");
+    let config = crate::config::test_config(&format!(
+        "[options]\nDBPath = {}\nCacheDir = {}\nSigLevel = Never\n[core]\nServer = file://{}\n",
+        dbpath.display(),
+        cachedir.display(),
+        mirror.display(),
+    ));
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    rt.block_on(async {
+        let results = refresh_syncdbs(config.clone(), false, Default::default(), NoEvents)
+            .await
+            .unwrap();
+        assert_eq!(results[0].0, "core");
+        assert!(results[0].1.is_ok());
+        let sync = dbpath.join("sync");
+        let packages = with_interner(move |i| {
+            crate::db::parse_syncdb_at(i, &sync, "core", &NoEvents).map(|p| p.len())
+        });
+        assert_eq!(packages.await.unwrap(), 0);
+        assert!(
+            update_candidates(config.clone(), NoEvents)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        let downloads = download_upgrades(config, cachedir, Default::default(), NoEvents).await;
+        assert!(downloads.unwrap().is_empty());
+    });
+}
@@ -0,0 +1,448 @@
+//! Verifying detached signatures against the pacman keyring, used with the pgp feature.
+//! Only membership in the keyring is checked, GnuPG's ownertrust is not evaluated,
+//! so [crate::config::SigTrust::TrustedOnly] is treated like TrustAll.
+use crate::config::{PacmanConfig, SigRequirement};
+use crate::db::{Interner, Package, QuickResolve};
+use openpgp::KeyHandle;
+use openpgp::cert::{Cert, CertParser};
+use openpgp::parse::Parse;
+use openpgp::parse::stream::{
+    DetachedVerifierBuilder, MessageLayer, MessageStructure, VerificationError, VerificationHelper,
+};
+use openpgp::policy::StandardPolicy;
+use sequoia_openpgp as openpgp;
+use std::io;
+use std::path::Path;
+
+/// The public keys signatures are checked against,
+/// usually the `pubring.gpg` maintained by pacman-key.
+pub struct Keyring {
+    certs: Vec<Cert>,
+}
+
+/// Outcome of checking the detached signature of a file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Verification {
+    /// Made by a key in the keyring, fingerprint is the hex fingerprint of its certificate
+    /// and key_id the hex key id of the (sub)key that signed.
+    Valid { fingerprint: String, key_id: String },
+    /// There is no `.sig` file.
+    Missing,
+    /// Made by a key that is not in the keyring, issuer is its hex key id or fingerprint.
+    UnknownKey { issuer: String },
+    /// Does not match the file, or the key is expired or revoked.
+    Invalid(String),
+}
+
+impl Verification {
+    /// Whether the file can be trusted under requirement.
+    /// Optional accepts missing signatures, but not bad ones.
+    pub fn satisfies(&self, requirement: SigRequirement) -> bool {
+        match requirement {
+            SigRequirement::Never => true,
+            SigRequirement::Optional => matches!(self, Self::Valid { .. } | Self::Missing),
+            SigRequirement::Required => matches!(self, Self::Valid { .. }),
+        }
+    }
+}
+
+/// Whether a key in the keyring can still make valid signatures.
+/// Like the rest of this module, GnuPG's ownertrust is not considered.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum KeyStatus {
+    Valid,
+    Expired,
+    Revoked,
+    /// Rejected by the standard policy, e.g. for weak algorithms or broken self signatures.
+    Invalid,
+}
+
+/// A certificate in the keyring, like a line of `pacman-key --list-keys`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyInfo {
+    /// Hex fingerprint of the primary key.
+    pub fingerprint: String,
+    /// Hex key ids of the primary key and its subkeys.
+    pub key_ids: Vec<String>,
+    pub user_ids: Vec<String>,
+    pub status: KeyStatus,
+}
+
+impl KeyInfo {
+    fn of(cert: &Cert) -> Self {
+        use openpgp::types::RevocationStatus;
+        let policy = StandardPolicy::new();
+        let revoked = matches!(
+            cert.revocation_status(&policy, None),
+            RevocationStatus::Revoked(_)
+        );
+        let status = match cert.with_policy(&policy, None) {
+            _ if revoked => KeyStatus::Revoked,
+            Ok(valid) if valid.alive().is_ok() => KeyStatus::Valid,
+            Ok(_) => KeyStatus::Expired,
+            Err(_) => KeyStatus::Invalid,
+        };
+        Self {
+            fingerprint: cert.fingerprint().to_hex(),
+            key_ids: cert.keys().map(|k| k.key().keyid().to_hex()).collect(),
+            user_ids: cert
+                .userids()
+                .map(|u| String::from_utf8_lossy(u.userid().value()).into_owned())
+                .collect(),
+            status,
+        }
+    }
+}
+
+/// Who made the base64 encoded signature pgpsig, as stored in a sync db,
+/// as hex key ids or fingerprints. Look them up with [Keyring::key].
+pub fn pgpsig_issuers(pgpsig: &str) -> io::Result<Vec<String>> {
+    use base64::Engine;
+    use base64::prelude::BASE64_STANDARD as B64;
+    use openpgp::{Packet, PacketPile};
+    let sig = B64.decode(pgpsig.trim()).map_err(invalid)?;
+    let pile = PacketPile::from_bytes(&sig).map_err(invalid)?;
+    let mut issuers = Vec::new();
+    for packet in pile.descendants() {
+        if let Packet::Signature(sig) = packet {
+            issuers.extend(sig.get_issuers().iter().map(|i| i.to_hex()));
+        }
+    }
+    Ok(issuers)
+}
+
+fn invalid(e: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+impl Keyring {
+    /// Reads `<gpg_dir>/pubring.gpg`, see [PacmanConfig::gpg_dir].
+    pub fn open(gpg_dir: &Path) -> io::Result<Self> {
+        Self::from_bytes(&std::fs::read(gpg_dir.join("pubring.gpg"))?)
+    }
+
+    /// Binary or armored certificates.
+    /// Certificates sequoia can not parse are skipped, like gpg does.
+    pub fn from_bytes(keyring: &[u8]) -> io::Result<Self> {
+        if keyring.is_empty() {
+            return Ok(Self { certs: Vec::new() });
+        }
+        let certs = CertParser::from_bytes(keyring)
+            .map_err(invalid)?
+            .filter_map(Result::ok)
+            .collect();
+        Ok(Self { certs })
+    }
+
+    pub fn len(&self) -> usize {
+        self.certs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.certs.is_empty()
+    }
+
+    /// All certificates, in keyring order.
+    pub fn keys(&self) -> Vec<KeyInfo> {
+        self.certs.iter().map(KeyInfo::of).collect()
+    }
+
+    /// The certificate containing the key with id, a hex key id or fingerprint
+    /// of the primary key or a subkey, with or without `0x`, in any case.
+    /// None if the keyring does not have it or id is not hex.
+    pub fn key(&self, id: &str) -> Option<KeyInfo> {
+        let id = id.strip_prefix("0x").unwrap_or(id);
+        let handle: KeyHandle = id.parse().ok()?;
+        self.certs
+            .iter()
+            .find(|c| c.keys().any(|k| k.key().key_handle().aliases(&handle)))
+            .map(KeyInfo::of)
+    }
+
+    /// Like pacman's pre-flight key check: the keys that made the PGPSIG of packages,
+    /// but are missing from the keyring, so installing them would fail.
+    /// Packages without PGPSIG are skipped, an unparseable one is an error.
+    /// returns (package name, issuer) in the order of packages
+    pub fn missing_keys(
+        &self,
+        i: &Interner,
+        packages: &[Package],
+    ) -> io::Result<Vec<(String, String)>> {
+        let mut ret = Vec::new();
+        for p in packages {
+            let i = i.borrow();
+            let Some(pgpsig) = p.pgpsig.map(|s| s.r(&i)) else {
+                continue;
+            };
+            for issuer in pgpsig_issuers(pgpsig)? {
+                if self.key(&issuer).is_none() {
+                    ret.push((p.name.r(&i).to_owned(), issuer));
+                }
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Checks file against `<file>.sig`.
+    pub fn verify(&self, file: &Path) -> io::Result<Verification> {
+        let mut sigfile = file.as_os_str().to_owned();
+        sigfile.push(".sig");
+        let sig = match std::fs::read(sigfile) {
+            Ok(sig) => sig,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Verification::Missing),
+            Err(e) => return Err(e),
+        };
+        self.verify_detached(&std::fs::read(file)?, &sig)
+    }
+
+    /// Checks a package file against `<file>.sig`, or if there is none against pgpsig,
+    /// the base64 encoded signature of its sync db entry.
+    pub fn verify_package(&self, file: &Path, pgpsig: Option<&str>) -> io::Result<Verification> {
+        use base64::Engine;
+        use base64::prelude::BASE64_STANDARD as B64;
+        match (self.verify(file)?, pgpsig) {
+            (Verification::Missing, Some(pgpsig)) => {
+                let sig = B64.decode(pgpsig.trim()).map_err(invalid)?;
+                self.verify_detached(&std::fs::read(file)?, &sig)
+            }
+            (v, _) => Ok(v),
+        }
+    }
+
+    /// Checks data against the detached signature sig.
+    pub fn verify_detached(&self, data: &[u8], sig: &[u8]) -> io::Result<Verification> {
+        let policy = StandardPolicy::new();
+        let helper = Helper {
+            certs: &self.certs,
+            results: Vec::new(),
+        };
+        let mut verifier = match DetachedVerifierBuilder::from_bytes(sig)
+            .and_then(|b| b.with_policy(&policy, None, helper))
+        {
+            Ok(v) => v,
+            Err(e) => return Ok(Verification::Invalid(e.to_string())),
+        };
+        if let Err(e) = verifier.verify_bytes(data) {
+            return Ok(Verification::Invalid(e.to_string()));
+        }
+        let mut results = verifier.into_helper().results;
+        let valid = results
+            .iter()
+            .position(|r| matches!(r, Verification::Valid { .. }));
+        match valid.or((!results.is_empty()).then_some(0)) {
+            Some(pos) => Ok(results.swap_remove(pos)),
+            None => Err(invalid("no signature found")),
+        }
+    }
+}
+
+struct Helper<'k> {
+    certs: &'k [Cert],
+    results: Vec<Verification>,
+}
+
+impl VerificationHelper for Helper<'_> {
+    fn get_certs(&mut self, ids: &[KeyHandle]) -> openpgp::Result<Vec<Cert>> {
+        Ok(self
+            .certs
+            .iter()
+            .filter(|c| {
+                c.keys()
+                    .any(|k| ids.iter().any(|id| k.key().key_handle().aliases(id)))
+            })
+            .cloned()
+            .collect())
+    }
+
+    fn check(&mut self, structure: MessageStructure) -> openpgp::Result<()> {
+        for layer in structure {
+            let MessageLayer::SignatureGroup { results } = layer else {
+                continue;
+            };
+            for r in results {
+                self.results.push(match r {
+                    Ok(good) => Verification::Valid {
+                        fingerprint: good.ka.cert().fingerprint().to_hex(),
+                        key_id: good.ka.key().keyid().to_hex(),
+                    },
+                    Err(VerificationError::MissingKey { sig }) => Verification::UnknownKey {
+                        issuer: sig
+                            .get_issuers()
+                            .first()
+                            .map(|i| i.to_hex())
+                            .unwrap_or_default(),
+                    },
+                    Err(e) => Verification::Invalid(e.to_string()),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Checks `<repo>.db.sig` of every repo whose SigLevel wants database signatures,
+/// to be done before trusting what [crate::db::parse_syncdb] reads from them.
+/// returns (repo, verification) in repo order, check them with [Verification::satisfies]
+pub fn verify_syncdbs(config: &PacmanConfig) -> io::Result<Vec<(String, Verification)>> {
+    let keyring = Keyring::open(&config.gpg_dir)?;
+    let sync = config.db_path.join("sync");
+    let mut ret = Vec::new();
+    for repo in &config.repos {
+        if repo.sig_level.database.requirement == SigRequirement::Never {
+            continue;
+        }
+        let v = keyring.verify(&sync.join(format!("{}.db", repo.name)))?;
+        ret.push((repo.name.clone(), v));
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+pub(crate) fn test_cert(name: &str) -> Cert {
+    openpgp::cert::CertBuilder::general_purpose(Some(name))
+        .generate()
+        .unwrap()
+        .0
+}
+
+/// Detached signature of data by cert.
+#[cfg(test)]
+pub(crate) fn test_sign(cert: &Cert, data: &[u8]) -> Vec<u8> {
+    use openpgp::serialize::stream::{Message, Signer};
+    use std::io::Write;
+    let keypair = cert
+        .keys()
+        .unencrypted_secret()
+        .with_policy(&StandardPolicy::new(), None)
+        .for_signing()
+        .next()
+        .unwrap()
+        .key()
+        .clone()
+        .into_keypair()
+        .unwrap();
+    let mut sig = Vec::new();
+    let mut signer = Signer::new(Message::new(&mut sig), keypair)
+        .unwrap()
+        .detached()
+        .build()
+        .unwrap();
+    signer.write_all(data).unwrap();
+    signer.finalize().unwrap();
+    sig
+}
+
+#[cfg(test)]
+pub(crate) fn test_keyring(certs: &[&Cert]) -> Vec<u8> {
+    use openpgp::serialize::Serialize;
+    let mut keyring = Vec::new();
+    for cert in certs {
+        cert.serialize(&mut keyring).unwrap();
+    }
+    keyring
+}
+
+#[test]
+fn test_verify() {
+    let dir = crate::util::test_dir("pgp_verify");
+    let packager = test_cert("packager");
+    let stranger = test_cert("stranger");
+    let keyring = Keyring::from_bytes(&test_keyring(&[&packager])).unwrap();
+    assert_eq!(keyring.len(), 1);
+
+    let db = dir.join("core.db");
+    let sigfile = dir.join("core.db.sig");
+    std::fs::write(&db, b"db contents").unwrap();
+    assert_eq!(keyring.verify(&db).unwrap(), Verification::Missing);
+    assert!(
+        keyring
+            .verify(&db)
+            .unwrap()
+            .satisfies(SigRequirement::Optional)
+    );
+
+    std::fs::write(&sigfile, test_sign(&packager, b"db contents")).unwrap();
+    let fingerprint = packager.fingerprint().to_hex();
+    let v = keyring.verify(&db).unwrap();
+    assert!(matches!(&v, Verification::Valid { fingerprint: f, .. } if *f == fingerprint));
+
+    std::fs::write(&db, b"tampered").unwrap();
+    let v = keyring.verify(&db).unwrap();
+    assert!(matches!(v, Verification::Invalid(_)), "{v:?}");
+    assert!(!v.satisfies(SigRequirement::Optional));
+
+    std::fs::write(&sigfile, test_sign(&stranger, b"tampered")).unwrap();
+    let v = keyring.verify(&db).unwrap();
+    assert!(matches!(v, Verification::UnknownKey { .. }), "{v:?}");
+    assert!(!v.satisfies(SigRequirement::Required));
+    assert!(v.satisfies(SigRequirement::Never));
+}
+
+#[test]
+fn test_verify_package() {
+    use base64::Engine;
+    use base64::prelude::BASE64_STANDARD as B64;
+    let dir = crate::util::test_dir("pgp_verify_package");
+    let packager = test_cert("packager");
+    let keyring = Keyring::from_bytes(&test_keyring(&[&packager])).unwrap();
+    let pkg = dir.join("foo-1-1-x86_64.pkg.tar.zst");
+    std::fs::write(&pkg, b"package").unwrap();
+    assert_eq!(
+        keyring.verify_package(&pkg, None).unwrap(),
+        Verification::Missing
+    );
+
+    let pgpsig = B64.encode(test_sign(&packager, b"package"));
+    let v = keyring.verify_package(&pkg, Some(&pgpsig)).unwrap();
+    let Verification::Valid { key_id, .. } = v else {
+        panic!("{v:?}");
+    };
+    let signing_keys: Vec<_> = packager.keys().map(|k| k.key().keyid().to_hex()).collect();
+    assert!(signing_keys.contains(&key_id));
+
+    // a .sig file takes precedence over the db
+    std::fs::write(dir.join("foo-1-1-x86_64.pkg.tar.zst.sig"), b"garbage").unwrap();
+    let v = keyring.verify_package(&pkg, Some(&pgpsig)).unwrap();
+    assert!(matches!(v, Verification::Invalid(_)), "{v:?}");
+}
+
+#[test]
+fn test_inspect_keyring() {
+    use crate::db::{new_interner, test_desc};
+    use base64::Engine;
+    use base64::prelude::BASE64_STANDARD as B64;
+    let packager = test_cert("packager <packager@example.com>");
+    let stranger = test_cert("stranger");
+    let (revoked, revocation) = openpgp::cert::CertBuilder::general_purpose(Some("revoked"))
+        .generate()
+        .unwrap();
+    let revoked = revoked.insert_packets(revocation).unwrap().0;
+    let keyring = Keyring::from_bytes(&test_keyring(&[&packager, &revoked])).unwrap();
+
+    let keys = keyring.keys();
+    assert_eq!(keys.len(), 2);
+    assert_eq!(keys[0].user_ids, ["packager <packager@example.com>"]);
+    assert_eq!(keys[0].status, KeyStatus::Valid);
+    assert_eq!(keys[1].status, KeyStatus::Revoked);
+    let subkey = &keys[0].key_ids[1];
+    assert_eq!(keyring.key(&subkey.to_lowercase()).as_ref(), Some(&keys[0]));
+    let fingerprint = format!("0x{}", packager.fingerprint().to_hex());
+    assert_eq!(keyring.key(&fingerprint).as_ref(), Some(&keys[0]));
+    assert_eq!(keyring.key(&stranger.fingerprint().to_hex()), None);
+    assert_eq!(keyring.key("not hex"), None);
+
+    let i = new_interner();
+    let pkg = |name: &str, signer: &Cert| {
+        let pgpsig = B64.encode(test_sign(signer, name.as_bytes()));
+        let desc = test_desc(name, "1-1", &[("PGPSIG", &pgpsig)]);
+        Package::from_str(i.clone(), &desc).unwrap()
+    };
+    let unsigned = Package::from_str(i.clone(), &test_desc("unsigned", "1-1", &[])).unwrap();
+    let packages = [pkg("foo", &packager), pkg("bar", &stranger), unsigned];
+    let issuers = pgpsig_issuers(&B64.encode(test_sign(&stranger, b"bar"))).unwrap();
+    assert!(!issuers.is_empty());
+    let missing = keyring.missing_keys(&i, &packages).unwrap();
+    assert!(!missing.is_empty());
+    assert!(missing.iter().all(|(name, _)| name == "bar"));
+    assert!(missing.iter().all(|(_, issuer)| issuers.contains(issuer)));
+}
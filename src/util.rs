@@ -1,5 +1,8 @@
 #![allow(dead_code)]
 use std::cell::OnceCell;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
 
 #[derive(Default)]
 pub struct StableList<T> {
@@ -43,3 +46,158 @@ fn test_push() {
 
     println!("{v3} {v2} {v1}");
 }
+
+/// `.<name>.tmp` next to path, so it can be renamed over path.
+pub(crate) fn tmp_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{name}.tmp"))
+}
+
+/// Atomically replaces path with what write puts into a fresh file,
+/// readers see either the old or the new contents.
+pub(crate) fn replace(path: &Path, write: impl FnOnce(File) -> io::Result<()>) -> io::Result<()> {
+    let tmp = tmp_path(path);
+    let written = File::create(&tmp).and_then(write);
+    match written.and_then(|()| std::fs::rename(&tmp, path)) {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            let _ = std::fs::remove_file(&tmp);
+            Err(e)
+        }
+    }
+}
+
+/// Whether the digest D of the file at path is expected,
+/// which is the hex digest base64 decoded, as md5sum and sha256sum are stored in [crate::db::Package].
+pub(crate) fn digest_matches<D: sha2::Digest>(expected: &[u8], path: &Path) -> io::Result<bool> {
+    use base64::Engine;
+    use base64::prelude::BASE64_STANDARD_NO_PAD as B64;
+    use std::io::Read;
+    let mut f = File::open(path)?;
+    let mut digest = D::new();
+    let mut buf = vec![0; 1 << 16];
+    loop {
+        let n = f.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        digest.update(&buf[..n]);
+    }
+    let hex: String = digest
+        .finalize()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    Ok(B64.encode(expected) == hex)
+}
+
+/// Creates a fresh, empty directory below the system temp dir for tests.
+#[cfg(test)]
+pub(crate) fn test_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("libalpm-rs-{}-{name}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Shell style glob matching like fnmatch(3) without flags,
+/// so `*` and `?` also match `/`.
+/// Supports bracket expressions (`[a-z]`, `[!a]`) and backslash escapes.
+pub fn fnmatch(pattern: &str, name: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let n: Vec<char> = name.chars().collect();
+    fnmatch_(&p, &n)
+}
+
+fn fnmatch_(p: &[char], n: &[char]) -> bool {
+    match p.first() {
+        None => n.is_empty(),
+        Some('*') => {
+            let rest = &p[p.iter().take_while(|c| **c == '*').count()..];
+            (0..=n.len()).any(|skip| fnmatch_(rest, &n[skip..]))
+        }
+        Some('?') => !n.is_empty() && fnmatch_(&p[1..], &n[1..]),
+        Some('[') if !n.is_empty() => match bracket(&p[1..], n[0]) {
+            Some((matched, len)) => matched && fnmatch_(&p[1 + len..], &n[1..]),
+            // unterminated, treat as a literal [
+            None => n[0] == '[' && fnmatch_(&p[1..], &n[1..]),
+        },
+        Some('\\') if p.len() > 1 => n.first() == Some(&p[1]) && fnmatch_(&p[2..], &n[1..]),
+        Some(c) => n.first() == Some(c) && fnmatch_(&p[1..], &n[1..]),
+    }
+}
+
+/// Matches c against the bracket expression at the start of p (just after the `[`).
+/// Returns whether it matched and the length of the expression,
+/// None if the expression is unterminated.
+fn bracket(p: &[char], c: char) -> Option<(bool, usize)> {
+    let negate = matches!(p.first(), Some('!' | '^'));
+    let mut idx = usize::from(negate);
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        let cur = *p.get(idx)?;
+        if cur == ']' && !first {
+            return Some((matched != negate, idx + 1));
+        }
+        first = false;
+        match (p.get(idx + 1), p.get(idx + 2)) {
+            (Some('-'), Some(end)) if *end != ']' => {
+                matched |= (cur..=*end).contains(&c);
+                idx += 3;
+            }
+            _ => {
+                matched |= cur == c;
+                idx += 1;
+            }
+        }
+    }
+}
+
+#[test]
+fn test_fnmatch() {
+    assert!(fnmatch("etc/*", "etc/pacman.conf"));
+    assert!(fnmatch(
+        "usr/share/locale/*",
+        "usr/share/locale/de/LC_MESSAGES/x.mo"
+    ));
+    assert!(!fnmatch("etc/*", "usr/etc/x"));
+    assert!(fnmatch("*.conf", "etc/pacman.conf"));
+    assert!(fnmatch("etc/pac?an.conf", "etc/pacman.conf"));
+    assert!(fnmatch("[a-c]x", "bx"));
+    assert!(!fnmatch("[!a-c]x", "bx"));
+    assert!(fnmatch("[]]", "]"));
+    assert!(fnmatch("a\\*", "a*"));
+    assert!(!fnmatch("a\\*", "ab"));
+    assert!(fnmatch("[ab", "[ab"));
+    assert!(fnmatch("**", ""));
+    assert!(!fnmatch("a", ""));
+}
+
+/// Matches path against a pacman pattern list like NoExtract.
+/// Later patterns take precedence and a leading `!` negates a pattern,
+/// so `usr/share/locale/*` followed by `!usr/share/locale/en*` keeps english locales.
+pub fn match_patterns<S: AsRef<str>>(patterns: &[S], path: &str) -> bool {
+    let path = path.trim_start_matches('/');
+    for pattern in patterns.iter().rev() {
+        let pattern = pattern.as_ref();
+        let (negated, pattern) = match pattern.strip_prefix('!') {
+            Some(p) => (true, p),
+            None => (false, pattern),
+        };
+        if fnmatch(pattern, path) {
+            return !negated;
+        }
+    }
+    false
+}
+
+#[test]
+fn test_match_patterns() {
+    let p = ["usr/share/locale/*", "!usr/share/locale/en*"];
+    assert!(match_patterns(&p, "usr/share/locale/de/x.mo"));
+    assert!(match_patterns(&p, "/usr/share/locale/de/x.mo"));
+    assert!(!match_patterns(&p, "usr/share/locale/en_GB/x.mo"));
+    assert!(!match_patterns(&p, "usr/bin/pacman"));
+    assert!(!match_patterns::<&str>(&[], "usr/bin/pacman"));
+}